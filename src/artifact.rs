@@ -0,0 +1,323 @@
+use std::{mem::offset_of, path::Path, process::Command};
+
+use inkwell::{
+    context::Context,
+    execution_engine::ExecutionEngine,
+    module::{Linkage, Module},
+    targets::{CodeModel, FileType, RelocMode},
+    values::FunctionValue,
+    AddressSpace, IntPredicate, OptimizationLevel,
+};
+use tracing::warn;
+
+use crate::{
+    backend,
+    config::EngineConfig,
+    cpu::Cpu,
+    error::VmError,
+    program::analysis::Cfg,
+    translation::{self, TranslationContext, TranslationOptions},
+};
+
+/// Runs the program from the state in `cpu` until it halts, reaches code
+/// which was not compiled or `vt_stop` is set, returning the executed
+/// instructions.
+pub const ENTRY_NAME: &str = "vt_run";
+
+/// A byte the host sets to stop `vt_run` between blocks.
+pub const STOP_NAME: &str = "vt_stop";
+
+/// The declarations a C host needs to run an artifact. Compiled code hands
+/// the instructions accessing memory, host calls included, to
+/// `execute_on_host`, and reports side exits to `deoptimize`: outside of
+/// the engine, the host implements both over its own memory, passed to
+/// `vt_run` as an opaque pointer.
+pub const RUNTIME_HEADER: &str = "\
+#include <stdbool.h>
+#include <stdint.h>
+
+struct cpu {
+    int32_t acc;
+    int32_t lc;
+    uintptr_t pc;
+    bool halt;
+    uintptr_t sp;
+    int32_t gpr[6];
+    uint8_t flags;
+    uint64_t instret;
+    uint64_t cycles;
+    uint64_t timecmp;
+    uint8_t engine[32]; /* Trap state and overflow mode, untouched by compiled code */
+};
+
+extern volatile uint8_t vt_stop;
+uint64_t vt_run(struct cpu *cpu, void *memory);
+
+/* Provided by the host: returns 1 once the instruction ran, 0 if it faulted,
+   in which case the host sets halt or vt_stop for vt_run to return */
+uint64_t execute_on_host(struct cpu *cpu, void *memory, uint8_t opcode, uint16_t operand);
+void deoptimize(struct cpu *cpu, void *memory, uint8_t reason);
+";
+
+/// What `write_program` emits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArtifactKind {
+    Object,        // A relocatable object file
+    SharedLibrary, // A shared library, linked by the system C compiler
+}
+
+impl ArtifactKind {
+    /// The kind of artifact named by the extension of `path`.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("so" | "dylib" | "dll") => ArtifactKind::SharedLibrary,
+            _ => ArtifactKind::Object,
+        }
+    }
+}
+
+/// Compiles the program whose graph is `cfg` ahead of time into a native
+/// artifact at `path`, to run it outside of the engine, see
+/// `RUNTIME_HEADER`. The blocks are the ones the engine would compile,
+/// built by the LLVM backend following `config`, and `vt_run` dispatches
+/// between them on the program counter. Shared libraries are linked from
+/// an object file written next to them. Returns the number of blocks
+/// compiled, the ones LLVM fails on are left out.
+pub fn write_program(
+    cfg: &Cfg,
+    config: &EngineConfig,
+    path: &Path,
+    kind: ArtifactKind,
+) -> Result<usize, VmError> {
+    let context = Context::create();
+    let execution_engine = translation::create_execution_engine(&context, config.opt_level)?;
+    let module = context.create_module("program");
+    let options = TranslationOptions::from_config(config);
+
+    let mut blocks = Vec::new();
+    for (pc, block) in cfg.engine_blocks() {
+        let tbb = TranslationContext::new(
+            &context,
+            &execution_engine,
+            backend::symbol(pc, 0),
+            block.to_vec(),
+            options.clone(),
+        )?;
+        let linked = tbb.build_dynamic_basic_block().and_then(|()| {
+            module
+                .link_in_module(tbb.module().clone())
+                .map_err(|msg| VmError::CompilationFailed(msg.to_string()))
+        });
+        match linked {
+            Ok(()) => blocks.push((pc, tbb.name().to_string())),
+            Err(e) => warn!("wasn't capable to compile the block {:#04x}: {}", pc, e),
+        }
+    }
+
+    let functions: Vec<(usize, FunctionValue)> = blocks
+        .iter()
+        .filter_map(|(pc, name)| module.get_function(name).map(|function| (*pc, function)))
+        .collect();
+    build_entry(&context, &module, &execution_engine, &functions);
+    write_module(&module, config.opt_level, path, kind)?;
+    Ok(functions.len())
+}
+
+/// Verifies `module` and emits it for the host at `path`, as an object file
+/// or a shared library linked from an object file written next to it.
+pub(crate) fn write_module(
+    module: &Module,
+    opt_level: OptimizationLevel,
+    path: &Path,
+    kind: ArtifactKind,
+) -> Result<(), VmError> {
+    module
+        .verify()
+        .map_err(|msg| VmError::VerificationFailed(msg.to_string()))?;
+
+    // Shared libraries need position independent code
+    let machine = translation::host_target_machine(opt_level, RelocMode::PIC, CodeModel::Default)?;
+    module.set_triple(&machine.get_triple());
+    module.set_data_layout(&machine.get_target_data().get_data_layout());
+    let object = match kind {
+        ArtifactKind::Object => path.to_path_buf(),
+        ArtifactKind::SharedLibrary => path.with_extension("o"),
+    };
+    machine
+        .write_to_file(module, FileType::Object, &object)
+        .map_err(|msg| VmError::Io(format!("{}: {}", object.display(), msg)))?;
+
+    if kind == ArtifactKind::SharedLibrary {
+        let status = Command::new("cc")
+            .arg("-shared")
+            .arg("-o")
+            .arg(path)
+            .arg(&object)
+            .status()
+            .map_err(|e| VmError::Io(format!("cc: {}", e)))?;
+        if !status.success() {
+            return Err(VmError::Io(format!("cc failed to link {}", path.display())));
+        }
+    }
+    Ok(())
+}
+
+/// Builds `vt_run`, calling the function of the block at the program
+/// counter until the guest halts, gets stopped or leaves the compiled code.
+fn build_entry<'ctx>(
+    context: &'ctx Context,
+    module: &Module<'ctx>,
+    execution_engine: &ExecutionEngine<'ctx>,
+    blocks: &[(usize, FunctionValue<'ctx>)],
+) {
+    let i8_type = context.i8_type();
+    let i64_type = context.i64_type();
+    let pc_type = module
+        .get_context()
+        .ptr_sized_int_type(execution_engine.get_target_data(), None);
+    let byte_ptr_type = i8_type.ptr_type(AddressSpace::default());
+
+    let stop = module.add_global(i8_type, None, STOP_NAME);
+    stop.set_initializer(&i8_type.const_zero());
+
+    let entry_type = i64_type.fn_type(&[byte_ptr_type.into(), byte_ptr_type.into()], false);
+    let function = module.add_function(ENTRY_NAME, entry_type, Some(Linkage::External));
+    let cpu = function.get_nth_param(0).unwrap().into_pointer_value();
+    let memory = function.get_nth_param(1).unwrap().into_pointer_value();
+
+    let builder = context.create_builder();
+    let entry = context.append_basic_block(function, "entry");
+    let dispatch = context.append_basic_block(function, "dispatch");
+    let lookup = context.append_basic_block(function, "lookup");
+    let done = context.append_basic_block(function, "done");
+
+    builder.position_at_end(entry);
+    let executed = builder.build_alloca(i64_type, "executed");
+    builder.build_store(executed, i64_type.const_zero());
+    builder.build_unconditional_branch(dispatch);
+
+    // The Cpu is addressed byte-wise, its layout is the one of the Rust side
+    let field = |offset: usize, name: &str| unsafe {
+        builder.build_in_bounds_gep(cpu, &[i64_type.const_int(offset as u64, false)], name)
+    };
+    builder.position_at_end(dispatch);
+    let halt = builder.build_load(field(offset_of!(Cpu, halt), "halt_ptr"), "halt");
+    let stopped = builder.build_load(stop.as_pointer_value(), "stopped");
+    let leaving = builder.build_or(halt.into_int_value(), stopped.into_int_value(), "");
+    let leaving = builder.build_int_compare(IntPredicate::NE, leaving, i8_type.const_zero(), "");
+    builder.build_conditional_branch(leaving, done, lookup);
+
+    builder.position_at_end(lookup);
+    let pc_ptr = field(offset_of!(Cpu, pc), "pc_ptr");
+    let pc_ptr = builder.build_pointer_cast(pc_ptr, pc_type.ptr_type(AddressSpace::default()), "");
+    let pc = builder.build_load(pc_ptr, "pc").into_int_value();
+
+    let mut cases = Vec::with_capacity(blocks.len());
+    for (address, block) in blocks {
+        let case = context.append_basic_block(function, "");
+        builder.position_at_end(case);
+        // Blocks take typed pointers to the Cpu and the stop flag
+        let cpu_type = block
+            .get_nth_param(0)
+            .unwrap()
+            .into_pointer_value()
+            .get_type();
+        let stop_type = block
+            .get_nth_param(3)
+            .unwrap()
+            .into_pointer_value()
+            .get_type();
+        let run = builder
+            .build_call(
+                *block,
+                &[
+                    builder.build_pointer_cast(cpu, cpu_type, "").into(),
+                    memory.into(),
+                    i64_type.const_all_ones().into(),
+                    builder
+                        .build_pointer_cast(stop.as_pointer_value(), stop_type, "")
+                        .into(),
+                ],
+                "run",
+            )
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_int_value();
+        let total = builder.build_load(executed, "").into_int_value();
+        builder.build_store(executed, builder.build_int_nuw_add(total, run, ""));
+        builder.build_unconditional_branch(dispatch);
+        cases.push((pc_type.const_int(*address as u64, false), case));
+    }
+    builder.position_at_end(lookup);
+    builder.build_switch(pc, done, &cases);
+
+    builder.position_at_end(done);
+    let total = builder.build_load(executed, "total");
+    builder.build_return(Some(&total));
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+
+    use std::{
+        env,
+        ffi::{c_char, c_int, c_void, CString},
+        fs, mem, process, ptr,
+    };
+
+    use super::ENTRY_NAME;
+    use crate::{cpu::Cpu, program::asm, tests::init, EmulationEngine};
+
+    #[link(name = "dl")]
+    extern "C" {
+        fn dlopen(filename: *const c_char, flag: c_int) -> *mut c_void;
+        fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+    }
+
+    #[test]
+    pub fn programs_compile_to_native_artifacts() {
+        init();
+        // Compiled code only calls back the host for memory accesses, which
+        // the guest does not make, so the library runs without a runtime
+        let prog = asm::assemble(
+            "
+                LI 1000
+                SETL
+                CLRA
+            loop: INC3A; ADDI -1; MOV R2, A; DECA; BACK7 loop
+                HALT
+            ",
+        )
+        .unwrap();
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog.clone()).unwrap();
+        let start = vm.cpu;
+
+        let dir = env::temp_dir().join(format!("vt-vm-dyn-{}-artifact", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let object = dir.join("program.o");
+        assert!(vm.compile_to_file(&prog, &object).unwrap() > 0);
+        assert!(fs::read(&object).unwrap().starts_with(b"\x7fELF"));
+        let library = dir.join("program.so");
+        assert!(vm.compile_to_file(&prog, &library).unwrap() > 0);
+        vm.main_loop().unwrap();
+
+        // The library runs the program from the Cpu it is handed
+        type Entry = extern "C" fn(*mut Cpu, *mut c_void) -> u64;
+        let mut cpu = start;
+        let path = CString::new(library.to_str().unwrap()).unwrap();
+        let entry = CString::new(ENTRY_NAME).unwrap();
+        let executed = unsafe {
+            let handle = dlopen(path.as_ptr(), 1); // RTLD_LAZY
+            assert!(!handle.is_null());
+            let vt_run = dlsym(handle, entry.as_ptr());
+            assert!(!vt_run.is_null());
+            let vt_run = mem::transmute::<*mut c_void, Entry>(vt_run);
+            vt_run(&mut cpu, ptr::null_mut())
+        };
+        assert_eq!(cpu, vm.cpu);
+        assert_eq!(executed, vm.cpu.instret);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+use std::sync::{atomic::AtomicBool, Arc};
+
+use crate::{
+    baseline::BaselineBlock,
+    counted::CountedBlock,
+    cpu::{Cpu, Instruction},
+    error::VmError,
+    memory::MemoryPort,
+    profile::BranchCounts,
+    shared::SharedCode,
+    timing::CostTable,
+    trace::{ThreadedTrace, Trace},
+};
+
+/// The code generators shipped with the crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    #[cfg(feature = "jit")]
+    Llvm, // JIT compilation to native code through LLVM
+    Interpreter, // Threaded code, no code generation involved
+    Reference,   // Every instruction interpreted, without code cache: the semantic oracle
+    #[cfg(feature = "cranelift")]
+    Cranelift, // JIT compilation to native code through Cranelift
+}
+
+impl Default for BackendKind {
+    #[cfg(feature = "jit")]
+    fn default() -> Self {
+        BackendKind::Llvm
+    }
+
+    #[cfg(not(feature = "jit"))]
+    fn default() -> Self {
+        BackendKind::Interpreter
+    }
+}
+
+/// A dynamic basic block turned into executable code by a Backend. Blocks
+/// run on whichever thread runs the engine, and engines sharing code run
+/// the same blocks at once, see `shared::SharedCache`.
+pub trait CompiledBlock: Send + Sync {
+    /// Runs the block, which must not be handed a budget smaller than its
+    /// instruction count. Returns the executed instructions, a faulting
+    /// instruction is not counted and leaves its error in `memory`. Native
+    /// loops leave at the head of an iteration once `stop` is set.
+    fn execute(
+        &self,
+        cpu: &mut Cpu,
+        memory: &mut MemoryPort,
+        budget: u64,
+        stop: &AtomicBool,
+    ) -> u64;
+
+    /// What the backend produced for the block, as far as it can tell.
+    fn code_stats(&self) -> CodeStats {
+        CodeStats::default()
+    }
+
+    /// Where the native code of the block starts, if the backend emitted any.
+    fn code_address(&self) -> Option<usize> {
+        None
+    }
+
+    /// The intermediate representation the backend built for the block, in
+    /// its textual form, e.g. the LLVM IR of its module.
+    fn ir_text(&self) -> Option<String> {
+        None
+    }
+
+    /// The name of the native function of the block, see `symbol`.
+    fn symbol(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl<T: CompiledBlock + ?Sized> CompiledBlock for Arc<T> {
+    fn execute(
+        &self,
+        cpu: &mut Cpu,
+        memory: &mut MemoryPort,
+        budget: u64,
+        stop: &AtomicBool,
+    ) -> u64 {
+        T::execute(self, cpu, memory, budget, stop)
+    }
+
+    fn code_stats(&self) -> CodeStats {
+        T::code_stats(self)
+    }
+
+    fn code_address(&self) -> Option<usize> {
+        T::code_address(self)
+    }
+
+    fn ir_text(&self) -> Option<String> {
+        T::ir_text(self)
+    }
+
+    fn symbol(&self) -> Option<&str> {
+        T::symbol(self)
+    }
+}
+
+/// The size of the code generated for a block.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CodeStats {
+    pub ir_instructions: Option<usize>, // Instructions of the backend's intermediate representation
+    pub code_size: Option<usize>,       // Bytes of machine code
+}
+
+/// Turns the hot dynamic basic blocks found by the engine into code. The
+/// backend moves with the engine: state bound to the thread creating it,
+/// like an LLVM context, stays on a compiler thread of its own.
+pub trait Backend<'ctx>: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Compiles `block`, which starts at `pc` in the guest.
+    fn compile(
+        &self,
+        pc: usize,
+        block: &[Instruction],
+    ) -> Result<Box<dyn CompiledBlock + 'ctx>, VmError>;
+
+    /// Compiles `block` knowing how often the interpreter saw the BACK7
+    /// ending it taken, for backends laying the code out accordingly.
+    fn compile_profiled(
+        &self,
+        pc: usize,
+        block: &[Instruction],
+        _back7: Option<BranchCounts>,
+    ) -> Result<Box<dyn CompiledBlock + 'ctx>, VmError> {
+        self.compile(pc, block)
+    }
+
+    /// What the code of the backend depends on besides the instructions of
+    /// a block, i.e. its options, for backends whose code engines can share
+    /// through the `SharedCache`. None when the code lives and dies with
+    /// the engine.
+    fn share_key(&self) -> Option<String> {
+        None
+    }
+
+    /// Compiles `block` into code any engine may run, on any thread, see
+    /// `share_key`.
+    fn compile_shared(
+        &self,
+        _pc: usize,
+        _block: &[Instruction],
+        _back7: Option<BranchCounts>,
+    ) -> Result<SharedCode, VmError> {
+        Err(VmError::CompilationFailed(format!(
+            "the {} backend does not share its code",
+            self.name()
+        )))
+    }
+
+    /// Compiles a trace recorded across blocks, see `trace::Trace`. The
+    /// engine keeps running the blocks of the traces a backend refuses.
+    fn compile_trace(&self, _trace: &Trace) -> Result<Box<dyn CompiledBlock + 'ctx>, VmError> {
+        Err(VmError::CompilationFailed(format!(
+            "the {} backend does not compile traces",
+            self.name()
+        )))
+    }
+}
+
+/// The name of the native function compiled from the block at `pc`, the
+/// `version`th one compiled there, e.g. `dbb_0x1f_0`. Profilers and
+/// debuggers show it for the code of the block, see `symbol_pc`.
+pub fn symbol(pc: usize, version: u32) -> String {
+    format!("dbb_{:#x}_{}", pc, version)
+}
+
+/// The guest address of the block a function named by `symbol` was
+/// compiled from.
+pub fn symbol_pc(name: &str) -> Option<usize> {
+    let (pc, version) = name.strip_prefix("dbb_0x")?.split_once('_')?;
+    version.parse::<u32>().ok()?;
+    usize::from_str_radix(pc, 16).ok()
+}
+
+/// Runs blocks without generating any machine code, for hosts where LLVM
+/// is not available.
+pub struct InterpreterBackend {
+    costs: CostTable,
+}
+
+impl InterpreterBackend {
+    pub fn new(costs: CostTable) -> Self {
+        Self { costs }
+    }
+}
+
+impl<'ctx> Backend<'ctx> for InterpreterBackend {
+    fn name(&self) -> &'static str {
+        "interpreter"
+    }
+
+    fn compile(
+        &self,
+        _pc: usize,
+        block: &[Instruction],
+    ) -> Result<Box<dyn CompiledBlock + 'ctx>, VmError> {
+        match CountedBlock::compile(block, &self.costs) {
+            Some(counted) => Ok(Box::new(counted)),
+            None => Ok(Box::new(BaselineBlock::compile(block, &self.costs))),
+        }
+    }
+
+    fn share_key(&self) -> Option<String> {
+        Some(format!("{:?}", self.costs))
+    }
+
+    fn compile_shared(
+        &self,
+        _pc: usize,
+        block: &[Instruction],
+        _back7: Option<BranchCounts>,
+    ) -> Result<SharedCode, VmError> {
+        match CountedBlock::compile(block, &self.costs) {
+            Some(counted) => Ok(Arc::new(counted)),
+            None => Ok(Arc::new(BaselineBlock::compile(block, &self.costs))),
+        }
+    }
+
+    fn compile_trace(&self, trace: &Trace) -> Result<Box<dyn CompiledBlock + 'ctx>, VmError> {
+        Ok(Box::new(ThreadedTrace::compile(trace, &self.costs)))
+    }
+}
+
+impl CompiledBlock for BaselineBlock {
+    fn execute(
+        &self,
+        cpu: &mut Cpu,
+        memory: &mut MemoryPort,
+        _budget: u64,
+        _stop: &AtomicBool,
+    ) -> u64 {
+        BaselineBlock::execute(self, cpu, memory)
+    }
+}
+
+impl CompiledBlock for CountedBlock {
+    fn execute(
+        &self,
+        cpu: &mut Cpu,
+        memory: &mut MemoryPort,
+        budget: u64,
+        stop: &AtomicBool,
+    ) -> u64 {
+        CountedBlock::execute(self, cpu, memory, budget, stop)
+    }
+}
+
+impl CompiledBlock for ThreadedTrace {
+    fn execute(
+        &self,
+        cpu: &mut Cpu,
+        memory: &mut MemoryPort,
+        _budget: u64,
+        _stop: &AtomicBool,
+    ) -> u64 {
+        ThreadedTrace::execute(self, cpu, memory)
+    }
+}
@@ -0,0 +1,177 @@
+use crate::{
+    cpu::{Cpu, Handler, Instruction, OpCode, HANDLERS},
+    error::VmError,
+    memory::MemoryPort,
+    timing::CostTable,
+};
+
+/// Instructions fused when a block is quickened, run with a single
+/// dispatch. They behave exactly like the instructions they replace, flags
+/// and faults included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Superinstruction {
+    Inc3a(u64), // INC3A repeated
+    Deca(u64),  // DECA repeated
+    DecaBack7,  // DECA then BACK7, the tail of countdown loops
+}
+
+impl Superinstruction {
+    /// The number of instructions fused.
+    pub fn length(&self) -> u64 {
+        match self {
+            Superinstruction::Inc3a(count) | Superinstruction::Deca(count) => *count,
+            Superinstruction::DecaBack7 => 2,
+        }
+    }
+
+    /// Runs the fused instructions, returning how many completed before
+    /// one faulted, if any.
+    fn run(&self, cpu: &mut Cpu) -> (u64, Result<(), VmError>) {
+        match *self {
+            Superinstruction::Inc3a(count) => repeat(cpu, Cpu::inc3a, count),
+            Superinstruction::Deca(count) => repeat(cpu, Cpu::deca, count),
+            Superinstruction::DecaBack7 => match cpu.deca() {
+                Ok(()) => {
+                    cpu.back7();
+                    (2, Ok(()))
+                }
+                Err(e) => (0, Err(e)),
+            },
+        }
+    }
+}
+
+fn repeat(
+    cpu: &mut Cpu,
+    step: impl Fn(&mut Cpu) -> Result<(), VmError>,
+    count: u64,
+) -> (u64, Result<(), VmError>) {
+    for done in 0..count {
+        if let Err(e) = step(cpu) {
+            return (done, Err(e));
+        }
+    }
+    (count, Ok(()))
+}
+
+enum Op {
+    Single(Handler, Instruction),
+    Fused(Superinstruction),
+}
+
+/// The baseline tier: a block turned into threaded code, i.e. the list of
+/// the handlers of its instructions. Running it skips fetching and decoding
+/// while costing next to nothing to build, unlike an LLVM compilation.
+/// Blocks are quickened on the way, replacing common sequences with
+/// superinstructions.
+pub struct BaselineBlock {
+    ops: Vec<Op>,
+    host_instruction: Option<Instruction>, // The instruction ending the block run by the host, if any
+    cycles: Vec<u64>, // Cycles taken by the first n instructions of the block, at index n
+}
+
+impl BaselineBlock {
+    pub fn compile(block: &[Instruction], costs: &CostTable) -> Self {
+        let (body, host_instruction) = match block.split_last() {
+            Some((last, body)) if last.opcode.needs_host() => (body, Some(*last)),
+            _ => (block, None),
+        };
+
+        Self {
+            ops: quicken(body),
+            host_instruction,
+            cycles: std::iter::once(0)
+                .chain(block.iter().scan(0, |cycles, instr| {
+                    *cycles += costs.cost(instr.opcode);
+                    Some(*cycles)
+                }))
+                .collect(),
+        }
+    }
+
+    /// The superinstructions the block was quickened with, in order.
+    pub fn superinstructions(&self) -> Vec<Superinstruction> {
+        self.ops
+            .iter()
+            .filter_map(|op| match op {
+                Op::Fused(fused) => Some(*fused),
+                Op::Single(..) => None,
+            })
+            .collect()
+    }
+
+    /// The number of dispatches running the whole block takes.
+    pub fn dispatches(&self) -> usize {
+        self.ops.len() + usize::from(self.host_instruction.is_some())
+    }
+
+    /// Runs the whole block, returning the number of executed instructions.
+    /// A faulting instruction stops the block and leaves its error in
+    /// `memory`.
+    pub fn execute(&self, cpu: &mut Cpu, memory: &mut MemoryPort) -> u64 {
+        let executed = self.run(cpu, memory);
+        cpu.instret += executed;
+        cpu.cycles += self.cycles[executed as usize];
+        executed
+    }
+
+    fn run(&self, cpu: &mut Cpu, memory: &mut MemoryPort) -> u64 {
+        let mut executed = 0;
+        for op in &self.ops {
+            let (done, result) = match op {
+                Op::Single(handler, instr) => (1, handler(cpu, memory, *instr)),
+                Op::Fused(fused) => fused.run(cpu),
+            };
+            if let Err(e) = result {
+                memory.fault(e);
+                return executed + done;
+            }
+            executed += done;
+        }
+
+        if let Some(instr) = self.host_instruction {
+            match cpu.execute(instr, memory) {
+                Ok(()) => executed += 1,
+                Err(e) => memory.fault(e),
+            }
+        }
+        executed
+    }
+}
+
+/// Replaces runs of INC3A or DECA and the DECA BACK7 pairs of `block` with
+/// superinstructions, the other instructions keep their handler.
+fn quicken(block: &[Instruction]) -> Vec<Op> {
+    let mut ops = Vec::new();
+    let mut rest = block;
+    while let Some((first, tail)) = rest.split_first() {
+        let run = 1 + tail
+            .iter()
+            .take_while(|instr| instr.opcode == first.opcode)
+            .count();
+        let next = |index: usize| rest.get(index).map(|instr| instr.opcode);
+        let single = (Op::Single(HANDLERS[first.opcode as usize], *first), 1);
+
+        let (op, length) = match first.opcode {
+            OpCode::INC3A if run > 1 => (Op::Fused(Superinstruction::Inc3a(run as u64)), run),
+            OpCode::DECA if run == 1 && next(1) == Some(OpCode::BACK7) => {
+                (Op::Fused(Superinstruction::DecaBack7), 2)
+            }
+            OpCode::DECA => {
+                // The last DECA of a run followed by BACK7 is fused with it
+                let count = match next(run) {
+                    Some(OpCode::BACK7) => run - 1,
+                    _ => run,
+                };
+                match count {
+                    1 => single,
+                    _ => (Op::Fused(Superinstruction::Deca(count as u64)), count),
+                }
+            }
+            _ => single,
+        };
+        ops.push(op);
+        rest = &rest[length..];
+    }
+    ops
+}
@@ -0,0 +1,68 @@
+use std::{
+    panic,
+    sync::{Mutex, PoisonError},
+    thread,
+};
+
+use crate::{
+    config::EngineConfig, error::VmError, memory::Memory, program::Program,
+    report::ExecutionReport, EmulationEngine,
+};
+
+/// Runs every program of `programs` until it halts, each on an engine of
+/// its own built from `config`, so with its own Cpu, memory and code
+/// cache. Up to `parallelism` engines run at once, on as many threads,
+/// which take the next program waiting once theirs is done. Returns the
+/// reports in the order of the programs, the ones failing with their error.
+pub fn run_batch(
+    config: &EngineConfig,
+    programs: Vec<Program>,
+    parallelism: usize,
+) -> Result<Vec<Result<ExecutionReport, VmError>>, VmError> {
+    config.validate()?;
+    if parallelism == 0 {
+        return Err(VmError::InvalidConfig(
+            "a batch needs at least one thread".to_string(),
+        ));
+    }
+
+    let count = programs.len();
+    let pending = Mutex::new(programs.into_iter().enumerate());
+    let mut reports: Vec<Option<Result<ExecutionReport, VmError>>> =
+        (0..count).map(|_| None).collect();
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..parallelism.min(count))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        // Released before running the program
+                        let next = pending
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .next();
+                        let Some((index, program)) = next else {
+                            break done;
+                        };
+                        done.push((index, run(config, program)));
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            // Engines report their failures, only a panic gets here
+            let done = worker.join().unwrap_or_else(|e| panic::resume_unwind(e));
+            for (index, report) in done {
+                reports[index] = Some(report);
+            }
+        }
+    });
+    Ok(reports.into_iter().flatten().collect())
+}
+
+fn run(config: &EngineConfig, program: Program) -> Result<ExecutionReport, VmError> {
+    let memory = Box::new(Memory::new(config.memory_size));
+    let mut vm = EmulationEngine::from_config(config.clone(), memory)?;
+    vm.load_program(program)?;
+    vm.main_loop()
+}
@@ -0,0 +1,458 @@
+//! Command line front-end of the VM.
+//!
+//! vtvm run prog.vt [--jit-threshold N] [--backend NAME] [--fuel N]
+//!                  [--timeline trace.json] [--perf-map] [--trace-threshold N]
+//!                  [--save-profile prog.prof] [--load-profile prog.prof] [--align-blocks]
+//!                  [--aot] [--dump-ir dir] [--timeout ms] [--console] [--seed N]
+//!                  [--wall-clock HZ] [--pace HZ] [--digest N] [--export trace.jsonl]
+//! vtvm disasm prog.vt
+//! vtvm cfg prog.vt [--out cfg.dot]
+//! vtvm compile prog.vt --out prog.o [--opt-level N]
+//! vtvm trace prog.vt [--out trace.json] [--jit-threshold N] [--backend NAME]
+//! vtvm monitor prog.vt [--jit-threshold N] [--backend NAME]
+//! vtvm coverage prog.vt [--jit-threshold N] [--backend NAME] [--fuel N]
+//! vtvm profile prog.vt [--top N] [--jit-threshold N] [--backend NAME] [--fuel N]
+//! vtvm replay prog.vt --trace trace.jsonl [--jit-threshold N] [--backend NAME]
+//!
+//! Programs are read from program files, or assembled when their name ends
+//! with `.asm`. `--timeline` writes when blocks were run and compiled as a
+//! Chrome trace, to be opened with chrome://tracing or Perfetto. With
+//! `--perf-map`, `perf record` attributes the time spent in compiled blocks
+//! to their guest address. `--trace-threshold N` compiles the paths run
+//! from the loop heads reached N times as traces. `--save-profile` writes
+//! the hot blocks and branches of the run, which `--load-profile` compiles
+//! at startup on the next runs, skipping the warmup. `--align-blocks` ends
+//! blocks at the basic block leaders of the program, and `--aot` compiles
+//! all of them before running it. `--dump-ir` writes the IR of the blocks
+//! compiled by the backend, as one file per block named by its address.
+//! `--timeout` fails the runs going on for longer than the milliseconds
+//! given, e.g. guests stuck in a loop. `--console` maps the console at
+//! 0xff00 and prints the bytes the guest stores there on the standard output.
+//! `--seed N` maps a random number generator seeded with N at 0xff04, the
+//! same seed giving the same bytes on every run. `--wall-clock HZ` makes
+//! RDTIME read the cycles of a processor at HZ hertz since the engine started
+//! instead of the virtual ones, which are deterministic. `--pace HZ` slows
+//! the guest down to HZ virtual cycles a second, e.g. its original speed.
+//! `--digest N` prints a hash of the pc, acc and lc of the guest taken
+//! every N instructions, the same for every backend as long as the program
+//! behaves the same. `--export` writes every instruction interpreted and
+//! every block compiled code ran, with the registers they changed, as JSON
+//! Lines, or in binary when the file name ends with `.bin`. `replay` runs
+//! the program through the events of such a trace, and reports the first
+//! one it does not reproduce.
+//! Every command running the program accepts `--opt-level N`, from 0 to 3,
+//! the optimization level of the backend. `cfg` writes the static
+//! control-flow graph of the program in the DOT language, e.g.
+//! `vtvm cfg prog.vt | dot -Tsvg`.
+//! `compile`, with the jit feature, compiles the program ahead of time into
+//! an object file, or a shared library when the output ends with `.so`,
+//! next to a C header declaring how to run it.
+
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::Path,
+    process::ExitCode,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
+
+#[cfg(feature = "jit")]
+use vt_vm_dyn::artifact;
+use vt_vm_dyn::{
+    backend::BackendKind,
+    config::{EmulationEngineBuilder, OptimizationLevel},
+    cpu::Cpu,
+    error::VmError,
+    export::{self, BinaryTrace, JsonLines, TraceExporter, TraceGranularity},
+    monitor::Monitor,
+    observer::{ExecutionObserver, Tier},
+    playback::TraceReplayer,
+    profile::WarmupProfile,
+    program::{analysis::Cfg, asm, disasm, Program},
+    rng::RNG_ADDRESS,
+    EmulationEngine,
+};
+
+const USAGE: &str = "usage:
+    vtvm run <program> [--jit-threshold N] [--backend NAME] [--fuel N] [--timeline FILE]
+             [--perf-map] [--trace-threshold N] [--save-profile FILE] [--load-profile FILE]
+             [--align-blocks] [--aot] [--dump-ir DIR] [--timeout MS] [--console]
+             [--seed N] [--wall-clock HZ] [--pace HZ] [--digest N] [--export FILE]
+    vtvm disasm <program>
+    vtvm cfg <program> [--out FILE]
+    vtvm compile <program> --out FILE (jit feature)
+    vtvm trace <program> [--out FILE] [--jit-threshold N] [--backend NAME]
+    vtvm monitor <program> [--jit-threshold N] [--backend NAME]
+    vtvm coverage <program> [--jit-threshold N] [--backend NAME] [--fuel N]
+    vtvm profile <program> [--top N] [--jit-threshold N] [--backend NAME] [--fuel N]
+    vtvm replay <program> --trace FILE [--jit-threshold N] [--backend NAME]
+
+backends: interpreter, reference, llvm (jit feature), cranelift (cranelift feature)
+--opt-level N, from 0 to 3, sets the optimization level of the llvm and cranelift backends";
+
+fn main() -> ExitCode {
+    // Closing spans report the time spent in them, e.g. RUST_LOG=debug
+    // shows how long every block took to compile and to run
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(std::io::stderr)
+        .init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match vtvm(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(msg) => {
+            eprintln!("vtvm: {}", msg);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn vtvm(args: &[String]) -> Result<(), String> {
+    let (Some(command), Some(path)) = (args.first(), args.get(1)) else {
+        return Err(USAGE.to_string());
+    };
+    let options = Options::parse(&args[2..])?;
+    let program = load(path).map_err(|e| e.to_string())?;
+
+    match command.as_str() {
+        "run" => run(program, &options),
+        "disasm" => {
+            print!("{}", disasm::disassemble(&program.data, 0));
+            Ok(())
+        }
+        "cfg" => {
+            let dot = Cfg::build(&program).to_dot();
+            match &options.out {
+                Some(path) => std::fs::write(path, dot).map_err(|e| format!("{}: {}", path, e)),
+                None => {
+                    print!("{}", dot);
+                    Ok(())
+                }
+            }
+        }
+        #[cfg(feature = "jit")]
+        "compile" => compile(program, &options),
+        "trace" => trace(program, &options),
+        "monitor" => monitor(program, &options),
+        "coverage" => coverage(program, &options),
+        "profile" => profile(program, &options),
+        "replay" => replay(program, &options),
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+#[derive(Default)]
+struct Options {
+    jit_threshold: Option<u64>,
+    trace_threshold: Option<u64>,
+    backend: Option<BackendKind>,
+    opt_level: Option<OptimizationLevel>,
+    fuel: Option<u64>,
+    timeout: Option<u64>,
+    seed: Option<u64>,
+    wall_clock: Option<u64>,
+    pace: Option<u64>,
+    digest: Option<u64>,
+    out: Option<String>,
+    timeline: Option<String>,
+    save_profile: Option<String>,
+    load_profile: Option<String>,
+    dump_ir: Option<String>,
+    export: Option<String>,
+    trace: Option<String>,
+    perf_map: bool,
+    align_blocks: bool,
+    aot: bool,
+    console: bool,
+    top: Option<u64>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Options::default();
+        let mut args = args.iter();
+        while let Some(option) = args.next() {
+            if option == "--perf-map" {
+                options.perf_map = true;
+                continue;
+            }
+            if option == "--align-blocks" {
+                options.align_blocks = true;
+                continue;
+            }
+            if option == "--aot" {
+                options.aot = true;
+                continue;
+            }
+            if option == "--console" {
+                options.console = true;
+                continue;
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} expects a value", option))?;
+            let number = || {
+                value
+                    .parse::<u64>()
+                    .map_err(|_| format!("{} expects a number, not {}", option, value))
+            };
+            match option.as_str() {
+                "--jit-threshold" => options.jit_threshold = Some(number()?),
+                "--trace-threshold" => options.trace_threshold = Some(number()?),
+                "--fuel" => options.fuel = Some(number()?),
+                "--timeout" => options.timeout = Some(number()?),
+                "--seed" => options.seed = Some(number()?),
+                "--wall-clock" => options.wall_clock = Some(number()?),
+                "--pace" => options.pace = Some(number()?),
+                "--digest" => options.digest = Some(number()?),
+                "--top" => options.top = Some(number()?),
+                "--backend" => options.backend = Some(backend(value)?),
+                "--opt-level" => options.opt_level = Some(opt_level(value)?),
+                "--out" => options.out = Some(value.clone()),
+                "--timeline" => options.timeline = Some(value.clone()),
+                "--save-profile" => options.save_profile = Some(value.clone()),
+                "--load-profile" => options.load_profile = Some(value.clone()),
+                "--dump-ir" => options.dump_ir = Some(value.clone()),
+                "--export" => options.export = Some(value.clone()),
+                "--trace" => options.trace = Some(value.clone()),
+                _ => return Err(format!("unknown option {}\n{}", option, USAGE)),
+            }
+        }
+        Ok(options)
+    }
+
+    fn engine(&self) -> Result<EmulationEngine, String> {
+        let mut builder = EmulationEngineBuilder::new()
+            .perf_map(self.perf_map)
+            .align_blocks(self.align_blocks)
+            .ahead_of_time(self.aot);
+        if self.console {
+            builder = builder.console_sink(Box::new(std::io::stdout()));
+        }
+        if let Some(seed) = self.seed {
+            builder = builder.rng(RNG_ADDRESS, seed);
+        }
+        if let Some(frequency) = self.wall_clock {
+            builder = builder.wall_clock(frequency);
+        }
+        if let Some(frequency) = self.pace {
+            builder = builder.pace(frequency);
+        }
+        if let Some(threshold) = self.jit_threshold {
+            builder = builder.compile_threshold(threshold);
+        }
+        if let Some(threshold) = self.trace_threshold {
+            builder = builder.trace_threshold(threshold);
+        }
+        if let Some(backend) = self.backend {
+            builder = builder.backend(backend);
+        }
+        if let Some(opt_level) = self.opt_level {
+            builder = builder.opt_level(opt_level);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.deadline(Duration::from_millis(timeout));
+        }
+        builder.build().map_err(|e| e.to_string())
+    }
+}
+
+fn backend(name: &str) -> Result<BackendKind, String> {
+    match name {
+        "interpreter" => Ok(BackendKind::Interpreter),
+        "reference" => Ok(BackendKind::Reference),
+        #[cfg(feature = "jit")]
+        "llvm" => Ok(BackendKind::Llvm),
+        #[cfg(feature = "cranelift")]
+        "cranelift" => Ok(BackendKind::Cranelift),
+        _ => Err(format!("unknown backend {}", name)),
+    }
+}
+
+fn opt_level(level: &str) -> Result<OptimizationLevel, String> {
+    match level {
+        "0" => Ok(OptimizationLevel::None),
+        "1" => Ok(OptimizationLevel::Less),
+        "2" => Ok(OptimizationLevel::Default),
+        "3" => Ok(OptimizationLevel::Aggressive),
+        _ => Err(format!(
+            "unknown optimization level {}, expected 0 to 3",
+            level
+        )),
+    }
+}
+
+fn load(path: &str) -> Result<Program, VmError> {
+    if Path::new(path).extension().is_some_and(|ext| ext == "asm") {
+        let source =
+            std::fs::read_to_string(path).map_err(|e| VmError::Io(format!("{}: {}", path, e)))?;
+        return asm::assemble(&source);
+    }
+    Program::from_file(path)
+}
+
+fn run(program: Program, options: &Options) -> Result<(), String> {
+    let mut vm = options.engine()?;
+    vm.load_program(program).map_err(|e| e.to_string())?;
+    if let Some(path) = &options.load_profile {
+        let profile = WarmupProfile::from_file(path).map_err(|e| e.to_string())?;
+        vm.warm_up(&profile).map_err(|e| e.to_string())?;
+    }
+    if options.timeline.is_some() {
+        vm.start_timeline();
+    }
+    if let Some(interval) = options.digest {
+        vm.start_digest(interval).map_err(|e| e.to_string())?;
+    }
+    if let Some(path) = &options.export {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        let file = BufWriter::new(file);
+        let granularity = TraceGranularity::Instructions;
+        let extension = Path::new(path).extension().and_then(|e| e.to_str());
+        match extension {
+            Some("bin") => vm.add_observer(Box::new(TraceExporter::new(
+                BinaryTrace::new(file),
+                granularity,
+            ))),
+            _ => vm.add_observer(Box::new(TraceExporter::new(
+                JsonLines::new(file),
+                granularity,
+            ))),
+        }
+    }
+
+    let result = match options.fuel {
+        Some(fuel) => vm
+            .run_for(fuel)
+            .map(|outcome| print!("{:?}, {}", outcome, vm.cpu())),
+        None => vm.main_loop().map(|report| print!("{}", report)),
+    };
+
+    if let Some(digest) = vm.stop_digest() {
+        println!("digest: {:016x}", digest);
+    }
+    // The timeline of a failed run is the most interesting one
+    if let (Some(path), Some(timeline)) = (&options.timeline, vm.stop_timeline()) {
+        timeline.save(path).map_err(|e| e.to_string())?;
+    }
+    if let Some(path) = &options.save_profile {
+        vm.warmup_profile().save(path).map_err(|e| e.to_string())?;
+    }
+    if let Some(dir) = &options.dump_ir {
+        vm.dump_ir(dir).map_err(|e| e.to_string())?;
+    }
+    result.map_err(|e| e.to_string())
+}
+
+/// Runs the program, then lists the parts of it that were never executed.
+fn coverage(program: Program, options: &Options) -> Result<(), String> {
+    let mut vm = options.engine()?;
+    let code = program.data.clone();
+    vm.load_program(program).map_err(|e| e.to_string())?;
+    vm.start_coverage();
+    let result = match options.fuel {
+        Some(fuel) => vm.run_for(fuel).map(|_| ()),
+        None => vm.main_loop().map(|_| ()),
+    };
+
+    let coverage = vm.stop_coverage().unwrap_or_default();
+    print!("{}", coverage.report(0..code.len()));
+    for region in coverage.uncovered(0..code.len()) {
+        print!(
+            "\n{}",
+            disasm::disassemble(&code[region.clone()], region.start)
+        );
+    }
+    result.map_err(|e| e.to_string())
+}
+
+/// Runs the program through the events of an exported trace.
+fn replay(program: Program, options: &Options) -> Result<(), String> {
+    let path = options.trace.as_ref().ok_or(USAGE)?;
+    let events = export::read_trace_file(path).map_err(|e| e.to_string())?;
+    let count = events.len();
+    let mut vm = options.engine()?;
+    vm.load_program(program).map_err(|e| e.to_string())?;
+    let outcome = TraceReplayer::new(events)
+        .replay(&mut vm)
+        .map_err(|mismatch| mismatch.to_string())?;
+    println!("{} events reproduced, {:?}", count, outcome);
+    Ok(())
+}
+
+/// Runs the program, then ranks its hottest blocks, 10 by default.
+fn profile(program: Program, options: &Options) -> Result<(), String> {
+    let mut vm = options.engine()?;
+    vm.load_program(program).map_err(|e| e.to_string())?;
+    vm.start_profiling();
+    let result = match options.fuel {
+        Some(fuel) => vm.run_for(fuel).map(|_| ()),
+        None => vm.main_loop().map(|_| ()),
+    };
+
+    let top = options.top.unwrap_or(10) as usize;
+    print!("{}", vm.profile_report(top).map_err(|e| e.to_string())?);
+    result.map_err(|e| e.to_string())
+}
+
+/// Debugs the program interactively, reading commands from the standard input.
+fn monitor(program: Program, options: &Options) -> Result<(), String> {
+    let mut vm = options.engine()?;
+    vm.load_program(program).map_err(|e| e.to_string())?;
+    Monitor::new(vm)
+        .run(std::io::stdin().lock(), std::io::stdout())
+        .map_err(|e| e.to_string())
+}
+
+/// Compiles the program ahead of time into the file named by `--out`,
+/// writing the C header declaring its runtime interface next to it.
+#[cfg(feature = "jit")]
+fn compile(program: Program, options: &Options) -> Result<(), String> {
+    let out = options.out.as_ref().ok_or("compile expects --out FILE")?;
+    let path = Path::new(out);
+    let vm = options.engine()?;
+    let compiled = vm
+        .compile_to_file(&program, path)
+        .map_err(|e| e.to_string())?;
+    let header = path.with_extension("h");
+    std::fs::write(&header, artifact::RUNTIME_HEADER)
+        .map_err(|e| format!("{}: {}", header.display(), e))?;
+    println!("{} blocks compiled into {}", compiled, out);
+    Ok(())
+}
+
+/// Writes the blocks executed by the program as a JSON array.
+fn trace(program: Program, options: &Options) -> Result<(), String> {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let mut vm = options.engine()?;
+    vm.add_observer(Box::new(Tracer {
+        events: events.clone(),
+    }));
+    vm.load_program(program).map_err(|e| e.to_string())?;
+    let result = vm.main_loop();
+
+    let json = format!("[\n{}\n]\n", events.lock().unwrap().join(",\n"));
+    match &options.out {
+        Some(path) => std::fs::write(path, json).map_err(|e| format!("{}: {}", path, e))?,
+        None => print!("{}", json),
+    }
+    result.map(|_| ()).map_err(|e| e.to_string())
+}
+
+struct Tracer {
+    events: Arc<Mutex<Vec<String>>>,
+}
+
+impl ExecutionObserver for Tracer {
+    fn on_block_executed(&mut self, pc: usize, tier: Tier, cpu: &Cpu) {
+        self.events.lock().unwrap().push(format!(
+            "  {{\"pc\": {}, \"tier\": \"{:?}\", \"instret\": {}, \"cycles\": {}, \"acc\": {}, \"lc\": {}}}",
+            pc, tier, cpu.instret, cpu.cycles, cpu.acc, cpu.lc
+        ));
+    }
+}
@@ -0,0 +1,149 @@
+use std::ops::Range;
+
+use crate::{error::VmError, memory::Addressable};
+
+/// A device answering to the accesses in the address range it claimed on
+/// the Bus. Offsets are relative to the start of that range. Devices move
+/// with the engine, which may run on another thread than the one creating
+/// it, or be shared by several threads.
+pub trait MmioDevice: Send + Sync {
+    /// Reads the register at `offset` for the host, e.g. a debugger, which
+    /// must not change the state of the device.
+    fn read(&self, offset: usize) -> u8;
+
+    /// Reads the register at `offset` for the guest. Returns None while the
+    /// device has nothing to answer yet, which blocks the guest on the
+    /// access, see `mailbox::Mailbox`.
+    fn try_read(&self, offset: usize) -> Option<u8> {
+        Some(self.read(offset))
+    }
+
+    fn write(&mut self, offset: usize, value: u8);
+}
+
+struct Mapping {
+    range: Range<usize>,
+    device: Box<dyn MmioDevice>,
+}
+
+/// The memory bus: accesses go to the device mapped at the address, if
+/// any, and to the guest memory otherwise.
+pub struct Bus {
+    memory: Box<dyn Addressable<u8> + Send + Sync>,
+    mappings: Vec<Mapping>,
+    stack: Range<usize>, // Region of the guest memory holding the stack
+}
+
+impl Bus {
+    pub fn new(memory: Box<dyn Addressable<u8> + Send + Sync>) -> Self {
+        Self {
+            memory,
+            mappings: Vec::new(),
+            stack: 0..0,
+        }
+    }
+
+    /// Reserves the top `size` bytes of the guest memory for the stack. The
+    /// region stays in place when the memory grows.
+    pub fn reserve_stack(&mut self, size: usize) -> Result<(), VmError> {
+        let top = self.memory.size();
+        if size > top {
+            return Err(VmError::InvalidConfig(format!(
+                "a stack of {} bytes does not fit in {} bytes of memory",
+                size, top
+            )));
+        }
+
+        self.stack = top - size..top;
+        Ok(())
+    }
+
+    /// Maps `device` over `range`, which must not be empty nor overlap the
+    /// range of another device. Devices shadow the memory they cover.
+    pub fn attach(
+        &mut self,
+        range: Range<usize>,
+        device: Box<dyn MmioDevice>,
+    ) -> Result<(), VmError> {
+        if range.is_empty() {
+            return Err(VmError::InvalidConfig(format!(
+                "cannot map a device over the empty range {:#04x}..{:#04x}",
+                range.start, range.end
+            )));
+        }
+
+        let overlaps = self
+            .mappings
+            .iter()
+            .any(|m| m.range.start < range.end && range.start < m.range.end);
+        if overlaps {
+            return Err(VmError::InvalidConfig(format!(
+                "the range {:#04x}..{:#04x} is already claimed by another device",
+                range.start, range.end
+            )));
+        }
+
+        self.mappings.push(Mapping { range, device });
+        Ok(())
+    }
+
+    /// Whether a device answers to `address` instead of the memory.
+    pub fn is_mapped(&self, address: usize) -> bool {
+        self.mappings.iter().any(|m| m.range.contains(&address))
+    }
+
+    /// Reads `address` for the host, leaving the devices as they are.
+    pub fn peek(&self, address: usize) -> Result<u8, VmError> {
+        match self.mappings.iter().find(|m| m.range.contains(&address)) {
+            Some(m) => Ok(m.device.read(address - m.range.start)),
+            None => self.memory.read(address),
+        }
+    }
+
+    pub fn memory(&self) -> &dyn Addressable<u8> {
+        self.memory.as_ref()
+    }
+
+    pub fn memory_mut(&mut self) -> &mut dyn Addressable<u8> {
+        self.memory.as_mut()
+    }
+}
+
+impl Addressable<u8> for Bus {
+    fn read(&self, address: usize) -> Result<u8, VmError> {
+        match self.mappings.iter().find(|m| m.range.contains(&address)) {
+            Some(m) => m
+                .device
+                .try_read(address - m.range.start)
+                .ok_or(VmError::WouldBlock { address }),
+            None => self.memory.read(address),
+        }
+    }
+
+    fn write(&mut self, address: usize, value: u8) -> Result<(), VmError> {
+        match self
+            .mappings
+            .iter_mut()
+            .find(|m| m.range.contains(&address))
+        {
+            Some(m) => {
+                m.device.write(address - m.range.start, value);
+                Ok(())
+            }
+            None => self.memory.write(address, value),
+        }
+    }
+
+    fn write_chunk(&mut self, chunk: Vec<u8>) -> Result<(), VmError> {
+        // Programs are always loaded in the guest memory
+        self.memory.write_chunk(chunk)
+    }
+
+    fn size(&self) -> usize {
+        self.memory.size()
+    }
+
+    fn stack(&self) -> Range<usize> {
+        self.stack.clone()
+    }
+}
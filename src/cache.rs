@@ -0,0 +1,514 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    ops::Range,
+    sync::{Arc, Weak},
+};
+
+use caches::{AdaptiveCache, Cache, CacheError, LRUCache, PutResult, TwoQueueCache};
+
+use crate::{
+    backend::CompiledBlock,
+    baseline::BaselineBlock,
+    cpu::{Instruction, OpCode},
+    observer::Tier,
+};
+
+/// Granularity used to track which blocks were translated from an address.
+pub const PAGE_SIZE: usize = 256;
+
+/// How the code cache picks the block to evict when it is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CachePolicy {
+    Lru, // The least recently used block
+    Lfu, // The least frequently used block, the oldest one on ties
+    #[default]
+    Adaptive, // ARC: balances recency and frequency, adapting to the workload
+    TwoQueue, // 2Q: blocks used once are evicted before the ones used again
+}
+
+/// What happened to the code cache since it was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,          // Lookups finding a block
+    pub misses: u64,        // Lookups finding none
+    pub insertions: u64,    // Blocks added
+    pub evictions: u64,     // Blocks dropped to make room for others
+    pub invalidations: u64, // Blocks dropped because their code was overwritten
+    pub splits: u64,        // Blocks cut short at a leader found inside them
+    pub reaped: u64,        // Compiled blocks dropped to keep their native code within budget
+}
+
+/// The translated blocks, indexed by entry point. Every block remembers the
+/// guest bytes it was built from, so writing to them drops the block.
+///
+/// Pinned blocks are kept aside from the others and never evicted, nor
+/// counted in the capacity. Only overwriting their code drops them.
+pub(crate) struct CodeCache<'ctx> {
+    blocks: Blocks<'ctx>,
+    pinned: HashMap<usize, CachedBlock<'ctx>>,
+    pins: BTreeSet<usize>, // Entry points to pin, whether a block is cached there or not
+    pages: HashMap<usize, BTreeSet<usize>>, // Entry points of the blocks read from each page
+    policy: CachePolicy,
+    capacity: usize,
+    stats: CacheStats,
+}
+
+impl<'ctx> CodeCache<'ctx> {
+    pub fn new(policy: CachePolicy, capacity: usize) -> Result<Self, CacheError> {
+        Ok(Self {
+            blocks: Blocks::new(policy, capacity)?,
+            pinned: HashMap::new(),
+            pins: BTreeSet::new(),
+            pages: HashMap::new(),
+            policy,
+            capacity,
+            stats: CacheStats::default(),
+        })
+    }
+
+    pub fn policy(&self) -> CachePolicy {
+        self.policy
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Moves the cached blocks to a cache with another policy or capacity.
+    /// When it is smaller, the blocks that do not fit are evicted.
+    pub fn resize(&mut self, policy: CachePolicy, capacity: usize) -> Result<(), CacheError> {
+        let mut blocks = Blocks::new(policy, capacity)?;
+        let entries: BTreeSet<usize> = self.pages.values().flatten().copied().collect();
+        for pc in entries {
+            if let Some(block) = self.blocks.remove(&pc) {
+                if blocks.put(pc, block).is_some() {
+                    self.stats.evictions += 1;
+                }
+            }
+        }
+
+        self.blocks = blocks;
+        self.policy = policy;
+        self.capacity = capacity;
+        Ok(())
+    }
+
+    /// Keeps the block at `pc`, and the ones translated there later on, from
+    /// being evicted.
+    pub fn pin(&mut self, pc: usize) {
+        self.pins.insert(pc);
+        if let Some(block) = self.blocks.remove(&pc) {
+            self.pinned.insert(pc, block);
+        }
+    }
+
+    /// Lets the block at `pc` be evicted again, which may evict another one
+    /// to make room for it. Returns whether it was pinned.
+    pub fn unpin(&mut self, pc: usize) -> bool {
+        if let Some(block) = self.pinned.remove(&pc) {
+            if self.blocks.put(pc, block).is_some() {
+                self.stats.evictions += 1;
+            }
+        }
+        self.pins.remove(&pc)
+    }
+
+    /// The entry points pinned, ordered.
+    pub fn pins(&self) -> impl Iterator<Item = usize> + '_ {
+        self.pins.iter().copied()
+    }
+
+    /// Drops the coldest block with native code of its own, the one
+    /// executed the least times, for its code to be freed. Pinned blocks and
+    /// the one at `keep` are spared. Returns the block dropped, if any.
+    pub fn reap(&mut self, keep: usize) -> Option<CachedBlock<'ctx>> {
+        let pc = self
+            .blocks()
+            .filter(|block| block.code_size > 0 && block.pc != keep)
+            .filter(|block| !self.pins.contains(&block.pc))
+            .min_by_key(|block| block.executions)
+            .map(|block| block.pc)?;
+        self.stats.reaped += 1;
+        self.blocks.remove(&pc)
+    }
+
+    /// Looks the block at `pc` up, counting a hit or a miss.
+    pub fn get_mut<'a>(&'a mut self, pc: &'a usize) -> Option<&'a mut CachedBlock<'ctx>> {
+        let block = match self.pinned.get_mut(pc) {
+            Some(block) => Some(block),
+            None => self.blocks.get_mut(pc),
+        };
+        match block {
+            Some(_) => self.stats.hits += 1,
+            None => self.stats.misses += 1,
+        }
+        block
+    }
+
+    /// Like `get_mut`, without counting the lookup nor touching the
+    /// eviction order.
+    pub fn peek_mut<'a>(&'a mut self, pc: &'a usize) -> Option<&'a mut CachedBlock<'ctx>> {
+        match self.pinned.get_mut(pc) {
+            Some(block) => Some(block),
+            None => self.blocks.peek_mut(pc),
+        }
+    }
+
+    pub fn insert(&mut self, block: CachedBlock<'ctx>) {
+        let span = block.span();
+        for page in pages(&span) {
+            self.pages.entry(page).or_default().insert(span.start);
+        }
+        self.stats.insertions += 1;
+        if self.pins.contains(&span.start) {
+            self.pinned.insert(span.start, block);
+        } else if self.blocks.put(span.start, block).is_some() {
+            self.stats.evictions += 1;
+        }
+    }
+
+    /// The cached blocks ordered by entry point, without touching their
+    /// eviction order.
+    pub fn blocks(&self) -> impl Iterator<Item = &CachedBlock<'ctx>> {
+        let entries: BTreeSet<&usize> = self.pages.values().flatten().collect();
+        entries
+            .into_iter()
+            .filter_map(|pc| self.pinned.get(pc).or_else(|| self.blocks.peek(pc)))
+    }
+
+    /// Drops every block built from bytes in `range`, returning their entry
+    /// points. Evicted blocks are forgotten along the way.
+    pub fn invalidate(&mut self, range: Range<usize>) -> Vec<usize> {
+        let mut invalidated = Vec::new();
+
+        for page in pages(&range) {
+            let Some(entries) = self.pages.get_mut(&page) else {
+                continue;
+            };
+
+            let (blocks, pinned) = (&mut self.blocks, &mut self.pinned);
+            entries.retain(|pc| {
+                let block = pinned.get(pc).or_else(|| blocks.peek(pc));
+                let live = block.is_some_and(|block| {
+                    let span = block.span();
+                    span.end <= range.start || range.end <= span.start
+                });
+                if !live && (pinned.remove(pc).is_some() || blocks.remove(pc).is_some()) {
+                    invalidated.push(*pc);
+                }
+                live
+            });
+
+            if entries.is_empty() {
+                self.pages.remove(&page);
+            }
+        }
+
+        self.stats.invalidations += invalidated.len() as u64;
+        invalidated
+    }
+
+    /// Cuts the blocks running over `pc` short of it, once the guest was
+    /// found entering the code there: the instructions from `pc` on belong
+    /// to the block starting at `pc`. Blocks left empty are dropped.
+    /// Returns the entry points of the blocks split.
+    pub fn split(&mut self, pc: usize) -> Vec<usize> {
+        let mut split = Vec::new();
+        let Some(entries) = self.pages.get(&(pc / PAGE_SIZE)) else {
+            return split;
+        };
+
+        for &entry in entries.iter().filter(|entry| **entry < pc) {
+            let block = match self.pinned.get_mut(&entry) {
+                Some(block) => Some(block),
+                None => self.blocks.peek_mut(&entry),
+            };
+            let Some(block) = block.filter(|block| block.span().contains(&pc)) else {
+                continue;
+            };
+            if !block.truncate(pc) {
+                self.pinned.remove(&entry);
+                self.blocks.remove(&entry);
+            }
+            split.push(entry);
+        }
+
+        self.stats.splits += split.len() as u64;
+        split
+    }
+}
+
+/// The blocks stored by the policy chosen for the cache.
+enum Blocks<'ctx> {
+    Lru(LRUCache<usize, CachedBlock<'ctx>>),
+    Lfu(LfuCache<CachedBlock<'ctx>>),
+    Adaptive(AdaptiveCache<usize, CachedBlock<'ctx>>),
+    TwoQueue(TwoQueueCache<usize, CachedBlock<'ctx>>),
+}
+
+// Runs `$action` on the cache behind `$blocks`, whatever its policy
+macro_rules! dispatch {
+    ($blocks:expr, $cache:ident => $action:expr) => {
+        match $blocks {
+            Blocks::Lru($cache) => $action,
+            Blocks::Lfu($cache) => $action,
+            Blocks::Adaptive($cache) => $action,
+            Blocks::TwoQueue($cache) => $action,
+        }
+    };
+}
+
+impl<'ctx> Blocks<'ctx> {
+    fn new(policy: CachePolicy, capacity: usize) -> Result<Self, CacheError> {
+        Ok(match policy {
+            CachePolicy::Lru => Blocks::Lru(LRUCache::new(capacity)?),
+            CachePolicy::Lfu => Blocks::Lfu(LfuCache::new(capacity)?),
+            CachePolicy::Adaptive => Blocks::Adaptive(AdaptiveCache::new(capacity)?),
+            CachePolicy::TwoQueue => Blocks::TwoQueue(TwoQueueCache::new(capacity)?),
+        })
+    }
+
+    /// Stores `block`, returning the entry point of the block evicted for it.
+    fn put(&mut self, pc: usize, block: CachedBlock<'ctx>) -> Option<usize> {
+        let result = match self {
+            Blocks::Lfu(cache) => return cache.put(pc, block),
+            Blocks::Lru(cache) => cache.put(pc, block),
+            Blocks::Adaptive(cache) => cache.put(pc, block),
+            Blocks::TwoQueue(cache) => cache.put(pc, block),
+        };
+        match result {
+            PutResult::Evicted { key, .. } => Some(key),
+            PutResult::EvictedAndUpdate { evicted, .. } => Some(evicted.0),
+            PutResult::Put | PutResult::Update(_) => None,
+        }
+    }
+
+    fn get_mut<'a>(&'a mut self, pc: &'a usize) -> Option<&'a mut CachedBlock<'ctx>> {
+        dispatch!(self, cache => cache.get_mut(pc))
+    }
+
+    fn peek<'a>(&'a self, pc: &'a usize) -> Option<&'a CachedBlock<'ctx>> {
+        dispatch!(self, cache => cache.peek(pc))
+    }
+
+    fn peek_mut<'a>(&'a mut self, pc: &'a usize) -> Option<&'a mut CachedBlock<'ctx>> {
+        dispatch!(self, cache => cache.peek_mut(pc))
+    }
+
+    fn remove(&mut self, pc: &usize) -> Option<CachedBlock<'ctx>> {
+        dispatch!(self, cache => cache.remove(pc))
+    }
+}
+
+/// Evicts the block used the least times since it entered the cache, the
+/// one inserted first on ties. Finding it takes a scan of the cache, which
+/// is cheap next to the compilation that filling it again takes.
+struct LfuCache<V> {
+    entries: HashMap<usize, (V, u64, u64)>, // Value, uses and insertion order
+    capacity: usize,
+    inserted: u64, // Insertions so far, ordering the entries
+}
+
+impl<V> LfuCache<V> {
+    fn new(capacity: usize) -> Result<Self, CacheError> {
+        if capacity == 0 {
+            return Err(CacheError::InvalidSize(capacity));
+        }
+        Ok(Self {
+            entries: HashMap::with_capacity(capacity),
+            capacity,
+            inserted: 0,
+        })
+    }
+
+    fn put(&mut self, key: usize, value: V) -> Option<usize> {
+        self.inserted += 1;
+        if let Some(entry) = self.entries.get_mut(&key) {
+            entry.0 = value;
+            return None;
+        }
+
+        let mut evicted = None;
+        if self.entries.len() >= self.capacity {
+            evicted = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, uses, order))| (*uses, *order))
+                .map(|(key, _)| *key);
+            if let Some(key) = evicted {
+                self.entries.remove(&key);
+            }
+        }
+        self.entries.insert(key, (value, 0, self.inserted));
+        evicted
+    }
+
+    fn get_mut(&mut self, key: &usize) -> Option<&mut V> {
+        self.entries.get_mut(key).map(|(value, uses, _)| {
+            *uses += 1;
+            value
+        })
+    }
+
+    fn peek(&self, key: &usize) -> Option<&V> {
+        self.entries.get(key).map(|(value, _, _)| value)
+    }
+
+    fn peek_mut(&mut self, key: &usize) -> Option<&mut V> {
+        self.entries.get_mut(key).map(|(value, _, _)| value)
+    }
+
+    fn remove(&mut self, key: &usize) -> Option<V> {
+        self.entries.remove(key).map(|(value, _, _)| value)
+    }
+}
+
+fn pages(range: &Range<usize>) -> Range<usize> {
+    if range.is_empty() {
+        return 0..0;
+    }
+    range.start / PAGE_SIZE..(range.end - 1) / PAGE_SIZE + 1
+}
+
+/// The code compiled for every sequence of instructions, shared by the
+/// blocks made of them wherever they are in memory. Compiled blocks read
+/// the program counter from the Cpu and branch to absolute addresses, so
+/// their code does not depend on the address of the block. The code is
+/// freed once no block in the code cache uses it.
+#[derive(Default)]
+pub(crate) struct Translations<'ctx> {
+    code: HashMap<Vec<Instruction>, Weak<dyn CompiledBlock + 'ctx>>,
+}
+
+impl<'ctx> Translations<'ctx> {
+    pub fn get(&self, bytecode: &[Instruction]) -> Option<Arc<dyn CompiledBlock + 'ctx>> {
+        self.code.get(bytecode).and_then(Weak::upgrade)
+    }
+
+    pub fn insert(&mut self, bytecode: &[Instruction], compiled: &Arc<dyn CompiledBlock + 'ctx>) {
+        // Forget the code freed since, rather than keeping a key per block ever compiled
+        self.code.retain(|_, code| code.strong_count() > 0);
+        self.code
+            .insert(bytecode.to_vec(), Arc::downgrade(compiled));
+    }
+}
+
+/// A snapshot of a block in the code cache, as reported to embedders.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockInfo {
+    pub pc: usize,
+    pub instructions: usize,
+    pub executions: u64,
+    pub tier: Tier, // The fastest tier the block has code for
+}
+
+/// A dynamic basic block kept in the code cache, together with the code
+/// of every tier it has been promoted to.
+pub struct CachedBlock<'ctx> {
+    pub executions: u64,
+    #[cfg(feature = "jit")]
+    pub(crate) pending: bool, // Whether the block is queued for background compilation
+    #[cfg(feature = "jit")]
+    pub(crate) native_runs: u64, // Runs of the code emitted by the backend
+    #[cfg(feature = "jit")]
+    pub(crate) recompiled: bool, // Whether the block is queued for recompilation, or was recompiled
+    pc: usize,
+    bytecode: Arc<[Instruction]>, // Decoded once, shared with the interpreter
+    pub(crate) baseline: Option<BaselineBlock>,
+    pub(crate) compiled: Option<Arc<dyn CompiledBlock + 'ctx>>, // Code emitted by the backend
+    pub(crate) code_size: usize, // Bytes of native code compiled for the block alone
+}
+
+impl<'ctx> CachedBlock<'ctx> {
+    pub fn new(pc: usize, bytecode: Vec<Instruction>) -> Self {
+        Self {
+            executions: 0,
+            #[cfg(feature = "jit")]
+            pending: false,
+            #[cfg(feature = "jit")]
+            native_runs: 0,
+            #[cfg(feature = "jit")]
+            recompiled: false,
+            pc,
+            bytecode: bytecode.into(),
+            baseline: None,
+            compiled: None,
+            code_size: 0,
+        }
+    }
+
+    /// The guest addresses the block was decoded from.
+    pub fn span(&self) -> Range<usize> {
+        let length: usize = self.bytecode.iter().map(Instruction::length).sum();
+        self.pc..self.pc + length
+    }
+
+    pub fn bytecode(&self) -> &[Instruction] {
+        &self.bytecode
+    }
+
+    /// The address of the BACK7 ending the block, if any.
+    pub fn back7_pc(&self) -> Option<usize> {
+        let last = self.bytecode.last()?;
+        (last.opcode == OpCode::BACK7).then(|| self.span().end - last.length())
+    }
+
+    /// The instructions of the block, to run them while the code cache is
+    /// borrowed or changes.
+    pub(crate) fn shared_bytecode(&self) -> Arc<[Instruction]> {
+        self.bytecode.clone()
+    }
+
+    /// Drops the instructions of the block running over `end`, and the code
+    /// compiled for the longer block. Returns whether instructions are left.
+    pub(crate) fn truncate(&mut self, end: usize) -> bool {
+        let mut address = self.pc;
+        let count = self
+            .bytecode
+            .iter()
+            .take_while(|instr| {
+                address += instr.length();
+                address <= end
+            })
+            .count();
+        self.bytecode = self.bytecode[..count].into();
+        self.baseline = None;
+        self.compiled = None;
+        self.code_size = 0;
+        #[cfg(feature = "jit")]
+        {
+            self.pending = false;
+            self.native_runs = 0;
+            self.recompiled = false;
+        }
+        count > 0
+    }
+
+    pub fn instruction_count(&self) -> usize {
+        self.bytecode.len()
+    }
+
+    pub fn has_compiled(&self) -> bool {
+        self.compiled.is_some()
+    }
+
+    pub fn info(&self) -> BlockInfo {
+        let tier = if self.has_compiled() {
+            Tier::Native
+        } else if self.baseline.is_some() {
+            Tier::Baseline
+        } else {
+            Tier::Interpreter
+        };
+        BlockInfo {
+            pc: self.pc,
+            instructions: self.instruction_count(),
+            executions: self.executions,
+            tier,
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use std::{
+    collections::HashMap,
+    ffi::c_void,
+    sync::{atomic::AtomicBool, Arc, Mutex},
+};
+
+#[cfg(feature = "jit")]
+use crate::deopt::{DeoptReason, SideExit};
+use crate::{
+    backend,
+    cpu::{Cpu, Instruction, OpCode},
+    memory::MemoryPort,
+};
+
+// Compiled blocks receive the guest memory, the instruction budget and the
+// stop flag of the engine and return how many instructions they executed,
+// which only differs from the block length for native loops and faulting
+// instructions. The memory is a MemoryPort, only ever handed back to
+// `execute_on_host`.
+pub(crate) type CompiledFunc =
+    unsafe extern "C" fn(*mut Cpu, *mut c_void, u64, *const AtomicBool) -> u64;
+
+/// Hands out the names of the functions compiled for an engine, whose
+/// versions count the compilations of every block. Clones share the count,
+/// so that the backend and the compilation workers of an engine never give
+/// two functions the same name.
+#[derive(Clone, Default)]
+pub struct Symbols {
+    versions: Arc<Mutex<HashMap<usize, u32>>>, // The next version of each block
+}
+
+impl Symbols {
+    /// The name of the next function compiled from the block at `pc`.
+    pub fn next(&self, pc: usize) -> String {
+        let mut versions = self.versions.lock().unwrap();
+        let version = versions.entry(pc).or_insert(0);
+        *version += 1;
+        backend::symbol(pc, *version - 1)
+    }
+}
+
+/// Runs the instruction ending a compiled block that needs the host, i.e.
+/// memory accesses and host calls. The block flushed its registers to `cpu`
+/// beforehand. Returns 1 if the instruction executed
+/// and 0 if it faulted, leaving the fault in `memory`.
+pub(crate) unsafe extern "C" fn execute_on_host(
+    cpu: &mut Cpu,
+    memory: *mut c_void,
+    opcode: u8,
+    operand: u16,
+) -> u64 {
+    let memory = unsafe { &mut *memory.cast::<MemoryPort>() };
+    let opcode = OpCode::try_from(opcode).expect("compiled code passes valid opcodes");
+    match cpu.execute(Instruction::new(opcode, operand), memory) {
+        Ok(()) => 1,
+        Err(e) => {
+            memory.fault(e);
+            0
+        }
+    }
+}
+
+/// Records the side exit compiled code takes through a failed guard, once
+/// it wrote the registers back to `cpu`.
+#[cfg(feature = "jit")]
+pub(crate) unsafe extern "C" fn deoptimize(cpu: &Cpu, memory: *mut c_void, reason: u8) {
+    let memory = unsafe { &mut *memory.cast::<MemoryPort>() };
+    let reason = DeoptReason::try_from(reason).expect("compiled code passes valid reasons");
+    memory.side_exit(SideExit { pc: cpu.pc, reason });
+}
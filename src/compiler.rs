@@ -0,0 +1,383 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::AtomicBool,
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+#[cfg(test)]
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use inkwell::context::Context;
+use tracing::{debug_span, field};
+
+use crate::{
+    backend::{CodeStats, CompiledBlock},
+    codegen::{CompiledFunc, Symbols},
+    cpu::{Cpu, Instruction},
+    error::VmError,
+    memory::MemoryPort,
+    observer::Tier,
+    profile::BranchCounts,
+    translation::{
+        create_execution_engine, TranslationBlock, TranslationContext, TranslationOptions,
+    },
+};
+
+enum Job {
+    Compile,   // A block was queued, the hottest one gets compiled
+    Free(u64), // A block no longer used by the engine
+    Stop,      // The engine is dropping the worker
+}
+
+/// A block waiting for the worker.
+pub(crate) struct Queued {
+    pub pc: usize,
+    pub bytecode: Vec<Instruction>,
+    pub back7: Option<BranchCounts>, // Outcomes of the BACK7 of the block
+    priority: u64,                   // Instructions run outside native code while queued
+    submitted: Instant,
+}
+
+/// The blocks waiting for the worker, the hottest first: the ones the
+/// engine spent the most instructions on without native code, the oldest
+/// on ties. Finding it takes a scan of the queue, which is cheap next to
+/// the compilation that follows.
+#[derive(Default)]
+pub(crate) struct CompileQueue {
+    entries: Vec<Queued>, // In submission order
+}
+
+impl CompileQueue {
+    /// Queues the block at `pc`, which ran `priority` instructions so far.
+    pub fn push(
+        &mut self,
+        pc: usize,
+        bytecode: Vec<Instruction>,
+        back7: Option<BranchCounts>,
+        priority: u64,
+    ) {
+        self.entries.push(Queued {
+            pc,
+            bytecode,
+            back7,
+            priority,
+            submitted: Instant::now(),
+        });
+    }
+
+    /// Counts `instructions` more run by the block at `pc`, if queued.
+    pub fn bump(&mut self, pc: usize, instructions: u64) {
+        for entry in self.entries.iter_mut().filter(|entry| entry.pc == pc) {
+            entry.priority += instructions;
+        }
+    }
+
+    /// Takes the hottest block out of the queue, with the time it waited.
+    pub fn pop(&mut self) -> Option<(Queued, Duration)> {
+        let (index, _) = self
+            .entries
+            .iter()
+            .enumerate()
+            .min_by_key(|(index, entry)| (std::cmp::Reverse(entry.priority), *index))?;
+        let entry = self.entries.remove(index);
+        let waited = entry.submitted.elapsed();
+        Some((entry, waited))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// What the worker sends back: the native function of a block, its name and
+/// the key of its translation, rather than the block freeing it.
+type Finished = (
+    usize,
+    Vec<Instruction>,
+    Result<(CompiledFunc, String, u64, CodeStats), VmError>,
+    Duration,
+    Instant,
+    Duration,
+);
+pub(crate) type Compiled = (
+    usize,
+    Vec<Instruction>,
+    Result<(BackgroundBlock, CodeStats), VmError>,
+    Duration,
+    Instant,
+    Duration,
+);
+
+/// A thread compiling translation blocks in the background, so the guest
+/// keeps being interpreted while LLVM is busy. Blocks wait in a
+/// `CompileQueue`, for the hottest ones to get native code first.
+///
+/// The worker owns its own LLVM context and execution engine, and the
+/// translation of every block it compiled, handing out their native
+/// functions only. A translation is freed by the worker once the engine
+/// drops its block, e.g. when it's evicted from the code cache, and the
+/// native code of every block when the worker is dropped.
+pub struct CompilationWorker {
+    jobs: Sender<Job>,
+    queue: Arc<Mutex<CompileQueue>>,
+    results: Mutex<Receiver<Finished>>,
+    handle: Option<JoinHandle<()>>,
+    #[cfg(test)]
+    translations: Arc<AtomicUsize>, // Execution engines the worker keeps
+}
+
+impl CompilationWorker {
+    pub fn spawn(options: TranslationOptions, symbols: Symbols) -> Self {
+        let (jobs, job_queue) = mpsc::channel::<Job>();
+        let (result_queue, results) = mpsc::channel::<Finished>();
+        let queue = Arc::new(Mutex::new(CompileQueue::default()));
+        #[cfg(test)]
+        let translations = Arc::new(AtomicUsize::new(0));
+        #[cfg(test)]
+        let kept = translations.clone();
+
+        let compile_queue = queue.clone();
+        let handle = thread::spawn(move || {
+            let context = Context::create();
+            let execution_engine = create_execution_engine(&context, options.opt_level);
+            let mut compiled = HashMap::new();
+            let mut next_id = 0;
+
+            for job in job_queue {
+                let popped = match job {
+                    // Every block queued sends a job, the hottest one gets taken
+                    Job::Compile => compile_queue.lock().unwrap().pop(),
+                    Job::Free(id) => {
+                        // Removes the module of the block from the engine
+                        compiled.remove(&id);
+                        #[cfg(test)]
+                        kept.store(compiled.len(), Ordering::Relaxed);
+                        continue;
+                    }
+                    Job::Stop => break,
+                };
+                let Some((entry, waited)) = popped else {
+                    continue;
+                };
+                let Queued {
+                    pc,
+                    bytecode,
+                    back7,
+                    ..
+                } = entry;
+                let _span = debug_span!(
+                    "compile",
+                    pc,
+                    length = bytecode.len(),
+                    tier = field::debug(Tier::Native),
+                    backend = "llvm",
+                )
+                .entered();
+                let start = Instant::now();
+
+                // Without an engine, every compilation fails the same way
+                let result = execution_engine
+                    .as_ref()
+                    .map_err(Clone::clone)
+                    .and_then(|execution_engine| {
+                        TranslationContext::new(
+                            &context,
+                            execution_engine,
+                            symbols.next(pc),
+                            bytecode.clone(),
+                            options.clone(),
+                        )
+                    })
+                    .and_then(|mut tbb| {
+                        tbb.set_branch_profile(back7);
+                        tbb.compile_dynamic_basic_block().map(|_| tbb)
+                    })
+                    .map(|tbb| (tbb.native_function().unwrap(), tbb));
+                let time = start.elapsed();
+
+                // Measuring the code is not part of the compilation
+                let result = result.map(|(fun, tbb)| {
+                    let stats = tbb.code_stats();
+                    let name = tbb.name().to_string();
+                    let id = next_id;
+                    next_id += 1;
+                    compiled.insert(id, tbb);
+                    #[cfg(test)]
+                    kept.store(compiled.len(), Ordering::Relaxed);
+                    (fun, name, id, stats)
+                });
+
+                if result_queue
+                    .send((pc, bytecode, result, waited, start, time))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            jobs,
+            queue,
+            results: Mutex::new(results),
+            handle: Some(handle),
+            #[cfg(test)]
+            translations,
+        }
+    }
+
+    /// Queues the block at `pc` for compilation, `priority` being the
+    /// instructions the engine ran in it so far.
+    pub fn submit(
+        &self,
+        pc: usize,
+        bytecode: Vec<Instruction>,
+        back7: Option<BranchCounts>,
+        priority: u64,
+    ) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.push(pc, bytecode, back7, priority);
+        let _ = self.jobs.send(Job::Compile);
+    }
+
+    /// Moves the block at `pc` forward in the queue, if it is still waiting,
+    /// by the `instructions` the engine ran in it meanwhile.
+    pub fn bump(&self, pc: usize, instructions: u64) {
+        self.queue.lock().unwrap().bump(pc, instructions);
+    }
+
+    /// The blocks waiting to be compiled.
+    pub fn queue_depth(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    /// Returns a block compiled since the last call, if any, together with
+    /// the bytecode it was compiled from, the time it waited in the queue,
+    /// when its compilation started, the time it took and the size of its
+    /// code.
+    pub fn try_recv(&self) -> Option<Compiled> {
+        let (pc, bytecode, result, waited, start, time) =
+            self.results.lock().unwrap().try_recv().ok()?;
+        let result = result.map(|(fun, name, id, stats)| {
+            let block = BackgroundBlock {
+                // SAFETY: the execution engine owning `fun` is only dropped
+                // with the worker, after the code cache.
+                block: unsafe { TranslationBlock::new(fun) },
+                name,
+                id,
+                free_queue: self.jobs.clone(),
+            };
+            (block, stats)
+        });
+        Some((pc, bytecode, result, waited, start, time))
+    }
+}
+
+impl Drop for CompilationWorker {
+    fn drop(&mut self) {
+        // Blocks handed out keep the queue open, so the worker is stopped
+        // explicitly. It frees the native code left on its way out
+        let _ = self.jobs.send(Job::Stop);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// A block compiled by a [`CompilationWorker`], whose translation is freed
+/// by the worker when the block is dropped.
+pub struct BackgroundBlock {
+    block: TranslationBlock, // Native function of the block
+    name: String,            // Of the native function, see `backend::symbol`
+    id: u64,                 // Key of its translation in the worker
+    free_queue: Sender<Job>, // Jobs of the worker, to free the block
+}
+
+impl CompiledBlock for BackgroundBlock {
+    fn execute(
+        &self,
+        cpu: &mut Cpu,
+        memory: &mut MemoryPort,
+        budget: u64,
+        stop: &AtomicBool,
+    ) -> u64 {
+        self.block.execute(cpu, memory, budget, stop)
+    }
+
+    fn code_address(&self) -> Option<usize> {
+        self.block.code_address()
+    }
+
+    fn symbol(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+}
+
+impl Drop for BackgroundBlock {
+    fn drop(&mut self) {
+        // The worker is gone already when it was dropped first, along with
+        // the translation of the block
+        let _ = self.free_queue.send(Job::Free(self.id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::{
+        sync::atomic::Ordering,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{observer::Tier, program::generator, tests::init, EmulationEngine};
+
+    #[test]
+    pub fn evicted_blocks_free_their_code() {
+        init();
+        let prog = generator::generate(2_000, 3, [1, 9, 1, 5, 5]);
+        let mut vm = EmulationEngine::builder()
+            .background_compilation(true)
+            .compile_threshold(1)
+            .cache_size(4)
+            .build()
+            .unwrap();
+        vm.load_program(prog.program).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(vm.cpu.acc, prog.expected_acc);
+        assert!(vm.cache_stats().evictions > 0);
+
+        // Blocks the worker publishes are installed, or dropped when they were
+        // evicted meanwhile. Only the code of the blocks still cached is kept,
+        // blocks with the same instructions sharing theirs
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            vm.install_compiled_blocks();
+            let native = vm
+                .cached_blocks()
+                .iter()
+                .filter(|block| block.tier == Tier::Native)
+                .count();
+            let kept = vm
+                .compiler
+                .as_ref()
+                .unwrap()
+                .translations
+                .load(Ordering::Relaxed);
+            if kept <= native {
+                break;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "{kept} translations for {native} blocks"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(vm.compile_stats().len() > 4);
+    }
+}
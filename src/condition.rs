@@ -0,0 +1,212 @@
+use std::{fmt::Display, str::FromStr};
+
+use crate::{
+    cpu::Cpu,
+    error::VmError,
+    watch::{number, register_index, register_name, Comparison},
+};
+
+/// A value a Condition compares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Register(usize), // By index, as `acc`, `lc` or `r2` to `r7`
+    Pc,
+    Instret,
+    Value(i64),
+}
+
+impl Operand {
+    fn value(self, cpu: &Cpu) -> i64 {
+        match self {
+            Operand::Register(index) => cpu.register(index) as i64,
+            Operand::Pc => cpu.pc as i64,
+            Operand::Instret => cpu.instret as i64,
+            Operand::Value(value) => value,
+        }
+    }
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operand::Register(index) => write!(f, "{}", register_name(*index)),
+            Operand::Pc => write!(f, "pc"),
+            Operand::Instret => write!(f, "instret"),
+            Operand::Value(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// A predicate over the Cpu, e.g. for conditional breakpoints, see
+/// `EmulationEngine::set_conditional_breakpoint`.
+///
+/// Conditions parse from text such as `pc == 0x30 && lc < 0`: comparisons
+/// of registers, `pc`, `instret` and numbers, decimal or `0x` prefixed,
+/// joined by `&&`, binding tighter, and `||`, negated by `!` and grouped
+/// with parentheses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    Compare(Operand, Comparison, Operand),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+    Not(Box<Condition>),
+}
+
+impl Condition {
+    pub fn holds(&self, cpu: &Cpu) -> bool {
+        match self {
+            Condition::Compare(left, comparison, right) => {
+                comparison.holds(left.value(cpu), right.value(cpu))
+            }
+            Condition::And(left, right) => left.holds(cpu) && right.holds(cpu),
+            Condition::Or(left, right) => left.holds(cpu) || right.holds(cpu),
+            Condition::Not(condition) => !condition.holds(cpu),
+        }
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Only disjunctions bind looser than their parent
+        let operand = |condition: &Condition| match condition {
+            Condition::Or(..) => format!("({})", condition),
+            _ => condition.to_string(),
+        };
+        match self {
+            Condition::Compare(left, comparison, right) => {
+                write!(f, "{} {} {}", left, comparison.symbol(), right)
+            }
+            Condition::And(left, right) => write!(f, "{} && {}", operand(left), operand(right)),
+            Condition::Or(left, right) => write!(f, "{} || {}", left, right),
+            Condition::Not(condition) => match **condition {
+                Condition::Not(_) => write!(f, "!{}", condition),
+                _ => write!(f, "!({})", condition),
+            },
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = VmError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s),
+            next: 0,
+        };
+        match (parser.disjunction(), parser.next == parser.tokens.len()) {
+            (Some(condition), true) => Ok(condition),
+            _ => Err(VmError::InvalidCondition(s.trim().to_string())),
+        }
+    }
+}
+
+const SYMBOLS: [&str; 11] = ["&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "(", ")"];
+
+/// Splits `text` into symbols and words, i.e. names and numbers.
+fn tokenize(text: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let length = match SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+            Some(symbol) => symbol.len(),
+            None => rest
+                .find(|c: char| c.is_whitespace() || SYMBOLS.iter().any(|s| s.starts_with(c)))
+                .unwrap_or(rest.len())
+                .max(1),
+        };
+        tokens.push(&rest[..length]);
+        rest = rest[length..].trim_start();
+    }
+    tokens
+}
+
+/// A recursive descent parser over tokens, the failing rules returning None.
+struct Parser<'a> {
+    tokens: Vec<&'a str>,
+    next: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.next).copied()
+    }
+
+    fn take(&mut self) -> Option<&str> {
+        let token = self.tokens.get(self.next).copied();
+        self.next += 1;
+        token
+    }
+
+    fn disjunction(&mut self) -> Option<Condition> {
+        let mut condition = self.conjunction()?;
+        while self.peek() == Some("||") {
+            self.next += 1;
+            condition = Condition::Or(Box::new(condition), Box::new(self.conjunction()?));
+        }
+        Some(condition)
+    }
+
+    fn conjunction(&mut self) -> Option<Condition> {
+        let mut condition = self.negation()?;
+        while self.peek() == Some("&&") {
+            self.next += 1;
+            condition = Condition::And(Box::new(condition), Box::new(self.negation()?));
+        }
+        Some(condition)
+    }
+
+    fn negation(&mut self) -> Option<Condition> {
+        match self.peek()? {
+            "!" => {
+                self.next += 1;
+                Some(Condition::Not(Box::new(self.negation()?)))
+            }
+            "(" => {
+                self.next += 1;
+                let condition = self.disjunction()?;
+                (self.take()? == ")").then_some(condition)
+            }
+            _ => {
+                let left = self.operand()?;
+                let comparison = Comparison::from_symbol(self.take()?)?;
+                Some(Condition::Compare(left, comparison, self.operand()?))
+            }
+        }
+    }
+
+    fn operand(&mut self) -> Option<Operand> {
+        match self.take()? {
+            "pc" => Some(Operand::Pc),
+            "instret" => Some(Operand::Instret),
+            word => match register_index(word) {
+                Some(index) => Some(Operand::Register(index)),
+                None => number(word).map(Operand::Value),
+            },
+        }
+    }
+}
+
+/// What a conditional breakpoint checks once the guest reaches it.
+pub enum BreakCondition {
+    Parsed(Condition),
+    Predicate(Box<dyn Fn(&Cpu) -> bool + Send + Sync>), // Given by the host, shown as `<predicate>`
+}
+
+impl BreakCondition {
+    pub fn holds(&self, cpu: &Cpu) -> bool {
+        match self {
+            BreakCondition::Parsed(condition) => condition.holds(cpu),
+            BreakCondition::Predicate(predicate) => predicate(cpu),
+        }
+    }
+}
+
+impl Display for BreakCondition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BreakCondition::Parsed(condition) => write!(f, "{}", condition),
+            BreakCondition::Predicate(_) => write!(f, "<predicate>"),
+        }
+    }
+}
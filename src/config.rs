@@ -0,0 +1,511 @@
+use std::{io::Write, path::PathBuf, time::Duration};
+
+#[cfg(feature = "jit")]
+pub use inkwell::OptimizationLevel;
+
+use crate::{
+    backend::BackendKind,
+    cache::CachePolicy,
+    console::CONSOLE_ADDRESS,
+    cpu::OverflowMode,
+    error::VmError,
+    memory::{Addressable, Memory, MEMORY_SIZE},
+    rng::RngConfig,
+    timing::{CostTable, TimerConfig},
+    EmulationEngine,
+};
+
+/// Stands in for inkwell's optimization levels when the crate is built
+/// without the jit feature, so configurations keep compiling.
+#[cfg(not(feature = "jit"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OptimizationLevel {
+    None = 0,
+    Less = 1,
+    Default = 2,
+    Aggressive = 3,
+}
+
+/// The function passes LLVM can run over a block before compiling it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Pass {
+    PromoteMemoryToRegister, // mem2reg
+    InstructionCombining,    // instcombine
+    Reassociate,             // reassociate
+    Gvn,                     // gvn
+    CfgSimplification,       // simplifycfg
+    LoopRotate,              // loop-rotate
+    Licm,                    // licm
+    IndVarSimplify,          // indvars
+    LoopUnroll,              // loop-unroll
+    DeadStoreElimination,    // dse
+}
+
+impl Pass {
+    /// The passes run at `opt_level`, unless configured otherwise.
+    pub fn pipeline(opt_level: OptimizationLevel) -> Vec<Pass> {
+        let mut passes = match opt_level {
+            OptimizationLevel::None => return Vec::new(),
+            OptimizationLevel::Less => vec![
+                Pass::PromoteMemoryToRegister,
+                Pass::InstructionCombining,
+                Pass::CfgSimplification,
+            ],
+            OptimizationLevel::Default | OptimizationLevel::Aggressive => vec![
+                Pass::PromoteMemoryToRegister,
+                Pass::InstructionCombining,
+                Pass::Reassociate,
+                Pass::Gvn,
+                Pass::CfgSimplification,
+            ],
+        };
+        if opt_level == OptimizationLevel::Aggressive {
+            passes.extend([
+                Pass::LoopRotate,
+                Pass::Licm,
+                Pass::IndVarSimplify,
+                Pass::LoopUnroll,
+                Pass::DeadStoreElimination,
+                Pass::InstructionCombining,
+                Pass::CfgSimplification,
+            ]);
+        }
+        passes
+    }
+}
+
+pub const DEFAULT_CACHE_SIZE: usize = 32;
+pub const DEFAULT_COMPILE_THRESHOLD: u64 = 1;
+pub const DEFAULT_STACK_SIZE: usize = 1024;
+pub const DEFAULT_MAX_COMPILE_FAILURES: u32 = 3;
+
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    pub cache_size: usize, // Number of translation blocks kept in the code cache
+    pub cache_policy: CachePolicy, // Which block the code cache evicts when full
+    pub auto_pin: Option<Duration>, // Compile time pinning a block in the code cache
+    pub deadline: Option<Duration>, // Wall-clock time a run may take before timing out
+    pub compile_threshold: u64, // Executions needed before a block gets compiled
+    pub recompile_threshold: Option<u64>, // Native runs before LLVM recompiles a block
+    pub max_compile_failures: u32, // Failed compilations before a block is blacklisted
+    pub code_budget: Option<usize>, // Bytes of native code the cached blocks may take
+    pub precompile_depth: usize, // Levels of successors compiled ahead of hot blocks
+    pub safepoint_interval: u64, // Native loop iterations between polls of the stop flag
+    pub baseline_threshold: Option<u64>, // Executions needed to enter the baseline tier
+    pub trace_threshold: Option<u64>, // Visits of a loop head before its trace is recorded
+    pub opt_level: OptimizationLevel, // Optimization level used by the JIT
+    pub passes: Option<Vec<Pass>>, // LLVM passes run on blocks, None for those of opt_level
+    pub memory_size: usize, // Size of the guest memory in bytes
+    pub max_memory_size: Option<usize>, // Size the guest memory may grow to, if growable
+    pub stack_size: usize, // Bytes at the top of the guest memory holding the stack
+    pub trap_handler: Option<u16>, // Address the guest jumps to when it traps
+    pub overflow_mode: OverflowMode, // Behavior of arithmetic instructions on signed overflow
+    pub cost_table: CostTable, // Virtual cycles taken by every OpCode
+    pub timer: Option<TimerConfig>, // Periodic timer interrupt, if any
+    pub wall_clock: Option<u64>, // Frequency of the wall clock read by RDTIME, if any
+    pub pace: Option<u64>, // Frequency the virtual cycles run at, if paced
+    pub background_compilation: bool, // Compile hot blocks on a worker thread
+    pub object_cache: Option<PathBuf>, // Directory keeping the native code of blocks across runs
+    pub share_code: bool,  // Share compiled blocks with the other engines
+    pub console: Option<usize>, // Address of the console device, if any
+    pub rng: Option<RngConfig>, // Random number generator device, if any
+    pub backend: BackendKind, // Code generator used for hot blocks
+    pub verify: bool,      // Check every native block against the interpreter
+    pub perf_map: bool,    // Name the native code of blocks in the perf map
+    pub align_blocks: bool, // Start and end blocks at static basic block leaders
+    pub ahead_of_time: bool, // Compile the whole program when it is loaded
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            cache_size: DEFAULT_CACHE_SIZE,
+            cache_policy: CachePolicy::default(),
+            auto_pin: None,
+            compile_threshold: DEFAULT_COMPILE_THRESHOLD,
+            recompile_threshold: None,
+            max_compile_failures: DEFAULT_MAX_COMPILE_FAILURES,
+            code_budget: None,
+            deadline: None,
+            precompile_depth: 0,
+            safepoint_interval: 1,
+            baseline_threshold: None,
+            trace_threshold: None,
+            opt_level: OptimizationLevel::Default,
+            passes: None,
+            memory_size: MEMORY_SIZE,
+            max_memory_size: None,
+            stack_size: DEFAULT_STACK_SIZE,
+            trap_handler: None,
+            overflow_mode: OverflowMode::default(),
+            cost_table: CostTable::default(),
+            timer: None,
+            wall_clock: None,
+            pace: None,
+            background_compilation: false,
+            object_cache: None,
+            share_code: false,
+            console: None,
+            rng: None,
+            backend: BackendKind::default(),
+            verify: false,
+            perf_map: false,
+            align_blocks: false,
+            ahead_of_time: false,
+        }
+    }
+}
+
+impl EngineConfig {
+    /// The passes run over the blocks compiled by LLVM.
+    pub fn llvm_passes(&self) -> Vec<Pass> {
+        self.passes
+            .clone()
+            .unwrap_or_else(|| Pass::pipeline(self.opt_level))
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), VmError> {
+        if self.cache_size == 0 {
+            return Err(VmError::InvalidConfig(
+                "the code cache must hold at least one block".to_string(),
+            ));
+        }
+        if self.safepoint_interval == 0 {
+            return Err(VmError::InvalidConfig(
+                "native loops must poll the stop flag".to_string(),
+            ));
+        }
+        if self.max_compile_failures == 0 {
+            return Err(VmError::InvalidConfig(
+                "blocks must be allowed at least one compilation".to_string(),
+            ));
+        }
+        if self.memory_size == 0 {
+            return Err(VmError::InvalidConfig(
+                "the guest memory cannot be empty".to_string(),
+            ));
+        }
+        if self.timer.is_some_and(|timer| timer.period == 0) {
+            return Err(VmError::InvalidConfig(
+                "the timer period must be at least one cycle".to_string(),
+            ));
+        }
+        if self.wall_clock == Some(0) {
+            return Err(VmError::InvalidConfig(
+                "the wall clock must run at least at one hertz".to_string(),
+            ));
+        }
+        if self.pace == Some(0) {
+            return Err(VmError::InvalidConfig(
+                "the guest must be paced at least at one hertz".to_string(),
+            ));
+        }
+        if self
+            .max_memory_size
+            .is_some_and(|max| max < self.memory_size)
+        {
+            return Err(VmError::InvalidConfig(
+                "the maximum memory size is smaller than the initial one".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct EmulationEngineBuilder {
+    config: EngineConfig,
+    memory: Option<Box<dyn Addressable<u8> + Send + Sync>>,
+    console_sink: Option<Box<dyn Write + Send>>,
+}
+
+impl EmulationEngineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cache_size(mut self, cache_size: usize) -> Self {
+        self.config.cache_size = cache_size;
+        self
+    }
+
+    pub fn cache_policy(mut self, cache_policy: CachePolicy) -> Self {
+        self.config.cache_policy = cache_policy;
+        self
+    }
+
+    /// Pins the blocks whose compilation took at least `compile_time` in the
+    /// code cache, as recompiling them would, up to `cache_size` of them.
+    pub fn auto_pin(mut self, compile_time: Duration) -> Self {
+        self.config.auto_pin = Some(compile_time);
+        self
+    }
+
+    pub fn compile_threshold(mut self, compile_threshold: u64) -> Self {
+        self.config.compile_threshold = compile_threshold;
+        self
+    }
+
+    /// Recompiles the blocks compiled by LLVM once their code ran
+    /// `recompile_threshold` times, at the aggressive optimization level and
+    /// on a thread of its own. The old code keeps running in the meantime,
+    /// the new one replaces it between two blocks.
+    pub fn recompile_threshold(mut self, recompile_threshold: u64) -> Self {
+        self.config.recompile_threshold = Some(recompile_threshold);
+        self
+    }
+
+    /// Blacklists the blocks the backend failed to compile
+    /// `max_compile_failures` times: they are left to the interpreter, and
+    /// the baseline tier, for good rather than compiled again whenever they
+    /// are run hot. See `EmulationEngine::blacklisted_blocks`.
+    pub fn max_compile_failures(mut self, max_compile_failures: u32) -> Self {
+        self.config.max_compile_failures = max_compile_failures;
+        self
+    }
+
+    /// Caps the native code of the blocks in the code cache to `bytes`, as
+    /// told by the backend: once a compilation exceeds it, the coldest
+    /// compiled blocks are dropped from the cache until the code fits, and
+    /// have to get hot again to be compiled anew. Cranelift frees their
+    /// code right away. MCJIT only frees the IR of LLVM blocks, their code
+    /// lives as long as the engine, or the compilation thread.
+    pub fn code_budget(mut self, bytes: usize) -> Self {
+        self.config.code_budget = Some(bytes);
+        self
+    }
+
+    /// Stops runs going on for longer than `deadline`, measured from the
+    /// call to `run` or `run_for`, which return Outcome::TimedOut with the
+    /// Cpu state at that point. The guest is stopped like with the stop
+    /// flag, native loops included at their safepoint, see
+    /// `safepoint_interval`. Every run then starts a watchdog thread.
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.config.deadline = Some(deadline);
+        self
+    }
+
+    /// Compiles the blocks following a block which gets hot, and the ones
+    /// following them, up to `depth` levels, before they get hot themselves:
+    /// with background compilation, the guest finds their code ready rather
+    /// than waiting for it in the interpreter. Only the successors found in
+    /// guest memory at the time are compiled, the targets of returns are
+    /// not known. 0, the default, compiles blocks once hot only.
+    pub fn precompile_depth(mut self, depth: usize) -> Self {
+        self.config.precompile_depth = depth;
+        self
+    }
+
+    /// Makes native loops poll the stop flag, at their safepoint, every
+    /// `iterations` iterations rather than every one of them: a stop
+    /// request, e.g. to pause the guest or take a snapshot, then waits up
+    /// to that many iterations, and loops skip the load in between. The
+    /// budget and the timer interrupt are still checked every iteration,
+    /// and loops computed in closed form poll once. Defaults to 1.
+    pub fn safepoint_interval(mut self, iterations: u64) -> Self {
+        self.config.safepoint_interval = iterations;
+        self
+    }
+
+    /// Enables the baseline tier, used by blocks executed at least
+    /// `baseline_threshold` times until they reach the compile threshold.
+    pub fn baseline_threshold(mut self, baseline_threshold: u64) -> Self {
+        self.config.baseline_threshold = Some(baseline_threshold);
+        self
+    }
+
+    /// Enables trace compilation: once the target of a backward branch was
+    /// reached `trace_threshold` times, the path the guest runs from there
+    /// is recorded across blocks and compiled as a whole, see `trace`.
+    pub fn trace_threshold(mut self, trace_threshold: u64) -> Self {
+        self.config.trace_threshold = Some(trace_threshold);
+        self
+    }
+
+    pub fn opt_level(mut self, opt_level: OptimizationLevel) -> Self {
+        self.config.opt_level = opt_level;
+        self
+    }
+
+    /// Runs `passes` over the blocks compiled by LLVM, in order, instead of
+    /// the pipeline of the optimization level. Other backends ignore them.
+    pub fn passes(mut self, passes: Vec<Pass>) -> Self {
+        self.config.passes = Some(passes);
+        self
+    }
+
+    pub fn memory_size(mut self, memory_size: usize) -> Self {
+        self.config.memory_size = memory_size;
+        self
+    }
+
+    /// Lets the guest memory grow up to `max_memory_size` bytes, either
+    /// through `EmulationEngine::grow_memory` or to fit a loaded program.
+    pub fn max_memory_size(mut self, max_memory_size: usize) -> Self {
+        self.config.max_memory_size = Some(max_memory_size);
+        self
+    }
+
+    /// Runs the guest on `memory` instead of a Memory of `memory_size`
+    /// bytes, `max_memory_size` still bounds its growth.
+    pub fn memory(mut self, memory: Box<dyn Addressable<u8> + Send + Sync>) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    /// Reserves `stack_size` bytes at the top of the guest memory for the
+    /// stack used by PUSH/POP and CALL/RET.
+    pub fn stack_size(mut self, stack_size: usize) -> Self {
+        self.config.stack_size = stack_size;
+        self
+    }
+
+    /// Lets the guest handle its own traps: instead of stopping, the engine
+    /// calls `handler` with ACC holding the TrapCause.
+    pub fn trap_handler(mut self, handler: u16) -> Self {
+        self.config.trap_handler = Some(handler);
+        self
+    }
+
+    /// Selects what arithmetic instructions do on signed overflow, in every
+    /// tier alike.
+    pub fn overflow_mode(mut self, overflow_mode: OverflowMode) -> Self {
+        self.config.overflow_mode = overflow_mode;
+        self
+    }
+
+    /// Sets the virtual cycles taken by the instructions, which advance the
+    /// cycle counter of the Cpu.
+    pub fn cost_table(mut self, cost_table: CostTable) -> Self {
+        self.config.cost_table = cost_table;
+        self
+    }
+
+    /// Raises a timer interrupt every `period` virtual cycles, delivered
+    /// between blocks by calling the handler at `vector`.
+    pub fn timer(mut self, period: u64, vector: u16) -> Self {
+        self.config.timer = Some(TimerConfig { period, vector });
+        self
+    }
+
+    /// Makes RDTIME read a wall clock at `frequency` hertz, started with the
+    /// engine, rather than the virtual cycles: the time read no longer
+    /// depends on the instructions run alone, see `timing::Clock`.
+    pub fn wall_clock(mut self, frequency: u64) -> Self {
+        self.config.wall_clock = Some(frequency);
+        self
+    }
+
+    /// Paces the runs on real time, see `timing::Pacer`: the engine sleeps
+    /// between blocks so that the guest runs `frequency` virtual cycles a
+    /// second, e.g. 1 MHz for the timing of the processor it emulates, per
+    /// its CostTable. Native loops only pause between their blocks, so that
+    /// a long one runs ahead before the engine catches up.
+    pub fn pace(mut self, frequency: u64) -> Self {
+        self.config.pace = Some(frequency);
+        self
+    }
+
+    /// Replays every native block with the interpreter on a copy of the
+    /// guest, failing with VerificationMismatch when they disagree. Blocks
+    /// making host calls are not checked.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.config.verify = verify;
+        self
+    }
+
+    pub fn background_compilation(mut self, background_compilation: bool) -> Self {
+        self.config.background_compilation = background_compilation;
+        self
+    }
+
+    /// Keeps the native code of the blocks compiled by LLVM in `dir` across
+    /// runs, on Unix hosts, see `objcache::ObjectCache`: blocks whose code a
+    /// previous run left there are loaded rather than compiled. Blocks
+    /// compiled in background are not cached.
+    pub fn object_cache(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.object_cache = Some(dir.into());
+        self
+    }
+
+    /// Shares the code of compiled blocks with the other engines of the
+    /// process through the `shared::SharedCache`: blocks getting hot run the
+    /// code another engine compiled for the same instructions, if any, and
+    /// the blocks compiled here are published for the others. The code is
+    /// kept by the compiler thread of the backend that built it, which
+    /// outlives its engine as long as other engines run the code. Blocks
+    /// compiled in background are not shared.
+    pub fn share_code(mut self, share_code: bool) -> Self {
+        self.config.share_code = share_code;
+        self
+    }
+
+    /// Maps a `console::Console` at `address`: the bytes the guest stores
+    /// there are captured, see `EmulationEngine::console_output`.
+    pub fn console(mut self, address: usize) -> Self {
+        self.config.console = Some(address);
+        self
+    }
+
+    /// Forwards the output of the console to `sink` instead of capturing
+    /// it, mapping the console at `console::CONSOLE_ADDRESS` unless another
+    /// address was given.
+    pub fn console_sink(mut self, sink: Box<dyn Write + Send>) -> Self {
+        self.config.console.get_or_insert(CONSOLE_ADDRESS);
+        self.console_sink = Some(sink);
+        self
+    }
+
+    /// Maps a `rng::Rng` seeded with `seed` at `address`: reading it gives
+    /// the guest random bytes, the same ones on every engine with the seed.
+    pub fn rng(mut self, address: usize, seed: u64) -> Self {
+        self.config.rng = Some(RngConfig { address, seed });
+        self
+    }
+
+    pub fn backend(mut self, backend: BackendKind) -> Self {
+        self.config.backend = backend;
+        self
+    }
+
+    /// Lists the native code of every compiled block in the perf map of the
+    /// process, see `perf::PerfMap`, so that `perf report` names it.
+    pub fn perf_map(mut self, perf_map: bool) -> Self {
+        self.config.perf_map = perf_map;
+        self
+    }
+
+    /// Aligns blocks to the basic blocks of the program: its leaders are
+    /// found when it is loaded, see `program::analysis::leaders`, and blocks
+    /// end before them. An address entered later on becomes a leader too,
+    /// splitting the blocks running over it, so that a loop entered halfway
+    /// is not translated twice.
+    pub fn align_blocks(mut self, align_blocks: bool) -> Self {
+        self.config.align_blocks = align_blocks;
+        self
+    }
+
+    /// Compiles the whole program when it is loaded rather than its hot
+    /// blocks: every basic block of its static control-flow graph, see
+    /// `program::analysis::Cfg`, is compiled by the backend and pinned in
+    /// the code cache, and blocks are aligned to them. Deterministic
+    /// programs then run natively from the start, without warming up.
+    /// Code the graph misses, like the one only reached by computed
+    /// returns, is still interpreted until it gets hot.
+    pub fn ahead_of_time(mut self, ahead_of_time: bool) -> Self {
+        self.config.ahead_of_time = ahead_of_time;
+        self
+    }
+
+    pub fn build(self) -> Result<EmulationEngine, VmError> {
+        self.config.validate()?;
+        let memory = self
+            .memory
+            .unwrap_or_else(|| Box::new(Memory::new(self.config.memory_size)));
+        let mut engine = EmulationEngine::from_config(self.config, memory)?;
+        if let Some(sink) = self.console_sink {
+            engine.forward_console(sink);
+        }
+        Ok(engine)
+    }
+}
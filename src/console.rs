@@ -0,0 +1,47 @@
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+use tracing::warn;
+
+use crate::bus::MmioDevice;
+
+/// Where the console is mapped unless configured otherwise.
+pub const CONSOLE_ADDRESS: usize = 0xff00;
+
+/// Where the bytes written to a Console go.
+pub enum ConsoleOutput {
+    Captured(Vec<u8>),                // Kept for the host, see `console_output`
+    Forwarded(Box<dyn Write + Send>), // Written to a sink of the host as they come
+}
+
+/// A device printing the bytes the guest stores to its only register, so
+/// that programs can report more than their final registers. Reads return
+/// 0. The output is shared with the engine, which hands it to the host.
+pub struct Console {
+    output: Arc<Mutex<ConsoleOutput>>,
+}
+
+impl Console {
+    pub fn new(output: Arc<Mutex<ConsoleOutput>>) -> Self {
+        Self { output }
+    }
+}
+
+impl MmioDevice for Console {
+    fn read(&self, _offset: usize) -> u8 {
+        0
+    }
+
+    fn write(&mut self, _offset: usize, value: u8) {
+        match &mut *self.output.lock().unwrap() {
+            ConsoleOutput::Captured(output) => output.push(value),
+            ConsoleOutput::Forwarded(sink) => {
+                if let Err(e) = sink.write_all(&[value]).and_then(|()| sink.flush()) {
+                    warn!("wasn't capable to write to the console: {}", e);
+                }
+            }
+        }
+    }
+}
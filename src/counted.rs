@@ -0,0 +1,173 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    baseline::BaselineBlock,
+    cpu::{Cpu, Instruction, OpCode, OverflowMode, FLAG_NEGATIVE, FLAG_OVERFLOW, FLAG_ZERO},
+    memory::MemoryPort,
+    mir,
+    timing::CostTable,
+};
+
+/// A loop closed by BACK7 whose body only adds constants to the
+/// accumulator, like the ones of the generated "INC3A xN; SETL; [body];
+/// BACK7" programs. With wrapping arithmetic its effect on ACC and LC is a
+/// linear function of the trip count, so it is computed in constant time
+/// rather than iterated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CountedLoop {
+    pub body: Vec<Instruction>, // The loop body, BACK7 excluded
+}
+
+impl CountedLoop {
+    /// Recognizes the loop made of `body` followed by BACK7. Saturating and
+    /// trapping arithmetic are not linear, their loops are left alone.
+    pub fn analyze(body: &[Instruction], overflow_mode: OverflowMode) -> Option<Self> {
+        let linear = !body.is_empty()
+            && body
+                .iter()
+                .all(|instr| matches!(instr.opcode, OpCode::INC3A | OpCode::DECA | OpCode::ADDI));
+        (overflow_mode == OverflowMode::Wrapping && linear).then(|| Self {
+            body: body.to_vec(),
+        })
+    }
+
+    /// The wrapping sum added to ACC by an iteration.
+    pub fn step(&self) -> i32 {
+        self.body.iter().map(increment).fold(0, i32::wrapping_add)
+    }
+
+    /// The constant added by the last instruction of the body, the one
+    /// setting the flags.
+    pub fn last(&self) -> i32 {
+        self.body.last().map_or(0, increment)
+    }
+
+    /// Instructions run by an iteration, BACK7 included.
+    pub fn instructions(&self) -> u64 {
+        self.body.len() as u64 + 1
+    }
+
+    /// Cycles taken by an iteration, BACK7 included.
+    pub fn cycles(&self, costs: &CostTable) -> u64 {
+        let body: u64 = self.body.iter().map(|instr| costs.cost(instr.opcode)).sum();
+        body + costs.cost(OpCode::BACK7)
+    }
+
+    /// Bytes from the loop header to the end of the BACK7.
+    pub fn length(&self) -> usize {
+        self.body.iter().map(Instruction::length).sum::<usize>() + OpCode::BACK7.length()
+    }
+
+    /// The iterations run from the header with `lc` in LC: the body runs
+    /// once whatever LC holds, then again as long as BACK7 leaves it positive.
+    pub fn trip_count(lc: i32) -> u64 {
+        lc.wrapping_sub(1).max(0) as u64 + 1
+    }
+
+    /// Runs the loop from its header, where the Cpu is, in constant time.
+    /// Like native loops, it stops at the header once another iteration
+    /// would not fit `budget`, the timer interrupt is due or `stop` is set,
+    /// `cycles` being what an iteration takes. Returns the executed
+    /// instructions.
+    pub fn execute(&self, cpu: &mut Cpu, budget: u64, cycles: u64, stop: &AtomicBool) -> u64 {
+        let trips = Self::trip_count(cpu.lc);
+        let instructions = self.instructions();
+        // The first iteration always runs, the next ones as long as they fit
+        let fitting = (budget / instructions).max(1);
+        let due = match cpu.timecmp.checked_sub(cpu.cycles) {
+            Some(gap) if gap > 0 && cycles > 0 => gap.div_ceil(cycles),
+            Some(gap) if gap > 0 => u64::MAX,
+            _ => 1,
+        };
+        let stopped = match stop.load(Ordering::Relaxed) {
+            true => 1,
+            false => u64::MAX,
+        };
+        let iterations = trips.min(fitting).min(due).min(stopped);
+
+        // Both registers wrap, only the iterations modulo 2^32 matter
+        let count = iterations as u32 as i32;
+        let acc = cpu.acc.wrapping_add(self.step().wrapping_mul(count));
+        let overflow = acc
+            .wrapping_sub(self.last())
+            .checked_add(self.last())
+            .is_none();
+        cpu.acc = acc;
+        cpu.flags = 0;
+        if acc == 0 {
+            cpu.flags |= FLAG_ZERO;
+        }
+        if acc < 0 {
+            cpu.flags |= FLAG_NEGATIVE;
+        }
+        if overflow {
+            cpu.flags |= FLAG_OVERFLOW;
+        }
+        cpu.lc = cpu.lc.wrapping_sub(count);
+        if iterations == trips {
+            cpu.pc += self.length();
+        }
+
+        let executed = iterations * instructions;
+        cpu.instret += executed;
+        cpu.cycles += iterations * cycles;
+        executed
+    }
+}
+
+fn increment(instr: &Instruction) -> i32 {
+    match instr.opcode {
+        OpCode::INC3A => 3,
+        OpCode::DECA => -1,
+        _ => instr.operand as u8 as i8 as i32,
+    }
+}
+
+/// A block ending with a counted loop, as the interpreter backend compiles
+/// it: the instructions before the loop run as threaded code, the loop in
+/// constant time. Guests running with saturating or trapping arithmetic run
+/// the whole block as threaded code instead.
+pub struct CountedBlock {
+    prefix: BaselineBlock,
+    prefix_length: u64, // Instructions before the loop
+    counted: CountedLoop,
+    cycles: u64,          // Cycles taken by an iteration
+    block: BaselineBlock, // The whole block, for the other overflow modes
+}
+
+impl CountedBlock {
+    /// Compiles `block` if it ends with a counted loop.
+    pub fn compile(block: &[Instruction], costs: &CostTable) -> Option<Self> {
+        let head = mir::loop_head(block)?;
+        let body = &block[head..block.len() - 1];
+        let counted = CountedLoop::analyze(body, OverflowMode::Wrapping)?;
+        Some(Self {
+            prefix: BaselineBlock::compile(&block[..head], costs),
+            prefix_length: head as u64,
+            cycles: counted.cycles(costs),
+            counted,
+            block: BaselineBlock::compile(block, costs),
+        })
+    }
+
+    pub fn execute(
+        &self,
+        cpu: &mut Cpu,
+        memory: &mut MemoryPort,
+        budget: u64,
+        stop: &AtomicBool,
+    ) -> u64 {
+        if cpu.overflow_mode != OverflowMode::Wrapping {
+            return self.block.execute(cpu, memory);
+        }
+
+        let executed = self.prefix.execute(cpu, memory);
+        if executed < self.prefix_length {
+            return executed;
+        }
+        let looped = self
+            .counted
+            .execute(cpu, budget - executed, self.cycles, stop);
+        executed + looped
+    }
+}
@@ -0,0 +1,87 @@
+use std::ops::Range;
+
+use crate::cpu::Instruction;
+
+/// The bytes of guest memory holding instructions that were executed, one
+/// bit per address. Every tier contributes, compiled blocks included.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    bitmap: Vec<u64>,
+}
+
+impl Coverage {
+    /// Marks the bytes of `instr`, executed at `pc`.
+    pub fn mark(&mut self, pc: usize, instr: Instruction) {
+        for address in pc..pc + instr.length() {
+            let (word, bit) = (address / 64, address % 64);
+            if word >= self.bitmap.len() {
+                self.bitmap.resize(word + 1, 0);
+            }
+            self.bitmap[word] |= 1 << bit;
+        }
+    }
+
+    /// Marks the first `executed` instructions of the block at `pc`, all of
+    /// them when it looped.
+    pub(crate) fn mark_block(&mut self, pc: usize, block: &[Instruction], executed: u64) {
+        let mut address = pc;
+        for instr in block.iter().take(executed as usize) {
+            self.mark(address, *instr);
+            address += instr.length();
+        }
+    }
+
+    pub fn is_covered(&self, address: usize) -> bool {
+        self.bitmap
+            .get(address / 64)
+            .is_some_and(|word| word & 1 << (address % 64) != 0)
+    }
+
+    /// One bit per address, starting from 0: the bit `n % 64` of the word
+    /// `n / 64` is set when the address `n` was executed.
+    pub fn bitmap(&self) -> &[u64] {
+        &self.bitmap
+    }
+
+    /// The number of addresses in `range` that were executed.
+    pub fn covered(&self, range: Range<usize>) -> usize {
+        range.filter(|&address| self.is_covered(address)).count()
+    }
+
+    /// The regions of `range` never executed, e.g. dead code or data.
+    pub fn uncovered(&self, range: Range<usize>) -> Vec<Range<usize>> {
+        let mut regions: Vec<Range<usize>> = Vec::new();
+        for address in range.filter(|&address| !self.is_covered(address)) {
+            match regions.last_mut() {
+                Some(region) if region.end == address => region.end += 1,
+                _ => regions.push(address..address + 1),
+            }
+        }
+        regions
+    }
+
+    /// A summary of the coverage of `range` followed by the regions never
+    /// executed, one per line.
+    pub fn report(&self, range: Range<usize>) -> String {
+        let covered = self.covered(range.clone());
+        let percent = match range.len() {
+            0 => 100.0,
+            length => covered as f64 * 100.0 / length as f64,
+        };
+        let mut report = format!(
+            "{} of {} bytes executed ({:.1}%)\n",
+            covered,
+            range.len(),
+            percent
+        );
+        for region in self.uncovered(range) {
+            report += &format!(
+                "never executed: {:#06x}..{:#06x} ({} bytes)\n",
+                region.start,
+                region.end,
+                region.len()
+            );
+        }
+        report
+    }
+}
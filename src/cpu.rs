@@ -1,17 +1,485 @@
 use std::fmt::Display;
 
+use crate::{
+    error::VmError,
+    memory::{Addressable, MemoryPort},
+};
+
 #[repr(C)]
-#[derive(Default, Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Cpu {
-    pub acc: i32,   // The accumulator register
-    pub lc: i32,    // The loop counter register
-    pub pc: usize,  // The program counter register
-    pub halt: bool, // Flag keeping the current running state
+    pub acc: i32,                    // The accumulator register
+    pub lc: i32,                     // The loop counter register
+    pub pc: usize,                   // The program counter register
+    pub halt: bool,                  // Flag keeping the current running state
+    pub sp: usize,                   // The stack pointer, relative to the start of the stack region
+    pub gpr: [i32; 6],               // R2 to R7, R0 and R1 being the ACC and LC registers
+    pub flags: u8,                   // Status flags of the last accumulator arithmetic
+    pub instret: u64,                // Instructions retired, whichever tier executed them
+    pub cycles: u64,                 // Virtual cycles elapsed according to the engine's CostTable
+    pub timecmp: u64,                // Cycle count raising the timer interrupt, u64::MAX when off
+    pub trap: Option<Trap>, // The last trap raised by the guest, never touched by compiled code
+    pub overflow_mode: OverflowMode, // Set by the engine, compiled code follows its configuration
 }
 
+/// Number of registers addressable by register-register instructions.
+pub const REGISTER_COUNT: usize = 8;
+
+/// Size in bytes of the values pushed on the stack.
+pub const STACK_SLOT_SIZE: usize = 4;
+
+/// Bits of the flags register, set by CLRA, INC3A, DECA and ADDI from their
+/// result and tested by the flag branches.
+pub const FLAG_ZERO: u8 = 1 << 0;
+pub const FLAG_NEGATIVE: u8 = 1 << 1;
+pub const FLAG_OVERFLOW: u8 = 1 << 2;
+
+/// Runs an instruction on the Cpu, see `Cpu::execute`.
+pub type Handler = fn(&mut Cpu, &mut MemoryPort, Instruction) -> Result<(), VmError>;
+
+const FLAG_BRANCH: Handler = |cpu, _, instr| {
+    cpu.branch_on_flag(instr.opcode.flag_condition().unwrap(), instr.operand);
+    Ok(())
+};
+
+/// The handler of every opcode, indexed by its encoding: instructions are
+/// dispatched with a lookup rather than a match on the opcode.
+pub static HANDLERS: [Handler; 28] = [
+    // HALT
+    |cpu, _, _| {
+        cpu.halt();
+        Ok(())
+    },
+    // CLRA
+    |cpu, _, _| {
+        cpu.clra();
+        Ok(())
+    },
+    // INC3A
+    |cpu, _, _| cpu.inc3a(),
+    // DECA
+    |cpu, _, _| cpu.deca(),
+    // SETL
+    |cpu, _, _| {
+        cpu.setl();
+        Ok(())
+    },
+    // BACK7
+    |cpu, _, _| {
+        cpu.back7();
+        Ok(())
+    },
+    // LDA
+    |cpu, memory, instr| cpu.lda(memory, instr.operand),
+    // STA
+    |cpu, memory, instr| cpu.sta(memory, instr.operand),
+    // ADDI
+    |cpu, _, instr| cpu.addi(instr.operand as u8 as i8),
+    // LI
+    |cpu, _, instr| {
+        cpu.li(instr.operand as i16);
+        Ok(())
+    },
+    // JMP
+    |cpu, _, instr| {
+        cpu.jmp(instr.operand);
+        Ok(())
+    },
+    // BEQZ
+    |cpu, _, instr| {
+        cpu.beqz(instr.operand);
+        Ok(())
+    },
+    // BNEZ
+    |cpu, _, instr| {
+        cpu.bnez(instr.operand);
+        Ok(())
+    },
+    // PUSH
+    |cpu, memory, _| cpu.push(memory),
+    // POP
+    |cpu, memory, _| cpu.pop(memory),
+    // CALL
+    |cpu, memory, instr| cpu.call(memory, instr.operand),
+    // RET
+    |cpu, memory, _| cpu.ret(memory),
+    // MOV
+    |cpu, _, instr| {
+        cpu.mov(instr.registers());
+        Ok(())
+    },
+    // ADD
+    |cpu, _, instr| cpu.add(instr.registers()),
+    // SUB
+    |cpu, _, instr| cpu.sub(instr.registers()),
+    // HCALL
+    |cpu, memory, instr| cpu.hcall(memory, instr.operand as u8),
+    // BEQ, BNE, BMI, BPL, BVS and BVC
+    FLAG_BRANCH,
+    FLAG_BRANCH,
+    FLAG_BRANCH,
+    FLAG_BRANCH,
+    FLAG_BRANCH,
+    FLAG_BRANCH,
+    // RDTIME
+    |cpu, memory, _| {
+        cpu.rdtime(memory);
+        Ok(())
+    },
+];
+
 impl Cpu {
+    /// Creates a Cpu with an empty stack.
     pub fn new(acc: i32, lc: i32, pc: usize, halt: bool) -> Self {
-        Self { acc, lc, pc, halt }
+        Self {
+            acc,
+            lc,
+            pc,
+            halt,
+            sp: 0,
+            gpr: [0; 6],
+            flags: 0,
+            instret: 0,
+            cycles: 0,
+            timecmp: u64::MAX,
+            trap: None,
+            overflow_mode: OverflowMode::Wrapping,
+        }
+    }
+
+    /// Whether the timer interrupt is due.
+    pub fn interrupt_pending(&self) -> bool {
+        self.cycles >= self.timecmp
+    }
+
+    /// Reads the register `index`, R0 and R1 being aliases of ACC and LC.
+    pub fn register(&self, index: usize) -> i32 {
+        match index {
+            0 => self.acc,
+            1 => self.lc,
+            _ => self.gpr[index - 2],
+        }
+    }
+
+    pub fn register_mut(&mut self, index: usize) -> &mut i32 {
+        match index {
+            0 => &mut self.acc,
+            1 => &mut self.lc,
+            _ => &mut self.gpr[index - 2],
+        }
+    }
+
+    /// Executes a single instruction, this is the reference semantics of the ISA.
+    /// A faulting instruction leaves the Cpu untouched, unless a host call
+    /// modified it before failing.
+    pub fn execute(&mut self, instr: Instruction, memory: &mut MemoryPort) -> Result<(), VmError> {
+        HANDLERS[instr.opcode as usize](self, memory, instr)
+    }
+
+    pub fn halt(&mut self) {
+        self.halt = true;
+        self.pc += 1;
+    }
+
+    pub fn clra(&mut self) {
+        self.acc = 0;
+        self.flags = FLAG_ZERO;
+        self.pc += 1;
+    }
+
+    pub fn inc3a(&mut self) -> Result<(), VmError> {
+        self.add_to_acc(3)?;
+        self.pc += 1;
+        Ok(())
+    }
+
+    pub fn deca(&mut self) -> Result<(), VmError> {
+        self.add_to_acc(-1)?;
+        self.pc += 1;
+        Ok(())
+    }
+
+    pub fn setl(&mut self) {
+        self.lc = self.acc;
+        self.pc += 1;
+    }
+
+    // The loop counter always wraps around, whatever the overflow mode. A
+    // BACK7 near address zero wraps the pc too, the next fetch traps
+    pub fn back7(&mut self) {
+        self.lc = self.lc.wrapping_sub(1);
+        if self.lc > 0 {
+            self.pc = self.pc.wrapping_sub(6);
+        } else {
+            self.pc += 1;
+        }
+    }
+
+    pub fn addi(&mut self, imm: i8) -> Result<(), VmError> {
+        self.add_to_acc(imm as i32)?;
+        self.pc += 2;
+        Ok(())
+    }
+
+    pub fn li(&mut self, imm: i16) {
+        self.acc = imm as i32;
+        self.pc += 3;
+    }
+
+    pub fn jmp(&mut self, target: u16) {
+        self.pc = target as usize;
+    }
+
+    pub fn beqz(&mut self, target: u16) {
+        if self.acc == 0 {
+            self.pc = target as usize;
+        } else {
+            self.pc += 3;
+        }
+    }
+
+    pub fn bnez(&mut self, target: u16) {
+        if self.acc != 0 {
+            self.pc = target as usize;
+        } else {
+            self.pc += 3;
+        }
+    }
+
+    /// Jumps to `target` when the flag is set, or clear, as required by the
+    /// condition of the branch.
+    pub fn branch_on_flag(&mut self, (flag, set): (u8, bool), target: u16) {
+        if (self.flags & flag != 0) == set {
+            self.pc = target as usize;
+        } else {
+            self.pc += 3;
+        }
+    }
+
+    pub fn mov(&mut self, (rd, rs): (usize, usize)) {
+        *self.register_mut(rd) = self.register(rs);
+        self.pc += 2;
+    }
+
+    pub fn add(&mut self, (rd, rs): (usize, usize)) -> Result<(), VmError> {
+        let (lhs, rhs) = (self.register(rd), self.register(rs));
+        let (value, _) = self.on_overflow(
+            lhs.checked_add(rhs),
+            lhs.wrapping_add(rhs),
+            lhs.saturating_add(rhs),
+        )?;
+        *self.register_mut(rd) = value;
+        self.pc += 2;
+        Ok(())
+    }
+
+    pub fn sub(&mut self, (rd, rs): (usize, usize)) -> Result<(), VmError> {
+        let (lhs, rhs) = (self.register(rd), self.register(rs));
+        let (value, _) = self.on_overflow(
+            lhs.checked_sub(rhs),
+            lhs.wrapping_sub(rhs),
+            lhs.saturating_sub(rhs),
+        )?;
+        *self.register_mut(rd) = value;
+        self.pc += 2;
+        Ok(())
+    }
+
+    pub fn hcall(&mut self, memory: &mut MemoryPort, index: u8) -> Result<(), VmError> {
+        memory.call_host(self, index)?;
+        self.pc += 2;
+        Ok(())
+    }
+
+    /// Loads the low 32 bits of the time of the host clock, see `Clock`.
+    pub fn rdtime(&mut self, memory: &mut MemoryPort) {
+        self.acc = memory.time(self) as i32;
+        self.pc += 1;
+    }
+
+    /// Enters the trap handler at `handler` as if the faulting instruction
+    /// called it, with ACC holding the cause of the trap. RET goes back to
+    /// the faulting instruction.
+    pub fn enter_trap_handler(
+        &mut self,
+        memory: &mut dyn Addressable<u8>,
+        trap: Trap,
+        handler: u16,
+    ) -> Result<(), VmError> {
+        self.push_slot(memory, trap.pc as u32)?;
+        self.acc = trap.cause as i32;
+        self.pc = handler as usize;
+        Ok(())
+    }
+
+    /// Enters the interrupt handler at `vector` as if the interrupted
+    /// instruction called it, RET resumes it. Registers are left untouched,
+    /// saving them is up to the handler.
+    pub fn enter_interrupt_handler(
+        &mut self,
+        memory: &mut dyn Addressable<u8>,
+        vector: u16,
+    ) -> Result<(), VmError> {
+        self.push_slot(memory, self.pc as u32)?;
+        self.pc = vector as usize;
+        Ok(())
+    }
+
+    pub fn lda(&mut self, memory: &dyn Addressable<u8>, address: u16) -> Result<(), VmError> {
+        self.acc = memory.read(address as usize)? as i32;
+        self.pc += 3;
+        Ok(())
+    }
+
+    pub fn sta(&mut self, memory: &mut dyn Addressable<u8>, address: u16) -> Result<(), VmError> {
+        memory.write(address as usize, self.acc as u8)?;
+        self.pc += 3;
+        Ok(())
+    }
+
+    pub fn push(&mut self, memory: &mut dyn Addressable<u8>) -> Result<(), VmError> {
+        self.push_slot(memory, self.acc as u32)?;
+        self.pc += 1;
+        Ok(())
+    }
+
+    pub fn pop(&mut self, memory: &dyn Addressable<u8>) -> Result<(), VmError> {
+        self.acc = self.pop_slot(memory)? as i32;
+        self.pc += 1;
+        Ok(())
+    }
+
+    pub fn call(&mut self, memory: &mut dyn Addressable<u8>, target: u16) -> Result<(), VmError> {
+        self.push_slot(memory, (self.pc + 3) as u32)?;
+        self.pc = target as usize;
+        Ok(())
+    }
+
+    pub fn ret(&mut self, memory: &dyn Addressable<u8>) -> Result<(), VmError> {
+        self.pc = self.pop_slot(memory)? as usize;
+        Ok(())
+    }
+
+    // Accumulator arithmetic reports signed overflows in the flags, unless
+    // they trap.
+    fn add_to_acc(&mut self, value: i32) -> Result<(), VmError> {
+        let (acc, overflow) = self.on_overflow(
+            self.acc.checked_add(value),
+            self.acc.wrapping_add(value),
+            self.acc.saturating_add(value),
+        )?;
+        self.acc = acc;
+        self.flags = 0;
+        if acc == 0 {
+            self.flags |= FLAG_ZERO;
+        }
+        if acc < 0 {
+            self.flags |= FLAG_NEGATIVE;
+        }
+        if overflow {
+            self.flags |= FLAG_OVERFLOW;
+        }
+        Ok(())
+    }
+
+    /// Picks the result of an operation according to the overflow mode,
+    /// together with whether it overflowed.
+    fn on_overflow(
+        &self,
+        checked: Option<i32>,
+        wrapped: i32,
+        saturated: i32,
+    ) -> Result<(i32, bool), VmError> {
+        match (checked, self.overflow_mode) {
+            (Some(value), _) => Ok((value, false)),
+            (None, OverflowMode::Wrapping) => Ok((wrapped, true)),
+            (None, OverflowMode::Saturating) => Ok((saturated, true)),
+            (None, OverflowMode::Trapping) => Err(VmError::ArithmeticOverflow { pc: self.pc }),
+        }
+    }
+
+    // The stack grows upwards from the start of its region, values are
+    // stored little-endian.
+    fn push_slot(&mut self, memory: &mut dyn Addressable<u8>, value: u32) -> Result<(), VmError> {
+        let stack = memory.stack();
+        if self.sp + STACK_SLOT_SIZE > stack.len() {
+            return Err(VmError::StackOverflow { pc: self.pc });
+        }
+
+        let address = stack.start + self.sp;
+        for (offset, byte) in value.to_le_bytes().into_iter().enumerate() {
+            memory.write(address + offset, byte)?;
+        }
+        self.sp += STACK_SLOT_SIZE;
+        Ok(())
+    }
+
+    fn pop_slot(&mut self, memory: &dyn Addressable<u8>) -> Result<u32, VmError> {
+        let stack = memory.stack();
+        if self.sp < STACK_SLOT_SIZE {
+            return Err(VmError::StackUnderflow { pc: self.pc });
+        }
+
+        let sp = self.sp - STACK_SLOT_SIZE;
+        let mut bytes = [0; STACK_SLOT_SIZE];
+        for (offset, byte) in bytes.iter_mut().enumerate() {
+            *byte = memory.read(stack.start + sp + offset)?;
+        }
+        self.sp = sp;
+        Ok(u32::from_le_bytes(bytes))
+    }
+}
+
+/// What INC3A, DECA, ADDI, ADD and SUB do when their signed result does not
+/// fit in a register.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OverflowMode {
+    #[default]
+    Wrapping, // The result wraps around, as in two's complement hardware
+    Saturating, // The result is clamped to the closest representable value
+    Trapping,   // The instruction faults with an ArithmeticOverflow
+}
+
+/// What made the guest trap, ACC holds its code in the trap handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TrapCause {
+    UnknownOpCode = 1,
+    InvalidRegister = 2,
+    MemoryOutOfBounds = 3,
+    StackOverflow = 4,
+    StackUnderflow = 5,
+    UnknownHostCall = 6,
+    ArithmeticOverflow = 7,
+}
+
+impl TryFrom<u8> for TrapCause {
+    type Error = ();
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            v if v == Self::UnknownOpCode as u8 => Ok(Self::UnknownOpCode),
+            v if v == Self::InvalidRegister as u8 => Ok(Self::InvalidRegister),
+            v if v == Self::MemoryOutOfBounds as u8 => Ok(Self::MemoryOutOfBounds),
+            v if v == Self::StackOverflow as u8 => Ok(Self::StackOverflow),
+            v if v == Self::StackUnderflow as u8 => Ok(Self::StackUnderflow),
+            v if v == Self::UnknownHostCall as u8 => Ok(Self::UnknownHostCall),
+            v if v == Self::ArithmeticOverflow as u8 => Ok(Self::ArithmeticOverflow),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A trap raised by the instruction at `pc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trap {
+    pub cause: TrapCause,
+    pub pc: usize,
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new(0, 0, 0, false)
     }
 }
 
@@ -21,15 +489,108 @@ impl Display for Cpu {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u8)]
 pub enum OpCode {
-    HALT = 0,  // HALT = true
-    CLRA = 1,  // A  = 0, PC += 1
-    INC3A = 2, // A += 3, PC += 1
-    DECA = 3,  // A -= 1, PC += 1
-    SETL = 4,  // L  = A, PC += 1
-    BACK7 = 5, // L -= 1, if L > 0 then PC -= 6 else PC += 1
+    HALT = 0,    // HALT = true
+    CLRA = 1,    // A  = 0, PC += 1
+    INC3A = 2,   // A += 3, PC += 1
+    DECA = 3,    // A -= 1, PC += 1
+    SETL = 4,    // L  = A, PC += 1
+    BACK7 = 5,   // L -= 1, if L > 0 then PC -= 6 else PC += 1
+    LDA = 6,     // A  = M[addr], PC += 3
+    STA = 7,     // M[addr] = A (low byte), PC += 3
+    ADDI = 8,    // A += imm8 (sign extended), PC += 2
+    LI = 9,      // A  = imm16 (sign extended), PC += 3
+    JMP = 10,    // PC = addr
+    BEQZ = 11,   // if A == 0 then PC = addr else PC += 3
+    BNEZ = 12,   // if A != 0 then PC = addr else PC += 3
+    PUSH = 13,   // S[SP] = A, SP += 4, PC += 1
+    POP = 14,    // SP -= 4, A = S[SP], PC += 1
+    CALL = 15,   // S[SP] = PC + 3, SP += 4, PC = addr
+    RET = 16,    // SP -= 4, PC = S[SP]
+    MOV = 17,    // Rd  = Rs, PC += 2
+    ADD = 18,    // Rd += Rs, PC += 2
+    SUB = 19,    // Rd -= Rs, PC += 2
+    HCALL = 20,  // Host function imm8 (Cpu, M), PC += 2
+    BEQ = 21,    // if Z then PC = addr else PC += 3
+    BNE = 22,    // if !Z then PC = addr else PC += 3
+    BMI = 23,    // if N then PC = addr else PC += 3
+    BPL = 24,    // if !N then PC = addr else PC += 3
+    BVS = 25,    // if V then PC = addr else PC += 3
+    BVC = 26,    // if !V then PC = addr else PC += 3
+    RDTIME = 27, // A = time (low 32 bits), PC += 1
+}
+
+impl OpCode {
+    /// Whether the instruction terminates a dynamic basic block. Those run
+    /// by the host do, so that faults and writes to code are only observed
+    /// between blocks.
+    pub fn ends_block(&self) -> bool {
+        self.is_branch() || self.needs_host() || *self == OpCode::HALT
+    }
+
+    /// Whether the instruction starts a block as well as ending it, running
+    /// on its own: compiled code only stores the cycles it took when it
+    /// exits, so reads of the clock must come first to see them.
+    pub fn starts_block(&self) -> bool {
+        *self == OpCode::RDTIME
+    }
+
+    /// Whether the instruction is run by the host rather than by compiled
+    /// code: memory accesses, host calls and reads of the clock.
+    pub fn needs_host(&self) -> bool {
+        self.accesses_memory() || matches!(self, OpCode::HCALL | OpCode::RDTIME)
+    }
+
+    /// Whether the instruction may not continue with the next one.
+    pub fn is_branch(&self) -> bool {
+        matches!(
+            self,
+            OpCode::BACK7 | OpCode::JMP | OpCode::BEQZ | OpCode::BNEZ | OpCode::CALL | OpCode::RET
+        ) || self.flag_condition().is_some()
+    }
+
+    /// The flag tested by a flag branch, and whether it must be set for the
+    /// branch to be taken.
+    pub fn flag_condition(&self) -> Option<(u8, bool)> {
+        match self {
+            OpCode::BEQ => Some((FLAG_ZERO, true)),
+            OpCode::BNE => Some((FLAG_ZERO, false)),
+            OpCode::BMI => Some((FLAG_NEGATIVE, true)),
+            OpCode::BPL => Some((FLAG_NEGATIVE, false)),
+            OpCode::BVS => Some((FLAG_OVERFLOW, true)),
+            OpCode::BVC => Some((FLAG_OVERFLOW, false)),
+            _ => None,
+        }
+    }
+
+    /// Whether the instruction reads or writes the guest memory, the stack
+    /// included.
+    pub fn accesses_memory(&self) -> bool {
+        matches!(
+            self,
+            OpCode::LDA | OpCode::STA | OpCode::PUSH | OpCode::POP | OpCode::CALL | OpCode::RET
+        )
+    }
+
+    /// Whether the operand is a byte naming the destination register in its
+    /// high nibble and the source register in its low one.
+    pub fn has_register_operands(&self) -> bool {
+        matches!(self, OpCode::MOV | OpCode::ADD | OpCode::SUB)
+    }
+
+    /// The size of the encoded instruction in bytes, the operand bytes
+    /// follow the opcode.
+    pub fn length(&self) -> usize {
+        match self {
+            OpCode::ADDI | OpCode::MOV | OpCode::ADD | OpCode::SUB | OpCode::HCALL => 2,
+            OpCode::LDA | OpCode::STA | OpCode::LI => 3,
+            OpCode::JMP | OpCode::BEQZ | OpCode::BNEZ | OpCode::CALL => 3,
+            op if op.flag_condition().is_some() => 3,
+            _ => 1,
+        }
+    }
 }
 
 impl Display for OpCode {
@@ -49,7 +610,164 @@ impl TryFrom<u8> for OpCode {
             v if v == Self::DECA as u8 => Ok(Self::DECA),
             v if v == Self::SETL as u8 => Ok(Self::SETL),
             v if v == Self::BACK7 as u8 => Ok(Self::BACK7),
+            v if v == Self::LDA as u8 => Ok(Self::LDA),
+            v if v == Self::STA as u8 => Ok(Self::STA),
+            v if v == Self::ADDI as u8 => Ok(Self::ADDI),
+            v if v == Self::LI as u8 => Ok(Self::LI),
+            v if v == Self::JMP as u8 => Ok(Self::JMP),
+            v if v == Self::BEQZ as u8 => Ok(Self::BEQZ),
+            v if v == Self::BNEZ as u8 => Ok(Self::BNEZ),
+            v if v == Self::PUSH as u8 => Ok(Self::PUSH),
+            v if v == Self::POP as u8 => Ok(Self::POP),
+            v if v == Self::CALL as u8 => Ok(Self::CALL),
+            v if v == Self::RET as u8 => Ok(Self::RET),
+            v if v == Self::MOV as u8 => Ok(Self::MOV),
+            v if v == Self::ADD as u8 => Ok(Self::ADD),
+            v if v == Self::SUB as u8 => Ok(Self::SUB),
+            v if v == Self::HCALL as u8 => Ok(Self::HCALL),
+            v if v == Self::BEQ as u8 => Ok(Self::BEQ),
+            v if v == Self::BNE as u8 => Ok(Self::BNE),
+            v if v == Self::BMI as u8 => Ok(Self::BMI),
+            v if v == Self::BPL as u8 => Ok(Self::BPL),
+            v if v == Self::BVS as u8 => Ok(Self::BVS),
+            v if v == Self::BVC as u8 => Ok(Self::BVC),
+            v if v == Self::RDTIME as u8 => Ok(Self::RDTIME),
             _ => Err(()),
         }
     }
 }
+
+/// A decoded instruction, `operand` is zero for instructions without one.
+/// Immediates are kept as raw bits, sign extension is up to the semantics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Instruction {
+    pub opcode: OpCode,
+    pub operand: u16,
+}
+
+impl Instruction {
+    pub fn new(opcode: OpCode, operand: u16) -> Self {
+        Self { opcode, operand }
+    }
+
+    pub fn length(&self) -> usize {
+        self.opcode.length()
+    }
+
+    /// The destination and source registers of register-register instructions.
+    pub fn registers(&self) -> (usize, usize) {
+        ((self.operand >> 4) as usize, (self.operand & 0xf) as usize)
+    }
+}
+
+impl From<OpCode> for Instruction {
+    fn from(opcode: OpCode) -> Self {
+        Self::new(opcode, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{OpCode, HANDLERS};
+    use crate::{
+        backend::BackendKind,
+        error::VmError,
+        program::{asm, Program},
+        tests::init,
+        EmulationEngine, Outcome,
+    };
+
+    #[test]
+    pub fn instret_does_not_depend_on_the_tier() {
+        init();
+        let builders = [
+            EmulationEngine::builder().backend(BackendKind::Reference),
+            EmulationEngine::builder().backend(BackendKind::Interpreter),
+            EmulationEngine::builder()
+                .baseline_threshold(1)
+                .compile_threshold(u64::MAX),
+            EmulationEngine::builder().compile_threshold(1),
+            #[cfg(feature = "cranelift")]
+            EmulationEngine::builder().backend(BackendKind::Cranelift),
+        ];
+        for builder in builders {
+            let mut vm = builder.build().unwrap();
+            // 1000 iterations of 6 INC3A and a BACK7, then the HALT
+            let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 0], 0, 1000);
+            vm.load_program(prog).unwrap();
+
+            // Every run retires exactly the fuel it was given
+            let mut runs = 0;
+            while vm.run_for(333) == Ok(Outcome::FuelExhausted) {
+                runs += 1;
+                assert_eq!(vm.cpu.instret, runs * 333);
+            }
+            assert!(vm.cpu.halt);
+            assert_eq!(vm.cpu.instret, 7001);
+        }
+    }
+
+    #[test]
+    pub fn instructions_dispatch_to_their_handler() {
+        init();
+        // Runs `source` from ACC 5 and LC 2, returning the ACC, LC, PC, SP
+        // and R2 it leaves
+        let run = |source: &str| {
+            let prog = asm::assemble(source).unwrap();
+            let mut vm = EmulationEngine::default();
+            vm.load_program(Program::new(prog.data, 5, 2)).unwrap();
+            for _ in source.lines().filter(|line| !line.trim().is_empty()) {
+                vm.step().unwrap();
+            }
+            let cpu = vm.cpu;
+            (cpu.acc, cpu.lc, cpu.pc, cpu.sp, cpu.gpr[0])
+        };
+        let cases = [
+            ("CLRA", (0, 2, 1, 0, 0)),
+            ("INC3A", (8, 2, 1, 0, 0)),
+            ("DECA", (4, 2, 1, 0, 0)),
+            ("SETL", (5, 5, 1, 0, 0)),
+            (
+                "CLRA\n CLRA\n CLRA\n CLRA\n CLRA\n CLRA\n BACK7",
+                (0, 1, 0, 0, 0),
+            ),
+            ("LDA 0", (OpCode::LDA as i32, 2, 3, 0, 0)),
+            ("STA 0x80", (5, 2, 3, 0, 0)),
+            ("ADDI -2", (3, 2, 2, 0, 0)),
+            ("LI 0x1234", (0x1234, 2, 3, 0, 0)),
+            ("JMP 0x40", (5, 2, 0x40, 0, 0)),
+            ("BEQZ 0x40", (5, 2, 3, 0, 0)),
+            ("BNEZ 0x40", (5, 2, 0x40, 0, 0)),
+            ("PUSH", (5, 2, 1, 4, 0)),
+            ("PUSH\n CLRA\n POP", (5, 2, 3, 0, 0)),
+            ("CALL 0x40", (5, 2, 0x40, 4, 0)),
+            ("CALL 3\n RET", (5, 2, 3, 0, 0)),
+            ("MOV R2, A", (5, 2, 2, 0, 5)),
+            ("ADD A, L", (7, 2, 2, 0, 0)),
+            ("SUB A, L", (3, 2, 2, 0, 0)),
+            // No flag is set
+            ("BEQ 0x40", (5, 2, 3, 0, 0)),
+            ("BNE 0x40", (5, 2, 0x40, 0, 0)),
+            ("BMI 0x40", (5, 2, 3, 0, 0)),
+            ("BPL 0x40", (5, 2, 0x40, 0, 0)),
+            ("BVS 0x40", (5, 2, 3, 0, 0)),
+            ("BVC 0x40", (5, 2, 0x40, 0, 0)),
+            ("RDTIME", (0, 2, 1, 0, 0)),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(run(source), expected, "{}", source);
+        }
+
+        let prog = asm::assemble("STA 0x80\n HCALL 3\n HALT").unwrap();
+        let mut vm = EmulationEngine::default();
+        vm.load_program(Program::new(prog.data, 5, 2)).unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.read_memory(0x80), Ok(5));
+        assert_eq!(vm.step(), Err(VmError::UnknownHostCall { pc: 3, index: 3 }));
+        vm.cpu.pc += 2;
+        vm.step().unwrap();
+        assert!(vm.cpu.halt);
+        assert_eq!(HANDLERS.len(), OpCode::RDTIME as usize + 1);
+    }
+}
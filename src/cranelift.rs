@@ -0,0 +1,718 @@
+use std::{collections::HashMap, mem};
+
+use crate::{
+    backend::CodeStats,
+    codegen::{execute_on_host, CompiledFunc, Symbols},
+    config::OptimizationLevel,
+    cpu::{Cpu, Instruction, OpCode, OverflowMode, FLAG_NEGATIVE, FLAG_OVERFLOW, FLAG_ZERO},
+    error::VmError,
+    mir::loop_head,
+    profile::BranchCounts,
+    remote::{Compiler, CompilerThread, Native},
+    timing::CostTable,
+};
+use cranelift_codegen::{
+    entity::EntityRef,
+    ir::{condcodes::IntCC, types, AbiParam, InstBuilder, MemFlags, Signature, Type, Value},
+    isa::OwnedTargetIsa,
+    settings::{self, Configurable},
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+
+const ACC: i32 = mem::offset_of!(Cpu, acc) as i32;
+const LC: i32 = mem::offset_of!(Cpu, lc) as i32;
+const PC: i32 = mem::offset_of!(Cpu, pc) as i32;
+const HALT: i32 = mem::offset_of!(Cpu, halt) as i32;
+const GPR: i32 = mem::offset_of!(Cpu, gpr) as i32;
+const FLAGS: i32 = mem::offset_of!(Cpu, flags) as i32;
+const INSTRET: i32 = mem::offset_of!(Cpu, instret) as i32;
+const CYCLES: i32 = mem::offset_of!(Cpu, cycles) as i32;
+const TIMECMP: i32 = mem::offset_of!(Cpu, timecmp) as i32;
+
+/// Compiles blocks with Cranelift, which generates slower code than LLVM
+/// in a fraction of the time, on the `CompilerThread` owning the modules,
+/// see `CraneliftBackend::spawn`. Every block is compiled in a module of
+/// its own, whose memory is freed once the engine drops the block.
+pub struct CraneliftBackend {
+    isa: OwnedTargetIsa,
+    overflow_mode: OverflowMode,
+    costs: CostTable,
+    safepoint_interval: u64, // Iterations of native loops between polls of the stop flag
+    symbols: Symbols,        // Names of the functions, see `backend::symbol`
+    modules: HashMap<u64, JITModule>, // Of the blocks, by key
+}
+
+impl CraneliftBackend {
+    pub fn new(
+        opt_level: OptimizationLevel,
+        overflow_mode: OverflowMode,
+        costs: CostTable,
+        safepoint_interval: u64,
+    ) -> Result<Self, VmError> {
+        let opt_level = match opt_level {
+            OptimizationLevel::None => "none",
+            OptimizationLevel::Less | OptimizationLevel::Default => "speed",
+            OptimizationLevel::Aggressive => "speed_and_size",
+        };
+
+        let mut flags = settings::builder();
+        flags
+            .set("opt_level", opt_level)
+            .map_err(|e| VmError::JitCreationFailed(e.to_string()))?;
+
+        let isa = cranelift_native::builder()
+            .map_err(|msg| VmError::JitCreationFailed(msg.to_string()))?
+            .finish(settings::Flags::new(flags))
+            .map_err(|e| VmError::JitCreationFailed(e.to_string()))?;
+
+        Ok(Self {
+            isa,
+            overflow_mode,
+            costs,
+            safepoint_interval,
+            symbols: Symbols::default(),
+            modules: HashMap::new(),
+        })
+    }
+
+    /// Runs a backend created as by `new` on a `CompilerThread`, so that the
+    /// modules are created, used and freed by the same thread, whichever
+    /// runs the engine.
+    pub(crate) fn spawn(
+        opt_level: OptimizationLevel,
+        overflow_mode: OverflowMode,
+        costs: CostTable,
+        safepoint_interval: u64,
+    ) -> Result<CompilerThread, VmError> {
+        let share_key = format!(
+            "{:?} {:?} {:?} {}",
+            opt_level, overflow_mode, costs, safepoint_interval
+        );
+        CompilerThread::spawn("cranelift", Some(share_key), move |requests| {
+            let backend = Self::new(opt_level, overflow_mode, costs, safepoint_interval);
+            match backend {
+                Ok(mut backend) => requests.serve(Ok(&mut backend)),
+                Err(e) => requests.serve(Err(e)),
+            }
+        })
+    }
+}
+
+impl Compiler for CraneliftBackend {
+    fn compile(
+        &mut self,
+        key: u64,
+        pc: usize,
+        block: &[Instruction],
+        _back7: Option<BranchCounts>,
+    ) -> Result<Native, VmError> {
+        let mut module = JITModule::new(JITBuilder::with_isa(
+            self.isa.clone(),
+            default_libcall_names(),
+        ));
+        let pointer_type = module.target_config().pointer_type();
+
+        let mut ctx = module.make_context();
+        ctx.func.signature.params.push(AbiParam::new(pointer_type));
+        ctx.func.signature.params.push(AbiParam::new(pointer_type));
+        ctx.func.signature.params.push(AbiParam::new(types::I64));
+        ctx.func.signature.params.push(AbiParam::new(pointer_type));
+        ctx.func.signature.returns.push(AbiParam::new(types::I64));
+
+        let name = self.symbols.next(pc);
+        let func_id = module
+            .declare_function(&name, Linkage::Export, &ctx.func.signature)
+            .map_err(|e| VmError::CompilationFailed(e.to_string()))?;
+
+        let mut builder_context = FunctionBuilderContext::new();
+        let builder = FunctionBuilder::new(&mut ctx.func, &mut builder_context);
+        let mut host_signature = module.make_signature();
+        host_signature.params.extend([
+            AbiParam::new(pointer_type),
+            AbiParam::new(pointer_type),
+            AbiParam::new(types::I8),
+            AbiParam::new(types::I16),
+        ]);
+        host_signature.returns.push(AbiParam::new(types::I64));
+
+        FunctionTranslator::new(
+            builder,
+            pointer_type,
+            host_signature,
+            self.overflow_mode,
+            &self.costs,
+            self.safepoint_interval,
+        )
+        .translate(block);
+
+        let ir_instructions = ctx.func.dfg.num_insts();
+        let compiled = module
+            .define_function(func_id, &mut ctx)
+            .map_err(|e| VmError::CompilationFailed(e.to_string()))?;
+        module.clear_context(&mut ctx);
+        module.finalize_definitions();
+
+        let code = module.get_finalized_function(func_id);
+        let fun = unsafe { mem::transmute::<*const u8, CompiledFunc>(code) };
+
+        self.modules.insert(key, module);
+        Ok(Native {
+            fun,
+            name,
+            stats: CodeStats {
+                ir_instructions: Some(ir_instructions),
+                code_size: Some(compiled.size as usize),
+            },
+        })
+    }
+
+    fn free(&mut self, key: u64) {
+        if let Some(module) = self.modules.remove(&key) {
+            // SAFETY: the block was dropped, nothing runs its code anymore
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+/// Emits the code of a block, with the same shape as the LLVM translation:
+/// registers live in variables and in-block BACK7 loops become native loops.
+struct FunctionTranslator<'a> {
+    builder: FunctionBuilder<'a>,
+    pointer_type: Type,
+    host_signature: Signature,
+    overflow_mode: OverflowMode,
+    costs: &'a CostTable,
+    safepoint_interval: u64, // Iterations of native loops between polls of the stop flag
+    cpu: Value,
+    memory: Value,
+    budget: Value,
+    stop: Value,
+    acc: Variable,
+    lc: Variable,
+    pc: Variable,
+    flags: Variable,
+    executed: Variable, // Instructions executed before those being built, counted per iteration
+    cycles: Variable,   // Cycles taken by the instructions executed before, likewise
+    pending_cycles: u64, // Cycles of the instructions built since `cycles` was last updated
+    halted: bool,
+}
+
+impl<'a> FunctionTranslator<'a> {
+    fn new(
+        mut builder: FunctionBuilder<'a>,
+        pointer_type: Type,
+        host_signature: Signature,
+        overflow_mode: OverflowMode,
+        costs: &'a CostTable,
+        safepoint_interval: u64,
+    ) -> Self {
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+
+        let params = builder.block_params(entry);
+        let (cpu, memory, budget, stop) = (params[0], params[1], params[2], params[3]);
+
+        let acc = Variable::new(0);
+        let lc = Variable::new(1);
+        let pc = Variable::new(2);
+        let flags = Variable::new(3);
+        let executed = Variable::new(4);
+        let cycles = Variable::new(5);
+        builder.declare_var(acc, types::I32);
+        builder.declare_var(lc, types::I32);
+        builder.declare_var(pc, pointer_type);
+        builder.declare_var(flags, types::I8);
+        builder.declare_var(executed, types::I64);
+        builder.declare_var(cycles, types::I64);
+
+        // Registers are loaded once here and written back in the epilogue
+        let mem_flags = MemFlags::trusted();
+        let value = builder.ins().load(types::I32, mem_flags, cpu, ACC);
+        builder.def_var(acc, value);
+        let value = builder.ins().load(types::I32, mem_flags, cpu, LC);
+        builder.def_var(lc, value);
+        let value = builder.ins().load(pointer_type, mem_flags, cpu, PC);
+        builder.def_var(pc, value);
+        let value = builder.ins().load(types::I8, mem_flags, cpu, FLAGS);
+        builder.def_var(flags, value);
+        let value = builder.ins().iconst(types::I64, 0);
+        builder.def_var(executed, value);
+        builder.def_var(cycles, value);
+
+        Self {
+            builder,
+            pointer_type,
+            host_signature,
+            overflow_mode,
+            costs,
+            safepoint_interval,
+            cpu,
+            memory,
+            budget,
+            stop,
+            acc,
+            lc,
+            pc,
+            flags,
+            executed,
+            cycles,
+            pending_cycles: 0,
+            halted: false,
+        }
+    }
+
+    fn translate(mut self, block: &[Instruction]) {
+        match loop_head(block) {
+            Some(head) => {
+                self.build_instructions(&block[..head]);
+                self.build_loop(head, &block[head..block.len() - 1]);
+            }
+            None => match block.split_last() {
+                Some((last, body)) if last.opcode.needs_host() => {
+                    self.build_instructions(body);
+                    self.build_exit_to_host(*last, body.len());
+                }
+                _ => {
+                    self.build_instructions(block);
+                    let executed = self.builder.ins().iconst(types::I64, block.len() as i64);
+                    self.build_epilogue(executed);
+                }
+            },
+        }
+
+        self.builder.seal_all_blocks();
+        self.builder.finalize();
+    }
+
+    fn build_instructions(&mut self, instructions: &[Instruction]) {
+        for (position, instr) in instructions.iter().enumerate() {
+            self.build_instruction(*instr, position);
+            self.pending_cycles += self.costs.cost(instr.opcode);
+        }
+    }
+
+    /// Builds `instr`, which follows `position` instructions of the current
+    /// loop iteration, or of the block outside of loops.
+    fn build_instruction(&mut self, instr: Instruction, position: usize) {
+        match instr.opcode {
+            OpCode::HALT => self.halted = true,
+            OpCode::CLRA => {
+                let zero = self.builder.ins().iconst(types::I32, 0);
+                self.builder.def_var(self.acc, zero);
+                let flags = self.builder.ins().iconst(types::I8, FLAG_ZERO as i64);
+                self.builder.def_var(self.flags, flags);
+            }
+            OpCode::INC3A => self.add_to_acc(instr, position, 3),
+            OpCode::DECA => self.add_to_acc(instr, position, -1),
+            OpCode::SETL => {
+                let acc = self.builder.use_var(self.acc);
+                self.builder.def_var(self.lc, acc);
+            }
+            // Branches update the pc by themselves
+            OpCode::BACK7 => {
+                self.add_to(self.lc, -1);
+                let lc = self.builder.use_var(self.lc);
+                let taken = self.builder.ins().icmp_imm(IntCC::SignedGreaterThan, lc, 0);
+                let pc = self.builder.use_var(self.pc);
+                let back = self.builder.ins().iadd_imm(pc, -6);
+                let next = self.builder.ins().iadd_imm(pc, 1);
+                let pc = self.builder.ins().select(taken, back, next);
+                self.builder.def_var(self.pc, pc);
+                return;
+            }
+            OpCode::ADDI => self.add_to_acc(instr, position, instr.operand as u8 as i8 as i64),
+            OpCode::LI => {
+                let imm = self
+                    .builder
+                    .ins()
+                    .iconst(types::I32, instr.operand as i16 as i64);
+                self.builder.def_var(self.acc, imm);
+            }
+            OpCode::MOV | OpCode::ADD | OpCode::SUB => {
+                let (rd, rs) = instr.registers();
+                let source = self.read_register(rs);
+                let value = match instr.opcode {
+                    OpCode::ADD | OpCode::SUB => {
+                        let destination = self.read_register(rd);
+                        let (wrapped, overflow) =
+                            self.overflowing(instr.opcode, destination, source);
+                        self.overflow(instr, position, destination, wrapped, overflow)
+                    }
+                    _ => source,
+                };
+                self.write_register(rd, value);
+            }
+            OpCode::JMP => {
+                let target = self
+                    .builder
+                    .ins()
+                    .iconst(self.pointer_type, instr.operand as i64);
+                self.builder.def_var(self.pc, target);
+                return;
+            }
+            OpCode::BEQZ | OpCode::BNEZ => {
+                let condition = match instr.opcode {
+                    OpCode::BEQZ => IntCC::Equal,
+                    _ => IntCC::NotEqual,
+                };
+                let acc = self.builder.use_var(self.acc);
+                let taken = self.builder.ins().icmp_imm(condition, acc, 0);
+                let target = self
+                    .builder
+                    .ins()
+                    .iconst(self.pointer_type, instr.operand as i64);
+                let pc = self.builder.use_var(self.pc);
+                let next = self.builder.ins().iadd_imm(pc, instr.length() as i64);
+                let pc = self.builder.ins().select(taken, target, next);
+                self.builder.def_var(self.pc, pc);
+                return;
+            }
+            OpCode::BEQ | OpCode::BNE | OpCode::BMI | OpCode::BPL | OpCode::BVS | OpCode::BVC => {
+                let (flag, set) = instr.opcode.flag_condition().unwrap();
+                let condition = if set { IntCC::NotEqual } else { IntCC::Equal };
+                let flags = self.builder.use_var(self.flags);
+                let mask = self.builder.ins().iconst(types::I8, flag as i64);
+                let masked = self.builder.ins().band(flags, mask);
+                let taken = self.builder.ins().icmp_imm(condition, masked, 0);
+                let target = self
+                    .builder
+                    .ins()
+                    .iconst(self.pointer_type, instr.operand as i64);
+                let pc = self.builder.use_var(self.pc);
+                let next = self.builder.ins().iadd_imm(pc, instr.length() as i64);
+                let pc = self.builder.ins().select(taken, target, next);
+                self.builder.def_var(self.pc, pc);
+                return;
+            }
+            OpCode::LDA
+            | OpCode::STA
+            | OpCode::PUSH
+            | OpCode::POP
+            | OpCode::CALL
+            | OpCode::RET
+            | OpCode::HCALL
+            | OpCode::RDTIME => unreachable!("instructions run by the host end blocks"),
+        }
+        self.add_to(self.pc, instr.length() as i64);
+    }
+
+    fn build_loop(&mut self, head: usize, body: &[Instruction]) {
+        // Instructions executed by an iteration, BACK7 included
+        let body_length = body.len() as i64 + 1;
+        let head_pc = self.builder.use_var(self.pc);
+
+        // The dispatcher guarantees that the first iteration fits the budget
+        let limit = self.builder.ins().iadd_imm(self.budget, -body_length);
+        let executed = self.executed;
+        let value = self.builder.ins().iconst(types::I64, head as i64);
+        self.builder.def_var(executed, value);
+        let prologue_cycles = mem::take(&mut self.pending_cycles);
+        self.add_to(self.cycles, prologue_cycles as i64);
+
+        // The cycle counter in memory is only updated in the epilogue
+        let flags = MemFlags::trusted();
+        let entry_cycles = self.builder.ins().load(types::I64, flags, self.cpu, CYCLES);
+        let timecmp = self
+            .builder
+            .ins()
+            .load(types::I64, flags, self.cpu, TIMECMP);
+        // Iterations left before the safepoint, when it is not every one
+        let countdown = Variable::new(6);
+        if self.safepoint_interval > 1 {
+            self.builder.declare_var(countdown, types::I64);
+            let value = self
+                .builder
+                .ins()
+                .iconst(types::I64, self.safepoint_interval as i64);
+            self.builder.def_var(countdown, value);
+        }
+
+        let loop_block = self.builder.create_block();
+        let exit_block = self.builder.create_block();
+        self.builder.ins().jump(loop_block, &[]);
+        self.builder.switch_to_block(loop_block);
+
+        self.builder.def_var(self.pc, head_pc);
+        self.build_instructions(body);
+
+        // BACK7, with the backward branch turned into the loop latch
+        self.add_to(self.lc, -1);
+        self.add_to(executed, body_length);
+        let iteration_cycles = mem::take(&mut self.pending_cycles) + self.costs.cost(OpCode::BACK7);
+        self.add_to(self.cycles, iteration_cycles as i64);
+
+        let lc = self.builder.use_var(self.lc);
+        let taken = self.builder.ins().icmp_imm(IntCC::SignedGreaterThan, lc, 0);
+        let executed_value = self.builder.use_var(executed);
+        let fits = self
+            .builder
+            .ins()
+            .icmp(IntCC::UnsignedLessThanOrEqual, executed_value, limit);
+        let cycles = self.builder.use_var(self.cycles);
+        let now = self.builder.ins().iadd(entry_cycles, cycles);
+        let on_time = self
+            .builder
+            .ins()
+            .icmp(IntCC::UnsignedLessThan, now, timecmp);
+        let again = self.builder.ins().band(taken, fits);
+        let again = self.builder.ins().band(again, on_time);
+        match self.safepoint_interval {
+            1 => {
+                let running = self.build_stop_poll();
+                let again = self.builder.ins().band(again, running);
+                self.builder.ins().brnz(again, loop_block, &[]);
+                self.builder.ins().jump(exit_block, &[]);
+            }
+            interval => {
+                let check_block = self.builder.create_block();
+                let safepoint_block = self.builder.create_block();
+                self.add_to(countdown, -1);
+                self.builder.ins().brnz(again, check_block, &[]);
+                self.builder.ins().jump(exit_block, &[]);
+
+                self.builder.switch_to_block(check_block);
+                let left = self.builder.use_var(countdown);
+                self.builder.ins().brz(left, safepoint_block, &[]);
+                self.builder.ins().jump(loop_block, &[]);
+
+                self.builder.switch_to_block(safepoint_block);
+                let value = self.builder.ins().iconst(types::I64, interval as i64);
+                self.builder.def_var(countdown, value);
+                let running = self.build_stop_poll();
+                self.builder.ins().brnz(running, loop_block, &[]);
+                self.builder.ins().jump(exit_block, &[]);
+            }
+        }
+
+        // Leaving the loop: either the loop is over, the budget is, an
+        // interrupt is due or the host asked to stop
+        self.builder.switch_to_block(exit_block);
+        let pc = self.builder.use_var(self.pc);
+        let next_pc = self.builder.ins().iadd_imm(pc, 1);
+        let pc = self.builder.ins().select(taken, head_pc, next_pc);
+        self.builder.def_var(self.pc, pc);
+
+        let executed = self.builder.use_var(executed);
+        self.build_epilogue(executed);
+    }
+
+    /// Loads the stop flag of the host, returning whether the guest may go
+    /// on running.
+    fn build_stop_poll(&mut self) -> Value {
+        let stop = self
+            .builder
+            .ins()
+            .atomic_load(types::I8, MemFlags::trusted(), self.stop);
+        self.builder.ins().icmp_imm(IntCC::Equal, stop, 0)
+    }
+
+    /// Leaves the block by running `instr`, built at `position`, on the host,
+    /// which either completes it or records its fault.
+    fn build_exit_to_host(&mut self, instr: Instruction, position: usize) {
+        self.store_registers();
+
+        let signature = self.builder.import_signature(self.host_signature.clone());
+        let callee = self
+            .builder
+            .ins()
+            .iconst(self.pointer_type, execute_on_host as *const () as i64);
+        let opcode = self.builder.ins().iconst(types::I8, instr.opcode as i64);
+        let operand = self.builder.ins().iconst(types::I16, instr.operand as i64);
+        let call = self.builder.ins().call_indirect(
+            signature,
+            callee,
+            &[self.cpu, self.memory, opcode, operand],
+        );
+
+        let done = self.builder.inst_results(call)[0];
+        let executed = self.builder.use_var(self.executed);
+        let executed = self.builder.ins().iadd(executed, done);
+        let executed = self.builder.ins().iadd_imm(executed, position as i64);
+        let cost = self
+            .builder
+            .ins()
+            .iconst(types::I64, self.costs.cost(instr.opcode) as i64);
+        let cost = self.builder.ins().imul(done, cost);
+        let cycles = self.elapsed_cycles();
+        let cycles = self.builder.ins().iadd(cycles, cost);
+        self.retire(executed, cycles);
+        self.builder.ins().return_(&[executed]);
+    }
+
+    fn build_epilogue(&mut self, executed: Value) {
+        self.store_registers();
+        let cycles = self.elapsed_cycles();
+        self.retire(executed, cycles);
+        self.builder.ins().return_(&[executed]);
+    }
+
+    /// The cycles taken by the instructions executed before the one being
+    /// built.
+    fn elapsed_cycles(&mut self) -> Value {
+        let cycles = self.builder.use_var(self.cycles);
+        self.builder
+            .ins()
+            .iadd_imm(cycles, self.pending_cycles as i64)
+    }
+
+    /// Adds the instructions executed by the block to the retired ones, and
+    /// the cycles they took to the elapsed ones.
+    fn retire(&mut self, executed: Value, cycles: Value) {
+        let flags = MemFlags::trusted();
+        for (offset, value) in [(INSTRET, executed), (CYCLES, cycles)] {
+            let counter = self.builder.ins().load(types::I64, flags, self.cpu, offset);
+            let counter = self.builder.ins().iadd(counter, value);
+            self.builder.ins().store(flags, counter, self.cpu, offset);
+        }
+    }
+
+    fn store_registers(&mut self) {
+        let flags = MemFlags::trusted();
+        let acc = self.builder.use_var(self.acc);
+        self.builder.ins().store(flags, acc, self.cpu, ACC);
+        let lc = self.builder.use_var(self.lc);
+        self.builder.ins().store(flags, lc, self.cpu, LC);
+        let pc = self.builder.use_var(self.pc);
+        self.builder.ins().store(flags, pc, self.cpu, PC);
+        let value = self.builder.use_var(self.flags);
+        self.builder.ins().store(flags, value, self.cpu, FLAGS);
+
+        if self.halted {
+            let halt = self.builder.ins().iconst(types::I8, 1);
+            self.builder.ins().store(flags, halt, self.cpu, HALT);
+        }
+    }
+
+    // R2 to R7 live in the Cpu, only ACC and LC are kept in variables
+    fn read_register(&mut self, index: usize) -> Value {
+        match index {
+            0 => self.builder.use_var(self.acc),
+            1 => self.builder.use_var(self.lc),
+            _ => {
+                let offset = GPR + 4 * (index as i32 - 2);
+                self.builder
+                    .ins()
+                    .load(types::I32, MemFlags::trusted(), self.cpu, offset)
+            }
+        }
+    }
+
+    fn write_register(&mut self, index: usize, value: Value) {
+        match index {
+            0 => self.builder.def_var(self.acc, value),
+            1 => self.builder.def_var(self.lc, value),
+            _ => {
+                let offset = GPR + 4 * (index as i32 - 2);
+                self.builder
+                    .ins()
+                    .store(MemFlags::trusted(), value, self.cpu, offset);
+            }
+        }
+    }
+
+    /// Adds `imm` to the accumulator and sets the flags from the result as
+    /// the interpreter does.
+    fn add_to_acc(&mut self, instr: Instruction, position: usize, imm: i64) {
+        let acc = self.builder.use_var(self.acc);
+        let wrapped = self.builder.ins().iadd_imm(acc, imm);
+
+        // Adding a constant overflows when the result moves the wrong way
+        let condition = if imm < 0 {
+            IntCC::SignedGreaterThan
+        } else {
+            IntCC::SignedLessThan
+        };
+        let overflow = self.builder.ins().icmp(condition, wrapped, acc);
+        let result = self.overflow(instr, position, acc, wrapped, overflow);
+        self.builder.def_var(self.acc, result);
+
+        let zero = self.builder.ins().icmp_imm(IntCC::Equal, result, 0);
+        let negative = self
+            .builder
+            .ins()
+            .icmp_imm(IntCC::SignedLessThan, result, 0);
+
+        let zero = self.flag(zero, FLAG_ZERO);
+        let negative = self.flag(negative, FLAG_NEGATIVE);
+        let overflow = self.flag(overflow, FLAG_OVERFLOW);
+        let flags = self.builder.ins().bor(zero, negative);
+        let flags = self.builder.ins().bor(flags, overflow);
+        self.builder.def_var(self.flags, flags);
+    }
+
+    /// The wrapped result of ADD or SUB and whether it overflowed, i.e. it
+    /// has not the sign of `lhs` when `rhs` pushed it past the bounds.
+    fn overflowing(&mut self, opcode: OpCode, lhs: Value, rhs: Value) -> (Value, Value) {
+        let (result, sign_bits) = match opcode {
+            OpCode::ADD => {
+                let result = self.builder.ins().iadd(lhs, rhs);
+                let lhs_flipped = self.builder.ins().bxor(lhs, result);
+                let rhs_flipped = self.builder.ins().bxor(rhs, result);
+                (result, self.builder.ins().band(lhs_flipped, rhs_flipped))
+            }
+            _ => {
+                let result = self.builder.ins().isub(lhs, rhs);
+                let signs_differ = self.builder.ins().bxor(lhs, rhs);
+                let lhs_flipped = self.builder.ins().bxor(lhs, result);
+                (result, self.builder.ins().band(signs_differ, lhs_flipped))
+            }
+        };
+        let overflow = self
+            .builder
+            .ins()
+            .icmp_imm(IntCC::SignedLessThan, sign_bits, 0);
+        (result, overflow)
+    }
+
+    /// Applies the overflow mode to the `wrapped` result of an operation
+    /// whose left operand is `lhs`, returning the value to keep. Trapping
+    /// overflows leave the block through the host, which faults on `instr`.
+    fn overflow(
+        &mut self,
+        instr: Instruction,
+        position: usize,
+        lhs: Value,
+        wrapped: Value,
+        overflow: Value,
+    ) -> Value {
+        match self.overflow_mode {
+            OverflowMode::Wrapping => wrapped,
+            OverflowMode::Saturating => {
+                // Only results of the sign of `lhs` can overflow
+                let negative = self.builder.ins().icmp_imm(IntCC::SignedLessThan, lhs, 0);
+                let min = self.builder.ins().iconst(types::I32, i32::MIN as i64);
+                let max = self.builder.ins().iconst(types::I32, i32::MAX as i64);
+                let saturated = self.builder.ins().select(negative, min, max);
+                self.builder.ins().select(overflow, saturated, wrapped)
+            }
+            OverflowMode::Trapping => {
+                let trap_block = self.builder.create_block();
+                let continue_block = self.builder.create_block();
+                self.builder.ins().brnz(overflow, trap_block, &[]);
+                self.builder.ins().jump(continue_block, &[]);
+
+                self.builder.switch_to_block(trap_block);
+                self.build_exit_to_host(instr, position);
+
+                self.builder.switch_to_block(continue_block);
+                wrapped
+            }
+        }
+    }
+
+    /// The flags byte with `flag` set when `condition` holds.
+    fn flag(&mut self, condition: Value, flag: u8) -> Value {
+        let set = self.builder.ins().iconst(types::I8, flag as i64);
+        let clear = self.builder.ins().iconst(types::I8, 0);
+        self.builder.ins().select(condition, set, clear)
+    }
+
+    fn add_to(&mut self, var: Variable, imm: i64) {
+        let value = self.builder.use_var(var);
+        let value = self.builder.ins().iadd_imm(value, imm);
+        self.builder.def_var(var, value);
+    }
+}
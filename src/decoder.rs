@@ -0,0 +1,74 @@
+use std::{collections::BTreeMap, ops::Range};
+
+use crate::{
+    bus::Bus,
+    cpu::{Instruction, OpCode, REGISTER_COUNT},
+    error::VmError,
+    memory::Addressable,
+};
+
+/// Instructions take at most 3 bytes, the opcode and a 16 bits operand.
+const MAX_LENGTH: usize = 3;
+
+/// Decodes the instruction stored at `pc`. The operand, if any, follows
+/// the opcode in little-endian order and takes `length() - 1` bytes.
+pub fn decode(memory: &dyn Addressable<u8>, pc: usize) -> Result<Instruction, VmError> {
+    let byte = memory.read(pc)?;
+    let opcode = OpCode::try_from(byte).map_err(|_| VmError::UnknownOpCode { pc, byte })?;
+
+    let mut operand = [0; 2];
+    for (offset, byte) in operand.iter_mut().enumerate().take(opcode.length() - 1) {
+        *byte = memory.read(pc + 1 + offset)?;
+    }
+
+    let instr = Instruction::new(opcode, u16::from_le_bytes(operand));
+    if opcode.has_register_operands() {
+        let (rd, rs) = instr.registers();
+        if let Some(register) = [rd, rs].into_iter().find(|&r| r >= REGISTER_COUNT) {
+            return Err(VmError::InvalidRegister {
+                pc,
+                register: register as u8,
+            });
+        }
+    }
+
+    Ok(instr)
+}
+
+/// The instructions decoded from memory by address, shared by everything
+/// fetching from the guest: the interpreter and the blocks it builds for
+/// the backends. Instructions read from devices are never cached, their
+/// bytes may change without being written.
+#[derive(Debug, Default)]
+pub struct DecodeCache {
+    decoded: BTreeMap<usize, Instruction>,
+}
+
+impl DecodeCache {
+    /// Decodes the instruction at `pc`, unless it was decoded already.
+    pub fn decode(&mut self, bus: &Bus, pc: usize) -> Result<Instruction, VmError> {
+        if let Some(instr) = self.decoded.get(&pc) {
+            return Ok(*instr);
+        }
+
+        let instr = decode(bus, pc)?;
+        if !(pc..pc + instr.length()).any(|address| bus.is_mapped(address)) {
+            self.decoded.insert(pc, instr);
+        }
+        Ok(instr)
+    }
+
+    /// Drops the instructions with a byte in `range`, which was written.
+    pub fn invalidate(&mut self, range: Range<usize>) {
+        let first = range.start.saturating_sub(MAX_LENGTH - 1);
+        let stale: Vec<usize> = self
+            .decoded
+            .range(first..range.end)
+            .filter(|(&pc, instr)| pc + instr.length() > range.start)
+            .map(|(&pc, _)| pc)
+            .collect();
+        for pc in stale {
+            self.decoded.remove(&pc);
+        }
+    }
+}
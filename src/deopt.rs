@@ -0,0 +1,140 @@
+/// Why compiled code left before its end, through one of its guards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DeoptReason {
+    Branch = 1,      // A branch went the way the code was not compiled for
+    Speculation = 2, // A register did not hold the value the code was specialized for
+}
+
+impl TryFrom<u8> for DeoptReason {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(DeoptReason::Branch),
+            2 => Ok(DeoptReason::Speculation),
+            _ => Err(value),
+        }
+    }
+}
+
+/// A guard of compiled code that failed: the code wrote the Cpu back as it
+/// is at `pc` and returned, the engine then interprets the guest from there
+/// before running compiled code again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SideExit {
+    pub pc: usize,           // Where the interpreter resumes
+    pub reason: DeoptReason, // What the failed guard checked
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::{Arc, Mutex};
+
+    use super::{DeoptReason, SideExit};
+    use crate::{
+        backend::BackendKind,
+        cpu::{Cpu, Instruction},
+        observer::{ExecutionObserver, Tier},
+        program::asm,
+        tests::init,
+        EmulationEngine, Outcome,
+    };
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        SideExit(SideExit, Cpu),
+        Block(usize, Tier),
+        Instruction(usize),
+    }
+
+    struct EventLog(Arc<Mutex<Vec<Event>>>);
+
+    impl ExecutionObserver for EventLog {
+        fn on_block_executed(&mut self, pc: usize, tier: Tier, _cpu: &Cpu) {
+            self.0.lock().unwrap().push(Event::Block(pc, tier));
+        }
+
+        fn on_instruction(&mut self, pc: usize, _instr: Instruction, _cpu: &Cpu) {
+            self.0.lock().unwrap().push(Event::Instruction(pc));
+        }
+
+        fn on_side_exit(&mut self, exit: SideExit, cpu: &Cpu) {
+            self.0.lock().unwrap().push(Event::SideExit(exit, *cpu));
+        }
+    }
+
+    #[test]
+    pub fn side_exits_resume_in_the_interpreter() {
+        init();
+        let prog = asm::assemble(
+            "
+                LI 100
+            loop: DECA
+                BEQZ done
+                JMP loop
+            done: HALT
+            ",
+        )
+        .unwrap();
+        // The guest runs until the trace of the loop is compiled and running,
+        // then ACC is set for its guard to fail on the next iteration
+        let run = |vm: &mut EmulationEngine| {
+            vm.load_program(prog.clone()).unwrap();
+            assert_eq!(vm.run_for(30), Ok(Outcome::FuelExhausted));
+            vm.cpu.acc = 1;
+            vm.run()
+        };
+        let mut reference = EmulationEngine::builder()
+            .backend(BackendKind::Reference)
+            .build()
+            .unwrap();
+        assert_eq!(run(&mut reference), Ok(Outcome::Halted));
+
+        let builders = [
+            EmulationEngine::builder().backend(BackendKind::Interpreter),
+            #[cfg(feature = "jit")]
+            EmulationEngine::builder(),
+        ];
+        for builder in builders {
+            let mut vm = builder
+                .compile_threshold(100)
+                .trace_threshold(2)
+                .build()
+                .unwrap();
+            let events = Arc::new(Mutex::new(Vec::new()));
+            vm.add_observer(Box::new(EventLog(events.clone())));
+            assert_eq!(run(&mut vm), Ok(Outcome::Halted));
+            assert_eq!(vm.cpu, reference.cpu);
+
+            // The guard wrote the registers back as they are at the exit,
+            // and the interpreter resumed right there, with the HALT
+            let events = events.lock().unwrap();
+            let exits: Vec<usize> = (0..events.len())
+                .filter(|&index| matches!(events[index], Event::SideExit(..)))
+                .collect();
+            assert_eq!(exits.len(), 1);
+            let Event::SideExit(exit, cpu) = &events[exits[0]] else {
+                unreachable!()
+            };
+            assert_eq!(
+                *exit,
+                SideExit {
+                    pc: 10,
+                    reason: DeoptReason::Branch,
+                }
+            );
+            assert_eq!((cpu.pc, cpu.acc, cpu.lc), (10, 0, 0));
+            assert_eq!(cpu.instret + 1, reference.cpu.instret);
+            assert_eq!(
+                events[exits[0] + 1..],
+                [
+                    Event::Block(3, Tier::Native),
+                    Event::Instruction(10),
+                    Event::Block(10, Tier::Interpreter),
+                ]
+            );
+        }
+    }
+}
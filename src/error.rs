@@ -0,0 +1,160 @@
+use std::{fmt::Display, time::Duration};
+
+use crate::{cpu::TrapCause, verify::Mismatch};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    InvalidConfig(String), // The engine was built with an unusable configuration
+    UnknownOpCode { pc: usize, byte: u8 }, // The byte at `pc` does not decode to any OpCode
+    InvalidRegister { pc: usize, register: u8 }, // The instruction at `pc` names a register that does not exist
+    MemoryOutOfBounds { address: usize },        // An access fell outside of the guest memory
+    WouldBlock { address: usize }, // The device at `address` has nothing to answer the guest yet
+    ProgramTooLarge { size: usize, capacity: usize }, // The program does not fit in memory
+    MemoryLimitExceeded { size: usize, limit: usize }, // Growing the memory would exceed its maximum size
+    StackOverflow { pc: usize }, // The instruction at `pc` pushed onto a full stack
+    StackUnderflow { pc: usize }, // The instruction at `pc` popped from an empty stack
+    UnknownHostCall { pc: usize, index: u8 }, // No host function is registered for the HCALL at `pc`
+    ArithmeticOverflow { pc: usize }, // The instruction at `pc` overflowed with overflows trapping
+    MachineHalted,                    // Execution was requested on a halted machine
+    TimedOut(Duration),               // The guest ran past the deadline of the engine
+    InvalidSnapshot(String),          // The bytes do not hold a snapshot this engine can restore
+    InvalidLog(String),               // The text does not hold an execution log
+    InvalidAssembly { line: usize, message: String }, // The source cannot be assembled
+    InvalidProgram(String),           // The bytes do not hold a program file
+    InvalidProfile(String),           // The text does not hold a warmup profile
+    InvalidTrace(String),             // The bytes do not hold an exported trace
+    InvalidWatchpoint(String),        // The text does not describe a watchpoint
+    InvalidCondition(String),         // The text does not describe a condition
+    Io(String),                       // A file could not be read or written
+    ReplayDiverged { pc: usize, instret: u64 }, // The block at `pc` did not reach the recorded state
+    VerificationMismatch(Box<Mismatch>),        // A native block disagreed with the interpreter
+    JitCreationFailed(String),                  // LLVM refused to create an execution engine
+    VerificationFailed(String),                 // The generated module did not pass LLVM's verifier
+    CompilationFailed(String),                  // The compiled function could not be retrieved
+    DisassemblyFailed(String), // The native code of a block could not be disassembled
+}
+
+impl VmError {
+    /// Whether the error was caused by the guest program rather than the host.
+    pub fn is_trap(&self) -> bool {
+        self.trap_cause().is_some()
+    }
+
+    pub fn trap_cause(&self) -> Option<TrapCause> {
+        match self {
+            VmError::UnknownOpCode { .. } => Some(TrapCause::UnknownOpCode),
+            VmError::InvalidRegister { .. } => Some(TrapCause::InvalidRegister),
+            VmError::MemoryOutOfBounds { .. } => Some(TrapCause::MemoryOutOfBounds),
+            VmError::StackOverflow { .. } => Some(TrapCause::StackOverflow),
+            VmError::StackUnderflow { .. } => Some(TrapCause::StackUnderflow),
+            VmError::UnknownHostCall { .. } => Some(TrapCause::UnknownHostCall),
+            VmError::ArithmeticOverflow { .. } => Some(TrapCause::ArithmeticOverflow),
+            _ => None,
+        }
+    }
+}
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmError::InvalidConfig(msg) => write!(f, "Invalid engine configuration: {}", msg),
+            VmError::UnknownOpCode { pc, byte } => {
+                write!(
+                    f,
+                    "Unknown OpCode {:#04x} read at address {:#04x}",
+                    byte, pc
+                )
+            }
+            VmError::InvalidRegister { pc, register } => {
+                write!(
+                    f,
+                    "Invalid register R{} used at address {:#04x}",
+                    register, pc
+                )
+            }
+            VmError::MemoryOutOfBounds { address } => {
+                write!(f, "Memory access out of bounds at address {:#04x}", address)
+            }
+            VmError::WouldBlock { address } => {
+                write!(
+                    f,
+                    "The device at address {:#04x} has nothing to answer yet",
+                    address
+                )
+            }
+            VmError::ProgramTooLarge { size, capacity } => write!(
+                f,
+                "Program size ({} bytes) is larger than maximum memory ({} bytes)",
+                size, capacity
+            ),
+            VmError::MemoryLimitExceeded { size, limit } => write!(
+                f,
+                "Memory cannot grow to {} bytes, the limit is {} bytes",
+                size, limit
+            ),
+            VmError::StackOverflow { pc } => {
+                write!(f, "Stack overflow caused by the instruction at {:#04x}", pc)
+            }
+            VmError::StackUnderflow { pc } => {
+                write!(
+                    f,
+                    "Stack underflow caused by the instruction at {:#04x}",
+                    pc
+                )
+            }
+            VmError::UnknownHostCall { pc, index } => {
+                write!(f, "Unknown host call {} made at address {:#04x}", index, pc)
+            }
+            VmError::ArithmeticOverflow { pc } => {
+                write!(
+                    f,
+                    "Arithmetic overflow caused by the instruction at {:#04x}",
+                    pc
+                )
+            }
+            VmError::MachineHalted => write!(f, "The machine is halted"),
+            VmError::TimedOut(deadline) => {
+                write!(f, "The guest ran past its deadline of {:?}", deadline)
+            }
+            VmError::InvalidSnapshot(msg) => write!(f, "Invalid snapshot: {}", msg),
+            VmError::VerificationMismatch(mismatch) => {
+                write!(
+                    f,
+                    "Native code disagrees with the interpreter: {}",
+                    mismatch
+                )
+            }
+            VmError::InvalidLog(msg) => write!(f, "Invalid execution log: {}", msg),
+            VmError::InvalidProgram(msg) => write!(f, "Invalid program file: {}", msg),
+            VmError::InvalidProfile(msg) => write!(f, "Invalid warmup profile: {}", msg),
+            VmError::InvalidTrace(msg) => write!(f, "Invalid trace: {}", msg),
+            VmError::InvalidWatchpoint(msg) => write!(f, "Invalid watchpoint: {}", msg),
+            VmError::InvalidCondition(msg) => write!(f, "Invalid condition: {}", msg),
+            VmError::Io(msg) => write!(f, "I/O error: {}", msg),
+            VmError::InvalidAssembly { line, message } => {
+                write!(f, "Invalid assembly at line {}: {}", line, message)
+            }
+            VmError::ReplayDiverged { pc, instret } => write!(
+                f,
+                "Replay diverged in the block at {:#04x} after {} instructions",
+                pc, instret
+            ),
+            VmError::JitCreationFailed(msg) => {
+                write!(f, "Failed to create the JIT execution engine: {}", msg)
+            }
+            VmError::VerificationFailed(msg) => {
+                write!(f, "Function's verification failed: {}", msg)
+            }
+            VmError::CompilationFailed(msg) => write!(
+                f,
+                "Something went wrong when compiling the dynamic basic block: {}",
+                msg
+            ),
+            VmError::DisassemblyFailed(msg) => {
+                write!(f, "Failed to disassemble the native code: {}", msg)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
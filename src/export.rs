@@ -0,0 +1,359 @@
+use std::{
+    fs,
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use tracing::warn;
+
+use crate::{
+    cpu::{Cpu, Instruction, OpCode, REGISTER_COUNT},
+    error::VmError,
+    observer::{ExecutionObserver, Tier},
+};
+
+const TIERS: [Tier; 3] = [Tier::Interpreter, Tier::Baseline, Tier::Native]; // By binary code
+
+/// The first bytes of a binary trace, followed by its version.
+pub const BINARY_MAGIC: &[u8; 4] = b"VTTR";
+pub const BINARY_VERSION: u8 = 1;
+
+/// What a TraceExporter records an event for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceGranularity {
+    Instructions, // Every interpreted instruction, and every compiled block
+    Blocks,       // Every block, whichever tier ran it
+}
+
+/// An instruction or a block executed by the guest, with the registers it
+/// changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent {
+    pub pc: usize,
+    pub opcode: Option<OpCode>, // Of the instruction, None for a block
+    pub tier: Tier,
+    pub instret: u64,              // Instructions retired once it completed
+    pub deltas: Vec<(usize, i32)>, // The registers changed, by index, with their new value
+}
+
+impl TraceEvent {
+    /// The event as a line of JSON, without the line break.
+    pub fn to_json(&self) -> String {
+        let opcode = match self.opcode {
+            Some(opcode) => format!("\"{:?}\"", opcode),
+            None => "null".to_string(),
+        };
+        let deltas: Vec<String> = self
+            .deltas
+            .iter()
+            .map(|(index, value)| format!("[{}, {}]", index, value))
+            .collect();
+        format!(
+            "{{\"pc\": {}, \"opcode\": {}, \"tier\": \"{:?}\", \"instret\": {}, \"deltas\": [{}]}}",
+            self.pc,
+            opcode,
+            self.tier,
+            self.instret,
+            deltas.join(", ")
+        )
+    }
+
+    /// Appends the event to `bytes`: the pc on 4 bytes, the opcode, 0xff for
+    /// a block, the tier, the instret on 8 bytes, the number of deltas, then
+    /// every delta as the register index and its value on 4 bytes. Numbers
+    /// are little endian.
+    pub fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.extend((self.pc as u32).to_le_bytes());
+        bytes.push(self.opcode.map_or(0xff, |opcode| opcode as u8));
+        bytes.push(TIERS.iter().position(|&tier| tier == self.tier).unwrap() as u8);
+        bytes.extend(self.instret.to_le_bytes());
+        bytes.push(self.deltas.len() as u8);
+        for (index, value) in &self.deltas {
+            bytes.push(*index as u8);
+            bytes.extend(value.to_le_bytes());
+        }
+    }
+
+    /// Parses a line written by `to_json`.
+    pub fn from_json(line: &str) -> Option<Self> {
+        let opcode = match field(line, "opcode")? {
+            "null" => None,
+            name => Some(
+                (0..=u8::MAX)
+                    .map_while(|byte| OpCode::try_from(byte).ok())
+                    .find(|opcode| format!("\"{:?}\"", opcode) == name)?,
+            ),
+        };
+        let tier = field(line, "tier")?;
+        let tier = TIERS
+            .into_iter()
+            .find(|candidate| format!("\"{:?}\"", candidate) == tier)?;
+        let deltas = field(line, "deltas")?
+            .strip_prefix('[')?
+            .strip_suffix(']')?;
+        let deltas = match deltas {
+            "" => Vec::new(),
+            deltas => deltas
+                .strip_prefix('[')?
+                .strip_suffix(']')?
+                .split("], [")
+                .map(|delta| {
+                    let (index, value) = delta.split_once(", ")?;
+                    let index = index.parse().ok().filter(|&index| index < REGISTER_COUNT)?;
+                    Some((index, value.parse().ok()?))
+                })
+                .collect::<Option<_>>()?,
+        };
+        Some(Self {
+            pc: field(line, "pc")?.parse().ok()?,
+            opcode,
+            tier,
+            instret: field(line, "instret")?.parse().ok()?,
+            deltas,
+        })
+    }
+
+    /// Decodes the event `encode` wrote at the start of `bytes`, returning
+    /// it with its length.
+    pub fn decode(bytes: &[u8]) -> Option<(Self, usize)> {
+        let header = bytes.get(..15)?;
+        let opcode = match header[4] {
+            0xff => None,
+            byte => Some(OpCode::try_from(byte).ok()?),
+        };
+        let count = header[14] as usize;
+        let deltas = bytes
+            .get(15..15 + count * 5)?
+            .chunks(5)
+            .map(|delta| {
+                let index = Some(delta[0] as usize).filter(|&index| index < REGISTER_COUNT)?;
+                Some((index, i32::from_le_bytes(delta[1..].try_into().unwrap())))
+            })
+            .collect::<Option<_>>()?;
+        let event = Self {
+            pc: u32::from_le_bytes(header[..4].try_into().unwrap()) as usize,
+            opcode,
+            tier: *TIERS.get(header[5] as usize)?,
+            instret: u64::from_le_bytes(header[6..14].try_into().unwrap()),
+            deltas,
+        };
+        Some((event, 15 + count * 5))
+    }
+}
+
+/// The value of `key` in a line written by `TraceEvent::to_json`.
+fn field<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let start = line.find(&format!("\"{}\": ", key))? + key.len() + 4;
+    let rest = &line[start..];
+    let end = match rest.starts_with('[') {
+        true => rest
+            .find("]]")
+            .map(|end| end + 2)
+            .or(rest.find(']').map(|end| end + 1))?,
+        false => rest.find([',', '}'])?,
+    };
+    Some(&rest[..end])
+}
+
+/// Reads the events of a trace written by a BinaryTrace, or by JsonLines
+/// when it does not start with BINARY_MAGIC.
+pub fn read_trace(bytes: &[u8]) -> Result<Vec<TraceEvent>, VmError> {
+    let Some(mut bytes) = bytes.strip_prefix(BINARY_MAGIC) else {
+        let text = std::str::from_utf8(bytes)
+            .map_err(|_| VmError::InvalidTrace("neither text nor binary".to_string()))?;
+        return text
+            .lines()
+            .enumerate()
+            .map(|(index, line)| {
+                TraceEvent::from_json(line)
+                    .ok_or_else(|| VmError::InvalidTrace(format!("malformed line {}", index + 1)))
+            })
+            .collect();
+    };
+
+    match bytes.split_first() {
+        Some((&BINARY_VERSION, rest)) => bytes = rest,
+        _ => return Err(VmError::InvalidTrace("unsupported version".to_string())),
+    }
+    let mut events = Vec::new();
+    while !bytes.is_empty() {
+        let (event, length) = TraceEvent::decode(bytes).ok_or_else(|| {
+            VmError::InvalidTrace(format!("malformed event {}", events.len() + 1))
+        })?;
+        events.push(event);
+        bytes = &bytes[length..];
+    }
+    Ok(events)
+}
+
+pub fn read_trace_file(path: impl AsRef<Path>) -> Result<Vec<TraceEvent>, VmError> {
+    let path = path.as_ref();
+    let bytes = fs::read(path).map_err(|e| VmError::Io(format!("{}: {}", path.display(), e)))?;
+    read_trace(&bytes)
+}
+
+/// Where a TraceExporter writes its events.
+pub trait TraceSink: Send + Sync {
+    fn write_event(&mut self, event: &TraceEvent) -> std::io::Result<()>;
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes the events as JSON Lines, one object per line.
+pub struct JsonLines<W: Write + Send + Sync> {
+    writer: W,
+}
+
+impl<W: Write + Send + Sync> JsonLines<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Send + Sync> TraceSink for JsonLines<W> {
+    fn write_event(&mut self, event: &TraceEvent) -> std::io::Result<()> {
+        writeln!(self.writer, "{}", event.to_json())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Writes the events in binary, see `TraceEvent::encode`, after
+/// BINARY_MAGIC and BINARY_VERSION.
+pub struct BinaryTrace<W: Write + Send + Sync> {
+    writer: W,
+    started: bool, // Whether the header was written
+    buffer: Vec<u8>,
+}
+
+impl<W: Write + Send + Sync> BinaryTrace<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            started: false,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Send + Sync> TraceSink for BinaryTrace<W> {
+    fn write_event(&mut self, event: &TraceEvent) -> std::io::Result<()> {
+        self.buffer.clear();
+        if !self.started {
+            self.buffer.extend(BINARY_MAGIC);
+            self.buffer.push(BINARY_VERSION);
+            self.started = true;
+        }
+        event.encode(&mut self.buffer);
+        self.writer.write_all(&self.buffer)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Keeps the events in memory.
+impl TraceSink for Vec<TraceEvent> {
+    fn write_event(&mut self, event: &TraceEvent) -> std::io::Result<()> {
+        self.push(event.clone());
+        Ok(())
+    }
+}
+
+/// A sink the host keeps a handle on, e.g. to read the events once the
+/// engine, which owns its observers, is done.
+impl<S: TraceSink> TraceSink for Arc<Mutex<S>> {
+    fn write_event(&mut self, event: &TraceEvent) -> std::io::Result<()> {
+        self.lock().unwrap().write_event(event)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.lock().unwrap().flush()
+    }
+}
+
+/// An observer exporting what the guest executes to a sink, e.g. to diff
+/// the runs of two tiers after the fact. Compiled blocks do not report
+/// their single instructions, so they are exported as a whole. Each event
+/// lists the registers that changed since the last one, the first one
+/// those not zero. The sink is flushed when the guest halts. Once it
+/// failed, the exporter stops writing to it.
+pub struct TraceExporter<S: TraceSink> {
+    sink: S,
+    granularity: TraceGranularity,
+    registers: [i32; REGISTER_COUNT], // As of the last event
+    failed: bool,
+}
+
+impl<S: TraceSink> TraceExporter<S> {
+    pub fn new(sink: S, granularity: TraceGranularity) -> Self {
+        Self {
+            sink,
+            granularity,
+            registers: [0; REGISTER_COUNT],
+            failed: false,
+        }
+    }
+
+    fn export(&mut self, pc: usize, opcode: Option<OpCode>, tier: Tier, cpu: &Cpu) {
+        if self.failed {
+            return;
+        }
+        let mut deltas = Vec::new();
+        for (index, register) in self.registers.iter_mut().enumerate() {
+            if *register != cpu.register(index) {
+                *register = cpu.register(index);
+                deltas.push((index, *register));
+            }
+        }
+        let event = TraceEvent {
+            pc,
+            opcode,
+            tier,
+            instret: cpu.instret,
+            deltas,
+        };
+        if let Err(e) = self.sink.write_event(&event) {
+            warn!("wasn't capable to export the trace: {}", e);
+            self.failed = true;
+        }
+    }
+}
+
+impl<S: TraceSink> ExecutionObserver for TraceExporter<S> {
+    fn on_block_executed(&mut self, pc: usize, tier: Tier, cpu: &Cpu) {
+        let exported = match self.granularity {
+            TraceGranularity::Instructions => tier != Tier::Interpreter,
+            TraceGranularity::Blocks => true,
+        };
+        if exported {
+            self.export(pc, None, tier, cpu);
+        }
+    }
+
+    fn on_instruction(&mut self, pc: usize, instr: Instruction, cpu: &Cpu) {
+        if self.granularity == TraceGranularity::Instructions {
+            self.export(pc, Some(instr.opcode), Tier::Interpreter, cpu);
+        }
+    }
+
+    fn on_halt(&mut self, _cpu: &Cpu) {
+        if let Err(e) = self.sink.flush() {
+            warn!("wasn't capable to flush the trace: {}", e);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use std::{collections::VecDeque, ops::Range};
+
+use crate::{cpu::REGISTER_COUNT, error::VmError, rng::splitmix64};
+
+/// What a Fault flips a bit of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultTarget {
+    Register(usize), // By index, ACC and LC being 0 and 1 as for MOV
+    Memory(usize),   // A byte of the guest memory, by address
+}
+
+/// A bit flip, injected once `instret` instructions retired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub instret: u64,
+    pub target: FaultTarget,
+    pub bit: u8, // Below 32 for registers, below 8 for memory
+}
+
+/// A fault that was injected, with the pc of the instruction it preceded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InjectedFault {
+    pub fault: Fault,
+    pub pc: usize,
+}
+
+/// Bit flips scheduled in the registers and the memory of the guest, e.g.
+/// to study how soft errors propagate, see
+/// `EmulationEngine::start_fault_injection`. Faults are injected between
+/// blocks, and blocks are cut short where a fault is due, whichever tier
+/// runs them: a fault lands after exactly its count of instructions, so
+/// that runs with the same faults are reproducible.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FaultInjector {
+    pending: VecDeque<Fault>, // By instret, the next due first
+    injected: Vec<InjectedFault>,
+}
+
+impl FaultInjector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `count` faults drawn from `seed`, due within `instructions`. Half of
+    /// them flip a bit of a register, the others of a byte in `memory`, if
+    /// not empty.
+    pub fn random(seed: u64, count: usize, instructions: Range<u64>, memory: Range<usize>) -> Self {
+        let mut injector = Self::new();
+        if instructions.is_empty() {
+            return injector;
+        }
+        let mut state = seed;
+        for _ in 0..count {
+            let mut next = |bound: u64| splitmix64(&mut state) % bound;
+            let instret = instructions.start + next(instructions.end - instructions.start);
+            let fault = match memory.is_empty() || next(2) == 0 {
+                true => Fault {
+                    instret,
+                    target: FaultTarget::Register(next(REGISTER_COUNT as u64) as usize),
+                    bit: next(32) as u8,
+                },
+                false => Fault {
+                    instret,
+                    target: FaultTarget::Memory(memory.start + next(memory.len() as u64) as usize),
+                    bit: next(8) as u8,
+                },
+            };
+            injector.schedule(fault).expect("faults drawn in range");
+        }
+        injector
+    }
+
+    /// Schedules `fault`, after the faults due at the same time.
+    pub fn schedule(&mut self, fault: Fault) -> Result<(), VmError> {
+        let valid = match fault.target {
+            FaultTarget::Register(index) => index < REGISTER_COUNT && fault.bit < 32,
+            FaultTarget::Memory(_) => fault.bit < 8,
+        };
+        if !valid {
+            return Err(VmError::InvalidConfig(format!(
+                "cannot flip bit {} of {:?}",
+                fault.bit, fault.target
+            )));
+        }
+        let index = self.pending.partition_point(|f| f.instret <= fault.instret);
+        self.pending.insert(index, fault);
+        Ok(())
+    }
+
+    /// The faults not injected yet, the next due first.
+    pub fn pending(&self) -> impl Iterator<Item = &Fault> {
+        self.pending.iter()
+    }
+
+    /// The faults injected so far, in order.
+    pub fn injected(&self) -> &[InjectedFault] {
+        &self.injected
+    }
+
+    /// Takes the faults due once `instret` instructions retired.
+    pub(crate) fn take_due(&mut self, instret: u64) -> Vec<Fault> {
+        let due = self.pending.partition_point(|f| f.instret <= instret);
+        self.pending.drain(..due).collect()
+    }
+
+    pub(crate) fn record(&mut self, fault: Fault, pc: usize) {
+        self.injected.push(InjectedFault { fault, pc });
+    }
+
+    /// The instructions the guest may run from `instret` before the next
+    /// fault is due.
+    pub(crate) fn until_next(&self, instret: u64) -> u64 {
+        self.pending
+            .front()
+            .map_or(u64::MAX, |fault| fault.instret.saturating_sub(instret))
+    }
+}
@@ -0,0 +1,134 @@
+use std::fmt::Display;
+
+use crate::{
+    backend::BackendKind,
+    config::EngineConfig,
+    cpu::{Cpu, OpCode},
+    memory::Memory,
+    program::Program,
+    EmulationEngine, Outcome,
+};
+
+/// Instructions run by `fuzz_one`, generated programs need not terminate.
+pub const FUZZ_FUEL: u64 = 100_000;
+
+/// Where the engine under test and the reference interpreter parted ways.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub reference: (Outcome, Cpu), // How the reference interpreter stopped
+    pub engine: (Outcome, Cpu),    // How the engine under test stopped
+    pub memory: Vec<usize>,        // Addresses holding different bytes
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the reference stopped with {:?} on {:?}, the engine with {:?} on {:?}",
+            self.reference.0, self.reference.1, self.engine.0, self.engine.1
+        )?;
+        if !self.memory.is_empty() {
+            write!(f, ", memory differs at {:#04x?}", self.memory)?;
+        }
+        Ok(())
+    }
+}
+
+/// Turns arbitrary bytes into a program: the first two give the initial
+/// ACC and LC, every other byte is mapped to an opcode. Operands are taken
+/// as they come, so programs may jump anywhere, write to their own code or
+/// trap, all of which the engine must handle like the reference.
+pub fn arbitrary_program(data: &[u8]) -> Program {
+    let (registers, code) = data.split_at(data.len().min(2));
+    let register = |index: usize| registers.get(index).map_or(0, |&r| (r & 7) as i32);
+
+    let mut bytes = Vec::with_capacity(code.len() + 1);
+    let mut operands = 0;
+    for &byte in code {
+        if operands > 0 {
+            bytes.push(byte);
+            operands -= 1;
+            continue;
+        }
+        let opcode = OpCode::try_from(byte % (OpCode::BVC as u8 + 1)).unwrap();
+        bytes.push(opcode as u8);
+        operands = opcode.length() - 1;
+    }
+    bytes.push(OpCode::HALT as u8);
+
+    Program::new(bytes, register(0), register(1))
+}
+
+/// A program of `size` bytes generated from `seed`, see `arbitrary_program`.
+pub fn random_program(seed: u64, size: usize) -> Program {
+    // xorshift64*, which must not be seeded with zero
+    let mut state = seed | 1;
+    let data: Vec<u8> = (0..size)
+        .map(|_| {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            (state.wrapping_mul(0x2545f4914f6cdd1d) >> 56) as u8
+        })
+        .collect();
+    arbitrary_program(&data)
+}
+
+/// Runs `program` for at most `fuel` instructions on an engine configured
+/// by `config` and on the reference interpreter, and compares the outcome,
+/// the Cpu and the memory they end up with.
+pub fn check_program(
+    program: &Program,
+    config: &EngineConfig,
+    fuel: u64,
+) -> Result<(), Box<Divergence>> {
+    let reference_config = EngineConfig {
+        backend: BackendKind::Reference,
+        ..config.clone()
+    };
+    let (reference, reference_memory) = run(program, &reference_config, fuel);
+    let (engine, engine_memory) = run(program, config, fuel);
+
+    let memory: Vec<usize> = (0..reference_memory.len().max(engine_memory.len()))
+        .filter(|&address| reference_memory.get(address) != engine_memory.get(address))
+        .collect();
+    if reference == engine && memory.is_empty() {
+        return Ok(());
+    }
+    Err(Box::new(Divergence {
+        reference,
+        engine,
+        memory,
+    }))
+}
+
+/// Checks the program made of `data` against the default engine, compiling
+/// every block on first sight. Panics on divergence, for fuzzers to report,
+/// e.g. `fuzz_target!(|data: &[u8]| vt_vm_dyn::fuzz::fuzz_one(data));`
+pub fn fuzz_one(data: &[u8]) {
+    let config = EngineConfig {
+        compile_threshold: 1,
+        ..EngineConfig::default()
+    };
+    if let Err(divergence) = check_program(&arbitrary_program(data), &config, FUZZ_FUEL) {
+        panic!("{}", divergence);
+    }
+}
+
+fn run(program: &Program, config: &EngineConfig, fuel: u64) -> ((Outcome, Cpu), Vec<u8>) {
+    let mut vm = config
+        .validate()
+        .and_then(|_| {
+            EmulationEngine::from_config(config.clone(), Box::new(Memory::new(config.memory_size)))
+        })
+        .expect("the configuration is valid");
+
+    let outcome = vm
+        .load_program(program.clone())
+        .and_then(|_| vm.run_for(fuel))
+        .unwrap_or_else(Outcome::Trapped);
+    let memory = vm
+        .read_memory_range(0..vm.memory_size())
+        .unwrap_or_default();
+    ((outcome, *vm.cpu()), memory)
+}
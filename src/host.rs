@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+
+use crate::{cpu::Cpu, error::VmError, memory::Addressable, timing::Clock};
+
+/// A host function invoked by the guest through HCALL. It sees the Cpu with
+/// the pc still on the HCALL, which is advanced once the function returns.
+pub type HostFunction =
+    Box<dyn FnMut(&mut Cpu, &mut dyn Addressable<u8>) -> Result<(), VmError> + Send + Sync>;
+
+/// The host functions the guest can call, indexed by the HCALL operand,
+/// and the clock it reads with RDTIME.
+#[derive(Default)]
+pub struct HostCalls {
+    functions: HashMap<u8, HostFunction>,
+    clock: Clock,
+}
+
+impl HostCalls {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `function` as the host call `index`, replacing the previous
+    /// one if any.
+    pub fn register(&mut self, index: u8, function: HostFunction) {
+        self.functions.insert(index, function);
+    }
+
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    // The function is taken out of the table while it runs, so it can be
+    // handed the memory port owning the table.
+    pub(crate) fn take(&mut self, index: u8) -> Option<HostFunction> {
+        self.functions.remove(&index)
+    }
+
+    pub(crate) fn restore(&mut self, index: u8, function: HostFunction) {
+        self.functions.insert(index, function);
+    }
+}
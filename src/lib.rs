@@ -1,254 +1,6108 @@
+#[cfg(feature = "jit")]
+pub mod artifact;
+pub mod backend;
+pub mod baseline;
+pub mod batch;
+pub mod bus;
+pub mod cache;
+#[cfg(any(feature = "jit", feature = "cranelift"))]
+mod codegen;
+#[cfg(feature = "jit")]
+pub mod compiler;
+pub mod condition;
+pub mod config;
+pub mod console;
+pub mod counted;
+pub mod coverage;
 pub mod cpu;
+#[cfg(feature = "cranelift")]
+pub mod cranelift;
+pub mod decoder;
+pub mod deopt;
+pub mod error;
+pub mod export;
+pub mod fault;
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
+pub mod host;
+pub mod lockstep;
+pub mod mailbox;
 pub mod memory;
+pub mod mir;
+pub mod monitor;
+#[cfg(feature = "capstone")]
+pub mod native;
+#[cfg(all(feature = "jit", unix))]
+pub mod objcache;
+pub mod observer;
+pub mod osr;
+pub mod peephole;
+pub mod perf;
+pub mod playback;
+pub mod profile;
 pub mod program;
+#[cfg(any(feature = "jit", feature = "cranelift"))]
+mod remote;
+pub mod replay;
+pub mod report;
+pub mod rng;
+pub mod scheduler;
+pub mod shared;
+pub mod snapshot;
+pub mod timeline;
+pub mod timing;
+pub mod trace;
+#[cfg(feature = "jit")]
 pub mod translation;
+pub mod verify;
+pub mod watch;
+pub mod watchdog;
 
-use caches::Cache;
-use cpu::{Cpu, OpCode};
-use log::{debug, warn, info};
-use memory::{Addressable, Memory};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::Write,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
-use inkwell::context::Context;
-use program::Program;
-use translation::TranslationContext;
+use backend::{Backend, BackendKind, CompiledBlock, InterpreterBackend};
+use baseline::BaselineBlock;
+use bus::{Bus, MmioDevice};
+use cache::{BlockInfo, CachePolicy, CacheStats, CachedBlock, CodeCache, Translations};
+#[cfg(feature = "jit")]
+use codegen::Symbols;
+#[cfg(feature = "jit")]
+use compiler::CompilationWorker;
+use condition::{BreakCondition, Condition};
+use config::{EmulationEngineBuilder, EngineConfig};
+#[cfg(feature = "jit")]
+use config::{OptimizationLevel, Pass};
+use console::{Console, ConsoleOutput};
+use coverage::Coverage;
+use cpu::{Cpu, Instruction, OpCode, Trap, REGISTER_COUNT};
+use decoder::DecodeCache;
+use error::VmError;
+use fault::{FaultInjector, FaultTarget};
+use host::{HostCalls, HostFunction};
+use memory::{Addressable, Memory, MemoryPort};
+use observer::{ExecutionObserver, Tier};
+use osr::OsrEntry;
+use perf::PerfMap;
+use profile::{BranchCounts, BranchProfile, Profile, WarmupProfile};
+use replay::{state_hash, BlockRecord, ExecutionLog, Replay, StateDigest};
+use report::{CompileStats, CompileSummary, ExecutionReport};
+use rng::Rng;
+use shared::SharedCache;
+use snapshot::Snapshot;
+use timeline::{Activity, Timeline};
+use timing::{Clock, Pacer};
+use trace::{CompiledTrace, Trace, TraceRecorder};
+use tracing::{debug, debug_span, field, info, warn, Level};
+use verify::Shadow;
+use watch::{Watchpoint, WatchpointHit, Watchpoints};
+use watchdog::Watchdog;
 
-const CACHE_SIZE: usize = 32;
-const MAX_EXECUTIONS: u64 = 1;
+#[cfg(feature = "jit")]
+use artifact::ArtifactKind;
+use program::{
+    analysis::{self, Cfg},
+    disasm, Program,
+};
+#[cfg(feature = "jit")]
+use translation::{LlvmBackend, TranslationOptions};
 
-type CodeCache<'ctx> = caches::AdaptiveCache<usize, TranslationContext<'ctx>>;
+/// The reason why the engine gave control back to the caller.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Halted,                    // The guest executed HALT
+    FuelExhausted,             // The instruction budget was consumed
+    Breakpoint(usize),         // A breakpoint was hit, the instruction at pc has not run yet
+    Trapped(VmError),          // The guest faulted (e.g. unknown opcode)
+    Stopped(Cpu),              // The host set the stop flag, with the Cpu state at that point
+    TimedOut(Cpu),             // The run went past the deadline, with the Cpu state at that point
+    WaitingForMessage(Cpu), // A device blocked the access of the instruction at pc, see `Mailbox`
+    Watchpoint(WatchpointHit), // A watchpoint fired after an instruction, pc is at the next one
+}
+
+pub struct EmulationEngine {
+    pub(crate) cpu: Cpu,
+    bus: Bus,
+    host_calls: HostCalls,
+    config: EngineConfig,
+    breakpoints: BTreeSet<usize>,
+    conditions: BTreeMap<usize, BreakCondition>, // Of the breakpoints only stopping when they hold
+    watchpoints: Watchpoints,
+    observers: Vec<Box<dyn ExecutionObserver>>,
+    at_breakpoint: bool, // Whether the last run stopped on the breakpoint at pc
+    watch_hit: Option<WatchpointHit>, // Left by the interpreter for the run to stop
+    stop: Arc<AtomicBool>, // Set by the host to stop the guest, see `stop_flag`
+    report: ExecutionReport, // Filled in while running, reset by `main_loop`
+    compile_stats: Vec<CompileStats>, // Every block compiled, in order
+    recording: Option<ExecutionLog>, // The executed blocks, while recording
+    timeline: Option<Timeline>, // What the engine was busy with, while recording
+    perf_map: Option<PerfMap>, // Where compiled blocks are named, if enabled
+    symbols: BTreeMap<String, usize>, // Functions compiled so far, with the pc of their block
+    compile_failures: HashMap<usize, u32>, // Failed compilations of the blocks, by pc
+    blacklist: BTreeMap<usize, VmError>, // Blocks never compiled again, with the last error
+    coverage: Option<Coverage>, // The addresses executed, while tracking them
+    faults: Option<FaultInjector>, // The faults to inject and injected, while injecting
+    digest: Option<StateDigest>, // The states folded so far, while digesting
+    profile: Option<Profile>, // Time spent on every block, while profiling
+    branches: BranchProfile, // Outcomes of the BACK7 run by the interpreter
+    replay: Option<Replay>, // The log checked by the run in progress, if replaying
+    tracer: Option<TraceRecorder>, // Records the paths run from hot loop heads, if enabled
+    leaders: BTreeSet<usize>, // Addresses starting blocks, when aligning them
+    decoded: DecodeCache, // Instructions fetched so far, by address
+    code_cache: CodeCache<'static>,
+    translations: Translations<'static>, // Code shared by blocks with the same instructions
+    shared: Option<String>, // Key of the code of the backend in the SharedCache, if sharing it
+    console: Option<Arc<Mutex<ConsoleOutput>>>, // Shared with the console device, if mapped
+    traces: HashMap<usize, CompiledTrace<'static>>, // Compiled traces, by head
+    osr_entries: HashMap<usize, OsrEntry<'static>>, // Loops of compiled blocks, by header
+    backend: Box<dyn Backend<'static>>,
+    #[cfg(feature = "jit")]
+    compiler: Option<CompilationWorker>,
+    #[cfg(feature = "jit")]
+    recompiler: Option<CompilationWorker>, // Compiles the hottest blocks again, aggressively
+}
+
+impl Default for EmulationEngine {
+    fn default() -> Self {
+        let config = EngineConfig::default();
+        let memory = Box::new(Memory::new(config.memory_size));
+        Self::from_config(config, memory).expect("default configuration is valid")
+    }
+}
+
+impl EmulationEngine {
+    pub fn builder() -> EmulationEngineBuilder {
+        EmulationEngineBuilder::new()
+    }
+
+    pub(crate) fn from_config(
+        config: EngineConfig,
+        memory: Box<dyn Addressable<u8> + Send + Sync>,
+    ) -> Result<Self, VmError> {
+        let code_cache = CodeCache::new(config.cache_policy, config.cache_size)
+            .map_err(|e| VmError::InvalidConfig(format!("{:?}", e)))?;
+
+        // The backend and the workers name functions in the same table
+        #[cfg(feature = "jit")]
+        let symbols = Symbols::default();
+
+        let backend: Box<dyn Backend<'static>> = match config.backend {
+            #[cfg(feature = "jit")]
+            BackendKind::Llvm => Box::new(LlvmBackend::spawn(&config, symbols.clone())?),
+            // The reference interpreter never compiles anything
+            BackendKind::Interpreter | BackendKind::Reference => {
+                Box::new(InterpreterBackend::new(config.cost_table.clone()))
+            }
+            #[cfg(feature = "cranelift")]
+            BackendKind::Cranelift => Box::new(cranelift::CraneliftBackend::spawn(
+                config.opt_level,
+                config.overflow_mode,
+                config.cost_table.clone(),
+                config.safepoint_interval,
+            )?),
+        };
+
+        // Only LLVM is slow enough to be worth a compilation thread
+        #[cfg(feature = "jit")]
+        let background = config.background_compilation && config.backend == BackendKind::Llvm;
+        #[cfg(feature = "jit")]
+        let recompile = config.recompile_threshold.is_some() && config.backend == BackendKind::Llvm;
+
+        let mut host_calls = HostCalls::new();
+        if let Some(frequency) = config.wall_clock {
+            host_calls.set_clock(Clock::wall(frequency));
+        }
+
+        let mut bus = Bus::new(memory);
+        bus.reserve_stack(config.stack_size)?;
+
+        let console = match config.console {
+            Some(address) => {
+                let output = Arc::new(Mutex::new(ConsoleOutput::Captured(Vec::new())));
+                bus.attach(address..address + 1, Box::new(Console::new(output.clone())))?;
+                Some(output)
+            }
+            None => None,
+        };
+        if let Some(rng) = config.rng {
+            bus.attach(rng.address..rng.address + 1, Box::new(Rng::new(rng.seed)))?;
+        }
+
+        let shared = match config.share_code {
+            true => backend
+                .share_key()
+                .map(|key| format!("{} {}", backend.name(), key)),
+            false => None,
+        };
+
+        Ok(Self {
+            cpu: Cpu {
+                overflow_mode: config.overflow_mode,
+                timecmp: config.timer.map_or(u64::MAX, |timer| timer.period),
+                ..Cpu::default()
+            },
+            bus,
+            host_calls,
+            config: config.clone(),
+            breakpoints: BTreeSet::new(),
+            conditions: BTreeMap::new(),
+            watchpoints: Watchpoints::default(),
+            observers: Vec::new(),
+            at_breakpoint: false,
+            watch_hit: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            console,
+            report: ExecutionReport::default(),
+            compile_stats: Vec::new(),
+            recording: None,
+            timeline: None,
+            coverage: None,
+            faults: None,
+            digest: None,
+            profile: None,
+            branches: BranchProfile::default(),
+            perf_map: config.perf_map.then(PerfMap::open).transpose()?,
+            symbols: BTreeMap::new(),
+            compile_failures: HashMap::new(),
+            blacklist: BTreeMap::new(),
+            replay: None,
+            tracer: match config.backend {
+                BackendKind::Reference => None,
+                _ => config.trace_threshold.map(TraceRecorder::new),
+            },
+            leaders: BTreeSet::new(),
+            decoded: DecodeCache::default(),
+            code_cache,
+            translations: Translations::default(),
+            shared,
+            traces: HashMap::new(),
+            osr_entries: HashMap::new(),
+            backend,
+            #[cfg(feature = "jit")]
+            compiler: background.then(|| {
+                CompilationWorker::spawn(TranslationOptions::from_config(&config), symbols.clone())
+            }),
+            #[cfg(feature = "jit")]
+            recompiler: recompile.then(|| {
+                let options = TranslationOptions {
+                    opt_level: OptimizationLevel::Aggressive,
+                    passes: Pass::pipeline(OptimizationLevel::Aggressive),
+                    ..TranslationOptions::from_config(&config)
+                };
+                CompilationWorker::spawn(options, symbols.clone())
+            }),
+        })
+    }
+
+    pub fn load_program(&mut self, program: Program) -> Result<(), VmError> {
+        // Set the initial register values
+        self.cpu.acc = program.initial_acc;
+        self.cpu.lc = program.initial_lc;
+        self.cpu.pc = program.entry_point;
+
+        // Make room for the program when the memory is allowed to grow
+        let length = program.data.len();
+        let size = self.memory_size();
+        if length > size && self.config.max_memory_size.is_some_and(|max| length <= max) {
+            self.grow_memory(length - size)?;
+        }
+
+        // Blocks start where the guest may enter the code, see `align_blocks`
+        let entries = self.entries(&program);
+        if self.aligned() {
+            self.leaders = analysis::leaders(&program.data, entries.iter().copied());
+        }
+        let cfg = match self.config.ahead_of_time {
+            true => Some(Cfg::from_code(&program.data, entries)),
+            false => None,
+        };
+
+        // Load the program in memory, dropping the code of the previous one
+        self.bus.write_chunk(program.data)?;
+        self.invalidate_code(0..length);
+
+        if let Some(cfg) = cfg {
+            let compiled = self.compile_ahead_of_time(&cfg);
+            info!("{} blocks compiled ahead of time", compiled);
+        }
+        Ok(())
+    }
+
+    /// Writes `program` compiled ahead of time by LLVM to `path`, as an
+    /// object file or a shared library depending on its extension, see
+    /// `artifact::write_program`. Returns the number of blocks compiled.
+    #[cfg(feature = "jit")]
+    pub fn compile_to_file(
+        &self,
+        program: &Program,
+        path: impl AsRef<Path>,
+    ) -> Result<usize, VmError> {
+        let path = path.as_ref();
+        let cfg = Cfg::from_code(&program.data, self.entries(program));
+        artifact::write_program(&cfg, &self.config, path, ArtifactKind::from_path(path))
+    }
+
+    /// The addresses the guest enters `program` at: its entry point, the
+    /// trap handler and the timer vector.
+    fn entries(&self, program: &Program) -> Vec<usize> {
+        let mut entries = vec![program.entry_point];
+        entries.extend(self.config.trap_handler.map(usize::from));
+        entries.extend(self.config.timer.map(|timer| timer.vector as usize));
+        entries
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    /// Sets the register `index`, R0 and R1 being aliases of ACC and LC.
+    pub fn set_register(&mut self, index: usize, value: i32) -> Result<(), VmError> {
+        if index >= REGISTER_COUNT {
+            return Err(VmError::InvalidRegister {
+                pc: self.cpu.pc,
+                register: index as u8,
+            });
+        }
+        *self.cpu.register_mut(index) = value;
+        Ok(())
+    }
+
+    /// Moves the guest to `pc`, the next run starts from there.
+    pub fn set_pc(&mut self, pc: usize) {
+        self.cpu.pc = pc;
+        self.at_breakpoint = false;
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.bus.memory().size()
+    }
+
+    /// Grows the guest memory by `additional` bytes, returning its new size.
+    /// Fails unless the engine was configured with a large enough maximum.
+    pub fn grow_memory(&mut self, additional: usize) -> Result<usize, VmError> {
+        let size = self.memory_size().saturating_add(additional);
+        let limit = self.config.max_memory_size.unwrap_or(self.memory_size());
+        if size > limit {
+            return Err(VmError::MemoryLimitExceeded { size, limit });
+        }
+
+        self.bus.memory_mut().grow(additional)?;
+        Ok(size)
+    }
+
+    pub fn read_memory(&self, address: usize) -> Result<u8, VmError> {
+        self.bus.read(address)
+    }
+
+    /// Reads the guest memory in `range`, e.g. to dump it.
+    pub fn read_memory_range(&self, range: std::ops::Range<usize>) -> Result<Vec<u8>, VmError> {
+        range.map(|address| self.bus.peek(address)).collect()
+    }
+
+    /// Writes a byte of guest memory. Blocks translated from that address
+    /// are dropped from the code cache, so modified code is picked up.
+    pub fn write_memory(&mut self, address: usize, value: u8) -> Result<(), VmError> {
+        self.bus.write(address, value)?;
+        self.invalidate_code(address..address + 1);
+        Ok(())
+    }
+
+    /// Writes `bytes` to the guest memory starting at `address`, dropping the
+    /// blocks translated from them like `write_memory`.
+    pub fn write_memory_range(&mut self, address: usize, bytes: &[u8]) -> Result<(), VmError> {
+        let mut written = address;
+        let result = bytes.iter().try_for_each(|&value| {
+            self.bus.write(written, value)?;
+            written += 1;
+            Ok(())
+        });
+        self.invalidate_code(address..written);
+        result
+    }
+
+    /// Captures the state of the guest, devices excluded.
+    pub fn snapshot(&self) -> Result<Snapshot, VmError> {
+        let memory = self.bus.memory();
+        Ok(Snapshot {
+            cpu: self.cpu,
+            memory: (0..memory.size())
+                .map(|address| memory.read(address))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Resumes the guest captured in `snapshot`, growing the memory to its
+    /// size when allowed. The code cache is flushed, blocks are translated
+    /// again lazily.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), VmError> {
+        let size = snapshot.memory.len();
+        if size > self.memory_size() {
+            self.grow_memory(size - self.memory_size())?;
+        }
+        if size != self.memory_size() {
+            return Err(VmError::InvalidSnapshot(format!(
+                "the snapshot holds {} bytes of memory, the engine {}",
+                size,
+                self.memory_size()
+            )));
+        }
+
+        self.bus.memory_mut().write_chunk(snapshot.memory.clone())?;
+        self.invalidate_code(0..size);
+        self.cpu = Cpu {
+            overflow_mode: self.config.overflow_mode,
+            ..snapshot.cpu
+        };
+        self.at_breakpoint = false;
+        Ok(())
+    }
+
+    /// The blocks currently in the code cache, ordered by entry point.
+    pub fn cached_blocks(&self) -> Vec<BlockInfo> {
+        self.code_cache.blocks().map(CachedBlock::info).collect()
+    }
+
+    /// The machine code emitted by the backend for the blocks in the code
+    /// cache, disassembled by `native::disassemble`, by entry point. Blocks
+    /// without native code, or whose size the backend cannot tell, are left
+    /// out.
+    #[cfg(feature = "capstone")]
+    pub fn native_disassembly(&self) -> Result<BTreeMap<usize, String>, VmError> {
+        let mut listings = BTreeMap::new();
+        for block in self.code_cache.blocks() {
+            let Some(compiled) = &block.compiled else {
+                continue;
+            };
+            let native = compiled.code_address().zip(compiled.code_stats().code_size);
+            let Some((address, size)) = native else {
+                continue;
+            };
+            // The code lives as long as the compiled block, kept by the cache
+            let code = unsafe { std::slice::from_raw_parts(address as *const u8, size) };
+            listings.insert(block.span().start, native::disassemble(code, address)?);
+        }
+        Ok(listings)
+    }
+
+    /// Writes the IR the backend built for the blocks in the code cache to
+    /// `dir`, created if needed, one file per block named by its entry point,
+    /// e.g. `0x0004.ll` for the LLVM backend. Blocks compiled in the
+    /// background and backends without an IR are left out. Returns the
+    /// number of files written.
+    pub fn dump_ir(&self, dir: impl AsRef<Path>) -> Result<usize, VmError> {
+        let dir = dir.as_ref();
+        let io_error =
+            |path: &Path, e: std::io::Error| VmError::Io(format!("{}: {}", path.display(), e));
+        std::fs::create_dir_all(dir).map_err(|e| io_error(dir, e))?;
+
+        let mut written = 0;
+        for block in self.code_cache.blocks() {
+            let Some(ir) = block
+                .compiled
+                .as_ref()
+                .and_then(|compiled| compiled.ir_text())
+            else {
+                continue;
+            };
+            let path = dir.join(format!("{:#06x}.ll", block.span().start));
+            std::fs::write(&path, ir).map_err(|e| io_error(&path, e))?;
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    /// The blocks the backend failed to compile `max_compile_failures` times,
+    /// by entry point, with the error of their last compilation. They are
+    /// left to the interpreter until their code is written to.
+    pub fn blacklisted_blocks(&self) -> &BTreeMap<usize, VmError> {
+        &self.blacklist
+    }
+
+    /// The bytes of native code the blocks in the code cache were compiled
+    /// to, as told by the backend, code shared with other blocks excluded.
+    /// The engine keeps it within `code_budget`, when set.
+    pub fn native_code_size(&self) -> usize {
+        self.code_cache.blocks().map(|block| block.code_size).sum()
+    }
+
+    /// The blocks waiting for the background compiler and the recompiler,
+    /// which take the hottest ones first.
+    #[cfg(feature = "jit")]
+    pub fn compile_queue_depth(&self) -> usize {
+        let workers = self.compiler.iter().chain(&self.recompiler);
+        workers.map(CompilationWorker::queue_depth).sum()
+    }
+
+    /// The functions compiled by the backend since the engine was created,
+    /// by name, with the entry point of the block they were compiled from,
+    /// see `backend::symbol`. Backends without native code name none.
+    pub fn symbol_table(&self) -> &BTreeMap<String, usize> {
+        &self.symbols
+    }
+
+    /// The blocks compiled by the backend since the engine was created, in
+    /// the order their compilation completed.
+    pub fn compile_stats(&self) -> &[CompileStats] {
+        &self.compile_stats
+    }
+
+    /// The compile time and code size of every block compiled, aggregated.
+    pub fn compile_summary(&self) -> CompileSummary {
+        self.compile_stats.iter().collect()
+    }
+
+    /// The lookups, insertions and evictions of the code cache so far.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.code_cache.stats()
+    }
+
+    pub fn cache_policy(&self) -> (CachePolicy, usize) {
+        (self.code_cache.policy(), self.code_cache.capacity())
+    }
+
+    /// Keeps the block at `pc` in the code cache until `unpin_block`, so its
+    /// compiled code is never thrown away. Blocks translated at `pc` later
+    /// on, e.g. after the code was overwritten, are pinned too. Pinned
+    /// blocks do not count against the capacity of the cache.
+    pub fn pin_block(&mut self, pc: usize) {
+        self.code_cache.pin(pc);
+    }
+
+    /// Lets the block at `pc` be evicted again. Returns whether it was pinned.
+    pub fn unpin_block(&mut self, pc: usize) -> bool {
+        self.code_cache.unpin(pc)
+    }
+
+    /// The entry points pinned, by hand or by `auto_pin`, ordered.
+    pub fn pinned_blocks(&self) -> Vec<usize> {
+        self.code_cache.pins().collect()
+    }
+
+    /// Pins the block at `pc` if compiling it took long enough, see
+    /// `EmulationEngineBuilder::auto_pin`.
+    fn auto_pin(&mut self, pc: usize, compile_time: Duration) {
+        let slow = self.config.auto_pin.is_some_and(|min| compile_time >= min);
+        if slow && self.code_cache.pins().count() < self.config.cache_size {
+            debug!(
+                "translation block {:#04x} pinned after a slow compilation",
+                pc
+            );
+            self.code_cache.pin(pc);
+        }
+    }
+
+    /// Switches the code cache to `policy`, holding up to `capacity` blocks.
+    /// Cached blocks are kept as long as they fit.
+    pub fn set_cache_policy(
+        &mut self,
+        policy: CachePolicy,
+        capacity: usize,
+    ) -> Result<(), VmError> {
+        self.code_cache
+            .resize(policy, capacity)
+            .map_err(|e| VmError::InvalidConfig(format!("{:?}", e)))?;
+        self.config.cache_policy = policy;
+        self.config.cache_size = capacity;
+        Ok(())
+    }
+
+    /// How many times the instructions of the cached blocks were executed,
+    /// by address. Counts are those of the blocks holding the instructions,
+    /// the run which translated a block included: a native loop counts once
+    /// however many iterations it runs.
+    pub fn execution_counts(&self) -> HashMap<usize, u64> {
+        let mut counts = HashMap::new();
+        for block in self.code_cache.blocks() {
+            let mut address = block.span().start;
+            for instr in block.bytecode() {
+                *counts.entry(address).or_insert(0) += block.executions + 1;
+                address += instr.length();
+            }
+        }
+        counts
+    }
+
+    /// A listing of the guest memory in `range`, see `disasm::disassemble`.
+    /// With `annotated`, executed addresses show their `execution_counts`.
+    pub fn disassemble(
+        &self,
+        range: std::ops::Range<usize>,
+        annotated: bool,
+    ) -> Result<String, VmError> {
+        let base = range.start;
+        let code = self.read_memory_range(range)?;
+        if annotated {
+            return Ok(disasm::annotate(&code, base, &self.execution_counts()));
+        }
+        Ok(disasm::disassemble(&code, base))
+    }
+
+    fn invalidate_code(&mut self, range: std::ops::Range<usize>) {
+        self.decoded.invalidate(range.clone());
+        if let Some(tracer) = &mut self.tracer {
+            tracer.invalidate(&range);
+        }
+        self.traces.retain(|head, trace| {
+            let valid = !trace.trace.overlaps(&range);
+            if !valid {
+                debug!("trace {:#04x} invalidated by a memory write", head);
+            }
+            valid
+        });
+        self.osr_entries.retain(|_, entry| {
+            let span = entry.span();
+            span.end <= range.start || range.end <= span.start
+        });
+        // New code gets compiled again, even where the old one failed to
+        self.compile_failures.retain(|pc, _| !range.contains(pc));
+        self.blacklist.retain(|pc, _| !range.contains(pc));
+        for pc in self.code_cache.invalidate(range) {
+            debug!(
+                "translation block {:#04x} invalidated by a memory write",
+                pc
+            );
+            self.compile_failures.remove(&pc);
+            self.blacklist.remove(&pc);
+        }
+    }
+
+    /// Maps a memory-mapped device over `range` of the guest address space.
+    pub fn attach_device(
+        &mut self,
+        range: std::ops::Range<usize>,
+        device: Box<dyn MmioDevice>,
+    ) -> Result<(), VmError> {
+        self.bus.attach(range, device)
+    }
+
+    /// The bytes the guest wrote to the console so far, see
+    /// `EmulationEngineBuilder::console`. Empty if there is no console or its
+    /// output is forwarded to a sink.
+    pub fn console_output(&self) -> Vec<u8> {
+        match self.console.as_deref().map(|output| output.lock().unwrap()) {
+            Some(output) => match &*output {
+                ConsoleOutput::Captured(output) => output.clone(),
+                ConsoleOutput::Forwarded(_) => Vec::new(),
+            },
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the bytes the guest wrote to the console so far and clears
+    /// them, e.g. to check the output of every run on its own.
+    pub fn take_console_output(&mut self) -> Vec<u8> {
+        match self.console.as_deref().map(|output| output.lock().unwrap()) {
+            Some(mut output) => match &mut *output {
+                ConsoleOutput::Captured(output) => std::mem::take(output),
+                ConsoleOutput::Forwarded(_) => Vec::new(),
+            },
+            None => Vec::new(),
+        }
+    }
+
+    /// Writes the output of the console to `sink` from now on, once the
+    /// output captured so far is written to it.
+    pub(crate) fn forward_console(&mut self, mut sink: Box<dyn Write + Send>) {
+        let Some(output) = self.console.clone() else {
+            return;
+        };
+        let captured = self.take_console_output();
+        if let Err(e) = sink.write_all(&captured) {
+            warn!("wasn't capable to write to the console: {}", e);
+        }
+        *output.lock().unwrap() = ConsoleOutput::Forwarded(sink);
+    }
+
+    /// Makes `function` callable by the guest with `HCALL index`.
+    pub fn register_host_call(&mut self, index: u8, function: HostFunction) {
+        self.host_calls.register(index, function);
+    }
+
+    fn debug_state(&self) {
+        // Peeking at memory is not free, nor invisible to custom memories
+        if !tracing::enabled!(Level::DEBUG) {
+            return;
+        }
+
+        // The next four instructions take at most 12 bytes
+        let code: Vec<u8> = (self.cpu.pc..self.cpu.pc.saturating_add(12))
+            .map_while(|address| self.bus.read(address).ok())
+            .collect();
+        let next_fours = disasm::entries(&code, self.cpu.pc)
+            .into_iter()
+            .take(4)
+            .map(|(_, entry)| entry.to_string())
+            .collect::<Vec<_>>()
+            .join("; ");
+        debug!(
+            "State: PC: {:#04x}, ACC: {:#4}, LC: {:#4} | {}",
+            self.cpu.pc, self.cpu.acc, self.cpu.lc, next_fours
+        );
+    }
+
+    fn fetch(&mut self) -> Result<Instruction, VmError> {
+        self.decoded.decode(&self.bus, self.cpu.pc)
+    }
+
+    /// Executes `instr` on the Cpu, dropping the code overwritten by it.
+    /// Returns the addresses written.
+    fn execute_instruction(&mut self, instr: Instruction) -> Result<Vec<usize>, VmError> {
+        let pc = self.cpu.pc;
+        let mut port = MemoryPort::new(&mut self.bus, &mut self.host_calls);
+        self.cpu.execute(instr, &mut port)?;
+        if let Some(coverage) = &mut self.coverage {
+            coverage.mark(pc, instr);
+        }
+        if instr.opcode == OpCode::BACK7 {
+            self.branches.record(pc, self.cpu.pc < pc);
+        }
+        self.cpu.instret += 1;
+        self.cpu.cycles += self.config.cost_table.cost(instr.opcode);
+
+        let (written, ..) = port.into_parts();
+        for &address in &written {
+            self.invalidate_code(address..address + 1);
+        }
+        Ok(written)
+    }
+
+    /// Interprets a dynamic basic block, also telling whether it wrote to
+    /// its own bytes: such a block no longer describes the code at its pc.
+    /// Errors come with the instructions executed before, which the fuel is
+    /// charged for when the trap handler resumes the guest.
+    fn interpret(&mut self, budget: u64) -> Result<(Vec<Instruction>, bool), (VmError, u64)> {
+        let start = self.cpu.pc;
+        let mut end = start;
+        let mut self_modifying = false;
+
+        let mut dynamic_block = Vec::new();
+
+        // Stop early when the budget is over or a breakpoint is reached, the
+        // block is then left incomplete
+        while (dynamic_block.len() as u64) < budget {
+            let pc = self.cpu.pc;
+            if !dynamic_block.is_empty() && (self.breakpoints.contains(&pc) || self.is_leader(pc)) {
+                break;
+            }
+
+            let executed = dynamic_block.len() as u64;
+            let instr = self.fetch().map_err(|e| (e, executed))?;
+            if !dynamic_block.is_empty() && instr.opcode.starts_block() {
+                break;
+            }
+            end = end.max(pc + instr.length());
+
+            let written = self.execute_instruction(instr).map_err(|e| (e, executed))?;
+            dynamic_block.push(instr);
+            self_modifying |= written.iter().any(|address| (start..end).contains(address));
+
+            for observer in self.observers.iter_mut() {
+                observer.on_instruction(pc, instr, &self.cpu);
+            }
+
+            self.watch_hit = self.watchpoints.check(pc, &self.cpu, &written);
+            if instr.opcode.ends_block() || self.watch_hit.is_some() {
+                break;
+            }
+        }
+
+        Ok((dynamic_block, self_modifying))
+    }
+
+    /// Interprets the cached block at pc from its decoded instructions,
+    /// stopping like `interpret` does. A block overwriting itself stops
+    /// right after, its next instructions may be stale. Returns the
+    /// instructions executed, alongside the error too.
+    fn interpret_decoded(
+        &mut self,
+        block: &[Instruction],
+        budget: u64,
+    ) -> Result<u64, (VmError, u64)> {
+        let start = self.cpu.pc;
+        let end = start + block.iter().map(Instruction::length).sum::<usize>();
+        let mut executed = 0;
+
+        for &instr in block {
+            if executed >= budget || (executed > 0 && self.breakpoints.contains(&self.cpu.pc)) {
+                break;
+            }
+
+            let pc = self.cpu.pc;
+            let written = self.execute_instruction(instr).map_err(|e| (e, executed))?;
+            executed += 1;
+
+            for observer in self.observers.iter_mut() {
+                observer.on_instruction(pc, instr, &self.cpu);
+            }
+
+            self.watch_hit = self.watchpoints.check(pc, &self.cpu, &written);
+            let self_modifying = written.iter().any(|address| (start..end).contains(address));
+            if self_modifying || self.watch_hit.is_some() {
+                break;
+            }
+        }
+
+        Ok(executed)
+    }
+
+    /// Compiles the cached block at pc with the backend, recording the
+    /// compilation. Returns the time it took and whether it succeeded.
+    fn compile_block(&mut self, pc: usize) -> (Duration, bool) {
+        let block = self
+            .code_cache
+            .peek_mut(&pc)
+            .expect("compiling a cached block");
+        let _span = debug_span!(
+            "compile",
+            pc,
+            length = block.instruction_count(),
+            tier = field::debug(Tier::Native),
+            backend = self.backend.name(),
+        )
+        .entered();
+        let start = Instant::now();
+        let back7 = block.back7_pc().and_then(|pc| self.branches.get(pc));
+        // Shared code is published for the other engines as soon as it is built
+        let compiled: Result<Arc<dyn CompiledBlock>, VmError> = match &self.shared {
+            Some(key) => self
+                .backend
+                .compile_shared(pc, block.bytecode(), back7)
+                .map(|code| {
+                    SharedCache::global().insert(key, block.bytecode(), code.clone());
+                    Arc::new(code) as Arc<dyn CompiledBlock>
+                }),
+            None => self
+                .backend
+                .compile_profiled(pc, block.bytecode(), back7)
+                .map(Arc::from),
+        };
+        let time = start.elapsed();
+        self.report.record_compilation(time, compiled.is_ok());
+        if let Some(timeline) = &mut self.timeline {
+            let length = block.instruction_count();
+            timeline.record(Activity::Compile, pc, length, start, time);
+        }
+        if let Some(profile) = &mut self.profile {
+            profile.record_compilation(pc, time);
+        }
+        match compiled {
+            Ok(compiled) => {
+                let code = compiled.code_stats();
+                self.compile_stats.push(CompileStats {
+                    pc,
+                    instructions: block.instruction_count(),
+                    time,
+                    code,
+                    queued: None,
+                });
+                let symbol = Self::register_symbol(&mut self.symbols, pc, compiled.as_ref());
+                let native = compiled.code_address().zip(code.code_size);
+                if let (Some(perf_map), Some((address, size))) = (&mut self.perf_map, native) {
+                    if let Err(e) = perf_map.add(address, size, &symbol) {
+                        warn!("wasn't capable to update the perf map: {}", e);
+                    }
+                }
+                self.translations.insert(block.bytecode(), &compiled);
+                block.compiled = Some(compiled);
+                block.code_size = code.code_size.unwrap_or(0);
+                debug!(
+                    "translation block successfully compiled by the {} backend!",
+                    self.backend.name()
+                );
+                for observer in self.observers.iter_mut() {
+                    observer.on_block_compiled(pc, block.bytecode());
+                }
+                let bytecode = block.shared_bytecode();
+                self.compile_osr_entry(pc, &bytecode, back7);
+                self.enforce_code_budget(pc);
+                (time, true)
+            }
+            Err(e) => {
+                warn!("wasn't capable to compile the translation block: {}", e);
+                self.record_compile_failure(pc, e);
+                (time, false)
+            }
+        }
+    }
+
+    /// Counts a failed compilation of the block at `pc`, blacklisting it
+    /// with `error` once it failed `max_compile_failures` times.
+    fn record_compile_failure(&mut self, pc: usize, error: VmError) {
+        let failures = self.compile_failures.entry(pc).or_insert(0);
+        *failures += 1;
+        if *failures >= self.config.max_compile_failures {
+            warn!(
+                "translation block {:#04x} left to the interpreter after {} failed compilations",
+                pc, failures
+            );
+            self.compile_failures.remove(&pc);
+            self.blacklist.insert(pc, error);
+        }
+    }
+
+    /// Drops the coldest compiled blocks from the code cache, sparing the
+    /// one at `keep`, until their native code fits `code_budget`. Their
+    /// loop entries go with them, for the code to be freed once unused.
+    fn enforce_code_budget(&mut self, keep: usize) {
+        let Some(budget) = self.config.code_budget else {
+            return;
+        };
+        let mut size = self.native_code_size();
+        while size > budget {
+            let Some(block) = self.code_cache.reap(keep) else {
+                break;
+            };
+            let span = block.span();
+            debug!(
+                "translation block {:#04x} reaped to free native code",
+                span.start
+            );
+            self.osr_entries.retain(|header, _| !span.contains(header));
+            size -= block.code_size;
+        }
+    }
+
+    /// Adds the function of `compiled`, the code of the block at `pc`, to
+    /// `symbols`, the symbol table of the engine, which is borrowed alone
+    /// while the code cache is. Returns its name, the first version of the
+    /// block for backends naming none.
+    fn register_symbol(
+        symbols: &mut BTreeMap<String, usize>,
+        pc: usize,
+        compiled: &dyn CompiledBlock,
+    ) -> String {
+        let Some(symbol) = compiled.symbol() else {
+            return backend::symbol(pc, 0);
+        };
+        symbols.insert(symbol.to_string(), pc);
+        symbol.to_string()
+    }
+
+    /// Compiles the blocks of `cfg`, the graph of the loaded program, see
+    /// `Cfg::engine_blocks`, pinning them in the code cache, so that the
+    /// guest runs native code from its first instruction on. Returns the
+    /// number of blocks compiled.
+    fn compile_ahead_of_time(&mut self, cfg: &Cfg) -> usize {
+        if self.config.backend == BackendKind::Reference {
+            return 0;
+        }
+
+        let mut compiled = 0;
+        for (pc, block) in cfg.engine_blocks() {
+            self.code_cache.pin(pc);
+            self.code_cache.insert(CachedBlock::new(pc, block.to_vec()));
+            if self.compile_block(pc).1 {
+                compiled += 1;
+            }
+        }
+        compiled
+    }
+
+    /// Compiles the loop closing the block at `pc` on its own, when it starts
+    /// past the entry of the block, so that the guest reaching its header
+    /// runs it natively. Blocks compiled in background get no entry, their
+    /// loop has to get hot by itself.
+    fn compile_osr_entry(&mut self, pc: usize, block: &[Instruction], back7: Option<BranchCounts>) {
+        let Some((header, body)) = osr::loop_header(pc, block) else {
+            return;
+        };
+        // The loop may have been compiled as a block of its own already
+        let compiled = match self.translations.get(body) {
+            Some(compiled) => compiled,
+            None => {
+                let start = Instant::now();
+                let compiled = self.backend.compile_profiled(header, body, back7);
+                self.report.compile_time += start.elapsed();
+                match compiled {
+                    Ok(compiled) => {
+                        Self::register_symbol(&mut self.symbols, header, compiled.as_ref());
+                        let compiled = Arc::from(compiled);
+                        self.translations.insert(body, &compiled);
+                        compiled
+                    }
+                    Err(e) => {
+                        warn!("wasn't capable to compile the loop entry: {}", e);
+                        return;
+                    }
+                }
+            }
+        };
+        debug!("loop at {:#04x} can be entered at its header", header);
+        let body = body.to_vec();
+        self.osr_entries.insert(
+            header,
+            OsrEntry {
+                header,
+                body,
+                compiled,
+            },
+        );
+    }
+
+    /// Gives the block at `pc` the code of the loop starting there, when a
+    /// compiled block encloses it and the block has no code of its own: the
+    /// guest then enters native code with the registers of the iteration in
+    /// progress, instead of interpreting the loop until it gets hot.
+    fn enter_loop(&mut self, pc: usize) {
+        let Some(entry) = self.osr_entries.get(&pc) else {
+            return;
+        };
+        match self.code_cache.peek_mut(&pc) {
+            Some(block) if block.has_compiled() || block.bytecode() != entry.body => return,
+            Some(block) => block.compiled = Some(entry.compiled.clone()),
+            None => {
+                let mut block = CachedBlock::new(pc, entry.body.clone());
+                block.compiled = Some(entry.compiled.clone());
+                self.code_cache.insert(block);
+            }
+        }
+        self.report.osr_entries += 1;
+        debug!("entering the loop at {:#04x} in native code", pc);
+    }
+
+    /// Compiles a recorded trace and installs it at its head, where it runs
+    /// instead of the blocks it was recorded from.
+    fn install_trace(&mut self, trace: Trace) {
+        let pc = trace.head;
+        let _span = debug_span!(
+            "compile",
+            pc,
+            length = trace.steps.len(),
+            tier = field::debug(Tier::Native),
+            backend = self.backend.name(),
+        )
+        .entered();
+        let start = Instant::now();
+        let compiled = self.backend.compile_trace(&trace);
+        let time = start.elapsed();
+        self.report.compile_time += time;
+        if let Some(timeline) = &mut self.timeline {
+            timeline.record(Activity::Compile, pc, trace.steps.len(), start, time);
+        }
+        if let Some(profile) = &mut self.profile {
+            profile.record_compilation(pc, time);
+        }
+        match compiled {
+            Ok(compiled) => {
+                Self::register_symbol(&mut self.symbols, pc, compiled.as_ref());
+                self.report.traces_compiled += 1;
+                debug!("trace of {} instructions compiled", trace.steps.len());
+                self.traces.insert(pc, CompiledTrace { trace, compiled });
+            }
+            Err(e) => warn!("wasn't capable to compile the trace: {}", e),
+        }
+    }
+
+    /// Executes exactly one instruction through the interpreter, returning
+    /// the decoded instruction together with the resulting CPU state. Traps
+    /// are recorded in the Cpu and returned, the trap handler is not entered.
+    pub fn step(&mut self) -> Result<(Instruction, Cpu), VmError> {
+        if self.cpu.halt {
+            return Err(VmError::MachineHalted);
+        }
+
+        // Stepping runs the instruction a breakpoint stopped at, so the next
+        // run must not skip the breakpoint the guest is at then
+        self.at_breakpoint = false;
+
+        let pc = self.cpu.pc;
+        let executed = self.fetch().and_then(|instr| {
+            let written = self.execute_instruction(instr)?;
+            // Stepping stops anyway, watchpoints only keep track
+            self.watchpoints.check(pc, &self.cpu, &written);
+            Ok(instr)
+        });
+        let instr = match executed {
+            Ok(instr) => instr,
+            Err(e) => {
+                if let Some(cause) = e.trap_cause() {
+                    self.cpu.trap = Some(Trap { cause, pc });
+                }
+                return Err(e);
+            }
+        };
+
+        for observer in self.observers.iter_mut() {
+            observer.on_instruction(pc, instr, &self.cpu);
+        }
+        if self.cpu.halt {
+            self.notify_halt();
+        }
+
+        self.debug_state();
+
+        Ok((instr, self.cpu))
+    }
+
+    pub fn add_observer(&mut self, observer: Box<dyn ExecutionObserver>) {
+        self.observers.push(observer);
+    }
+
+    fn notify_halt(&mut self) {
+        for observer in self.observers.iter_mut() {
+            observer.on_halt(&self.cpu);
+        }
+    }
+
+    /// Swaps in the blocks published by the background compiler and the
+    /// recompiler so far.
+    #[cfg(feature = "jit")]
+    fn install_compiled_blocks(&mut self) {
+        while let Some((compiled, recompiled)) = self.next_compiled() {
+            let (pc, bytecode, result, queued, start, time) = compiled;
+            self.report.record_compilation(time, result.is_ok());
+            if recompiled && result.is_ok() {
+                self.report.blocks_recompiled += 1;
+            }
+            if let Some(timeline) = &mut self.timeline {
+                timeline.record(Activity::Background, pc, bytecode.len(), start, time);
+            }
+            if let Some(profile) = &mut self.profile {
+                profile.record_compilation(pc, time);
+            }
+            if let Ok((block, code)) = &result {
+                self.compile_stats.push(CompileStats {
+                    pc,
+                    instructions: bytecode.len(),
+                    time,
+                    code: *code,
+                    queued: Some(queued),
+                });
+                let symbol = Self::register_symbol(&mut self.symbols, pc, block);
+                if let (Some(perf_map), Some(address), Some(size)) =
+                    (&mut self.perf_map, block.code_address(), code.code_size)
+                {
+                    if let Err(e) = perf_map.add(address, size, &symbol) {
+                        warn!("wasn't capable to update the perf map: {}", e);
+                    }
+                }
+            }
+
+            // The block may have been invalidated and rebuilt in the meantime,
+            // dropping the result frees its translation
+            let Some(block) = self.code_cache.peek_mut(&pc) else {
+                continue;
+            };
+            if block.bytecode() != bytecode {
+                continue;
+            }
+            match recompiled {
+                true => block.recompiled = true,
+                false => block.pending = false,
+            }
+
+            match result {
+                Ok((compiled, code)) => {
+                    // The code it replaces is freed once no block uses it
+                    let compiled: Arc<dyn CompiledBlock> = Arc::new(compiled);
+                    self.translations.insert(block.bytecode(), &compiled);
+                    block.compiled = Some(compiled);
+                    block.code_size = code.code_size.unwrap_or(0);
+                    match recompiled {
+                        true => debug!("translation block recompiled at a higher level!"),
+                        false => debug!("translation block compiled in background is now native!"),
+                    }
+                    for observer in self.observers.iter_mut() {
+                        observer.on_block_compiled(pc, block.bytecode());
+                    }
+                    self.auto_pin(pc, time);
+                    self.enforce_code_budget(pc);
+                }
+                Err(e) => {
+                    warn!("wasn't capable to compile the translation block: {}", e);
+                    // Blocks failing to recompile keep their code
+                    if !recompiled {
+                        self.record_compile_failure(pc, e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The next block published by a compilation thread, and whether it was
+    /// recompiled.
+    #[cfg(feature = "jit")]
+    fn next_compiled(&self) -> Option<(compiler::Compiled, bool)> {
+        let compiled = self.compiler.as_ref().and_then(CompilationWorker::try_recv);
+        compiled.map(|compiled| (compiled, false)).or_else(|| {
+            let recompiled = self
+                .recompiler
+                .as_ref()
+                .and_then(CompilationWorker::try_recv);
+            recompiled.map(|compiled| (compiled, true))
+        })
+    }
+
+    pub fn set_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(pc);
+        self.conditions.remove(&pc);
+    }
+
+    /// Sets a breakpoint at `pc` only stopping the guest when `condition`
+    /// holds as it gets there, see `Condition`.
+    pub fn set_conditional_breakpoint(&mut self, pc: usize, condition: Condition) {
+        self.breakpoints.insert(pc);
+        self.conditions
+            .insert(pc, BreakCondition::Parsed(condition));
+    }
+
+    /// Like `set_conditional_breakpoint`, with a predicate of the host.
+    pub fn set_breakpoint_if(
+        &mut self,
+        pc: usize,
+        predicate: impl Fn(&Cpu) -> bool + Send + Sync + 'static,
+    ) {
+        self.breakpoints.insert(pc);
+        self.conditions
+            .insert(pc, BreakCondition::Predicate(Box::new(predicate)));
+    }
+
+    pub fn clear_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.remove(&pc);
+        self.conditions.remove(&pc);
+    }
+
+    /// The condition of the breakpoint at `pc`, if it has one.
+    pub fn breakpoint_condition(&self, pc: usize) -> Option<&BreakCondition> {
+        self.conditions.get(&pc)
+    }
+
+    /// The addresses holding a breakpoint, in increasing order.
+    pub fn breakpoints(&self) -> Vec<usize> {
+        self.breakpoints.iter().copied().collect()
+    }
+
+    /// Stops the runs after the instructions firing `watchpoint`, with
+    /// Outcome::Watchpoint, returning its id. Compiled blocks that may fire
+    /// it are interpreted while it is set.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        self.watchpoints.add(watchpoint, &self.cpu)
+    }
+
+    pub fn remove_watchpoint(&mut self, id: usize) -> Option<Watchpoint> {
+        self.watchpoints.remove(id)
+    }
+
+    /// The watchpoints set, with their ids, in increasing order.
+    pub fn watchpoints(&self) -> Vec<(usize, Watchpoint)> {
+        self.watchpoints
+            .iter()
+            .map(|(id, watchpoint)| (id, watchpoint.clone()))
+            .collect()
+    }
+
+    /// Handles an error raised while running the guest. Traps are recorded
+    /// in the Cpu and delivered to the trap handler if one is configured,
+    /// in which case the guest keeps running and None is returned.
+    fn trap(&mut self, error: VmError) -> Result<Option<Outcome>, VmError> {
+        // Not a fault, the instruction runs again once the guest is resumed
+        if let VmError::WouldBlock { address } = error {
+            debug!(
+                "{:#04x} waits for the device at {:#04x}",
+                self.cpu.pc, address
+            );
+            return Ok(Some(Outcome::WaitingForMessage(self.cpu)));
+        }
+        let Some(cause) = error.trap_cause() else {
+            return Err(error);
+        };
+        let trap = Trap {
+            cause,
+            pc: self.cpu.pc,
+        };
+        self.cpu.trap = Some(trap);
+
+        let Some(handler) = self.config.trap_handler else {
+            return Ok(Some(Outcome::Trapped(error)));
+        };
+
+        let mut port = MemoryPort::new(&mut self.bus, &mut self.host_calls);
+        let entered = self.cpu.enter_trap_handler(&mut port, trap, handler);
+        let (written, ..) = port.into_parts();
+        for address in written {
+            self.invalidate_code(address..address + 1);
+        }
+
+        match entered {
+            Ok(()) => {
+                debug!(
+                    "{} delivered to the trap handler at {:#04x}",
+                    error, handler
+                );
+                Ok(None)
+            }
+            // A trap that cannot be delivered, e.g. on a full stack, stops the guest
+            Err(_) => Ok(Some(Outcome::Trapped(error))),
+        }
+    }
+
+    /// Sends the guest to the timer handler when the timer interrupt is due,
+    /// arming the timer for the next period.
+    fn deliver_interrupt(&mut self) -> Result<Option<Outcome>, VmError> {
+        let Some(timer) = self.config.timer else {
+            return Ok(None);
+        };
+        if !self.cpu.interrupt_pending() {
+            return Ok(None);
+        }
+        self.cpu.timecmp += timer.period;
+
+        let mut port = MemoryPort::new(&mut self.bus, &mut self.host_calls);
+        let entered = self.cpu.enter_interrupt_handler(&mut port, timer.vector);
+        let (written, ..) = port.into_parts();
+        for address in written {
+            self.invalidate_code(address..address + 1);
+        }
+
+        match entered {
+            Ok(()) => {
+                debug!(
+                    "timer interrupt delivered to the handler at {:#04x}",
+                    timer.vector
+                );
+                Ok(None)
+            }
+            Err(e) => self.trap(e),
+        }
+    }
+
+    /// The flag stopping the engine, which the host may set from another
+    /// thread or a signal handler. The guest stops before the next block, or
+    /// the next safepoint of a native loop, see `safepoint_interval`, and the
+    /// run returns Outcome::Stopped. The flag is cleared then, so the guest can be resumed.
+    pub fn stop_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.stop)
+    }
+
+    /// Starts logging the blocks executed from now on, together with the
+    /// state of the Cpu after each of them.
+    pub fn start_recording(&mut self) {
+        self.recording = Some(ExecutionLog::default());
+    }
+
+    /// Returns the log recorded since `start_recording`, if any.
+    pub fn stop_recording(&mut self) -> Option<ExecutionLog> {
+        self.recording.take()
+    }
+
+    /// Starts tracking the addresses executed from now on, see `Coverage`.
+    pub fn start_coverage(&mut self) {
+        self.coverage = Some(Coverage::default());
+    }
+
+    /// The addresses executed since `start_coverage`, if tracked.
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    pub fn stop_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take()
+    }
+
+    /// Starts injecting the faults of `injector` from now on, see
+    /// `FaultInjector`. Its faults in memory must land on an address of the
+    /// bus.
+    pub fn start_fault_injection(&mut self, injector: FaultInjector) -> Result<(), VmError> {
+        let size = self.bus.memory().size();
+        for fault in injector.pending() {
+            if let FaultTarget::Memory(address) = fault.target {
+                if address >= size && !self.bus.is_mapped(address) {
+                    return Err(VmError::InvalidConfig(format!(
+                        "cannot flip a bit at {:#04x}, past the {} bytes of memory",
+                        address, size
+                    )));
+                }
+            }
+        }
+        self.faults = Some(injector);
+        Ok(())
+    }
+
+    /// The faults scheduled and injected since `start_fault_injection`, if
+    /// injecting them.
+    pub fn fault_injector(&self) -> Option<&FaultInjector> {
+        self.faults.as_ref()
+    }
+
+    pub fn stop_fault_injection(&mut self) -> Option<FaultInjector> {
+        self.faults.take()
+    }
+
+    /// Injects the faults due, returning the instructions the guest may run
+    /// before the next one.
+    fn inject_faults(&mut self) -> Result<u64, VmError> {
+        let Some(injector) = &mut self.faults else {
+            return Ok(u64::MAX);
+        };
+        for fault in injector.take_due(self.cpu.instret) {
+            debug!("injecting {:?} at {:#04x}", fault, self.cpu.pc);
+            match fault.target {
+                FaultTarget::Register(index) => *self.cpu.register_mut(index) ^= 1 << fault.bit,
+                FaultTarget::Memory(address) => {
+                    let value = self.bus.peek(address)?;
+                    self.write_memory(address, value ^ 1 << fault.bit)?;
+                }
+            }
+            let injector = self.faults.as_mut().expect("injecting faults");
+            injector.record(fault, self.cpu.pc);
+        }
+        let injector = self.faults.as_ref().expect("injecting faults");
+        Ok(injector.until_next(self.cpu.instret))
+    }
+
+    /// Starts folding the pc, acc and lc of the guest into a digest every
+    /// `interval` instructions from now on, see `StateDigest`.
+    pub fn start_digest(&mut self, interval: u64) -> Result<(), VmError> {
+        self.digest = Some(StateDigest::new(interval, self.cpu.instret)?);
+        Ok(())
+    }
+
+    /// The digest of the states reached since `start_digest`, the current
+    /// one included, if digesting them.
+    pub fn digest(&self) -> Option<u64> {
+        self.digest.map(|digest| digest.value(&self.cpu))
+    }
+
+    pub fn stop_digest(&mut self) -> Option<u64> {
+        let digest = self.digest();
+        self.digest = None;
+        digest
+    }
+
+    /// Starts measuring the time spent on every block from now on.
+    pub fn start_profiling(&mut self) {
+        self.profile = Some(Profile::default());
+    }
+
+    /// The blocks run since `start_profiling`, if profiling.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    pub fn stop_profiling(&mut self) -> Option<Profile> {
+        self.profile.take()
+    }
+
+    /// The `count` blocks of the profile run the most times, then the
+    /// `count` ones taking the most time, each followed by its disassembly.
+    /// Empty unless profiling.
+    pub fn profile_report(&self, count: usize) -> Result<String, VmError> {
+        let Some(profile) = &self.profile else {
+            return Ok(String::new());
+        };
+
+        let mut report = String::new();
+        let rankings = [
+            ("executions", profile.most_executed(count)),
+            ("time", profile.most_time(count)),
+        ];
+        for (criterion, blocks) in rankings {
+            report += &format!("hottest blocks by {}:\n", criterion);
+            for (rank, block) in blocks.into_iter().enumerate() {
+                report += &format!("#{} {}\n", rank + 1, block);
+                for line in self.disassemble(block.span.clone(), false)?.lines() {
+                    report += &format!("    {}\n", line);
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// How often the BACK7 instructions run by the interpreter were taken,
+    /// which orders the loops of the blocks compiled after them.
+    pub fn branch_profile(&self) -> &BranchProfile {
+        &self.branches
+    }
+
+    /// The hotness of the run so far: the executions of the cached blocks
+    /// and the outcomes of the BACK7 run by the interpreter.
+    pub fn warmup_profile(&self) -> WarmupProfile {
+        WarmupProfile {
+            blocks: self
+                .code_cache
+                .blocks()
+                .map(|block| (block.span().start, block.executions))
+                .collect(),
+            branches: self.branches.clone(),
+        }
+    }
+
+    /// Warms the engine up with the profile of a previous run of the loaded
+    /// program: the blocks it found hot are decoded and compiled right away,
+    /// or queued for the compilation thread, and its branch outcomes weigh
+    /// the loops compiled from now on. Loading a program drops those blocks,
+    /// so the profile comes after it. Returns the number of blocks compiled
+    /// or queued.
+    pub fn warm_up(&mut self, profile: &WarmupProfile) -> Result<usize, VmError> {
+        for (pc, counts) in profile.branches.branches() {
+            self.branches.merge(pc, counts);
+        }
+        if self.config.backend == BackendKind::Reference {
+            return Ok(0);
+        }
+
+        let mut warmed = 0;
+        for &(pc, executions) in &profile.blocks {
+            if executions < self.config.compile_threshold {
+                continue;
+            }
+            match self.compile_ahead(pc, executions) {
+                Ok(true) => warmed += 1,
+                Ok(false) => {}
+                Err(e) => {
+                    warn!(
+                        "wasn't capable to decode the profiled block {:#04x}: {}",
+                        pc, e
+                    )
+                }
+            }
+        }
+        Ok(warmed)
+    }
+
+    /// Compiles the block at `pc` before it gets hot, decoding it into the
+    /// code cache if needed, or queues it for the compilation thread as if
+    /// it ran `executions` times. Returns whether it was compiled or queued,
+    /// blocks compiled, queued or blacklisted already being left alone.
+    #[cfg_attr(not(feature = "jit"), allow(unused_variables))]
+    fn compile_ahead(&mut self, pc: usize, executions: u64) -> Result<bool, VmError> {
+        if self.code_cache.peek_mut(&pc).is_none() {
+            let bytecode = self.decode_block(pc)?;
+            self.code_cache.insert(CachedBlock::new(pc, bytecode));
+        }
+
+        let block = self.code_cache.peek_mut(&pc).expect("inserted above");
+        if block.has_compiled() || self.blacklist.contains_key(&pc) {
+            return Ok(false);
+        }
+        #[cfg(feature = "jit")]
+        if let Some(compiler) = &self.compiler {
+            if block.pending {
+                return Ok(false);
+            }
+            let back7 = block.back7_pc().and_then(|pc| self.branches.get(pc));
+            let priority = executions * block.instruction_count() as u64;
+            compiler.submit(pc, block.bytecode().to_vec(), back7, priority);
+            block.pending = true;
+            return Ok(true);
+        }
+        Ok(self.compile_block(pc).1)
+    }
+
+    /// Compiles the blocks the guest may run after the one at `pc`, which
+    /// just got compiled or queued, and theirs, up to `precompile_depth`
+    /// levels: with background compilation, their code is then built while
+    /// the guest runs, and ready once it gets there. They are queued behind
+    /// the blocks found hot.
+    fn precompile_successors(&mut self, pc: usize) {
+        let mut seen = BTreeSet::from([pc]);
+        let mut level = vec![pc];
+        for _ in 0..self.config.precompile_depth {
+            let mut next = Vec::new();
+            for pc in level {
+                let Some(block) = self.code_cache.peek_mut(&pc) else {
+                    continue;
+                };
+                let Some(&last) = block.bytecode().last() else {
+                    continue;
+                };
+                let branch = block.span().end - last.length();
+                for (successor, _) in analysis::successors(branch, last) {
+                    if seen.insert(successor) {
+                        next.push(successor);
+                    }
+                }
+            }
+            for &successor in &next {
+                match self.compile_ahead(successor, 0) {
+                    Ok(true) => self.report.blocks_precompiled += 1,
+                    Ok(false) => {}
+                    // Successors may well be data, or past the memory
+                    Err(e) => debug!("block {:#04x} not precompiled: {}", successor, e),
+                }
+            }
+            level = next;
+        }
+    }
+
+    /// Whether blocks end before `pc`, a leader of the program when they
+    /// are aligned to its basic blocks.
+    fn is_leader(&self, pc: usize) -> bool {
+        self.aligned() && self.leaders.contains(&pc)
+    }
+
+    /// Whether blocks end before `pc`, a leader or an instruction starting
+    /// blocks, see `OpCode::starts_block`.
+    fn starts_block(&mut self, pc: usize) -> bool {
+        let starting = |instr: Instruction| instr.opcode.starts_block();
+        self.is_leader(pc) || self.decoded.decode(&self.bus, pc).is_ok_and(starting)
+    }
+
+    /// Whether blocks are aligned to the basic blocks of the program, as
+    /// they are when it is compiled ahead of time.
+    fn aligned(&self) -> bool {
+        self.config.align_blocks || self.config.ahead_of_time
+    }
+
+    /// Decodes the dynamic basic block at pc without running it.
+    fn decode_block(&mut self, pc: usize) -> Result<Vec<Instruction>, VmError> {
+        let mut block = Vec::new();
+        let mut address = pc;
+        loop {
+            let instr = self.decoded.decode(&self.bus, address)?;
+            block.push(instr);
+            address += instr.length();
+            if instr.opcode.ends_block() || self.starts_block(address) {
+                return Ok(block);
+            }
+        }
+    }
+
+    /// Starts timing the blocks run and compiled from now on, see `Timeline`.
+    pub fn start_timeline(&mut self) {
+        self.timeline = Some(Timeline::default());
+    }
+
+    /// Returns the timeline recorded since `start_timeline`, if any.
+    pub fn stop_timeline(&mut self) -> Option<Timeline> {
+        self.timeline.take()
+    }
+
+    /// Runs the guest like `run`, checking that it goes through the states
+    /// recorded in `log`. The first mismatch fails with ReplayDiverged,
+    /// which names the block that went astray.
+    pub fn replay(&mut self, log: ExecutionLog) -> Result<Outcome, VmError> {
+        self.replay = Some(Replay::new(log));
+        let outcome = self.run();
+        let replay = self.replay.take().unwrap();
+
+        let outcome = outcome?;
+        if outcome == Outcome::Halted {
+            replay.finish(&self.cpu)?;
+        }
+        Ok(outcome)
+    }
+
+    /// Runs the guest until it halts, reporting what the engine did. Going
+    /// past the deadline fails with TimedOut.
+    pub fn main_loop(&mut self) -> Result<ExecutionReport, VmError> {
+        self.report = ExecutionReport::default();
+        let start = Instant::now();
+        let outcome = self.run()?;
+        self.report.wall_time = start.elapsed();
+        self.report.cpu = self.cpu;
+        info!("{}", self.report);
+
+        match outcome {
+            Outcome::Trapped(e) => Err(e),
+            Outcome::TimedOut(_) => {
+                let deadline = self.config.deadline.unwrap_or_default();
+                Err(VmError::TimedOut(deadline))
+            }
+            _ => Ok(self.report.clone()),
+        }
+    }
+
+    /// Runs the guest until it halts, traps or reaches a breakpoint. When
+    /// called again after a breakpoint, execution resumes past it.
+    pub fn run(&mut self) -> Result<Outcome, VmError> {
+        self.run_with_fuel(None)
+    }
+
+    /// Runs the guest for at most `max_instructions` instructions. The budget
+    /// is checked before dispatching every block, so native blocks that would
+    /// overrun it are interpreted instead and stop exactly when it runs out.
+    /// Native loops check the remaining budget before every iteration.
+    pub fn run_for(&mut self, max_instructions: u64) -> Result<Outcome, VmError> {
+        self.run_with_fuel(Some(max_instructions))
+    }
+
+    /// Runs the guest, under the watchdog when the runs have a deadline.
+    fn run_with_fuel(&mut self, fuel: Option<u64>) -> Result<Outcome, VmError> {
+        let Some(deadline) = self.config.deadline else {
+            return self.run_blocks(fuel);
+        };
+        let watchdog = Watchdog::arm(deadline, Arc::clone(&self.stop));
+        let outcome = self.run_blocks(fuel);
+        if !watchdog.disarm() {
+            return outcome;
+        }
+        match outcome {
+            Ok(Outcome::Stopped(cpu)) => {
+                info!("timed out after {:?} at {:#04x}", deadline, cpu.pc);
+                Ok(Outcome::TimedOut(cpu))
+            }
+            // The deadline passed as the run ended, the next one goes on
+            outcome => {
+                self.stop.store(false, Ordering::Relaxed);
+                outcome
+            }
+        }
+    }
+
+    /// Accounts for the `executed` instructions of the block at pc, run to
+    /// its end or up to a fault the trap handler resumes from.
+    fn account_block(&mut self, pc: usize, tier: Tier, executed: u64) -> Result<(), VmError> {
+        self.report.record_block(tier, executed);
+
+        for observer in self.observers.iter_mut() {
+            observer.on_block_executed(pc, tier, &self.cpu);
+        }
+        if let Some(log) = &mut self.recording {
+            log.blocks.push(BlockRecord {
+                pc,
+                instret: self.cpu.instret,
+                hash: state_hash(&self.cpu),
+            });
+        }
+        if let Some(replay) = &mut self.replay {
+            replay.check(pc, &self.cpu)?;
+        }
+        Ok(())
+    }
+
+    fn run_blocks(&mut self, mut fuel: Option<u64>) -> Result<Outcome, VmError> {
+        // The breakpoint we stopped on last time must not fire again
+        let mut skip_breakpoint = std::mem::take(&mut self.at_breakpoint);
+        let reference = self.config.backend == BackendKind::Reference;
+        // Set when compiled code left through a guard, for the interpreter to
+        // run the next block
+        let mut deoptimized = false;
+        // The guest resumes where the last run left it, halfway through a
+        // block when the fuel ran out, rather than entering the code there
+        let mut resuming = true;
+        // The time the guest gets ahead of is counted from the run start
+        let pacer = self
+            .config
+            .pace
+            .map(|frequency| Pacer::new(frequency, self.cpu.cycles));
+
+        // As long the machine is not stopped
+        while !self.cpu.halt {
+            let budget = fuel.unwrap_or(u64::MAX);
+            if budget == 0 {
+                return Ok(Outcome::FuelExhausted);
+            }
+
+            // Blocks end where the next fault is due
+            let budget = budget.min(self.inject_faults()?);
+            // And where the next state is due in the digest
+            let budget = match &mut self.digest {
+                Some(digest) => budget.min(digest.update(&self.cpu)),
+                None => budget,
+            };
+
+            if let Some(pacer) = &pacer {
+                let delay = pacer.delay(self.cpu.cycles);
+                if !delay.is_zero() {
+                    std::thread::sleep(delay);
+                    self.report.paced += delay;
+                }
+            }
+
+            if self.stop.swap(false, Ordering::Relaxed) {
+                info!("stopped by the host at {:#04x}", self.cpu.pc);
+                return Ok(Outcome::Stopped(self.cpu));
+            }
+
+            // Interrupts are only taken between blocks
+            let interrupted = self.cpu.pc;
+            if let Some(outcome) = self.deliver_interrupt()? {
+                return Ok(outcome);
+            }
+
+            let pc = self.cpu.pc;
+            skip_breakpoint &= pc == interrupted;
+            let condition = self.conditions.get(&pc);
+            let stops = condition.is_none_or(|condition| condition.holds(&self.cpu));
+            if !skip_breakpoint && self.breakpoints.contains(&pc) && stops {
+                self.at_breakpoint = true;
+                return Ok(Outcome::Breakpoint(pc));
+            }
+            skip_breakpoint = false;
+
+            #[cfg(feature = "jit")]
+            self.install_compiled_blocks();
+
+            // Filled in once the block and the tier running it are known
+            let span = debug_span!("block", pc, length = field::Empty, tier = field::Empty);
+            let _entered = span.enter();
+            let started = Instant::now();
+            let mut compile_time = Duration::ZERO; // Part of the time spent on the block
+            let mut block_span = pc..pc; // Guest addresses of the block
+            let mut compiled_now = false;
+            let mut hot_now = false; // Whether the block got compiled or queued
+
+            // Entering the code here makes a leader of pc
+            let resumed = std::mem::take(&mut resuming);
+            if self.aligned() && !reference && !resumed && self.leaders.insert(pc) {
+                let split = self.code_cache.split(pc);
+                if !split.is_empty() {
+                    debug!(
+                        "{:#04x} is a leader, splitting the blocks at {:x?}",
+                        pc, split
+                    );
+                }
+            }
+
+            let interpreting = std::mem::take(&mut deoptimized);
+            if !reference && !interpreting {
+                self.enter_loop(pc);
+            }
+
+            // A trace starting here runs instead of the block, as long as it
+            // fits the budget and no breakpoint stops it halfway
+            let trace = self.traces.get(&pc).filter(|installed| {
+                let steps = &installed.trace.steps;
+                !interpreting
+                    && steps.len() as u64 <= budget
+                    && !steps.iter().any(|step| self.breakpoints.contains(&step.pc))
+                    && !self.watchpoints.watch(&installed.trace.instructions())
+            });
+            let block = match reference || trace.is_some() {
+                true => None,
+                false => self.code_cache.get_mut(&pc),
+            };
+
+            // Written addresses and fault left behind by cached blocks
+            let mut memory_effects = (Vec::new(), None, None);
+            // The instructions of the block when it ran to its end, for traces
+            let mut completed: Option<Arc<[Instruction]>> = None;
+
+            let (executed, tier) = if let Some(installed) = trace {
+                let steps = &installed.trace.steps;
+                let length = steps.len() as u64;
+                span.record("length", length);
+                block_span = installed.trace.span();
+
+                let instructions = installed.trace.instructions();
+                let checked = self.config.verify
+                    && !instructions
+                        .iter()
+                        .any(|instr| matches!(instr.opcode, OpCode::HCALL | OpCode::RDTIME));
+                let shadow = match checked {
+                    true => Some(Shadow::new(&self.cpu, &self.bus)?),
+                    false => None,
+                };
+
+                let mut port = MemoryPort::new(&mut self.bus, &mut self.host_calls);
+                let compiled = &installed.compiled;
+                let executed = compiled.execute(&mut self.cpu, &mut port, budget, &self.stop);
+                memory_effects = port.into_parts();
+
+                if let Some(shadow) = shadow {
+                    shadow.verify(
+                        &instructions,
+                        executed,
+                        &self.cpu,
+                        &self.bus,
+                        &memory_effects.0,
+                        &self.config.cost_table,
+                    )?;
+                }
+                if let Some(coverage) = &mut self.coverage {
+                    for step in steps.iter().take(executed as usize) {
+                        coverage.mark(step.pc, step.instr);
+                    }
+                }
+                (executed, Tier::Native)
+            } else if let Some(mut block) = block {
+                self.report.cache_hits += 1;
+                block.executions += 1;
+
+                // The same instructions at another address may be compiled already
+                let hot = block.executions >= self.config.compile_threshold;
+                if hot && !block.has_compiled() {
+                    block.compiled = self.translations.get(block.bytecode());
+                    if block.has_compiled() {
+                        self.report.blocks_shared += 1;
+                        debug!("translation block shares the code compiled for its instructions");
+                    }
+                }
+
+                // Or another engine may have compiled them
+                if let (Some(key), true) = (&self.shared, hot && !block.has_compiled()) {
+                    if let Some(code) = SharedCache::global().get(key, block.bytecode()) {
+                        let compiled: Arc<dyn CompiledBlock> = Arc::new(code);
+                        self.translations.insert(block.bytecode(), &compiled);
+                        block.compiled = Some(compiled);
+                        self.report.blocks_imported += 1;
+                        debug!("translation block runs the code compiled by another engine");
+                    }
+                }
+
+                if hot && !block.has_compiled() && !self.blacklist.contains_key(&pc) {
+                    #[cfg(feature = "jit")]
+                    let queued = match &self.compiler {
+                        Some(compiler) => {
+                            // Keep interpreting until the worker publishes the block,
+                            // which moves forward in the queue meanwhile
+                            let length = block.instruction_count() as u64;
+                            match block.pending {
+                                true => compiler.bump(pc, length),
+                                false => {
+                                    let back7 =
+                                        block.back7_pc().and_then(|pc| self.branches.get(pc));
+                                    let bytecode = block.bytecode().to_vec();
+                                    compiler.submit(pc, bytecode, back7, block.executions * length);
+                                    block.pending = true;
+                                    hot_now = true;
+                                }
+                            }
+                            true
+                        }
+                        None => false,
+                    };
+                    #[cfg(not(feature = "jit"))]
+                    let queued = false;
+
+                    if !queued {
+                        (compile_time, compiled_now) = self.compile_block(pc);
+                        hot_now = compiled_now;
+                        block = self
+                            .code_cache
+                            .peek_mut(&pc)
+                            .expect("compiled in the cache");
+                    }
+                }
+
+                // Hot native code is worth compiling again, harder
+                #[cfg(feature = "jit")]
+                if let (Some(recompiler), Some(threshold)) =
+                    (&self.recompiler, self.config.recompile_threshold)
+                {
+                    if block.has_compiled() && !block.recompiled && block.native_runs >= threshold {
+                        let back7 = block.back7_pc().and_then(|pc| self.branches.get(pc));
+                        let priority = block.native_runs * block.instruction_count() as u64;
+                        recompiler.submit(pc, block.bytecode().to_vec(), back7, priority);
+                        block.recompiled = true;
+                    }
+                }
+
+                let warm = self
+                    .config
+                    .baseline_threshold
+                    .is_some_and(|threshold| block.executions >= threshold);
+                if warm && block.baseline.is_none() {
+                    let costs = &self.config.cost_table;
+                    block.baseline = Some(BaselineBlock::compile(block.bytecode(), costs));
+                }
+
+                // Compiled code cannot stop in the middle of a block, so blocks
+                // spanning a breakpoint, or that may fire a watchpoint, fall
+                // back to the interpreter
+                let length = block.instruction_count() as u64;
+                span.record("length", length);
+                block_span = block.span();
+                let spans_breakpoint = self.breakpoints.range(block.span()).next().is_some();
+                let watched = self.watchpoints.watch(block.bytecode());
+                let runnable = length <= budget && !spans_breakpoint && !watched && !interpreting;
+                if self.tracer.is_some() {
+                    completed = Some(block.shared_bytecode());
+                }
+
+                if let (Some(compiled), true) = (&block.compiled, runnable) {
+                    let checked = self.config.verify
+                        && !block
+                            .bytecode()
+                            .iter()
+                            .any(|instr| matches!(instr.opcode, OpCode::HCALL | OpCode::RDTIME));
+                    let shadow = match checked {
+                        true => Some(Shadow::new(&self.cpu, &self.bus)?),
+                        false => None,
+                    };
+
+                    let mut port = MemoryPort::new(&mut self.bus, &mut self.host_calls);
+                    let executed = compiled.execute(&mut self.cpu, &mut port, budget, &self.stop);
+                    memory_effects = port.into_parts();
+                    #[cfg(feature = "jit")]
+                    {
+                        block.native_runs += 1;
+                    }
+
+                    if let Some(shadow) = shadow {
+                        shadow.verify(
+                            block.bytecode(),
+                            executed,
+                            &self.cpu,
+                            &self.bus,
+                            &memory_effects.0,
+                            &self.config.cost_table,
+                        )?;
+                    }
+                    if let Some(coverage) = &mut self.coverage {
+                        coverage.mark_block(pc, block.bytecode(), executed);
+                    }
+                    (executed, Tier::Native)
+                } else if let (Some(baseline), true) = (&block.baseline, runnable) {
+                    let mut port = MemoryPort::new(&mut self.bus, &mut self.host_calls);
+                    let executed = baseline.execute(&mut self.cpu, &mut port);
+                    memory_effects = port.into_parts();
+                    if let Some(coverage) = &mut self.coverage {
+                        coverage.mark_block(pc, block.bytecode(), executed);
+                    }
+                    (executed, Tier::Baseline)
+                } else {
+                    // The block is decoded already, only its execution is left
+                    let bytecode = block.shared_bytecode();
+                    match self.interpret_decoded(&bytecode, budget) {
+                        Ok(executed) => (executed, Tier::Interpreter),
+                        Err((e, executed)) => {
+                            fuel = fuel.map(|f| f - executed);
+                            self.account_block(pc, Tier::Interpreter, executed)?;
+                            match self.trap(e)? {
+                                Some(outcome) => return Ok(outcome),
+                                None => continue,
+                            }
+                        }
+                    }
+                }
+            } else {
+                if !reference {
+                    self.report.cache_misses += 1;
+                }
+
+                // Interpret instructions normally and Build translation block
+                let (dbb, self_modifying) = match self.interpret(budget) {
+                    Ok(interpreted) => interpreted,
+                    Err((e, executed)) => {
+                        fuel = fuel.map(|f| f - executed);
+                        self.account_block(pc, Tier::Interpreter, executed)?;
+                        match self.trap(e)? {
+                            Some(outcome) => return Ok(outcome),
+                            None => continue,
+                        }
+                    }
+                };
+                let length = dbb.len() as u64;
+                span.record("length", length);
+                block_span = pc..pc + dbb.iter().map(Instruction::length).sum::<usize>();
+
+                // A block cut short does not describe the code at `pc`, nor
+                // does one resumed halfway through an aligned block
+                let complete = dbb.last().is_some_and(|instr| instr.opcode.ends_block())
+                    || (!dbb.is_empty() && self.starts_block(self.cpu.pc));
+                let complete = complete && (!self.aligned() || self.is_leader(pc));
+                if self.tracer.is_some() && !self_modifying && complete {
+                    completed = Some(dbb.as_slice().into());
+                }
+                if !reference && !self_modifying && complete {
+                    self.code_cache.insert(CachedBlock::new(pc, dbb));
+                }
+
+                (length, Tier::Interpreter)
+            };
+
+            span.record("tier", field::debug(tier));
+            if let Some(timeline) = &mut self.timeline {
+                let duration = started.elapsed();
+                timeline.record(
+                    Activity::Run(tier),
+                    pc,
+                    executed as usize,
+                    started,
+                    duration,
+                );
+            }
+            if let Some(profile) = &mut self.profile {
+                let time = started.elapsed().saturating_sub(compile_time);
+                profile.record_run(block_span, tier, executed, time);
+            }
+            if compiled_now {
+                self.auto_pin(pc, compile_time);
+            }
+
+            let (written, fault, side_exit) = memory_effects;
+            for address in written {
+                self.invalidate_code(address..address + 1);
+            }
+            if hot_now {
+                self.precompile_successors(pc);
+            }
+
+            // The interpreter resumes where compiled code left through a guard
+            if let Some(exit) = side_exit {
+                self.report.side_exits += 1;
+                debug!("side exit to {:#04x}: {:?}", exit.pc, exit.reason);
+                for observer in self.observers.iter_mut() {
+                    observer.on_side_exit(exit, &self.cpu);
+                }
+                deoptimized = true;
+            }
+
+            // Traces are recorded from the blocks run to their end
+            let completed =
+                completed.filter(|block| fault.is_none() && executed >= block.len() as u64);
+            let recorded = self
+                .tracer
+                .as_mut()
+                .and_then(|tracer| tracer.on_block(pc, completed.as_deref(), self.cpu.pc));
+            if let Some(trace) = recorded {
+                self.install_trace(trace);
+            }
+
+            fuel = fuel.map(|f| f - executed);
+            self.account_block(pc, tier, executed)?;
+            if let Some(hit) = self.watch_hit.take() {
+                info!("watchpoint {} fired at {:#04x}", hit.id, hit.pc);
+                return Ok(Outcome::Watchpoint(hit));
+            }
+
+            if let Some(e) = fault {
+                match self.trap(e)? {
+                    Some(outcome) => return Ok(outcome),
+                    None => continue,
+                }
+            }
+
+            self.debug_state();
+        }
+
+        info!("{}", self.cpu);
+        self.notify_halt();
+
+        Ok(Outcome::Halted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use tracing_subscriber::EnvFilter;
+
+    use crate::{
+        backend::CodeStats,
+        baseline::Superinstruction,
+        config::{OptimizationLevel, Pass},
+        counted::CountedLoop,
+        cpu::{OpCode, OverflowMode, TrapCause, FLAG_NEGATIVE, FLAG_OVERFLOW, FLAG_ZERO},
+        deopt::{DeoptReason, SideExit},
+        export::{
+            read_trace, BinaryTrace, JsonLines, TraceEvent, TraceExporter, TraceGranularity,
+            TraceSink,
+        },
+        fault::Fault,
+        memory::MEMORY_SIZE,
+        mir::{self, Condition, Exit, Inst, Operand, Target},
+        monitor::Monitor,
+        peephole::{self, Action},
+        playback::TraceReplayer,
+        profile::WarmupProfile,
+        program::{
+            analysis::{Cfg, EdgeKind},
+            asm, disasm, generator, Program,
+        },
+        report::Histogram,
+        scheduler::TaskState,
+        timing::CostTable,
+        trace::{Trace, TraceStep},
+        watch::Comparison,
+    };
+
+    pub(crate) fn init() {
+        let _ = tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::from_default_env())
+            .with_test_writer()
+            .try_init();
+    }
+
+    fn generate_scenario(size: usize, seed: u32, probabilities: [u32; 5]) -> Program {
+        generator::generate(size, seed, probabilities).program
+    }
+
+    #[test]
+    pub fn scenario_1() {
+        // acc: 30003, lc: 7
+        init();
+        let prog = generate_scenario(10_000, 1, [0, 1, 0, 0, 0]);
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 10000,
+                cycles: 10000,
+                ..Cpu::new(30003, 7, 10000, true)
+            }
+        );
+    }
+
+    #[test]
+    pub fn scenario_2() {
+        // acc: -1, lc: 7
+        init();
+        let prog = generate_scenario(10_000, 1, [1, 1, 1, 0, 0]);
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 10000,
+                flags: FLAG_NEGATIVE,
+                cycles: 10000,
+                ..Cpu::new(-1, 7, 10000, true)
+            }
+        );
+    }
+
+    #[test]
+    pub fn scenario_3() {
+        // acc: 95, lc: -21
+        init();
+        let prog = generate_scenario(10_000, 1, [1, 9, 1, 5, 5]);
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 719821,
+                cycles: 719821,
+                ..Cpu::new(95, -21, 10_000, true)
+            }
+        );
+    }
+
+    #[test]
+    pub fn scenario_4() {
+        // acc: 138, lc: 0
+        init();
+        let prog = generate_scenario(50_000, 1, [1, 9, 1, 5, 5]);
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 21516018,
+                cycles: 21516018,
+                ..Cpu::new(138, 0, 50_000, true)
+            }
+        );
+    }
+
+    #[test]
+    pub fn generated_scenarios() {
+        init();
+        for seed in 1..=100 {
+            let probabilities = [1 + seed % 4, 1 + seed % 7, seed % 3, seed % 5, seed % 6];
+            let scenario = generator::generate(1_000, seed, probabilities);
+            let mut vm = EmulationEngine::default();
+            vm.load_program(scenario.program).unwrap();
+            vm.main_loop().unwrap();
+            assert_eq!(
+                (vm.cpu.acc, vm.cpu.lc, vm.cpu.instret),
+                (
+                    scenario.expected_acc,
+                    scenario.expected_lc,
+                    scenario.expected_instret
+                ),
+                "seed {}",
+                seed
+            );
+        }
+    }
+
+    #[test]
+    pub fn generator_matches_gen_c() {
+        // Generated by `init` in the course's gen.c, seed 0 included
+        let generate = |seed| {
+            let prog = generate_scenario(24, seed, [1, 9, 1, 5, 5]);
+            (prog.initial_acc, prog.initial_lc, prog.data)
+        };
+        let (acc, lc, data) = generate(0);
+        assert_eq!((acc, lc), (7, 4));
+        assert_eq!(
+            data,
+            [2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 5, 1, 4, 2, 4, 4, 0]
+        );
+        let (acc, lc, data) = generate(5);
+        assert_eq!((acc, lc), (2, 3));
+        assert_eq!(
+            data,
+            [2, 2, 2, 1, 3, 2, 1, 2, 2, 5, 5, 1, 2, 2, 2, 2, 2, 3, 5, 4, 2, 4, 2, 0]
+        );
+    }
+
+    #[test]
+    pub fn scenario_custom_0() {
+        init();
+        let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 5, 0], 0, 2);
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 16,
+                cycles: 16,
+                ..Cpu::new(36, -1, 9, true)
+            }
+        );
+    }
+
+    #[test]
+    pub fn scenario_custom_1() {
+        init();
+        let prog = Program::new(vec![4, 2, 2, 2, 2, 2, 2, 2, 5, 5, 0], 0, 2);
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 11,
+                cycles: 11,
+                ..Cpu::new(21, -2, 11, true)
+            }
+        );
+    }
+
+    #[test]
+    pub fn unknown_opcode_is_reported() {
+        init();
+        let prog = Program::new(vec![2, 2, 0xff, 0], 0, 0);
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+        assert_eq!(
+            vm.main_loop(),
+            Err(VmError::UnknownOpCode { pc: 2, byte: 0xff })
+        );
+    }
+
+    #[test]
+    pub fn oversized_program_is_rejected() {
+        init();
+        let prog = Program::new(vec![0; MEMORY_SIZE + 1], 0, 0);
+        let mut vm = EmulationEngine::default();
+        assert_eq!(
+            vm.load_program(prog),
+            Err(VmError::ProgramTooLarge {
+                size: MEMORY_SIZE + 1,
+                capacity: MEMORY_SIZE
+            })
+        );
+    }
+
+    #[test]
+    pub fn running_off_memory_traps() {
+        init();
+        let prog = Program::new(vec![2, 2, 2, 2], 0, 0);
+        let mut vm = EmulationEngine::builder()
+            .memory_size(4)
+            .stack_size(0)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        assert_eq!(
+            vm.run(),
+            Ok(Outcome::Trapped(VmError::MemoryOutOfBounds { address: 4 }))
+        );
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 4,
+                trap: Some(Trap {
+                    cause: TrapCause::MemoryOutOfBounds,
+                    pc: 4
+                }),
+                cycles: 4,
+                ..Cpu::new(12, 0, 4, false)
+            }
+        );
+        assert_eq!(
+            vm.bus.read(4),
+            Err(VmError::MemoryOutOfBounds { address: 4 })
+        );
+    }
+
+    struct Rom(Vec<u8>);
+
+    impl MmioDevice for Rom {
+        fn read(&self, offset: usize) -> u8 {
+            self.0[offset]
+        }
+
+        fn write(&mut self, _offset: usize, _value: u8) {}
+    }
+
+    #[test]
+    pub fn mmio_devices_shadow_memory() {
+        init();
+        let prog = Program::new(vec![2, 2, 2, 2], 0, 0);
+        let mut vm = EmulationEngine::builder()
+            .memory_size(8)
+            .stack_size(0)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+
+        // The program falls through into the device, which answers HALT
+        vm.attach_device(4..6, Box::new(Rom(vec![2, 0]))).unwrap();
+        assert!(vm.attach_device(5..7, Box::new(Rom(vec![0, 0]))).is_err());
+
+        vm.bus.write(4, 3).unwrap();
+        assert_eq!(vm.bus.read(4), Ok(2));
+
+        assert_eq!(vm.run(), Ok(Outcome::Halted));
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 6,
+                cycles: 6,
+                ..Cpu::new(15, 0, 6, true)
+            }
+        );
+    }
+
+    #[test]
+    pub fn memory_writes_invalidate_code() {
+        init();
+        let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 0], 0, 3);
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+
+        // Once interpreted, once from the code cache
+        assert_eq!(vm.run_for(14), Ok(Outcome::FuelExhausted));
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 14,
+                cycles: 14,
+                ..Cpu::new(36, 1, 0, false)
+            }
+        );
+
+        // The last iteration runs DECA instead of the first INC3A
+        vm.write_memory(0, 3).unwrap();
+        assert_eq!(vm.read_memory(0), Ok(3));
+        assert_eq!(vm.run(), Ok(Outcome::Halted));
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 22,
+                cycles: 22,
+                ..Cpu::new(50, 0, 8, true)
+            }
+        );
+    }
+
+    #[test]
+    pub fn memory_grows_up_to_its_limit() {
+        init();
+        let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 2, 0], 0, 0);
+        let mut vm = EmulationEngine::builder()
+            .memory_size(4)
+            .max_memory_size(16)
+            .stack_size(0)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        assert_eq!(vm.memory_size(), 8);
+
+        assert_eq!(vm.grow_memory(8), Ok(16));
+        assert_eq!(
+            vm.grow_memory(1),
+            Err(VmError::MemoryLimitExceeded {
+                size: 17,
+                limit: 16
+            })
+        );
+
+        assert_eq!(vm.run(), Ok(Outcome::Halted));
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 8,
+                cycles: 8,
+                ..Cpu::new(21, 0, 8, true)
+            }
+        );
+    }
+
+    struct CountingMemory {
+        inner: Memory,
+        reads: Arc<Mutex<usize>>,
+    }
+
+    impl Addressable<u8> for CountingMemory {
+        fn read(&self, address: usize) -> Result<u8, VmError> {
+            *self.reads.lock().unwrap() += 1;
+            self.inner.read(address)
+        }
+
+        fn write(&mut self, address: usize, value: u8) -> Result<(), VmError> {
+            self.inner.write(address, value)
+        }
+
+        fn write_chunk(&mut self, chunk: Vec<u8>) -> Result<(), VmError> {
+            self.inner.write_chunk(chunk)
+        }
+
+        fn size(&self) -> usize {
+            self.inner.size()
+        }
+    }
+
+    #[test]
+    pub fn immediate_operands() {
+        init();
+        // LI 300, ADDI -2
+        let prog = Program::new(vec![9, 0x2c, 0x01, 8, 0xfe, 0], 0, 0);
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 3,
+                cycles: 3,
+                ..Cpu::new(298, 0, 6, true)
+            }
+        );
+    }
+
+    #[test]
+    pub fn loop_with_immediate_operands() {
+        init();
+        // The six bytes jumped over by BACK7 hold five instructions
+        let prog = Program::new(vec![8, 5, 2, 3, 2, 2, 5, 0], 0, 3);
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 19,
+                cycles: 19,
+                ..Cpu::new(39, 0, 8, true)
+            }
+        );
+    }
+
+    #[test]
+    pub fn jumps_and_branches() {
+        init();
+        let prog = Program::new(
+            vec![
+                9, 5, 0, // 0x00: LI 5
+                8, 0xff, // 0x03: ADDI -1
+                12, 3, 0, // 0x05: BNEZ 0x03
+                10, 12, 0, // 0x08: JMP 0x0c
+                0, // 0x0b: HALT
+                11, 16, 0, // 0x0c: BEQZ 0x10
+                2, // 0x0f: INC3A
+                0, // 0x10: HALT
+            ],
+            0,
+            0,
+        );
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 14,
+                flags: FLAG_ZERO,
+                cycles: 14,
+                ..Cpu::new(0, 0, 17, true)
+            }
+        );
+    }
+
+    #[test]
+    pub fn flags_and_flag_branches() {
+        init();
+        let prog = Program::new(
+            vec![
+                9, 2, 0, // 0x00: LI 2
+                3, // 0x03: DECA
+                22, 3, 0, // 0x04: BNE 0x03
+                3, // 0x07: DECA
+                23, 12, 0, // 0x08: BMI 0x0c
+                0, // 0x0b: HALT
+                0, // 0x0c: HALT
+            ],
+            0,
+            0,
+        );
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 8,
+                flags: FLAG_NEGATIVE,
+                cycles: 8,
+                ..Cpu::new(-1, 0, 13, true)
+            }
+        );
+
+        // Overflowing wraps around and sets the overflow flag
+        let prog = Program::new(
+            vec![
+                2, // 0x00: INC3A
+                25, 5, 0, // 0x01: BVS 0x05
+                0, // 0x04: HALT
+                0, // 0x05: HALT
+            ],
+            i32::MAX - 1,
+            0,
+        );
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 3,
+                flags: FLAG_NEGATIVE | FLAG_OVERFLOW,
+                cycles: 3,
+                ..Cpu::new(i32::MIN + 1, 0, 6, true)
+            }
+        );
+    }
+
+    #[test]
+    pub fn overflow_modes() {
+        init();
+        let program = || {
+            Program::new(
+                vec![
+                    17, 0x20, // 0x00: MOV R2, R0
+                    2,    // 0x02: INC3A
+                    9, 0xfe, 0xff, // 0x03: LI -2
+                    19, 0x20, // 0x06: SUB R2, R0
+                    0,    // 0x08: HALT
+                ],
+                i32::MAX - 1,
+                0,
+            )
+        };
+        let run = |mode| {
+            let mut vm = EmulationEngine::builder()
+                .overflow_mode(mode)
+                .build()
+                .unwrap();
+            vm.load_program(program()).unwrap();
+            (vm.run(), vm.cpu)
+        };
+
+        let (outcome, cpu) = run(OverflowMode::Wrapping);
+        assert_eq!(outcome, Ok(Outcome::Halted));
+        assert_eq!((cpu.acc, cpu.gpr[0]), (-2, i32::MIN));
+        assert_eq!(cpu.flags, FLAG_NEGATIVE | FLAG_OVERFLOW);
+
+        let (outcome, cpu) = run(OverflowMode::Saturating);
+        assert_eq!(outcome, Ok(Outcome::Halted));
+        assert_eq!((cpu.acc, cpu.gpr[0]), (-2, i32::MAX));
+        assert_eq!(cpu.flags, FLAG_OVERFLOW);
+
+        // The faulting instruction leaves the Cpu untouched
+        let (outcome, cpu) = run(OverflowMode::Trapping);
+        assert_eq!(
+            outcome,
+            Ok(Outcome::Trapped(VmError::ArithmeticOverflow { pc: 2 }))
+        );
+        assert_eq!((cpu.acc, cpu.pc), (i32::MAX - 1, 2));
+    }
+
+    #[test]
+    pub fn call_and_return() {
+        init();
+        let prog = Program::new(
+            vec![
+                9, 7, 0,  // 0x00: LI 7
+                13, // 0x03: PUSH
+                15, 10, 0,  // 0x04: CALL 0x0a
+                14, // 0x07: POP
+                0,  // 0x08: HALT
+                0,  // 0x09: HALT
+                8, 5,  // 0x0a: ADDI 5
+                16, // 0x0c: RET
+            ],
+            0,
+            0,
+        );
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 7,
+                cycles: 7,
+                ..Cpu::new(7, 0, 9, true)
+            }
+        );
+    }
+
+    #[test]
+    pub fn stack_overflow_and_underflow_trap() {
+        init();
+        let prog = Program::new(vec![13, 13, 0], 0, 0);
+        let mut vm = EmulationEngine::builder()
+            .memory_size(16)
+            .stack_size(4)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        assert_eq!(
+            vm.run(),
+            Ok(Outcome::Trapped(VmError::StackOverflow { pc: 1 }))
+        );
+
+        let prog = Program::new(vec![16], 0, 0);
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+        assert_eq!(
+            vm.run(),
+            Ok(Outcome::Trapped(VmError::StackUnderflow { pc: 0 }))
+        );
+    }
+
+    #[test]
+    pub fn register_file() {
+        init();
+        let prog = Program::new(
+            vec![
+                9, 5, 0, // 0x00: LI 5
+                17, 0x20, // 0x03: MOV R2, R0
+                18, 0x22, // 0x05: ADD R2, R2
+                9, 1, 0, // 0x07: LI 1
+                19, 0x20, // 0x0a: SUB R2, R0
+                17, 0x02, // 0x0c: MOV R0, R2
+                17, 0x10, // 0x0e: MOV R1, R0
+                0,    // 0x10: HALT
+            ],
+            0,
+            0,
+        );
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(vm.cpu.register(2), 9);
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 8,
+                gpr: [9, 0, 0, 0, 0, 0],
+                cycles: 8,
+                ..Cpu::new(9, 9, 17, true)
+            }
+        );
+
+        let prog = Program::new(vec![17, 0x28, 0], 0, 0);
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+        assert_eq!(
+            vm.run(),
+            Ok(Outcome::Trapped(VmError::InvalidRegister {
+                pc: 0,
+                register: 8
+            }))
+        );
+    }
+
+    #[test]
+    pub fn host_calls() {
+        init();
+        // LI 21, HCALL 1, HCALL 0, HCALL 2
+        let prog = Program::new(vec![9, 21, 0, 20, 1, 20, 0, 20, 2, 0], 0, 0);
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let sink = output.clone();
+        vm.register_host_call(
+            0,
+            Box::new(move |cpu, _memory| {
+                sink.lock().unwrap().push(cpu.acc);
+                Ok(())
+            }),
+        );
+        vm.register_host_call(
+            1,
+            Box::new(|cpu, memory| {
+                cpu.acc *= 2;
+                memory.write(0x40, cpu.acc as u8)
+            }),
+        );
+
+        assert_eq!(
+            vm.run(),
+            Ok(Outcome::Trapped(VmError::UnknownHostCall {
+                pc: 7,
+                index: 2
+            }))
+        );
+        assert_eq!(*output.lock().unwrap(), vec![42]);
+        assert_eq!(vm.read_memory(0x40), Ok(42));
+        assert_eq!((vm.cpu.acc, vm.cpu.pc), (42, 7));
+    }
+
+    #[test]
+    pub fn trap_handler() {
+        init();
+        let prog = Program::new(
+            vec![
+                2,    // 0x00: INC3A
+                0xff, // 0x01: unknown
+                0,    // 0x02: HALT
+                0,    // 0x03: HALT
+                17, 0x20, // 0x04: MOV R2, R0
+                14,   // 0x05: POP
+                8, 1,  // 0x06: ADDI 1
+                13, // 0x08: PUSH
+                16, // 0x09: RET
+            ],
+            0,
+            0,
+        );
+        let mut vm = EmulationEngine::builder().trap_handler(4).build().unwrap();
+        vm.load_program(prog).unwrap();
+
+        // The handler skips the faulting byte
+        assert_eq!(vm.run(), Ok(Outcome::Halted));
+        assert_eq!(vm.cpu.register(2), TrapCause::UnknownOpCode as i32);
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 7,
+                gpr: [1, 0, 0, 0, 0, 0],
+                trap: Some(Trap {
+                    cause: TrapCause::UnknownOpCode,
+                    pc: 1
+                }),
+                cycles: 7,
+                ..Cpu::new(2, 0, 3, true)
+            }
+        );
+    }
+
+    #[test]
+    pub fn timer_interrupts() {
+        init();
+        let prog = Program::new(
+            vec![
+                8, 1, // 0x00: ADDI 1
+                17, 0x30, // 0x02: MOV R3, R0
+                9, 200, 0, // 0x04: LI 200
+                4, // 0x07: SETL
+                2, 2, 2, 2, 2, 2, // 0x08: INC3A
+                5, // 0x0e: BACK7
+                0, // 0x0f: HALT
+                18, 0x23, // 0x10: ADD R2, R3
+                16,   // 0x12: RET
+            ],
+            0,
+            0,
+        );
+        let mut vm = EmulationEngine::builder()
+            .compile_threshold(2)
+            .timer(100, 0x10)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+
+        // The handler counts the interrupts in R2, the loop is left untouched
+        assert_eq!(vm.run(), Ok(Outcome::Halted));
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                gpr: [14, 1, 0, 0, 0, 0],
+                instret: 1433,
+                cycles: 1433,
+                timecmp: 1500,
+                ..Cpu::new(3800, 0, 16, true)
+            }
+        );
+    }
+
+    #[test]
+    pub fn stop_flag() {
+        init();
+        // 0x00: INC3A, 0x01: JMP 0x00
+        let prog = Program::new(vec![2, 10, 0, 0], 0, 0);
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+
+        let stop = vm.stop_flag();
+        stop.store(true, Ordering::Relaxed);
+        assert_eq!(vm.run(), Ok(Outcome::Stopped(Cpu::new(0, 0, 0, false))));
+
+        // A runaway guest is stopped from another thread
+        let stopper = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            stop.store(true, Ordering::Relaxed);
+        });
+        let Ok(Outcome::Stopped(cpu)) = vm.run() else {
+            panic!("the guest was not stopped");
+        };
+        stopper.join().unwrap();
+        assert_eq!(cpu, vm.cpu);
+        assert!(!cpu.halt);
+        assert!(cpu.instret > 0);
+    }
+
+    #[test]
+    pub fn safepoint_interval() {
+        init();
+        // Saturating loops are not computed in closed form, they run natively
+        let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 0], 0, 100);
+        let run = |interval| {
+            let mut vm = EmulationEngine::builder()
+                .overflow_mode(OverflowMode::Saturating)
+                .safepoint_interval(interval)
+                .build()
+                .unwrap();
+            vm.load_program(prog.clone()).unwrap();
+            vm.run_for(50).unwrap();
+            let fueled = vm.cpu;
+            vm.run().unwrap();
+            (fueled, vm.cpu)
+        };
+        // Polling less often leaves the budget and the result alone
+        assert_eq!(run(16), run(1));
+
+        assert!(matches!(
+            EmulationEngine::builder().safepoint_interval(0).build(),
+            Err(VmError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    pub fn deadline() {
+        init();
+        // 0x00: INC3A, 0x01: JMP 0x00
+        let prog = Program::new(vec![2, 10, 0, 0], 0, 0);
+        let mut vm = EmulationEngine::builder()
+            .deadline(Duration::from_millis(20))
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+
+        let Ok(Outcome::TimedOut(cpu)) = vm.run() else {
+            panic!("the guest did not time out");
+        };
+        assert_eq!(cpu, vm.cpu);
+        assert!(!cpu.halt);
+        assert!(cpu.instret > 0);
+        // The watchdog leaves the stop flag clear for the next run
+        assert!(!vm.stop_flag().load(Ordering::Relaxed));
+        assert_eq!(
+            vm.main_loop().map(|_| ()),
+            Err(VmError::TimedOut(Duration::from_millis(20)))
+        );
+    }
+
+    #[test]
+    pub fn faults_are_injected_reproducibly() {
+        init();
+        // Sums the bytes at 0x40 and 0x41, 10 times
+        let prog = asm::assemble(
+            "
+                LI 10
+                SETL
+            loop: LDA 0x40
+                ADD R2, A
+                LDA 0x41
+                ADD R2, A
+                MOV A, L
+                DECA
+                SETL
+                BNEZ loop
+                MOV A, R2
+                HALT
+            ",
+        )
+        .unwrap();
+        let faults = [
+            Fault {
+                instret: 13,
+                target: FaultTarget::Register(2),
+                bit: 4,
+            },
+            Fault {
+                instret: 40,
+                target: FaultTarget::Memory(0x41),
+                bit: 1,
+            },
+        ];
+        let run = |builder: EmulationEngineBuilder, injector: FaultInjector| {
+            let mut vm = builder.build().unwrap();
+            vm.load_program(prog.clone()).unwrap();
+            vm.write_memory(0x40, 1).unwrap();
+            vm.start_fault_injection(injector).unwrap();
+            assert_eq!(vm.run(), Ok(Outcome::Halted));
+            (vm.cpu, vm.stop_fault_injection().unwrap())
+        };
+
+        let mut injector = FaultInjector::new();
+        for fault in faults {
+            injector.schedule(fault).unwrap();
+        }
+        // The faults land at the same instructions whichever tier runs them
+        let builders = [
+            EmulationEngine::builder().backend(BackendKind::Reference),
+            EmulationEngine::builder().compile_threshold(1),
+            EmulationEngine::builder().baseline_threshold(1),
+            EmulationEngine::builder().trace_threshold(1),
+        ];
+        let results = builders.map(|builder| run(builder, injector.clone()));
+        let (cpu, injected) = &results[0];
+        // 16 more from the first flip, then 2 for each of the last 5 loops
+        assert_eq!(cpu.acc, 10 + 16 + 2 * 5);
+        assert_eq!(injected.pending().count(), 0);
+        let pcs: Vec<usize> = injected.injected().iter().map(|f| f.pc).collect();
+        assert_eq!(pcs, vec![0x0c, 0x11]);
+        for result in &results {
+            assert_eq!(result, &results[0]);
+        }
+
+        // Random faults are drawn from the seed
+        let random = || FaultInjector::random(7, 4, 0..60, 0x40..0x42);
+        assert_eq!(random(), random());
+        assert_eq!(random().pending().count(), 4);
+        let (cpu, _) = run(EmulationEngine::builder(), random());
+        assert_eq!(
+            run(EmulationEngine::builder().compile_threshold(1), random()).0,
+            cpu
+        );
+
+        let fault = Fault {
+            instret: 0,
+            target: FaultTarget::Register(REGISTER_COUNT),
+            bit: 0,
+        };
+        assert!(FaultInjector::new().schedule(fault).is_err());
+
+        // Nor past the end of the memory
+        let mut vm = EmulationEngine::builder().build().unwrap();
+        let beyond = FaultInjector::random(7, 4, 0..60, MEMORY_SIZE..MEMORY_SIZE + 2);
+        assert!(matches!(
+            vm.start_fault_injection(beyond),
+            Err(VmError::InvalidConfig(_))
+        ));
+        assert!(vm.fault_injector().is_none());
+    }
+
+    #[test]
+    pub fn digests_do_not_depend_on_the_tier() {
+        init();
+        // Sums the byte at 0x40, 6 times
+        let prog = asm::assemble(
+            "
+                LI 6
+                SETL
+            loop: LDA 0x40
+                ADD R2, A
+                MOV A, L
+                DECA
+                SETL
+                BNEZ loop
+                MOV A, R2
+                HALT
+            ",
+        )
+        .unwrap();
+        let run = |builder: EmulationEngineBuilder, interval: u64, value: u8| {
+            let mut vm = builder.build().unwrap();
+            vm.load_program(prog.clone()).unwrap();
+            vm.write_memory(0x40, value).unwrap();
+            vm.start_digest(interval).unwrap();
+            assert_eq!(vm.run(), Ok(Outcome::Halted));
+            vm.stop_digest().unwrap()
+        };
+
+        for interval in [1, 4, 1000] {
+            let builders = [
+                EmulationEngine::builder().backend(BackendKind::Reference),
+                EmulationEngine::builder().compile_threshold(1),
+                EmulationEngine::builder().baseline_threshold(1),
+                EmulationEngine::builder().trace_threshold(1),
+            ];
+            let digests = builders.map(|builder| run(builder, interval, 3));
+            for digest in digests {
+                assert_eq!(digest, digests[0]);
+            }
+            assert_ne!(run(EmulationEngine::builder(), interval, 4), digests[0]);
+        }
+        assert_ne!(
+            run(EmulationEngine::builder(), 1, 3),
+            run(EmulationEngine::builder(), 4, 3)
+        );
+
+        let mut vm = EmulationEngine::default();
+        assert!(vm.start_digest(0).is_err());
+        assert_eq!(vm.digest(), None);
+    }
+
+    #[test]
+    pub fn lockstep_finds_divergences() {
+        init();
+        // Sums 8 random bytes
+        let prog = asm::assemble(
+            "
+                LI 8
+                SETL
+            loop: LDA 0xff04
+                ADD R2, A
+                MOV A, L
+                DECA
+                SETL
+                BNEZ loop
+                MOV A, R2
+                HALT
+            ",
+        )
+        .unwrap();
+        let engine = |builder: EmulationEngineBuilder| {
+            let mut vm = builder.build().unwrap();
+            vm.load_program(prog.clone()).unwrap();
+            vm
+        };
+        let seeded = |seed| EmulationEngine::builder().rng(rng::RNG_ADDRESS, seed);
+
+        // Compiled code runs like the reference interpreter
+        let reference = engine(seeded(1).backend(BackendKind::Reference));
+        let compiled = engine(seeded(1).compile_threshold(1));
+        let mut lockstep = lockstep::Lockstep::new(reference, compiled, 3).unwrap();
+        assert_eq!(lockstep.run(), Ok(Outcome::Halted));
+        let (reference, compiled) = lockstep.into_engines();
+        assert_eq!(reference.cpu, compiled.cpu);
+
+        // Other seeds read other bytes from the first load on
+        let mut lockstep = lockstep::Lockstep::new(engine(seeded(1)), engine(seeded(2)), 1)
+            .unwrap()
+            .history(2);
+        let divergence = lockstep.run().unwrap_err();
+        assert_eq!(divergence.left.1.instret, 3);
+        assert_eq!(divergence.right.1.pc, 0x07);
+        let pcs: Vec<usize> = divergence
+            .left_trail
+            .iter()
+            .map(|record| record.pc)
+            .collect();
+        assert_eq!(pcs, vec![0x03, 0x04]);
+        assert_eq!(divergence.left_trail.len(), divergence.right_trail.len());
+
+        assert!(lockstep::Lockstep::new(engine(seeded(1)), engine(seeded(1)), 0).is_err());
+    }
+
+    #[test]
+    pub fn pacing_slows_the_guest_down() {
+        init();
+        // 42 cycles at 1 kHz
+        let prog = asm::assemble("LI 20; loop: DECA; BNEZ loop; HALT").unwrap();
+        let mut vm = EmulationEngine::builder().pace(1000).build().unwrap();
+        vm.load_program(prog).unwrap();
+        let report = vm.main_loop().unwrap();
+        assert_eq!(report.cpu.cycles, 42);
+        assert!(report.wall_time >= Duration::from_millis(40));
+        assert!(report.paced >= Duration::from_millis(30));
+
+        assert!(EmulationEngine::builder().pace(0).build().is_err());
+    }
+
+    #[test]
+    pub fn main_loop_reports_the_run() {
+        init();
+        let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 0], 0, 5);
+        let mut vm = EmulationEngine::builder()
+            .compile_threshold(2)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+
+        let report = vm.main_loop().unwrap();
+        assert_eq!(report.cpu, vm.cpu);
+        assert_eq!(report.instructions(), vm.cpu.instret);
+        assert_eq!(report.interpreted + report.native, 36);
+        assert_eq!(report.blocks_compiled, 1);
+        assert!(report.cache_hits > 0);
+        assert_eq!(report.cache_misses, 2);
+    }
+
+    #[test]
+    pub fn compile_statistics() {
+        init();
+        let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 0], 0, 5);
+        let mut vm = EmulationEngine::builder()
+            .backend(BackendKind::Interpreter)
+            .compile_threshold(2)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        let report = vm.main_loop().unwrap();
+
+        // The interpreter generates no code to measure
+        let stats = vm.compile_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].pc, 0);
+        assert_eq!(stats[0].instructions, 7);
+        assert_eq!(stats[0].time, report.compile_time);
+        assert_eq!(stats[0].code, CodeStats::default());
+
+        let summary = vm.compile_summary();
+        assert_eq!(summary.blocks, 1);
+        assert_eq!(summary.time_us.count(), 1);
+        assert_eq!(summary.ir_instructions.count(), 0);
+        assert_eq!(summary.code_size.count(), 0);
+
+        let histogram: Histogram = [0, 1, 2, 3, 4, 100].into_iter().collect();
+        assert_eq!(
+            histogram.buckets().collect::<Vec<_>>(),
+            vec![
+                (0..=0, 1),
+                (1..=1, 1),
+                (2..=3, 2),
+                (4..=7, 1),
+                (64..=127, 1)
+            ]
+        );
+        assert_eq!(histogram.count(), 6);
+    }
+
+    #[test]
+    pub fn shared_translations() {
+        init();
+        // Two copies of the same loop, at 0x04 and 0x0f
+        let mut loops = vec![9, 5, 0, 4, 2, 2, 2, 2, 2, 2, 5];
+        loops.extend_from_within(..);
+        loops.push(0);
+        let mut vm = EmulationEngine::builder()
+            .backend(BackendKind::Interpreter)
+            .compile_threshold(2)
+            .build()
+            .unwrap();
+        vm.load_program(Program::new(loops, 0, 0)).unwrap();
+        let report = vm.main_loop().unwrap();
+        assert_eq!(report.cpu.acc, 5 + 5 * 18);
+
+        // The second copy runs the code compiled for the first one
+        let stats = vm.compile_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].pc, 0x04);
+        assert_eq!(report.blocks_compiled, 1);
+        assert_eq!(report.blocks_shared, 1);
+        let native: Vec<usize> = vm
+            .cached_blocks()
+            .iter()
+            .filter(|block| block.tier == Tier::Native)
+            .map(|block| block.pc)
+            .collect();
+        assert_eq!(native, vec![0x04, 0x0f]);
+    }
+
+    #[test]
+    pub fn timeline() {
+        init();
+        let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 0], 0, 5);
+        let mut vm = EmulationEngine::builder()
+            .backend(BackendKind::Interpreter)
+            .compile_threshold(2)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        assert!(vm.stop_timeline().is_none());
+        vm.start_timeline();
+        vm.main_loop().unwrap();
+
+        let timeline = vm.stop_timeline().unwrap();
+        let activities: Vec<Activity> = timeline.events.iter().map(|e| e.activity).collect();
+        assert_eq!(
+            activities[..4],
+            [
+                Activity::Run(Tier::Interpreter),
+                Activity::Run(Tier::Interpreter),
+                Activity::Compile,
+                Activity::Run(Tier::Native),
+            ]
+        );
+
+        // The block compiled in the third run is part of it
+        let (compile, run) = (timeline.events[2], timeline.events[3]);
+        assert!(run.start <= compile.start);
+        assert!(compile.start + compile.duration <= run.start + run.duration);
+
+        let trace = timeline.to_chrome_trace();
+        assert!(trace.starts_with("{\"traceEvents\": ["));
+        assert!(trace.contains("\"name\": \"compile block @0x0000\", \"cat\": \"compile\""));
+        assert!(trace.contains("\"name\": \"native exec @0x0000\""));
+        assert!(trace.trim_end().ends_with("]}"));
+    }
+
+    #[test]
+    pub fn perf_map() {
+        let mut map = PerfMap::open().unwrap();
+        map.add(0x7f00_1000, 0x40, &backend::symbol(0x1a2b, 0))
+            .unwrap();
+        let lines = std::fs::read_to_string(PerfMap::path()).unwrap();
+        assert!(lines.lines().any(|line| line == "7f001000 40 dbb_0x1a2b_0"));
+        assert_eq!(backend::symbol_pc("dbb_0x1a2b_3"), Some(0x1a2b));
+        assert_eq!(backend::symbol_pc("dbb_7"), None);
+
+        // Blocks left to the interpreter have no native code to name
+        let mut vm = EmulationEngine::builder()
+            .backend(BackendKind::Interpreter)
+            .compile_threshold(1)
+            .perf_map(true)
+            .build()
+            .unwrap();
+        vm.load_program(Program::new(vec![2, 2, 2, 2, 2, 2, 5, 0], 0, 5))
+            .unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(std::fs::read_to_string(PerfMap::path()).unwrap(), lines);
+        assert!(vm.symbol_table().is_empty());
+        let _ = std::fs::remove_file(PerfMap::path());
+    }
+
+    #[test]
+    pub fn execution_coverage() {
+        init();
+        let prog = asm::assemble(
+            "
+            .lc 5
+            JMP loop
+            dead: .byte 0xff, 0xff
+            loop: INC3A; DECA; INC3A; DECA; INC3A; DECA; BACK7 loop
+            HALT
+            unused: DECA
+            ",
+        )
+        .unwrap();
+        let length = prog.data.len();
+
+        for backend in [BackendKind::Interpreter, BackendKind::Reference] {
+            let mut vm = EmulationEngine::builder()
+                .backend(backend)
+                .compile_threshold(2)
+                .build()
+                .unwrap();
+            vm.load_program(prog.clone()).unwrap();
+            assert!(vm.coverage().is_none());
+            vm.start_coverage();
+            vm.main_loop().unwrap();
+
+            let coverage = vm.coverage().unwrap();
+            assert_eq!(coverage.uncovered(0..length), vec![3..5, 13..14]);
+            assert_eq!(coverage.covered(0..length), 11);
+            assert!(coverage.is_covered(0) && coverage.is_covered(2) && !coverage.is_covered(3));
+            assert_eq!(coverage.bitmap(), [0b1_1111_1110_0111]);
+            assert_eq!(
+                coverage.report(0..length),
+                "11 of 14 bytes executed (78.6%)\n\
+                 never executed: 0x0003..0x0005 (2 bytes)\n\
+                 never executed: 0x000d..0x000e (1 bytes)\n"
+            );
+        }
+    }
+
+    #[test]
+    pub fn block_profile() {
+        init();
+        let prog = asm::assemble(
+            "
+            .lc 5
+            JMP loop
+            loop: INC3A; DECA; INC3A; DECA; INC3A; DECA; BACK7 loop
+            HALT
+            ",
+        )
+        .unwrap();
+        let mut vm = EmulationEngine::builder()
+            .backend(BackendKind::Interpreter)
+            .compile_threshold(2)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        assert_eq!(vm.profile_report(3).unwrap(), "");
+        vm.start_profiling();
+        vm.main_loop().unwrap();
+
+        let profile = vm.profile().unwrap();
+        let blocks: Vec<(usize, u64, u64, u64)> = profile
+            .blocks()
+            .iter()
+            .map(|b| (b.pc, b.executions, b.instructions, b.compilations))
+            .collect();
+        assert_eq!(blocks, vec![(0, 1, 1, 0), (3, 3, 35, 1), (10, 1, 1, 0)]);
+        assert_eq!(profile.get(3).unwrap().span, 3..10);
+        assert_eq!(profile.most_executed(1)[0].pc, 3);
+        assert_eq!(profile.most_time(5).len(), 3);
+
+        let report = vm.profile_report(1).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "hottest blocks by executions:");
+        assert!(lines[1].starts_with("#1 0x0003: 3 executions, 35 instructions in "));
+        assert_eq!(lines[2], "    0x0003: 02        INC3A");
+        assert_eq!(lines[8], "    0x0009: 05        BACK7");
+        assert_eq!(lines[9], "hottest blocks by time:");
+
+        // Timing decides the hottest block by time, listed with its code
+        let hottest = profile.most_time(1)[0];
+        assert!(lines[10].starts_with(&format!("#1 {:#06x}: ", hottest.pc)));
+        let code = vm.disassemble(hottest.span.clone(), false).unwrap();
+        assert_eq!(lines.len(), 11 + code.lines().count());
+    }
+
+    #[test]
+    pub fn warmup_profile() {
+        init();
+        let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 0], 0, 5);
+        let engine = || {
+            EmulationEngine::builder()
+                .backend(BackendKind::Interpreter)
+                .compile_threshold(3)
+                .build()
+                .unwrap()
+        };
+        let mut vm = engine();
+        vm.load_program(prog.clone()).unwrap();
+        vm.main_loop().unwrap();
+
+        let profile = vm.warmup_profile();
+        assert_eq!(profile.blocks, vec![(0, 3), (7, 0)]);
+        assert_eq!(profile.branches, *vm.branch_profile());
+        let text = profile.to_string();
+        assert!(text.starts_with("block 0x0000 3\nblock 0x0007 0\n"));
+        assert_eq!(text.parse(), Ok(profile.clone()));
+
+        let path = std::env::temp_dir().join(format!("vt-vm-dyn-{}.prof", std::process::id()));
+        profile.save(&path).unwrap();
+        let loaded = WarmupProfile::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, Ok(profile.clone()));
+
+        // The hot loop runs compiled from its first iteration
+        let mut vm = engine();
+        vm.load_program(prog).unwrap();
+        assert_eq!(vm.warm_up(&profile), Ok(1));
+        assert_eq!(vm.compile_stats().len(), 1);
+        assert_eq!(vm.branch_profile(), &profile.branches);
+        let report = vm.main_loop().unwrap();
+        assert_eq!((report.native, report.interpreted), (35, 1));
+        assert_eq!(vm.compile_stats().len(), 1);
+
+        assert_eq!(
+            "block 0x0000".parse::<WarmupProfile>(),
+            Err(VmError::InvalidProfile("malformed line 1".to_string()))
+        );
+    }
+
+    #[test]
+    pub fn trace_compilation() {
+        init();
+        // The loop spans two blocks, its last iteration leaves the trace
+        let prog = asm::assemble(
+            "
+                LI 10
+            loop: DECA
+                BEQZ done
+                JMP loop
+            done: HALT
+            ",
+        )
+        .unwrap();
+        let mut reference = EmulationEngine::builder()
+            .backend(BackendKind::Reference)
+            .build()
+            .unwrap();
+        reference.load_program(prog.clone()).unwrap();
+        reference.main_loop().unwrap();
+
+        let mut vm = EmulationEngine::builder()
+            .backend(BackendKind::Interpreter)
+            .compile_threshold(100)
+            .trace_threshold(2)
+            .verify(true)
+            .build()
+            .unwrap();
+        let counts = Arc::new(Mutex::new(EventCounts::default()));
+        vm.add_observer(Box::new(CountingObserver(counts.clone())));
+        vm.load_program(prog).unwrap();
+        let report = vm.main_loop().unwrap();
+        assert_eq!(vm.cpu, reference.cpu);
+        assert_eq!((report.traces_compiled, report.side_exits), (1, 1));
+        assert_eq!(
+            counts.lock().unwrap().side_exits,
+            [SideExit {
+                pc: 10,
+                reason: DeoptReason::Branch,
+            }]
+        );
+        assert_eq!(report.native, 6 * 3 + 2);
+
+        // Writing to the trace drops it
+        vm.write_memory(4, 0).unwrap();
+        assert!(vm.traces.is_empty());
+
+        let trace = Trace {
+            head: 3,
+            steps: vec![
+                TraceStep {
+                    pc: 3,
+                    instr: Instruction::new(OpCode::DECA, 0),
+                    next: 4,
+                },
+                TraceStep {
+                    pc: 4,
+                    instr: Instruction::new(OpCode::BEQZ, 10),
+                    next: 7,
+                },
+                TraceStep {
+                    pc: 7,
+                    instr: Instruction::new(OpCode::JMP, 3),
+                    next: 3,
+                },
+            ],
+        };
+        assert_eq!(trace.span(), 3..10);
+        assert_eq!(trace.steps[1].side_exit(), 10);
+        assert_eq!(
+            mir::lower_trace(&trace, OverflowMode::Wrapping).code[3..],
+            [
+                Inst::Retire(OpCode::BEQZ),
+                Inst::Guard {
+                    condition: Condition::Zero(mir::ACC),
+                    expected: false,
+                    exit: 10,
+                    next: 7,
+                    reason: DeoptReason::Branch,
+                },
+                Inst::Jump(3),
+                Inst::Retire(OpCode::JMP),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn osr_entry() {
+        init();
+        let prog = asm::assemble(
+            "
+            LI 50
+            SETL
+            loop: INC3A; INC3A; INC3A; INC3A; INC3A; INC3A; BACK7 loop
+            HALT
+            ",
+        )
+        .unwrap();
+        let mut block = vec![
+            Instruction::new(OpCode::LI, 50),
+            Instruction::new(OpCode::SETL, 0),
+        ];
+        block.extend([Instruction::new(OpCode::INC3A, 0); 6]);
+        block.push(Instruction::new(OpCode::BACK7, 0));
+        assert_eq!(osr::loop_header(0, &block), Some((4, &block[2..])));
+        assert_eq!(osr::loop_header(4, &block[2..]), None);
+
+        let mut reference = EmulationEngine::builder()
+            .backend(BackendKind::Reference)
+            .build()
+            .unwrap();
+        reference.load_program(prog.clone()).unwrap();
+        reference.main_loop().unwrap();
+
+        // The block holding the loop is compiled, but the guest leaves it
+        // before the loop: the loop is entered at its header
+        let mut vm = EmulationEngine::builder()
+            .backend(BackendKind::Interpreter)
+            .compile_threshold(100)
+            .verify(true)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        let profile = WarmupProfile {
+            blocks: vec![(0, 100)],
+            ..WarmupProfile::default()
+        };
+        assert_eq!(vm.warm_up(&profile), Ok(1));
+        assert_eq!(vm.run_for(2), Ok(Outcome::FuelExhausted));
+        let report = vm.main_loop().unwrap();
+        assert_eq!(vm.cpu, reference.cpu);
+        assert_eq!(report.osr_entries, 1);
+        assert_eq!(report.native, 50 * 7);
+
+        // Writing to the loop drops its entry
+        vm.write_memory(5, 2).unwrap();
+        assert!(vm.osr_entries.is_empty());
+    }
+
+    #[test]
+    pub fn counted_loops() {
+        init();
+        let body = [
+            Instruction::new(OpCode::ADDI, -7i8 as u8 as u16),
+            Instruction::new(OpCode::INC3A, 0),
+            Instruction::new(OpCode::DECA, 0),
+        ];
+        let counted = CountedLoop::analyze(&body, OverflowMode::Wrapping).unwrap();
+        assert_eq!((counted.step(), counted.last()), (-5, -1));
+        assert_eq!((counted.instructions(), counted.length()), (4, 5));
+        assert_eq!(CountedLoop::analyze(&body, OverflowMode::Saturating), None);
+        let with_mov = [body[0], Instruction::new(OpCode::MOV, 0x20)];
+        assert_eq!(
+            CountedLoop::analyze(&with_mov, OverflowMode::Wrapping),
+            None
+        );
+        let trips = [5, 1, 0, -3, i32::MIN].map(CountedLoop::trip_count);
+        assert_eq!(trips, [5, 1, 1, 1, 1 << 31]);
+
+        // The interpreter is the oracle, flags and overflows included
+        let cases = [
+            ("INC3A; INC3A; INC3A; INC3A; INC3A; INC3A", 0, 1000),
+            ("ADDI -7; INC3A; DECA; DECA; DECA", 100, 77),
+            ("INC3A; INC3A; INC3A; INC3A; INC3A; INC3A", i32::MAX - 53, 3),
+            ("DECA; DECA; DECA; DECA; DECA; DECA", 30, 5),
+            ("ADDI 100; ADDI -1; ADDI 2", 4, -3),
+        ];
+        for (body, acc, lc) in cases {
+            let prog = asm::assemble(&format!("loop: {}; BACK7 loop; HALT", body)).unwrap();
+            let run = |backend, fuel| {
+                let mut vm = EmulationEngine::builder()
+                    .backend(backend)
+                    .compile_threshold(1)
+                    .build()
+                    .unwrap();
+                vm.load_program(prog.clone()).unwrap();
+                vm.set_register(0, acc).unwrap();
+                vm.set_register(1, lc).unwrap();
+                let mut states = Vec::new();
+                while vm.run_for(fuel) == Ok(Outcome::FuelExhausted) {
+                    states.push(vm.cpu);
+                }
+                states.push(vm.cpu);
+                states
+            };
+            for fuel in [u64::MAX, 50] {
+                let reference = run(BackendKind::Reference, fuel);
+                assert_eq!(run(BackendKind::Interpreter, fuel), reference, "{}", body);
+            }
+        }
+
+        // INC3A xN; SETL; [body]; BACK7 compiled at startup runs in a
+        // single dispatch
+        let prog = asm::assemble(
+            "
+            INC3A; INC3A; INC3A; SETL
+            loop: ADDI 3; ADDI -5; INC3A; INC3A; BACK7 loop
+            HALT
+            ",
+        )
+        .unwrap();
+        let mut reference = EmulationEngine::builder()
+            .backend(BackendKind::Reference)
+            .build()
+            .unwrap();
+        reference.load_program(prog.clone()).unwrap();
+        reference.main_loop().unwrap();
+
+        let mut vm = EmulationEngine::builder()
+            .backend(BackendKind::Interpreter)
+            .compile_threshold(1)
+            .verify(true)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        let profile = WarmupProfile {
+            blocks: vec![(0, 1)],
+            ..WarmupProfile::default()
+        };
+        assert_eq!(vm.warm_up(&profile), Ok(1));
+        let report = vm.main_loop().unwrap();
+        assert_eq!(vm.cpu, reference.cpu);
+        assert_eq!((report.native, report.interpreted), (4 + 9 * 5, 1));
+        assert_eq!(report.cache_hits, 1);
+    }
+
+    #[test]
+    pub fn aligned_blocks() {
+        init();
+        // The loop body is entered through a computed RET, which the static
+        // leaders miss: the block at 0 is split once the guest enters it at 2
+        let prog = asm::assemble(
+            "
+                CLRA; INC3A
+            mid: INC3A; INC3A; ADDI -9; BEQZ jump
+                HALT
+            jump: LI 2; PUSH; CLRA; RET
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            analysis::leaders(&prog.data, [0]),
+            BTreeSet::from([0, 9, 10])
+        );
+
+        let mut reference = EmulationEngine::builder()
+            .backend(BackendKind::Reference)
+            .build()
+            .unwrap();
+        reference.load_program(prog.clone()).unwrap();
+        reference.main_loop().unwrap();
+
+        let mut vm = EmulationEngine::builder()
+            .backend(BackendKind::Interpreter)
+            .align_blocks(true)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(vm.cpu, reference.cpu);
+        let blocks: Vec<(usize, usize)> = vm
+            .cached_blocks()
+            .iter()
+            .map(|block| (block.pc, block.instructions))
+            .collect();
+        assert_eq!(blocks, [(0, 2), (2, 4), (9, 1), (10, 2), (14, 2)]);
+        assert_eq!(vm.cache_stats().splits, 1);
+
+        // The loop gets a block of its own, compiled without an OSR entry
+        let prog = asm::assemble(
+            "
+            LI 50
+            SETL
+            loop: INC3A; INC3A; INC3A; INC3A; INC3A; INC3A; BACK7 loop
+            HALT
+            ",
+        )
+        .unwrap();
+        let mut reference = EmulationEngine::builder()
+            .backend(BackendKind::Reference)
+            .build()
+            .unwrap();
+        reference.load_program(prog.clone()).unwrap();
+        reference.main_loop().unwrap();
+
+        let mut vm = EmulationEngine::builder()
+            .backend(BackendKind::Interpreter)
+            .compile_threshold(1)
+            .align_blocks(true)
+            .verify(true)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        let report = vm.main_loop().unwrap();
+        assert_eq!(vm.cpu, reference.cpu);
+        assert_eq!(report.osr_entries, 0);
+        let pcs: Vec<usize> = vm.cached_blocks().iter().map(|block| block.pc).collect();
+        assert_eq!(pcs, [0, 4, 11]);
+    }
+
+    #[test]
+    pub fn static_cfg() {
+        let prog = asm::assemble(
+            "
+                LI 10
+                SETL
+            loop: INC3A; INC3A; INC3A; INC3A; INC3A; INC3A; BACK7 loop
+                CALL f
+                HALT
+            f:  DECA
+                RET
+            ",
+        )
+        .unwrap();
+        let cfg = Cfg::build(&prog);
+        let blocks: Vec<_> = cfg
+            .blocks
+            .values()
+            .map(|block| {
+                (
+                    block.start,
+                    block.instructions.len(),
+                    block.successors.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            blocks,
+            [
+                (0, 2, vec![(4, EdgeKind::Fallthrough)]),
+                (
+                    4,
+                    7,
+                    vec![(4, EdgeKind::Branch), (11, EdgeKind::Fallthrough)]
+                ),
+                (
+                    11,
+                    1,
+                    vec![(15, EdgeKind::Call), (14, EdgeKind::Fallthrough)]
+                ),
+                (14, 1, vec![]),
+                (15, 2, vec![]),
+            ]
+        );
+        assert_eq!(cfg.block(4).unwrap().span(), 4..11);
+        assert_eq!(cfg.predecessors(4), [0, 4]);
+
+        let dot = cfg.to_dot();
+        assert!(dot.starts_with("digraph cfg {\n"));
+        assert!(dot.contains(
+            "    \"0x0000\" [label=\"0x0000: LI 10\\l0x0003: SETL\\l\", peripheries=2];\n"
+        ));
+        assert!(dot.contains("    \"0x0004\" -> \"0x0004\" [label=\"taken\"];\n"));
+        assert!(dot.contains("    \"0x000b\" -> \"0x000e\";\n"));
+
+        // The engine ends its blocks after the instructions run by the host
+        let prog = asm::assemble("LI 1; STA 0x40; INC3A; HALT").unwrap();
+        let cfg = Cfg::build(&prog);
+        let blocks: Vec<_> = cfg
+            .engine_blocks()
+            .into_iter()
+            .map(|(pc, block)| (pc, block.len()))
+            .collect();
+        assert_eq!(blocks, [(0, 2), (6, 2)]);
+
+        // Generated scenarios are made of blocks going to each other
+        let prog = generate_scenario(2_000, 7, [1, 9, 1, 5, 5]);
+        let cfg = Cfg::build(&prog);
+        for block in cfg.blocks.values() {
+            assert!(block.span().end <= prog.data.len());
+            for (next, _) in &block.successors {
+                assert!(cfg.block(*next).is_some(), "{:#06x} has no block", next);
+            }
+        }
+    }
+
+    #[test]
+    pub fn ahead_of_time_compilation() {
+        init();
+        let scenario = generate_scenario(2_000, 5, [1, 9, 1, 5, 5]);
+        let call = asm::assemble(
+            "
+                LI 20
+                SETL
+            loop: CALL f; ADDI -1; INC3A; BACK7 loop
+                HALT
+            f:  PUSH; POP; RET
+            ",
+        )
+        .unwrap();
+        for prog in [scenario, call] {
+            let mut reference = EmulationEngine::builder()
+                .backend(BackendKind::Reference)
+                .build()
+                .unwrap();
+            reference.load_program(prog.clone()).unwrap();
+            reference.main_loop().unwrap();
+
+            // Nothing gets hot, the whole program is compiled when loaded
+            let mut vm = EmulationEngine::builder()
+                .backend(BackendKind::Interpreter)
+                .compile_threshold(u64::MAX)
+                .cache_size(2)
+                .ahead_of_time(true)
+                .build()
+                .unwrap();
+            vm.load_program(prog.clone()).unwrap();
+            let cfg = Cfg::build(&prog);
+            assert!(vm.compile_stats().len() >= cfg.blocks.len());
+            assert!(vm
+                .cached_blocks()
+                .iter()
+                .all(|block| block.tier == Tier::Native));
+
+            let report = vm.main_loop().unwrap();
+            assert_eq!(vm.cpu, reference.cpu);
+            assert_eq!(report.interpreted, 0);
+            assert_eq!(report.native, reference.cpu.instret);
+        }
+    }
+
+    #[test]
+    pub fn cache_policies() {
+        init();
+        let prog = generator::generate(2_000, 3, [1, 9, 1, 5, 5]);
+        let policies = [
+            CachePolicy::Lru,
+            CachePolicy::Lfu,
+            CachePolicy::Adaptive,
+            CachePolicy::TwoQueue,
+        ];
+        for policy in policies {
+            let mut vm = EmulationEngine::builder()
+                .backend(BackendKind::Interpreter)
+                .cache_policy(policy)
+                .cache_size(4)
+                .build()
+                .unwrap();
+            vm.load_program(prog.program.clone()).unwrap();
+            let report = vm.main_loop().unwrap();
+            assert_eq!(vm.cpu.acc, prog.expected_acc);
+            assert_eq!(vm.cpu.instret, prog.expected_instret);
+
+            let stats = vm.cache_stats();
+            assert_eq!(stats.hits, report.cache_hits);
+            assert_eq!(stats.misses, report.cache_misses);
+            assert!(stats.evictions > 0, "{:?} evicted nothing", policy);
+            assert!(vm.cached_blocks().len() <= 4);
+            assert_eq!(vm.cache_policy(), (policy, 4));
+        }
+
+        // Shrinking the cache evicts what no longer fits
+        let mut vm = EmulationEngine::builder()
+            .backend(BackendKind::Interpreter)
+            .cache_size(64)
+            .build()
+            .unwrap();
+        vm.load_program(prog.program).unwrap();
+        vm.main_loop().unwrap();
+        let (cached, evictions) = (vm.cached_blocks().len(), vm.cache_stats().evictions);
+        assert!(cached > 2);
+        vm.set_cache_policy(CachePolicy::Lfu, 2).unwrap();
+        assert_eq!(vm.cached_blocks().len(), 2);
+        assert_eq!(vm.cache_stats().evictions, evictions + cached as u64 - 2);
+        assert_eq!(vm.cache_policy(), (CachePolicy::Lfu, 2));
+        assert!(matches!(
+            vm.set_cache_policy(CachePolicy::Lru, 0),
+            Err(VmError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    pub fn pinned_blocks() {
+        init();
+        // Three blocks running in turn thrash a LRU cache of two
+        let prog = asm::assemble(
+            "
+                LI 30
+            a:  ADDI -1; JMP b
+            b:  INC3A; DECA; DECA; DECA; JMP c
+            c:  BNEZ a
+                HALT
+            ",
+        )
+        .unwrap();
+        let engine = || {
+            let mut vm = EmulationEngine::builder()
+                .backend(BackendKind::Interpreter)
+                .cache_policy(CachePolicy::Lru)
+                .cache_size(2)
+                .build()
+                .unwrap();
+            vm.load_program(prog.clone()).unwrap();
+            vm
+        };
+
+        let mut vm = engine();
+        vm.main_loop().unwrap();
+        assert_eq!(vm.cache_stats().hits, 0);
+
+        let mut vm = engine();
+        vm.pin_block(3);
+        vm.main_loop().unwrap();
+        assert_eq!(vm.cpu.acc, 0);
+        assert_eq!(vm.cache_stats().hits, 28 + 29 + 29);
+        assert_eq!(vm.compile_stats().iter().filter(|s| s.pc == 3).count(), 1);
+        assert_eq!(vm.pinned_blocks(), vec![3]);
+        assert_eq!(vm.cached_blocks().len(), 3);
+
+        // Overwriting the code drops the block, the pin stays
+        vm.write_memory(3, OpCode::ADDI as u8).unwrap();
+        assert_eq!(vm.cached_blocks().len(), 2);
+        assert_eq!(vm.pinned_blocks(), vec![3]);
+        assert!(vm.unpin_block(3));
+        assert!(!vm.unpin_block(3));
+        assert!(vm.pinned_blocks().is_empty());
+
+        // Every compilation is slow enough
+        let mut vm = EmulationEngine::builder()
+            .backend(BackendKind::Interpreter)
+            .cache_size(3)
+            .auto_pin(Duration::ZERO)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(vm.pinned_blocks(), vec![3, 8, 15]);
+    }
+
+    #[test]
+    pub fn introspection() {
+        init();
+        let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 0], 0, 0);
+        let mut vm = EmulationEngine::builder()
+            .compile_threshold(2)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        vm.set_register(1, 5).unwrap();
+        assert_eq!(
+            vm.set_register(REGISTER_COUNT, 5),
+            Err(VmError::InvalidRegister { pc: 0, register: 8 })
+        );
+
+        vm.main_loop().unwrap();
+        assert_eq!(vm.cpu().acc, 90);
+        assert_eq!(
+            vm.cached_blocks(),
+            vec![
+                BlockInfo {
+                    pc: 0,
+                    instructions: 7,
+                    executions: 2,
+                    tier: Tier::Native
+                },
+                BlockInfo {
+                    pc: 7,
+                    instructions: 1,
+                    executions: 0,
+                    tier: Tier::Interpreter
+                },
+            ]
+        );
+
+        // Overwriting the loop drops its block
+        vm.write_memory_range(2, &[3, 3]).unwrap();
+        assert_eq!(vm.read_memory_range(0..4), Ok(vec![2, 2, 3, 3]));
+        assert_eq!(vm.cached_blocks().len(), 1);
+        assert_eq!(
+            vm.read_memory_range(7..MEMORY_SIZE + 1),
+            Err(VmError::MemoryOutOfBounds {
+                address: MEMORY_SIZE
+            })
+        );
+    }
+
+    #[test]
+    pub fn snapshot_and_restore() {
+        init();
+        let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 7, 0x40, 0, 0], 0, 10);
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+        assert_eq!(vm.run_for(20), Ok(Outcome::FuelExhausted));
+
+        let bytes = vm.snapshot().unwrap().to_bytes();
+        let snapshot = Snapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(snapshot.cpu, vm.cpu);
+        vm.main_loop().unwrap();
+
+        // A fresh engine picks up where the snapshot was taken
+        let mut restored = EmulationEngine::default();
+        restored.restore(&snapshot).unwrap();
+        assert!(restored.cached_blocks().is_empty());
+        restored.main_loop().unwrap();
+        assert_eq!(restored.cpu, vm.cpu);
+        assert_eq!(restored.read_memory(0x40), Ok(180));
+
+        assert!(matches!(
+            Snapshot::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(VmError::InvalidSnapshot(_))
+        ));
+        let mut small = EmulationEngine::builder()
+            .memory_size(MEMORY_SIZE / 2)
+            .stack_size(0)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            small.restore(&snapshot),
+            Err(VmError::MemoryLimitExceeded { .. })
+        ));
+    }
+
+    #[test]
+    pub fn record_and_replay() {
+        init();
+        let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 7, 0x40, 0, 0], 0, 10);
+        let mut interpreter = EmulationEngine::builder()
+            .compile_threshold(u64::MAX)
+            .build()
+            .unwrap();
+        interpreter.load_program(prog.clone()).unwrap();
+        interpreter.start_recording();
+        interpreter.main_loop().unwrap();
+        let log = interpreter.stop_recording().unwrap();
+        assert_eq!(log.blocks.len(), 12);
+        assert_eq!(log.to_string().parse::<ExecutionLog>(), Ok(log.clone()));
+
+        // The compiled loop goes through the states recorded by the interpreter
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog.clone()).unwrap();
+        assert_eq!(vm.replay(log.clone()), Ok(Outcome::Halted));
+
+        let mut diverging = log.clone();
+        diverging.blocks[5].hash ^= 1;
+        let mut vm = EmulationEngine::builder()
+            .compile_threshold(u64::MAX)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        assert_eq!(
+            vm.replay(diverging),
+            Err(VmError::ReplayDiverged { pc: 0, instret: 42 })
+        );
+        assert!(matches!(
+            "0x0000 7".parse::<ExecutionLog>(),
+            Err(VmError::InvalidLog(_))
+        ));
+    }
+
+    #[test]
+    pub fn verify_mode() {
+        init();
+        let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 7, 0x40, 0, 13, 0], 0, 10);
+        let mut vm = EmulationEngine::builder()
+            .compile_threshold(2)
+            .verify(true)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+
+        // Native code agrees with the interpreter
+        assert_eq!(vm.run(), Ok(Outcome::Halted));
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                sp: 4,
+                instret: 73,
+                cycles: 73,
+                ..Cpu::new(180, 0, 12, true)
+            }
+        );
+        assert_eq!(vm.read_memory(0x40), Ok(180));
+    }
+
+    #[test]
+    pub fn reference_interpreter() {
+        init();
+        let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 7, 0x40, 0, 0], 0, 10);
+        let mut vm = EmulationEngine::builder()
+            .backend(BackendKind::Reference)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+
+        let report = vm.main_loop().unwrap();
+        assert_eq!(report.interpreted, 72);
+        assert_eq!((report.cache_hits, report.cache_misses), (0, 0));
+        assert!(vm.cached_blocks().is_empty());
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 72,
+                cycles: 72,
+                ..Cpu::new(180, 0, 11, true)
+            }
+        );
+    }
+
+    #[test]
+    pub fn back7_branch_profile() {
+        init();
+        let mut vm = EmulationEngine::builder()
+            .backend(BackendKind::Reference)
+            .build()
+            .unwrap();
+        let code = vec![2, 2, 2, 2, 2, 2, 5, 7, 0x40, 0, 0];
+        vm.load_program(Program::new(code, 0, 10)).unwrap();
+        vm.main_loop().unwrap();
+
+        let back7 = vm.branch_profile().get(6).unwrap();
+        assert_eq!((back7.taken, back7.not_taken), (9, 1));
+        assert_eq!(back7.taken_probability(), Some(0.9));
+        assert_eq!(vm.branch_profile().branches(), vec![(6, back7)]);
+
+        let mut bytecode = vec![Instruction::new(OpCode::INC3A, 0); 6];
+        bytecode.push(Instruction::new(OpCode::BACK7, 0));
+        assert_eq!(CachedBlock::new(0, bytecode).back7_pc(), Some(6));
+        let halt = vec![Instruction::new(OpCode::HALT, 0)];
+        assert_eq!(CachedBlock::new(0, halt).back7_pc(), None);
+    }
+
+    #[cfg(feature = "fuzz")]
+    #[test]
+    pub fn differential_fuzzing() {
+        init();
+        let config = EngineConfig {
+            compile_threshold: 2,
+            ..EngineConfig::default()
+        };
+        for seed in 0..200 {
+            let prog = fuzz::random_program(seed, 64);
+            if let Err(divergence) = fuzz::check_program(&prog, &config, 10_000) {
+                panic!("seed {}: {}", seed, divergence);
+            }
+        }
+        fuzz::fuzz_one(&[3, 2, 2, 2, 2, 2, 2, 2, 5, 0]);
+    }
+
+    #[test]
+    pub fn assembler() {
+        init();
+        let prog = asm::assemble(
+            "
+            .lc 2
+            SETL
+            INC3A
+            loop: INC3A; INC3A; INC3A
+                INC3A; INC3A; INC3A # BACK7 branches 6 bytes back
+            BACK7 loop
+            BACK7
+            HALT
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            prog,
+            Program::new(vec![4, 2, 2, 2, 2, 2, 2, 2, 5, 5, 0], 0, 2)
+        );
+
+        let prog = asm::assemble(
+            "
+                LI 5
+            dec: ADDI -1
+                BNEZ dec
+                JMP skip
+                HALT
+            skip: BEQZ end
+                INC3A
+            end: HALT
+                MOV R2, A; ADD L, r7; HCALL 0xff; LDA 0x1234; .byte 1, -1
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            prog.data,
+            vec![
+                9, 5, 0, 8, 0xff, 12, 3, 0, 10, 12, 0, 0, 11, 16, 0, 2, 0, 17, 0x20, 18, 0x17, 20,
+                0xff, 6, 0x34, 0x12, 1, 0xff,
+            ]
+        );
+
+        let line = |source: &str| match asm::assemble(source) {
+            Err(VmError::InvalidAssembly { line, .. }) => line,
+            result => panic!("{:?} assembled to {:?}", source, result),
+        };
+        assert_eq!(line("CLRA\nFOO"), 2);
+        assert_eq!(line("JMP nowhere"), 1);
+        assert_eq!(line("loop: CLRA\nBACK7 loop"), 2);
+        assert_eq!(line("ADDI 256"), 1);
+        assert_eq!(line("MOV R8, A"), 1);
+        assert_eq!(line("HALT 1"), 1);
+        assert_eq!(line("a: CLRA; a: HALT"), 1);
+    }
+
+    #[test]
+    pub fn inline_assembly() {
+        init();
+        let prog = crate::vt_asm! {
+            .lc 2;
+            SETL; INC3A;
+            // BACK7 branches 6 bytes back
+            body: INC3A; INC3A; INC3A; INC3A; INC3A; INC3A;
+            BACK7 body;
+            LI -0x10; ADDI -1; MOV R3, A;
+            BMI end;
+            HALT;
+            end: HALT
+        };
+        assert_eq!(
+            prog,
+            Program::new(
+                vec![4, 2, 2, 2, 2, 2, 2, 2, 5, 9, 0xf0, 0xff, 8, 0xff, 17, 0x30, 23, 20, 0, 0, 0],
+                0,
+                2
+            )
+        );
+        assert_eq!(asm::assemble(". acc - 3").unwrap().initial_acc, -3);
+    }
+
+    #[test]
+    pub fn program_files() {
+        init();
+        let prog = asm::assemble(
+            "
+            .acc 3; .lc -2; .entry start
+            data: .byte 0xff, 0xff
+            start: INC3A; HALT
+            ",
+        )
+        .unwrap();
+        assert_eq!(prog.entry_point, 2);
+
+        let path = std::env::temp_dir().join(format!("vt-vm-dyn-{}.vt", std::process::id()));
+        prog.save(&path).unwrap();
+        let loaded = Program::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, Ok(prog.clone()));
+
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog.clone()).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!((vm.cpu.acc, vm.cpu.lc, vm.cpu.pc), (6, -2, 4));
+
+        let mut bytes = prog.to_bytes();
+        assert!(matches!(
+            Program::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(VmError::InvalidProgram(_))
+        ));
+        bytes[30] ^= 1;
+        assert_eq!(
+            Program::from_bytes(&bytes),
+            Err(VmError::InvalidProgram("checksum mismatch".to_string()))
+        );
+        assert!(matches!(
+            Program::from_file("/nonexistent/program.vt"),
+            Err(VmError::Io(_))
+        ));
+    }
+
+    #[test]
+    pub fn disassembler() {
+        init();
+        let source = "LI -2; ADDI 1; MOV R2, A; BNEZ 0x0003; HCALL 7; .byte 0xff; HALT";
+        let prog = asm::assemble(source).unwrap();
+        let entries: Vec<String> = disasm::entries(&prog.data, 0)
+            .into_iter()
+            .map(|(_, entry)| entry.to_string())
+            .collect();
+        assert_eq!(entries.join("; "), source);
+        assert_eq!(
+            disasm::disassemble(&[2, 10, 0x34, 0x12, 17, 0x90, 12], 0x10),
+            "0x0010: 02        INC3A\n\
+             0x0011: 0a 34 12  JMP 0x1234\n\
+             0x0014: 11        .byte 0x11\n\
+             0x0015: 90        .byte 0x90\n\
+             0x0016: 0c        .byte 0x0c\n"
+        );
+
+        let mut vm = EmulationEngine::builder()
+            .compile_threshold(1000)
+            .build()
+            .unwrap();
+        vm.load_program(asm::assemble("LI 3; loop: ADDI -1; BNEZ loop; HALT").unwrap())
+            .unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(
+            vm.disassemble(0..10, true).unwrap(),
+            "0x0000: 09 03 00  LI 3          # 1x\n\
+             0x0003: 08 ff     ADDI -1       # 3x\n\
+             0x0005: 0c 03 00  BNEZ 0x0003   # 3x\n\
+             0x0008: 00        HALT          # 1x\n\
+             0x0009: 00        HALT\n"
+        );
+    }
+
+    #[test]
+    pub fn load_and_store() {
+        init();
+        // The loop stores the accumulator at 0x40, which is loaded back at the end
+        let prog = Program::new(vec![2, 2, 2, 7, 0x40, 0, 5, 6, 0x40, 0, 0], 0, 3);
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 17,
+                cycles: 17,
+                ..Cpu::new(27, 0, 11, true)
+            }
+        );
+        assert_eq!(vm.read_memory(0x40), Ok(27));
+    }
+
+    #[test]
+    pub fn memory_faults_are_trapped() {
+        init();
+        let prog = Program::new(vec![2, 7, 0xff, 0xff, 0], 0, 0);
+        let mut vm = EmulationEngine::builder()
+            .memory_size(16)
+            .stack_size(0)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        assert_eq!(
+            vm.run(),
+            Ok(Outcome::Trapped(VmError::MemoryOutOfBounds {
+                address: 0xffff
+            }))
+        );
+        assert_eq!(
+            vm.cpu.trap,
+            Some(Trap {
+                cause: TrapCause::MemoryOutOfBounds,
+                pc: 1
+            })
+        );
+        assert_eq!(vm.cpu.pc, 1);
+    }
+
+    #[test]
+    pub fn custom_memory_backend() {
+        init();
+        let reads = Arc::new(Mutex::new(0));
+        let memory = CountingMemory {
+            inner: Memory::new(16),
+            reads: reads.clone(),
+        };
+        let prog = Program::new(vec![2, 2, 2, 0], 0, 0);
+        let mut vm = EmulationEngine::builder()
+            .memory(Box::new(memory))
+            .stack_size(0)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 4,
+                cycles: 4,
+                ..Cpu::new(9, 0, 4, true)
+            }
+        );
+        assert_eq!(vm.memory_size(), 16);
+        assert_eq!(*reads.lock().unwrap(), 4);
+        assert_eq!(
+            vm.grow_memory(1),
+            Err(VmError::MemoryLimitExceeded {
+                size: 17,
+                limit: 16
+            })
+        );
+    }
+
+    #[test]
+    pub fn cached_blocks_not_decoded_again() {
+        init();
+        let reads = Arc::new(Mutex::new(0));
+        let memory = CountingMemory {
+            inner: Memory::new(16),
+            reads: reads.clone(),
+        };
+        let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 0], 0, 5);
+        let mut vm = EmulationEngine::builder()
+            .memory(Box::new(memory))
+            .stack_size(0)
+            .backend(BackendKind::Interpreter)
+            .compile_threshold(u64::MAX)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        let report = vm.main_loop().unwrap();
+
+        // Only the first iteration of the loop and HALT are fetched
+        assert_eq!(report.interpreted, 5 * 7 + 1);
+        assert_eq!(report.cache_hits, 4);
+        assert_eq!(*reads.lock().unwrap(), 8);
+    }
+
+    #[test]
+    pub fn decoded_instructions_cached() {
+        init();
+        let reads = Arc::new(Mutex::new(0));
+        let memory = CountingMemory {
+            inner: Memory::new(16),
+            reads: reads.clone(),
+        };
+        // A loop of INC3A and ADDI 1
+        let prog = Program::new(vec![2, 2, 8, 1, 2, 2, 5, 0], 0, 5);
+        let mut vm = EmulationEngine::builder()
+            .memory(Box::new(memory))
+            .stack_size(0)
+            .backend(BackendKind::Reference)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+
+        // Without a code cache, instructions are still decoded once
+        assert_eq!(vm.run_for(12), Ok(Outcome::FuelExhausted));
+        assert_eq!(vm.cpu().acc, 2 * 13);
+        assert_eq!(*reads.lock().unwrap(), 7);
+
+        // Overwriting the operand of ADDI decodes it again
+        vm.write_memory(3, 2).unwrap();
+        assert_eq!(vm.run(), Ok(Outcome::Halted));
+        assert_eq!(vm.cpu().acc, 2 * 13 + 3 * 14);
+        assert_eq!(*reads.lock().unwrap(), 7 + 2 + 1);
+    }
+
+    #[test]
+    pub fn builder_interpret_only() {
+        init();
+        let prog = Program::new(vec![4, 2, 2, 2, 2, 2, 2, 2, 5, 5, 0], 0, 2);
+        let mut vm = EmulationEngine::builder()
+            .compile_threshold(u64::MAX)
+            .memory_size(16)
+            .stack_size(0)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 11,
+                cycles: 11,
+                ..Cpu::new(21, -2, 11, true)
+            }
+        );
+    }
+
+    #[test]
+    pub fn builder_rejects_invalid_config() {
+        assert!(matches!(
+            EmulationEngine::builder().cache_size(0).build(),
+            Err(VmError::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            EmulationEngine::builder().memory_size(0).build(),
+            Err(VmError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    pub fn step_single_instructions() {
+        init();
+        let prog = Program::new(vec![4, 2, 2, 2, 2, 2, 2, 5, 0], 0, 2);
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+
+        let (instr, cpu) = vm.step().unwrap();
+        assert_eq!(instr, OpCode::SETL.into());
+        assert_eq!(
+            cpu,
+            Cpu {
+                instret: 1,
+                cycles: 1,
+                ..Cpu::new(0, 0, 1, false)
+            }
+        );
+        let (instr, cpu) = vm.step().unwrap();
+        assert_eq!(instr, OpCode::INC3A.into());
+        assert_eq!(
+            cpu,
+            Cpu {
+                instret: 2,
+                cycles: 2,
+                ..Cpu::new(3, 0, 2, false)
+            }
+        );
+
+        while !vm.cpu.halt {
+            vm.step().unwrap();
+        }
+
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 9,
+                cycles: 9,
+                ..Cpu::new(18, -1, 9, true)
+            }
+        );
+        assert_eq!(vm.step(), Err(VmError::MachineHalted));
+    }
+
+    #[test]
+    pub fn run_for_resumes_after_fuel_exhausted() {
+        init();
+        let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 5, 0], 0, 2);
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+
+        assert_eq!(vm.run_for(3), Ok(Outcome::FuelExhausted));
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 3,
+                cycles: 3,
+                ..Cpu::new(9, 2, 3, false)
+            }
+        );
+
+        assert_eq!(vm.run_for(1_000), Ok(Outcome::Halted));
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 16,
+                cycles: 16,
+                ..Cpu::new(36, -1, 9, true)
+            }
+        );
+    }
+
+    #[test]
+    pub fn run_for_reports_traps() {
+        init();
+        let prog = Program::new(vec![2, 0xff, 0], 0, 0);
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+        assert_eq!(
+            vm.run_for(10),
+            Ok(Outcome::Trapped(VmError::UnknownOpCode {
+                pc: 1,
+                byte: 0xff
+            }))
+        );
+    }
+
+    #[test]
+    pub fn breakpoints_stop_and_resume() {
+        init();
+        let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 5, 0], 0, 2);
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
+        vm.set_breakpoint(3);
+
+        assert_eq!(vm.run(), Ok(Outcome::Breakpoint(3)));
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 3,
+                cycles: 3,
+                ..Cpu::new(9, 2, 3, false)
+            }
+        );
+
+        assert_eq!(vm.run(), Ok(Outcome::Breakpoint(3)));
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 10,
+                cycles: 10,
+                ..Cpu::new(27, 1, 3, false)
+            }
+        );
+
+        vm.clear_breakpoint(3);
+        assert_eq!(vm.run(), Ok(Outcome::Halted));
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 16,
+                cycles: 16,
+                ..Cpu::new(36, -1, 9, true)
+            }
+        );
+    }
+
+    #[derive(Default)]
+    struct EventCounts {
+        instructions: usize,
+        blocks: usize,
+        halts: usize,
+        side_exits: Vec<SideExit>,
+    }
+
+    struct CountingObserver(Arc<Mutex<EventCounts>>);
+
+    impl ExecutionObserver for CountingObserver {
+        fn on_block_executed(&mut self, _pc: usize, _tier: Tier, _cpu: &Cpu) {
+            self.0.lock().unwrap().blocks += 1;
+        }
+
+        fn on_instruction(&mut self, _pc: usize, _instr: Instruction, _cpu: &Cpu) {
+            self.0.lock().unwrap().instructions += 1;
+        }
+
+        fn on_side_exit(&mut self, exit: SideExit, _cpu: &Cpu) {
+            self.0.lock().unwrap().side_exits.push(exit);
+        }
+
+        fn on_halt(&mut self, _cpu: &Cpu) {
+            self.0.lock().unwrap().halts += 1;
+        }
+    }
+
+    #[test]
+    pub fn watchpoints_stop_the_guest() {
+        init();
+        // Adds 3 to R2 and stores it at 0x80, 5 times
+        let prog = asm::assemble(
+            "
+                LI 5
+                SETL
+            loop: MOV A, R2
+                INC3A
+                MOV R2, A
+                STA 0x80
+                MOV A, L
+                DECA
+                SETL
+                BNEZ loop
+                HALT
+            ",
+        )
+        .unwrap();
+        let builders = [
+            EmulationEngine::builder().backend(BackendKind::Reference),
+            EmulationEngine::builder().compile_threshold(1),
+            EmulationEngine::builder().baseline_threshold(1),
+        ];
+        for builder in builders {
+            let mut vm = builder.build().unwrap();
+            vm.load_program(prog.clone()).unwrap();
+
+            // Register watchpoints fire as their comparison starts holding
+            let id = vm.add_watchpoint("r2 > 7".parse().unwrap());
+            let Ok(Outcome::Watchpoint(hit)) = vm.run() else {
+                panic!("the watchpoint did not fire");
+            };
+            assert_eq!(hit, WatchpointHit { id, pc: 0x07 });
+            assert_eq!((vm.cpu.pc, vm.cpu.gpr[0]), (0x09, 9));
+            vm.remove_watchpoint(id);
+
+            // Write watchpoints fire on every store, 3 are left
+            let id = vm.add_watchpoint("write 0x80".parse().unwrap());
+            for _ in 0..3 {
+                let hit = WatchpointHit { id, pc: 0x09 };
+                assert_eq!(vm.run(), Ok(Outcome::Watchpoint(hit)));
+            }
+            assert_eq!(vm.run(), Ok(Outcome::Halted));
+            assert_eq!(vm.cpu.gpr[0], 15);
+        }
+
+        // Blocks that cannot fire the watchpoints still run compiled
+        let mut vm = EmulationEngine::builder()
+            .compile_threshold(1)
+            .build()
+            .unwrap();
+        vm.load_program(prog.clone()).unwrap();
+        vm.add_watchpoint("write 0x90..0xa0".parse().unwrap());
+        vm.add_watchpoint("r3 == 1".parse().unwrap());
+        let report = vm.main_loop().unwrap();
+        assert!(report.native > 0);
+        assert_eq!(vm.cpu.gpr[0], 15);
+
+        let watchpoint: Watchpoint = "acc >= -0x10".parse().unwrap();
+        assert_eq!(
+            watchpoint,
+            Watchpoint::Register {
+                index: 0,
+                comparison: Comparison::GreaterOrEqual,
+                value: -16
+            }
+        );
+        assert_eq!(watchpoint.to_string(), "acc >= -16");
+        let watchpoint: Watchpoint = "write 0x2000..0x2100".parse().unwrap();
+        assert_eq!(watchpoint, Watchpoint::Write(0x2000..0x2100));
+        assert_eq!(watchpoint.to_string().parse(), Ok(watchpoint));
+        for invalid in [
+            "acc >",
+            "r8 == 1",
+            "acc => 1",
+            "write 0x20..0x10",
+            "write -1",
+        ] {
+            assert!(invalid.parse::<Watchpoint>().is_err());
+        }
+
+        let mut monitor = Monitor::new(EmulationEngine::default());
+        assert_eq!(monitor.execute("watch lc < 0"), "watchpoint 0: lc < 0");
+        assert_eq!(monitor.execute("watch"), "0: lc < 0");
+        assert_eq!(monitor.execute("unwatch 0"), "watchpoint 0: lc < 0 removed");
+        assert_eq!(monitor.execute("unwatch 0"), "error: no watchpoint 0");
+    }
 
-#[derive(Default)]
-pub struct EmulationEngine {
-    pub(crate) cpu: Cpu,
-    memory: Memory,
-}
+    #[test]
+    pub fn conditional_breakpoints() {
+        init();
+        // Adds 3 to R2, 5 times
+        let prog = asm::assemble(
+            "
+                LI 5
+                SETL
+            loop: MOV A, R2
+                INC3A
+                MOV R2, A
+                MOV A, L
+                DECA
+                SETL
+                BNEZ loop
+                HALT
+            ",
+        )
+        .unwrap();
+        let builders = [
+            EmulationEngine::builder().backend(BackendKind::Reference),
+            EmulationEngine::builder().compile_threshold(1),
+        ];
+        for builder in builders {
+            let mut vm = builder.build().unwrap();
+            vm.load_program(prog.clone()).unwrap();
 
-impl EmulationEngine {
-    pub fn load_program(&mut self, program: Program) {
-        // Set the initial register values
-        self.cpu.acc = program.initial_acc;
-        self.cpu.lc = program.initial_lc;
+            // Only stops on the iterations the condition holds
+            vm.set_conditional_breakpoint(0x04, "lc == 4 || r2 > 100".parse().unwrap());
+            assert_eq!(vm.run(), Ok(Outcome::Breakpoint(0x04)));
+            assert_eq!((vm.cpu.lc, vm.cpu.gpr[0]), (4, 3));
+            vm.set_breakpoint_if(0x04, |cpu| cpu.lc == 2);
+            assert_eq!(vm.run(), Ok(Outcome::Breakpoint(0x04)));
+            assert_eq!((vm.cpu.lc, vm.cpu.gpr[0]), (2, 9));
+            assert_eq!(
+                vm.breakpoint_condition(0x04).unwrap().to_string(),
+                "<predicate>"
+            );
+
+            // Plain breakpoints drop the condition
+            vm.set_breakpoint(0x04);
+            assert!(vm.breakpoint_condition(0x04).is_none());
+            assert_eq!(vm.run(), Ok(Outcome::Breakpoint(0x04)));
+            assert_eq!((vm.cpu.lc, vm.cpu.gpr[0]), (1, 12));
+            vm.clear_breakpoint(0x04);
+            assert_eq!(vm.run(), Ok(Outcome::Halted));
+            assert_eq!(vm.cpu.gpr[0], 15);
+        }
+
+        let condition: condition::Condition = "!(acc == 0x10) && (lc < 0 || pc >= instret)"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            condition.to_string(),
+            "!(acc == 16) && (lc < 0 || pc >= instret)"
+        );
+        assert_eq!(condition.to_string().parse(), Ok(condition));
+        // && binds tighter than ||
+        let condition: condition::Condition = "acc == 1 || lc == 1 && r2 == 1".parse().unwrap();
+        let condition::Condition::Or(_, right) = &condition else {
+            panic!("&& did not bind tighter than ||");
+        };
+        assert!(matches!(**right, condition::Condition::And(..)));
+        for invalid in [
+            "",
+            "acc",
+            "acc == 1 &&",
+            "(acc == 1",
+            "r8 == 1",
+            "acc = 1",
+            "pc == 1)",
+        ] {
+            assert!(
+                invalid.parse::<condition::Condition>().is_err(),
+                "{:?} parsed",
+                invalid
+            );
+        }
 
-        // Load the program in memory
-        self.memory
-            .write_chunk(program.data)
-            .expect("Failed to write program into memory!");
+        let mut monitor = Monitor::new(EmulationEngine::default());
+        assert_eq!(
+            monitor.execute("break 0x30 if pc == 48&&lc<0"),
+            "breakpoint at 0x0030 if pc == 48 && lc < 0"
+        );
+        assert_eq!(monitor.execute("break 0x40"), "breakpoint at 0x0040");
+        assert_eq!(
+            monitor.execute("break"),
+            "0x0030 if pc == 48 && lc < 0\n0x0040"
+        );
+        assert_eq!(
+            monitor.execute("break 0x30 if lc"),
+            "error: Invalid condition: lc"
+        );
     }
 
-    fn debug_state(&self) {
-        let next_eights = (self.cpu.pc..self.cpu.pc + 8).fold(String::new(), |acc, address| {
-            acc + &format!("{:#04x} ", self.memory.read(address as usize))
-        });
-        debug!(
-            "State: PC: {:#04x}, ACC: {:#4}, LC: {:#4} | {}",
-            self.cpu.pc, self.cpu.acc, self.cpu.lc, next_eights
+    #[test]
+    pub fn monitor_commands() {
+        init();
+        let mut vm = EmulationEngine::default();
+        vm.load_program(asm::assemble("LI 2; loop: ADDI -1; BNEZ loop; STA 0x40; HALT").unwrap())
+            .unwrap();
+        let mut monitor = Monitor::new(vm);
+
+        assert_eq!(monitor.execute("step 2"), "0x0000: LI 2\n0x0003: ADDI -1");
+        assert_eq!(monitor.execute("b 0x03"), "breakpoint at 0x0003");
+        assert_eq!(monitor.execute("continue"), "Breakpoint(3)");
+        assert_eq!(monitor.execute("step"), "0x0003: ADDI -1");
+        assert_eq!(monitor.execute("delete 3"), "breakpoint at 0x0003 removed");
+        assert_eq!(monitor.execute("break"), "");
+        assert_eq!(monitor.execute("c"), "Halted");
+        assert!(monitor
+            .execute("regs")
+            .starts_with("pc: 0x000c  acc: 0  lc: 0"));
+        assert_eq!(monitor.execute("mem 0x3e 4"), "0x003e: 00 00 00 00");
+        assert_eq!(
+            monitor.execute("disasm 5 2"),
+            "0x0005: BNEZ 0x0003\n0x0008: STA 0x0040"
+        );
+        assert!(monitor
+            .execute("blocks")
+            .starts_with("0x0005: 1 instructions, 1 executions"));
+        assert!(monitor
+            .execute("cache")
+            .starts_with("Adaptive, 3 of 32 blocks\n"));
+        assert_eq!(monitor.execute("step"), "error: The machine is halted");
+        assert_eq!(
+            monitor.execute("jump 3"),
+            "error: unknown command `jump 3`, try `help`"
+        );
+
+        let mut output = Vec::new();
+        let mut monitor = Monitor::new(EmulationEngine::default());
+        monitor
+            .run("regs\nquit\nregs\n".as_bytes(), &mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.matches("(vtvm) ").count(), 2);
+        assert_eq!(output.matches("pc: ").count(), 1);
+    }
+
+    #[test]
+    pub fn observers_receive_events() {
+        init();
+        let counts = Arc::new(Mutex::new(EventCounts::default()));
+        let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 5, 0], 0, 2);
+        let mut vm = EmulationEngine::builder()
+            .compile_threshold(u64::MAX)
+            .build()
+            .unwrap();
+        vm.add_observer(Box::new(CountingObserver(counts.clone())));
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+
+        let counts = counts.lock().unwrap();
+        assert_eq!(counts.instructions, 16);
+        assert_eq!(counts.blocks, 4);
+        assert_eq!(counts.halts, 1);
+    }
+
+    #[test]
+    pub fn traces_are_exported() {
+        init();
+        // Runs the loop at 0x04 twice
+        let prog = asm::assemble(
+            "
+                LI 2
+                SETL
+            loop: INC3A
+                MOV R3, A
+                BNEZ next
+            next: MOV A, L
+                DECA
+                SETL
+                BNEZ loop
+                HALT
+            ",
+        )
+        .unwrap();
+        let export = |builder: EmulationEngineBuilder, granularity| {
+            let events = Arc::new(Mutex::new(Vec::new()));
+            let mut vm = builder.build().unwrap();
+            vm.add_observer(Box::new(TraceExporter::new(events.clone(), granularity)));
+            vm.load_program(prog.clone()).unwrap();
+            assert_eq!(vm.run(), Ok(Outcome::Halted));
+            let events = events.lock().unwrap().clone();
+            events
+        };
+
+        let events = export(
+            EmulationEngine::builder().backend(BackendKind::Reference),
+            TraceGranularity::Instructions,
+        );
+        assert_eq!(events.len(), 17);
+        let ops: Vec<Option<OpCode>> = events.iter().take(4).map(|e| e.opcode).collect();
+        let ops_expected = [OpCode::LI, OpCode::SETL, OpCode::INC3A, OpCode::MOV];
+        assert_eq!(ops, ops_expected.map(Some));
+        // Only the registers that changed are listed
+        assert_eq!(events[0].deltas, vec![(0, 2)]);
+        assert_eq!(events[1].deltas, vec![(1, 2)]);
+        assert_eq!(events[3].deltas, vec![(3, 5)]);
+        assert_eq!(events[4].deltas, vec![]);
+        assert!(events.iter().all(|e| e.tier == Tier::Interpreter));
+        assert_eq!(events.last().unwrap().instret, 17);
+
+        // The block at 0x0a is compiled once run
+        let blocks = export(
+            EmulationEngine::builder().compile_threshold(1),
+            TraceGranularity::Blocks,
         );
+        let pcs: Vec<usize> = blocks.iter().map(|e| e.pc).collect();
+        assert_eq!(pcs, vec![0x00, 0x0a, 0x04, 0x0a, 0x11]);
+        assert!(blocks.iter().all(|e| e.opcode.is_none()));
+        assert_eq!(blocks[2].deltas, vec![(0, 4), (3, 4)]);
+        assert_eq!(blocks[3].tier, Tier::Native);
+        assert_eq!(blocks[3].deltas, vec![(0, 0), (1, 0)]);
+
+        let event = TraceEvent {
+            pc: 0x0a,
+            opcode: Some(OpCode::MOV),
+            tier: Tier::Interpreter,
+            instret: 5,
+            deltas: vec![(0, -1), (3, 7)],
+        };
+        let mut json = JsonLines::new(Vec::new());
+        json.write_event(&event).unwrap();
+        assert_eq!(
+            String::from_utf8(json.into_inner()).unwrap(),
+            "{\"pc\": 10, \"opcode\": \"MOV\", \"tier\": \"Interpreter\", \"instret\": 5, \
+             \"deltas\": [[0, -1], [3, 7]]}\n"
+        );
+        let mut binary = BinaryTrace::new(Vec::new());
+        binary.write_event(&event).unwrap();
+        binary.write_event(&event).unwrap();
+        let bytes = binary.into_inner();
+        // The header, then 15 bytes and 5 per delta for each event
+        assert_eq!(bytes.len(), 5 + 2 * (15 + 2 * 5));
+        assert_eq!(&bytes[..9], b"VTTR\x01\x0a\x00\x00\x00");
     }
 
-    fn interpret(&mut self) -> Vec<OpCode> {
+    #[test]
+    pub fn traces_are_replayed() {
+        init();
+        // Runs the loop at 0x04 three times
+        let source = "
+                LI 3
+                SETL
+            loop: INC3A
+                MOV R3, A
+                MOV A, L
+                DECA
+                SETL
+                BNEZ loop
+                HALT
+            ";
+        let prog = asm::assemble(source).unwrap();
+        let export = |granularity| {
+            let events = Arc::new(Mutex::new(Vec::new()));
+            let mut vm = EmulationEngine::builder()
+                .backend(BackendKind::Reference)
+                .build()
+                .unwrap();
+            vm.add_observer(Box::new(TraceExporter::new(events.clone(), granularity)));
+            vm.load_program(prog.clone()).unwrap();
+            assert_eq!(vm.run(), Ok(Outcome::Halted));
+            // Read back as JSON Lines
+            let events = events.lock().unwrap();
+            let json: String = events.iter().map(|e| e.to_json() + "\n").collect();
+            read_trace(json.as_bytes()).unwrap()
+        };
+        let replay = |prog: Program, events: Vec<TraceEvent>| {
+            let mut vm = EmulationEngine::builder()
+                .compile_threshold(1)
+                .build()
+                .unwrap();
+            vm.load_program(prog).unwrap();
+            let mut replayer = TraceReplayer::new(events);
+            (replayer.replay(&mut vm), replayer.replayed())
+        };
+
+        // Compiled code runs the same instructions as the reference
+        let events = export(TraceGranularity::Instructions);
+        assert_eq!(events.len(), 21);
+        let (outcome, replayed) = replay(prog.clone(), events.clone());
+        assert_eq!(outcome, Ok(Outcome::Halted));
+        assert_eq!(replayed, 21);
+        let blocks = export(TraceGranularity::Blocks);
+        assert_eq!(replay(prog.clone(), blocks).0, Ok(Outcome::Halted));
 
-        let mut dynamic_block = Vec::new();
+        // Binary traces hold the same events
+        let mut binary = BinaryTrace::new(Vec::new());
+        for event in &events {
+            binary.write_event(event).unwrap();
+        }
+        assert_eq!(read_trace(&binary.into_inner()), Ok(events.clone()));
+        assert!(read_trace(b"VTTR\x01\x00").is_err());
+        assert!(read_trace(b"{\"pc\": 0}").is_err());
 
-        loop {
-            let instr = OpCode::try_from(self.memory.read(self.cpu.pc))
-                .expect("Unknown OpCode read from memory.");
+        // A program decrementing where the trace added 3 is caught at once
+        let changed = asm::assemble(&source.replace("INC3A", "DECA")).unwrap();
+        let (outcome, replayed) = replay(changed, events);
+        let mismatch = outcome.unwrap_err();
+        assert_eq!((mismatch.index, replayed), (2, 2));
+        assert_eq!(mismatch.entered, Some(0x04));
+        assert_eq!(mismatch.registers[0], 6);
+        assert_eq!(mismatch.cpu.acc, 2);
+    }
 
-            dynamic_block.push(instr);
+    #[test]
+    pub fn native_loop_respects_fuel() {
+        init();
+        let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 0], 0, 100);
+        let mut vm = EmulationEngine::default();
+        vm.load_program(prog).unwrap();
 
-            match instr {
-                OpCode::HALT => {
-                    self.cpu.halt = true;
-                    self.cpu.pc += 1;
-                    break dynamic_block;
-                }
-                OpCode::CLRA => {
-                    self.cpu.acc = 0;
-                    self.cpu.pc += 1;
-                }
-                OpCode::INC3A => {
-                    self.cpu.acc += 3;
-                    self.cpu.pc += 1;
-                }
-                OpCode::DECA => {
-                    self.cpu.acc -= 1;
-                    self.cpu.pc += 1;
-                }
-                OpCode::SETL => {
-                    self.cpu.lc = self.cpu.acc;
-                    self.cpu.pc += 1;
-                }
-                OpCode::BACK7 => {
-                    self.cpu.lc -= 1;
-                    if self.cpu.lc > 0 {
-                        self.cpu.pc -= 6;
-                    } else {
-                        self.cpu.pc += 1;
-                    }
-                    break dynamic_block;
-                }
+        // One interpreted iteration, six native ones and a single INC3A
+        assert_eq!(vm.run_for(50), Ok(Outcome::FuelExhausted));
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 50,
+                cycles: 50,
+                ..Cpu::new(129, 93, 1, false)
             }
+        );
+
+        assert_eq!(vm.run(), Ok(Outcome::Halted));
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 701,
+                cycles: 701,
+                ..Cpu::new(1800, 0, 8, true)
+            }
+        );
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    pub fn background_compilation() {
+        init();
+        let prog = generate_scenario(10_000, 1, [1, 9, 1, 5, 5]);
+        let mut vm = EmulationEngine::builder()
+            .background_compilation(true)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(vm.cpu, Cpu::new(95, -21, 10_000, true));
+        // Every block compiled went through the queue
+        assert!(vm
+            .compile_stats()
+            .iter()
+            .all(|stats| stats.queued.is_some()));
+        assert!(vm.compile_queue_depth() <= vm.cached_blocks().len());
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    pub fn compile_queue_priority() {
+        let mut queue = compiler::CompileQueue::default();
+        for pc in [0, 4, 8] {
+            queue.push(pc, vec![Instruction::new(OpCode::INC3A, 0)], None, 10);
         }
+        // The block interpreted the most goes first, the oldest on ties
+        queue.bump(8, 5);
+        let popped = std::iter::from_fn(|| queue.pop());
+        let order: Vec<usize> = popped.map(|(entry, _)| entry.pc).collect();
+        assert_eq!(order, vec![8, 0, 4]);
+        assert_eq!(queue.len(), 0);
     }
 
-    pub fn main_loop(&mut self) {
-        let llvm_context = Context::create();
-        let mut code_cache = CodeCache::new(CACHE_SIZE).unwrap();
+    #[cfg(feature = "jit")]
+    #[test]
+    pub fn adaptive_recompilation() {
+        init();
+        let prog = generate_scenario(10_000, 1, [1, 9, 1, 5, 5]);
+        let mut vm = EmulationEngine::builder()
+            .recompile_threshold(2)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        let report = vm.main_loop().unwrap();
+        assert_eq!(vm.cpu, Cpu::new(95, -21, 10_000, true));
+        // Recompiled blocks are swapped in whenever they are ready
+        assert!(report.blocks_recompiled <= report.blocks_compiled);
+    }
 
-        // As long the machine is not stopped
-        while !self.cpu.halt {
-            let pc = self.cpu.pc;
-            let tbb = code_cache.get_mut(&pc);
+    #[cfg(feature = "jit")]
+    #[test]
+    pub fn llvm_ir_dump() {
+        init();
+        let prog = generate_scenario(2_000, 1, [1, 9, 1, 5, 5]);
+        let mut vm = EmulationEngine::builder().build().unwrap();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
 
-            if let Some(tbb) = tbb {
-                tbb.executions += 1;
+        let dir = std::env::temp_dir().join(format!("vt-vm-dyn-{}-ir", std::process::id()));
+        let written = vm.dump_ir(&dir).unwrap();
+        let native: Vec<usize> = vm
+            .cached_blocks()
+            .into_iter()
+            .filter(|block| block.tier == Tier::Native)
+            .map(|block| block.pc)
+            .collect();
+        assert_eq!(written, native.len());
+        for pc in native {
+            let ir = std::fs::read_to_string(dir.join(format!("{:#06x}.ll", pc))).unwrap();
+            assert!(ir.contains("define i64 @"), "{:#06x} has no function", pc);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-                if tbb.executions >= MAX_EXECUTIONS && !tbb.has_compiled() {
-                    match tbb.compile_dynamic_basic_block() {
-                        Ok(_) => {
-                            debug!("translation block successfully compiled into native code!");
-                        }
-                        Err(e) => {
-                            warn!("wasn't capable to compile the translation block: {}", e);
-                        }
-                    }
-                }
+    #[cfg(feature = "jit")]
+    #[test]
+    pub fn symbol_table() {
+        init();
+        let prog = generate_scenario(2_000, 1, [1, 9, 1, 5, 5]);
+        let mut vm = EmulationEngine::builder()
+            .recompile_threshold(2)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
 
-                if tbb.has_compiled() {
-                    debug!("executing native code...");
-                    tbb.execute(&mut self.cpu);
-                } else {
-                    let _ = self.interpret();
-                }
+        // Recompiled blocks get a function of their own
+        let symbols = vm.symbol_table();
+        assert!(symbols.len() >= vm.compile_stats().len());
+        for (name, pc) in symbols {
+            assert_eq!(backend::symbol_pc(name), Some(*pc));
+        }
+        for stats in vm.compile_stats() {
+            assert!(symbols.contains_key(&backend::symbol(stats.pc, 0)));
+        }
+    }
 
-                self.debug_state();
+    #[cfg(all(feature = "jit", feature = "capstone"))]
+    #[test]
+    pub fn native_disassembly() {
+        init();
+        let prog = generate_scenario(2_000, 1, [1, 9, 1, 5, 5]);
+        let mut vm = EmulationEngine::builder().build().unwrap();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
 
-            } else {
+        let listings = vm.native_disassembly().unwrap();
+        let native: Vec<usize> = vm
+            .cached_blocks()
+            .into_iter()
+            .filter(|block| block.tier == Tier::Native)
+            .map(|block| block.pc)
+            .collect();
+        assert_eq!(listings.keys().copied().collect::<Vec<_>>(), native);
+        for (pc, listing) in &listings {
+            assert!(listing.lines().count() > 1, "{:#06x} has no code", pc);
+        }
+    }
 
-                debug!("translation block not found...");
+    #[test]
+    pub fn compile_failures_blacklist_blocks() {
+        init();
+        let prog = asm::assemble(".lc 5; loop: INC3A; DECA; INC3A; DECA; INC3A; DECA; BACK7 loop");
+        let prog = prog.unwrap();
+        let mut vm = EmulationEngine::builder()
+            .backend(BackendKind::Interpreter)
+            .compile_threshold(2)
+            .max_compile_failures(2)
+            .build()
+            .unwrap();
+        vm.load_program(prog.clone()).unwrap();
 
-                // Interpret instructions normally and Build translation block
-                let dbb = self.interpret();
-                let tbb = TranslationContext::new(&llvm_context, dbb);
-                code_cache.put(pc, tbb);
+        // Failures are counted until the block reaches the limit
+        let error = VmError::CompilationFailed("no code".to_string());
+        vm.record_compile_failure(0, error.clone());
+        assert!(vm.blacklisted_blocks().is_empty());
+        vm.record_compile_failure(0, error.clone());
+        assert_eq!(vm.blacklisted_blocks().get(&0), Some(&error));
 
-                self.debug_state();
-            }
+        // Blacklisted blocks run hot in the interpreter
+        let report = vm.main_loop().unwrap();
+        let tier = |vm: &EmulationEngine, pc| {
+            let blocks = vm.cached_blocks();
+            blocks
+                .iter()
+                .find(|block| block.pc == pc)
+                .map(|block| block.tier)
+        };
+        assert_eq!(tier(&vm, 0), Some(Tier::Interpreter));
+        assert_eq!((report.interpreted, report.native), (36, 0));
+
+        // Writing the code of a block lifts its blacklisting
+        vm.write_memory(0, prog.data[0]).unwrap();
+        assert!(vm.blacklisted_blocks().is_empty());
+
+        assert!(matches!(
+            EmulationEngine::builder().max_compile_failures(0).build(),
+            Err(VmError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    pub fn precompiled_successors() {
+        init();
+        let prog = generate_scenario(2_000, 1, [1, 9, 1, 5, 5]);
+        let run = |depth| {
+            let mut vm = EmulationEngine::builder()
+                .backend(BackendKind::Interpreter)
+                .precompile_depth(depth)
+                .build()
+                .unwrap();
+            vm.load_program(prog.clone()).unwrap();
+            let report = vm.main_loop().unwrap();
+            (vm.cpu, report)
+        };
+
+        let (cpu, report) = run(0);
+        assert_eq!(report.blocks_precompiled, 0);
+        // The blocks after the hot ones get compiled before running hot
+        let (precompiled, report) = run(2);
+        assert_eq!(precompiled, cpu);
+        assert!(report.blocks_precompiled > 0);
+    }
+
+    #[test]
+    pub fn code_budget_reaps_cold_blocks() {
+        init();
+        let prog = generate_scenario(1_000, 1, [1, 9, 1, 5, 5]);
+        let mut vm = EmulationEngine::builder()
+            .backend(BackendKind::Interpreter)
+            .code_budget(100)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+
+        // The interpreter backend emits no code, give the blocks some
+        let pcs: Vec<usize> = vm.cached_blocks().iter().map(|block| block.pc).collect();
+        assert!(pcs.len() >= 3);
+        for pc in &pcs {
+            vm.code_cache.peek_mut(pc).unwrap().code_size = 64;
         }
+        let coldest = vm
+            .cached_blocks()
+            .iter()
+            .min_by_key(|block| block.executions)
+            .unwrap()
+            .pc;
+        vm.enforce_code_budget(coldest);
 
-        info!("{}", self.cpu);
+        // Blocks are reaped until the code fits, the one just compiled stays
+        assert_eq!(vm.native_code_size(), 64);
+        assert_eq!(vm.cache_stats().reaped, pcs.len() as u64 - 1);
+        let cached: Vec<usize> = vm.cached_blocks().iter().map(|block| block.pc).collect();
+        assert_eq!(cached, vec![coldest]);
+    }
+
+    #[test]
+    pub fn interpreter_backend() {
+        init();
+        let prog = generate_scenario(10_000, 1, [1, 9, 1, 5, 5]);
+        let mut vm = EmulationEngine::builder()
+            .backend(BackendKind::Interpreter)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 719821,
+                cycles: 719821,
+                ..Cpu::new(95, -21, 10_000, true)
+            }
+        );
+    }
 
+    #[cfg(feature = "cranelift")]
+    #[test]
+    pub fn cranelift_backend() {
+        init();
+        let prog = generate_scenario(10_000, 1, [1, 9, 1, 5, 5]);
+        let mut vm = EmulationEngine::builder()
+            .backend(BackendKind::Cranelift)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(vm.cpu, Cpu::new(95, -21, 10_000, true));
     }
-}
 
-#[cfg(test)]
-mod tests {
+    #[test]
+    pub fn cycles_follow_the_cost_table() {
+        init();
+        let costs = CostTable::new()
+            .with_cost(OpCode::INC3A, 2)
+            .with_cost(OpCode::BACK7, 5);
+        let run = |builder: EmulationEngineBuilder| {
+            let mut vm = builder.cost_table(costs.clone()).build().unwrap();
+            vm.load_program(generate_scenario(10_000, 1, [1, 9, 1, 5, 5]))
+                .unwrap();
+            vm.main_loop().unwrap();
+            vm.cpu
+        };
 
-    use super::*;
+        // Every tier agrees on the virtual time
+        let interpreted = run(EmulationEngine::builder().compile_threshold(u64::MAX));
+        let tiered = run(EmulationEngine::builder()
+            .baseline_threshold(1)
+            .compile_threshold(100));
+        assert_eq!(tiered, interpreted);
+        assert_eq!(interpreted.instret, 719821);
+        assert!(interpreted.cycles > interpreted.instret);
+    }
+
+    #[test]
+    pub fn superinstructions() {
+        init();
+        let block: Vec<Instruction> = [2, 2, 2, 3, 3, 3, 5]
+            .map(|byte| Instruction::new(OpCode::try_from(byte).unwrap(), 0))
+            .to_vec();
+        let baseline = BaselineBlock::compile(&block, &CostTable::default());
+        assert_eq!(
+            baseline.superinstructions(),
+            vec![
+                Superinstruction::Inc3a(3),
+                Superinstruction::Deca(2),
+                Superinstruction::DecaBack7
+            ]
+        );
+        assert_eq!(baseline.dispatches(), 3);
 
-    use crate::program::Program;
+        // An overflow in the middle of a fused run traps like the interpreter
+        let run = |backend| {
+            let mut vm = EmulationEngine::builder()
+                .backend(backend)
+                .compile_threshold(1)
+                .overflow_mode(OverflowMode::Trapping)
+                .build()
+                .unwrap();
+            let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 0], i32::MAX - 30, 10);
+            vm.load_program(prog).unwrap();
+            (vm.run(), vm.cpu)
+        };
+        let (outcome, cpu) = run(BackendKind::Interpreter);
+        assert_eq!(
+            outcome,
+            Ok(Outcome::Trapped(VmError::ArithmeticOverflow { pc: 4 }))
+        );
+        assert_eq!((cpu.acc, cpu.instret), (i32::MAX, 7 + 4));
+        assert_eq!((outcome, cpu), run(BackendKind::Reference));
+    }
 
-    mod bytecode_gen {
+    #[test]
+    pub fn peephole_optimizer() {
+        init();
+        let optimize = |bytes: &[u8], mode| {
+            let block: Vec<Instruction> = bytes
+                .iter()
+                .map(|&byte| Instruction::new(OpCode::try_from(byte).unwrap(), 0))
+                .collect();
+            peephole::optimize(&block, mode)
+        };
+        use Action::*;
 
-        use std::os::raw::{c_char, c_int};
+        // INC3A, INC3A, DECA, SETL
+        assert_eq!(
+            optimize(&[2, 2, 3, 4], OverflowMode::Wrapping),
+            vec![Fold(6), Retire, Emit, Emit]
+        );
+        assert_eq!(
+            optimize(&[2, 2, 3, 4], OverflowMode::Saturating),
+            vec![Emit; 4]
+        );
 
-        extern "C" {
-            pub fn init(
-                buf: *mut c_char,
-                size: c_int,
-                prob: *mut c_int,
-                seed: c_int,
-                r_a: *mut c_int,
-                r_l: *mut c_int,
-            );
+        // LI, INC3A, CLRA: an overflow would trap before CLRA
+        assert_eq!(
+            optimize(&[9, 2, 1], OverflowMode::Wrapping),
+            vec![Retire, Retire, Emit]
+        );
+        assert_eq!(optimize(&[9, 2, 1], OverflowMode::Trapping), vec![Emit; 3]);
+
+        // SETL, INC3A, SETL, BACK7 but not SETL, MOV, SETL
+        assert_eq!(
+            optimize(&[4, 2, 4, 5], OverflowMode::Trapping),
+            vec![Emit; 4]
+        );
+        assert_eq!(
+            optimize(&[4, 9, 4, 5], OverflowMode::Trapping),
+            vec![Retire, Emit, Emit, Emit]
+        );
+        assert_eq!(optimize(&[4, 17, 4], OverflowMode::Wrapping), vec![Emit; 3]);
+    }
+
+    #[test]
+    pub fn mir_lowering() {
+        init();
+        let retire = |opcode| Inst::Retire(opcode);
+
+        // LI 5, SETL then a loop over the six bytes before BACK7
+        let block = [
+            Instruction::new(OpCode::LI, 5),
+            Instruction::new(OpCode::SETL, 0),
+            Instruction::new(OpCode::INC3A, 0),
+            Instruction::new(OpCode::INC3A, 0),
+            Instruction::new(OpCode::INC3A, 0),
+            Instruction::new(OpCode::MOV, 0x20),
+            Instruction::new(OpCode::DECA, 0),
+            Instruction::new(OpCode::BACK7, 0),
+        ];
+        let lowered = mir::lower(&block, OverflowMode::Wrapping);
+        assert_eq!(
+            lowered.code,
+            vec![
+                Inst::SetReg {
+                    register: mir::ACC,
+                    value: Operand::Const(5)
+                },
+                Inst::Next(3),
+                retire(OpCode::LI),
+                Inst::SetReg {
+                    register: mir::LC,
+                    value: Operand::Register(mir::ACC)
+                },
+                Inst::Next(1),
+                retire(OpCode::SETL),
+            ]
+        );
+        let Exit::Loop(body) = lowered.exit else {
+            panic!("{:?} is not a loop", lowered.exit);
+        };
+        assert_eq!(mir::instructions(&body), 5);
+        // The INC3A run is folded by the peephole optimizer
+        assert_eq!(
+            body[..8],
+            [
+                Inst::AddConst {
+                    register: mir::ACC,
+                    value: 6
+                },
+                Inst::Next(1),
+                retire(OpCode::INC3A),
+                Inst::Next(1),
+                retire(OpCode::INC3A),
+                Inst::AddFlagged {
+                    value: 3,
+                    origin: block[4]
+                },
+                Inst::Next(1),
+                retire(OpCode::INC3A),
+            ]
+        );
+
+        // BACK7 to the outside of the block and an exit through the host
+        let lowered = mir::lower(
+            &[
+                Instruction::new(OpCode::BACK7, 0),
+                Instruction::new(OpCode::STA, 64),
+            ],
+            OverflowMode::Trapping,
+        );
+        assert_eq!(
+            lowered.code[1],
+            Inst::CondBranch {
+                condition: Condition::Positive(mir::LC),
+                target: Target::Back(6),
+                length: 1
+            }
+        );
+        assert_eq!(lowered.exit, Exit::Host(Instruction::new(OpCode::STA, 64)));
+        assert_eq!(
+            mir::lower(&[Instruction::new(OpCode::HALT, 0)], OverflowMode::Wrapping),
+            mir::Mir {
+                code: vec![Inst::Halt, Inst::Next(1), retire(OpCode::HALT)],
+                exit: Exit::Return
+            }
+        );
+    }
+
+    #[test]
+    pub fn llvm_pass_pipelines() {
+        init();
+        assert!(Pass::pipeline(OptimizationLevel::None).is_empty());
+        let default = Pass::pipeline(OptimizationLevel::Default);
+        for pass in [
+            Pass::PromoteMemoryToRegister,
+            Pass::InstructionCombining,
+            Pass::Gvn,
+            Pass::CfgSimplification,
+        ] {
+            assert!(default.contains(&pass), "{:?} is missing", pass);
         }
+        assert!(Pass::pipeline(OptimizationLevel::Aggressive).starts_with(&default));
+
+        let vm = EmulationEngineBuilder::new()
+            .opt_level(OptimizationLevel::Less)
+            .build()
+            .unwrap();
+        assert_eq!(
+            vm.config.llvm_passes(),
+            Pass::pipeline(OptimizationLevel::Less)
+        );
+        let vm = EmulationEngineBuilder::new()
+            .passes(vec![Pass::PromoteMemoryToRegister])
+            .build()
+            .unwrap();
+        assert_eq!(vm.config.llvm_passes(), vec![Pass::PromoteMemoryToRegister]);
     }
 
-    fn init() {
-        let _ = env_logger::builder().is_test(true).try_init();
+    #[test]
+    pub fn tiered_compilation() {
+        init();
+        let prog = generate_scenario(10_000, 1, [1, 9, 1, 5, 5]);
+        let mut vm = EmulationEngine::builder()
+            .baseline_threshold(1)
+            .compile_threshold(100)
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        vm.main_loop().unwrap();
+        assert_eq!(
+            vm.cpu,
+            Cpu {
+                instret: 719821,
+                cycles: 719821,
+                ..Cpu::new(95, -21, 10_000, true)
+            }
+        );
     }
 
-    fn generate_scenario(size: usize, seed: i32, mut probs: [i32; 5]) -> Program {
-        let mut r_a: i32 = 0;
-        let mut r_l: i32 = 0;
+    #[test]
+    pub fn engines_run_on_worker_threads() {
+        init();
+        // Created here, run concurrently on workers and dropped back here
+        let workers: Vec<_> = (1..=4)
+            .map(|seed| {
+                let scenario = generator::generate(1_000, seed, [1, 2, 1, 2, 3]);
+                let mut vm = EmulationEngine::builder()
+                    .compile_threshold(2)
+                    .build()
+                    .unwrap();
+                std::thread::spawn(move || {
+                    vm.load_program(scenario.program).unwrap();
+                    let report = vm.main_loop().unwrap();
+                    assert!(report.blocks_compiled > 0);
+                    assert_eq!(
+                        (vm.cpu.acc, vm.cpu.lc, vm.cpu.instret),
+                        (
+                            scenario.expected_acc,
+                            scenario.expected_lc,
+                            scenario.expected_instret
+                        ),
+                        "seed {}",
+                        seed
+                    );
+                    vm
+                })
+            })
+            .collect();
 
-        let mut data: Vec<u8> = Vec::new();
-        data.resize(size, 0);
+        let engines: Vec<_> = workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .collect();
 
-        unsafe {
-            bytecode_gen::init(
-                data.as_mut_ptr() as *mut i8,
-                size as i32,
-                probs.as_mut_ptr(),
-                seed,
-                &mut r_a,
-                &mut r_l,
-            )
+        // Engines are Sync as well, threads look into them at once
+        std::thread::scope(|scope| {
+            for vm in &engines {
+                for _ in 0..2 {
+                    scope.spawn(move || {
+                        assert!(vm.cpu.halt);
+                        assert!(!vm.compile_stats().is_empty());
+                    });
+                }
+            }
+        });
+    }
+
+    #[test]
+    pub fn engines_share_compiled_code() {
+        init();
+        // No other test shares code, nor runs these instructions
+        let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 2, 2, 5, 2, 0], 0, 9);
+        let run = move || {
+            let mut vm = EmulationEngine::builder()
+                .compile_threshold(2)
+                .share_code(true)
+                .build()
+                .unwrap();
+            vm.load_program(prog).unwrap();
+            vm.main_loop().unwrap()
+        };
+
+        let compiled = run.clone()();
+        assert!(compiled.blocks_compiled > 0);
+        assert!(!SharedCache::global().is_empty());
+        let imported = std::thread::spawn(run).join().unwrap();
+        assert!(imported.blocks_imported > 0);
+        assert!(imported.blocks_compiled < compiled.blocks_compiled);
+        assert_eq!(imported.cpu, compiled.cpu);
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    pub fn engines_run_shared_code_on_several_threads() {
+        init();
+        // No other test shares code, nor runs these instructions
+        let prog = Program::new(vec![2, 3, 2, 2, 3, 2, 2, 2, 5, 3, 0], 0, 12);
+        let build = move || {
+            let mut vm = EmulationEngine::builder()
+                .compile_threshold(2)
+                .share_code(true)
+                .build()
+                .unwrap();
+            vm.load_program(prog.clone()).unwrap();
+            vm
+        };
+
+        // The engine compiling the code is dropped before the others run it,
+        // its compiler thread living as long as the code
+        let compiled = build().main_loop().unwrap();
+        assert!(compiled.blocks_compiled > 0);
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let mut vm = build();
+                std::thread::spawn(move || vm.main_loop().unwrap())
+            })
+            .collect();
+        for worker in workers {
+            let imported = worker.join().unwrap();
+            assert!(imported.blocks_imported > 0);
+            assert_eq!(imported.cpu, compiled.cpu);
+        }
+    }
+
+    #[test]
+    pub fn batch_runs_programs_concurrently() {
+        init();
+        let scenarios: Vec<_> = (1..=6)
+            .map(|seed| generator::generate(500, seed, [1, 2, 1, 2, 3]))
+            .collect();
+        let mut programs: Vec<Program> = scenarios.iter().map(|s| s.program.clone()).collect();
+        programs.insert(2, Program::new(vec![2, 2, 0xff, 0], 0, 0));
+        let config = EngineConfig::default();
+
+        let reports = batch::run_batch(&config, programs, 3).unwrap();
+        assert_eq!(reports.len(), 7);
+        assert_eq!(
+            reports[2],
+            Err(VmError::UnknownOpCode { pc: 2, byte: 0xff })
+        );
+        let reports = reports.iter().enumerate().filter(|(index, _)| *index != 2);
+        for (scenario, (_, report)) in scenarios.iter().zip(reports) {
+            let cpu = report.as_ref().unwrap().cpu;
+            assert_eq!(
+                (cpu.acc, cpu.lc, cpu.instret),
+                (
+                    scenario.expected_acc,
+                    scenario.expected_lc,
+                    scenario.expected_instret
+                )
+            );
         }
 
-        Program::new(data, r_a, r_l)
+        assert_eq!(batch::run_batch(&config, vec![], 2), Ok(vec![]));
+        assert!(matches!(
+            batch::run_batch(&config, vec![Program::new(vec![0], 0, 0)], 0),
+            Err(VmError::InvalidConfig(_))
+        ));
     }
 
     #[test]
-    pub fn scenario_1() {
-        // acc: 30003, lc: 7
+    pub fn scheduler_time_slices_programs() {
         init();
-        let prog = generate_scenario(10_000, 1, [0, 1, 0, 0, 0]);
+        let long = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 0], 0, 20);
+        let short = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 0], 0, 2);
+        let faulty = Program::new(vec![2, 2, 0xff, 0], 0, 0);
+        let config = EngineConfig::default();
+        let mut scheduler = scheduler::Scheduler::new(config.clone(), 10).unwrap();
+        let long_id = scheduler.spawn(long.clone()).unwrap();
+        let short_id = scheduler.spawn(short).unwrap();
+        let faulty_id = scheduler.spawn(faulty).unwrap();
+
+        // Preempted once its quantum is over, the task goes last
+        assert_eq!(scheduler.step(), Some((long_id, TaskState::Ready)));
+        assert_eq!(scheduler.run_queue(), vec![short_id, faulty_id, long_id]);
+        assert_eq!(scheduler.task(long_id).unwrap().cpu.instret, 10);
+
+        // A breakpoint suspends the task until it is resumed
+        let mut vm = EmulationEngine::default();
+        vm.load_program(long.clone()).unwrap();
+        vm.set_breakpoint(7);
+        let suspended_id = scheduler.spawn_engine(vm);
+        scheduler.run();
+        assert_eq!(
+            scheduler.task(suspended_id).unwrap().state,
+            TaskState::Suspended(Outcome::Breakpoint(7))
+        );
+        assert!(scheduler.resume(suspended_id));
+        assert!(!scheduler.resume(long_id));
+        assert!(scheduler.run() > 0);
+        assert!(scheduler.run_queue().is_empty());
+
         let mut vm = EmulationEngine::default();
-        vm.load_program(prog);
-        vm.main_loop();
-        assert_eq!(vm.cpu, Cpu::new(30003, 7, 10000, true));
+        vm.load_program(long).unwrap();
+        vm.main_loop().unwrap();
+        let tasks = scheduler.tasks();
+        assert_eq!(tasks.len(), 4);
+        for id in [long_id, suspended_id] {
+            assert_eq!(tasks[id].state, TaskState::Finished(Outcome::Halted));
+            assert_eq!(tasks[id].cpu, vm.cpu);
+        }
+        assert!(tasks[long_id].slices > tasks[short_id].slices);
+        assert_eq!(
+            tasks[faulty_id].state,
+            TaskState::Finished(Outcome::Trapped(VmError::UnknownOpCode {
+                pc: 2,
+                byte: 0xff
+            }))
+        );
+
+        assert!(scheduler.remove(short_id).is_some());
+        assert_eq!(scheduler.tasks().len(), 3);
+        assert!(matches!(
+            scheduler::Scheduler::new(config, 0),
+            Err(VmError::InvalidConfig(_))
+        ));
     }
 
     #[test]
-    pub fn scenario_2() {
-        // acc: -1, lc: 7
+    pub fn mailbox_exchanges_words() {
         init();
-        let prog = generate_scenario(10_000, 1, [1, 1, 1, 0, 0]);
+        let mailbox = 0x200..0x200 + mailbox::MAILBOX_SIZE;
+        // Sends 5 down to 0, then sums what it receives until 0 comes back
+        let producer = asm::assemble(
+            "
+                LI 5
+            loop: STA 0x200
+                STA 0x204
+                BEQZ done
+                DECA
+                JMP loop
+            done: HALT
+            ",
+        )
+        .unwrap();
+        let consumer = asm::assemble(
+            "
+            loop: LDA 0x204
+                BEQZ done
+                ADD R1, A
+                JMP loop
+            done: MOV A, R1
+                HALT
+            ",
+        )
+        .unwrap();
+
+        // Two tasks of a scheduler, the consumer blocking until words come
+        let (left, right) = mailbox::Mailbox::pair();
+        let mut scheduler = scheduler::Scheduler::new(EngineConfig::default(), 3).unwrap();
+        let tasks = [(consumer.clone(), left), (producer.clone(), right)].map(|(prog, device)| {
+            let mut vm = EmulationEngine::default();
+            vm.attach_device(mailbox.clone(), Box::new(device)).unwrap();
+            vm.load_program(prog).unwrap();
+            scheduler.spawn_engine(vm)
+        });
+        assert_eq!(scheduler.step(), Some((tasks[0], TaskState::Waiting)));
+        scheduler.run();
+        for id in tasks {
+            let task = scheduler.task(id).unwrap();
+            assert_eq!(task.state, TaskState::Finished(Outcome::Halted));
+        }
+        assert_eq!(scheduler.task(tasks[0]).unwrap().cpu.acc, 15);
+
+        // The host on the other end, waiting engines resume where they stopped
+        let (outbox, guest_inbox) = std::sync::mpsc::channel();
+        let (guest_outbox, inbox) = std::sync::mpsc::channel();
+        let mut vm = EmulationEngine::default();
+        let device = mailbox::Mailbox::new(guest_outbox.clone(), guest_inbox);
+        vm.attach_device(mailbox.clone(), Box::new(device)).unwrap();
+        vm.load_program(consumer).unwrap();
+        let Ok(Outcome::WaitingForMessage(cpu)) = vm.run() else {
+            panic!("the consumer did not wait");
+        };
+        assert_eq!((cpu.pc, cpu.instret), (0, 0));
+        outbox.send(7).unwrap();
+        outbox.send(0x100).unwrap();
+        // Peeking leaves the words to the guest
+        let status = mailbox.start + mailbox::STATUS;
+        assert_eq!(vm.read_memory_range(status..status + 1), Ok(vec![2]));
+        assert_eq!(vm.run(), Ok(Outcome::Halted));
+        assert_eq!(vm.cpu.acc, 7);
+        assert!(inbox.try_recv().is_err());
+
+        let (_, guest_inbox) = std::sync::mpsc::channel();
         let mut vm = EmulationEngine::default();
-        vm.load_program(prog);
-        vm.main_loop();
-        assert_eq!(vm.cpu, Cpu::new(-1, 7, 10000, true));
+        let device = mailbox::Mailbox::new(guest_outbox, guest_inbox);
+        vm.attach_device(mailbox, Box::new(device)).unwrap();
+        vm.load_program(producer).unwrap();
+        vm.main_loop().unwrap();
+        let words: Vec<u32> = inbox.try_iter().collect();
+        assert_eq!(words, vec![5, 4, 3, 2, 1, 0]);
     }
 
     #[test]
-    pub fn scenario_3() {
-        // acc: 95, lc: -21
+    pub fn console_captures_output() {
         init();
-        let prog = generate_scenario(10_000, 1, [1, 9, 1, 5, 5]);
-        let mut vm = EmulationEngine::default();
-        vm.load_program(prog);
-        vm.main_loop();
-        assert_eq!(vm.cpu, Cpu::new(95, -21, 10_000, true));
+        // Prints "cba" from a loop, which gets compiled
+        let prog = asm::assemble(
+            "
+                LI 0x63
+            loop: STA 0xff00
+                DECA
+                MOV R1, A
+                ADDI -0x60
+                BEQZ done
+                MOV A, R1
+                JMP loop
+            done: HALT
+            ",
+        )
+        .unwrap();
+        let mut vm = EmulationEngine::builder()
+            .compile_threshold(1)
+            .console(console::CONSOLE_ADDRESS)
+            .build()
+            .unwrap();
+        vm.load_program(prog.clone()).unwrap();
+        assert_eq!(vm.run(), Ok(Outcome::Halted));
+        assert_eq!(vm.console_output(), b"cba");
+        assert_eq!(vm.take_console_output(), b"cba");
+        assert!(vm.console_output().is_empty());
+        // Reads of the console return 0
+        assert_eq!(vm.read_memory_range(0xff00..0xff01), Ok(vec![0]));
+
+        // Forwarded to a sink of the host instead
+        struct Sink(Arc<Mutex<Vec<u8>>>);
+        impl Write for Sink {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let mut vm = EmulationEngine::builder()
+            .console_sink(Box::new(Sink(output.clone())))
+            .build()
+            .unwrap();
+        vm.load_program(prog).unwrap();
+        assert_eq!(vm.run(), Ok(Outcome::Halted));
+        assert_eq!(*output.lock().unwrap(), b"cba");
+        assert!(vm.console_output().is_empty());
     }
 
     #[test]
-    pub fn scenario_4() {
-        // acc: 138, lc: 0
+    pub fn rng_is_reproducible() {
         init();
-        let prog = generate_scenario(50_000, 1, [1, 9, 1, 5, 5]);
-        let mut vm = EmulationEngine::default();
-        vm.load_program(prog);
-        vm.main_loop();
-        assert_eq!(vm.cpu, Cpu::new(128, 0, 50_000, true));
+        // Sums 4 random bytes
+        let prog = asm::assemble(
+            "
+                LI 4
+                MOV R1, A
+            loop: LDA 0xff04
+                ADD R2, A
+                MOV A, R1
+                DECA
+                MOV R1, A
+                BNEZ loop
+                MOV A, R2
+                HALT
+            ",
+        )
+        .unwrap();
+        let run = |seed, backend| {
+            let mut vm = EmulationEngine::builder()
+                .compile_threshold(1)
+                .backend(backend)
+                .rng(rng::RNG_ADDRESS, seed)
+                .build()
+                .unwrap();
+            vm.load_program(prog.clone()).unwrap();
+            vm
+        };
+
+        let mut vm = run(42, BackendKind::Interpreter);
+        vm.start_recording();
+        assert_eq!(vm.run(), Ok(Outcome::Halted));
+        let log = vm.stop_recording().unwrap();
+        let sum = vm.cpu.acc;
+
+        // The same seed replays the same bytes, on the reference interpreter too
+        let mut replayed = run(42, BackendKind::Reference);
+        assert_eq!(replayed.replay(log), Ok(Outcome::Halted));
+        assert_eq!(replayed.cpu.acc, sum);
+        // Peeking leaves the sequence as it is
+        let next = vm.read_memory_range(0xff04..0xff05).unwrap();
+        assert_eq!(replayed.read_memory_range(0xff04..0xff05), Ok(next));
+
+        let mut other = run(7, BackendKind::Interpreter);
+        assert_eq!(other.run(), Ok(Outcome::Halted));
+        assert_ne!(other.cpu.acc, sum);
     }
 
     #[test]
-    pub fn scenario_custom_0() {
+    pub fn rdtime_reads_virtual_cycles() {
         init();
-        let prog = Program::new(vec![2, 2, 2, 2, 2, 2, 5, 5, 0], 0, 2);
-        let mut vm = EmulationEngine::default();
-        vm.load_program(prog);
-        vm.main_loop();
-        assert_eq!(vm.cpu, Cpu::new(36, -1, 9, true));
+        // Keeps the time read on the last of 2 iterations
+        let prog = asm::assemble(
+            "
+                LI 2
+                MOV R3, A
+            loop: INC3A
+                RDTIME
+                MOV R2, A
+                MOV A, R3
+                DECA
+                MOV R3, A
+                BNEZ loop
+                MOV A, R2
+                HALT
+            ",
+        )
+        .unwrap();
+        // The same time whichever tier runs the instructions before RDTIME
+        let builders = [
+            EmulationEngine::builder().compile_threshold(u64::MAX),
+            EmulationEngine::builder().compile_threshold(1),
+            EmulationEngine::builder().baseline_threshold(1),
+            EmulationEngine::builder().trace_threshold(1),
+            EmulationEngine::builder().ahead_of_time(true),
+        ];
+        for builder in builders {
+            let mut vm = builder.build().unwrap();
+            vm.load_program(prog.clone()).unwrap();
+            assert_eq!(vm.run(), Ok(Outcome::Halted));
+            assert_eq!(vm.cpu.acc, 10);
+        }
+
+        assert!(EmulationEngine::builder().wall_clock(0).build().is_err());
+        let mut vm = EmulationEngine::builder().wall_clock(1).build().unwrap();
+        vm.load_program(prog).unwrap();
+        assert_eq!(vm.run(), Ok(Outcome::Halted));
+        assert_eq!(vm.cpu.acc, 0);
     }
 
     #[test]
-    pub fn scenario_custom_1() {
+    pub fn trap_handler_resumes_within_fuel() {
         init();
-        let prog = Program::new(vec![4, 2, 2, 2, 2, 2, 2, 2, 5, 5, 0], 0, 2);
-        let mut vm = EmulationEngine::default();
-        vm.load_program(prog);
-        vm.main_loop();
-        assert_eq!(vm.cpu, Cpu::new(21, -2, 11, true));
+        let prog = Program::new(
+            vec![
+                2,    // 0x00: INC3A
+                2,    // 0x01: INC3A
+                0xff, // 0x02: unknown
+                10, 0, 0,  // 0x03: JMP 0x00
+                14, // 0x06: POP
+                8, 1,  // 0x07: ADDI 1
+                13, // 0x09: PUSH
+                16, // 0x0a: RET
+            ],
+            0,
+            0,
+        );
+        let builders = [
+            EmulationEngine::builder().backend(BackendKind::Reference),
+            EmulationEngine::builder().compile_threshold(1),
+        ];
+        for builder in builders {
+            let mut vm = builder.trap_handler(6).build().unwrap();
+            vm.load_program(prog.clone()).unwrap();
+            vm.start_recording();
+
+            // The instructions run before every fault count against the fuel
+            for fuel in [100, 7, 1] {
+                let instret = vm.cpu.instret;
+                assert_eq!(vm.run_for(fuel), Ok(Outcome::FuelExhausted));
+                assert_eq!(vm.cpu.instret, instret + fuel);
+            }
+            assert_eq!(vm.cpu.trap.map(|trap| trap.pc), Some(2));
+
+            // And are reported and recorded like the blocks run to their end
+            assert_eq!(vm.report.instructions(), vm.cpu.instret);
+            let log = vm.stop_recording().unwrap();
+            assert_eq!(
+                log.blocks.first().map(|block| (block.pc, block.instret)),
+                Some((0, 2))
+            );
+        }
     }
 }
@@ -0,0 +1,130 @@
+use std::{collections::VecDeque, fmt::Display};
+
+use crate::{
+    cpu::Cpu,
+    error::VmError,
+    replay::{state_hash, BlockRecord},
+    EmulationEngine, Outcome,
+};
+
+/// Blocks of each engine a LockstepDivergence shows by default.
+pub const DEFAULT_HISTORY: usize = 16;
+
+/// Where the engines of a Lockstep parted ways.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockstepDivergence {
+    pub left: (Outcome, Cpu),  // How the left engine stopped its last step
+    pub right: (Outcome, Cpu), // Likewise for the right engine
+    pub left_trail: Vec<BlockRecord>, // The last blocks run by the left engine
+    pub right_trail: Vec<BlockRecord>, // Likewise for the right engine
+}
+
+impl Display for LockstepDivergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "the left engine stopped with {:?} on {:?}, the right one with {:?} on {:?}",
+            self.left.0, self.left.1, self.right.0, self.right.1
+        )?;
+        let trails = [("left", &self.left_trail), ("right", &self.right_trail)];
+        for (side, trail) in trails {
+            write!(f, "{}:", side)?;
+            for record in trail.iter() {
+                write!(f, " {:#04x}@{}", record.pc, record.instret)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs two engines side by side, e.g. the JIT and the interpreter, or
+/// devices seeded differently, comparing a digest of their Cpu, see
+/// `replay::state_hash`, every `step` instructions. Steps are runs of as
+/// much fuel, so both engines stop after the same instructions, at the end
+/// of the blocks they ran: a step of 1 compares them after every
+/// instruction, and larger ones run faster. The blocks run lately by both
+/// engines are kept, to show how they got to a divergence. Both engines
+/// record their blocks while running, see `start_recording`.
+pub struct Lockstep {
+    left: EmulationEngine,
+    right: EmulationEngine,
+    step: u64,
+    history: usize,                     // Blocks kept in the trails
+    trails: [VecDeque<BlockRecord>; 2], // The last blocks run by each engine
+}
+
+impl Lockstep {
+    /// Pairs `left` and `right`, with their programs loaded.
+    pub fn new(left: EmulationEngine, right: EmulationEngine, step: u64) -> Result<Self, VmError> {
+        if step == 0 {
+            return Err(VmError::InvalidConfig(
+                "engines must run at least one instruction per step".to_string(),
+            ));
+        }
+        Ok(Self {
+            left,
+            right,
+            step,
+            history: DEFAULT_HISTORY,
+            trails: Default::default(),
+        })
+    }
+
+    /// Keeps the last `history` blocks of each engine for divergences.
+    pub fn history(mut self, history: usize) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Runs both engines until they stop the same way, returning how, or
+    /// until they diverge.
+    pub fn run(&mut self) -> Result<Outcome, Box<LockstepDivergence>> {
+        loop {
+            let left = self.run_step(0);
+            let right = self.run_step(1);
+            let diverged = left.0 != right.0 || state_hash(&left.1) != state_hash(&right.1);
+            if diverged {
+                return Err(Box::new(LockstepDivergence {
+                    left,
+                    right,
+                    left_trail: self.trails[0].iter().copied().collect(),
+                    right_trail: self.trails[1].iter().copied().collect(),
+                }));
+            }
+            if left.0 != Outcome::FuelExhausted {
+                return Ok(left.0);
+            }
+        }
+    }
+
+    /// Runs the engine `side` for a step, returning how it stopped.
+    fn run_step(&mut self, side: usize) -> (Outcome, Cpu) {
+        let engine = match side {
+            0 => &mut self.left,
+            _ => &mut self.right,
+        };
+        engine.start_recording();
+        let outcome = engine.run_for(self.step).unwrap_or_else(Outcome::Trapped);
+        let log = engine.stop_recording().unwrap_or_default();
+
+        let trail = &mut self.trails[side];
+        trail.extend(log.blocks);
+        let excess = trail.len().saturating_sub(self.history);
+        trail.drain(..excess);
+        (outcome, *engine.cpu())
+    }
+
+    pub fn left(&self) -> &EmulationEngine {
+        &self.left
+    }
+
+    pub fn right(&self) -> &EmulationEngine {
+        &self.right
+    }
+
+    /// Takes the engines back, e.g. to compare their memory.
+    pub fn into_engines(self) -> (EmulationEngine, EmulationEngine) {
+        (self.left, self.right)
+    }
+}
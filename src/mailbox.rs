@@ -0,0 +1,99 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Mutex, MutexGuard,
+    },
+};
+
+use crate::bus::MmioDevice;
+
+// The registers of a Mailbox, by offset from the start of its range. Words
+// are little endian.
+pub const DATA: usize = 0; // 4 bytes: written, the word to send; read, the last word received
+pub const DOORBELL: usize = 4; // Written, sends DATA; read, receives the next word, see `Mailbox`
+pub const STATUS: usize = 5; // Read, the words waiting to be received, up to 255
+pub const MAILBOX_SIZE: usize = 6; // Bytes of the range the device claims
+
+/// A device exchanging words with another mailbox, or with the host, over
+/// channels. The guest writes a word to DATA and sends it by writing the
+/// DOORBELL. Reading the DOORBELL receives the next word into DATA and
+/// returns its low byte: while no word came, the access blocks, and the
+/// engine stops with `Outcome::WaitingForMessage` at the reading
+/// instruction, which runs again once the guest is resumed. Words sent to
+/// a mailbox that was dropped are lost.
+pub struct Mailbox {
+    outbox: Sender<u32>,
+    inbox: Mutex<Receiver<u32>>,
+    waiting: Mutex<VecDeque<u32>>, // Words received by the channel, not by the guest yet
+    sending: [u8; 4],              // The word written to DATA
+    received: AtomicU32,           // The last word received by the guest
+}
+
+impl Mailbox {
+    /// A mailbox sending its words to `outbox` and receiving the ones of
+    /// `inbox`, e.g. to talk to the host.
+    pub fn new(outbox: Sender<u32>, inbox: Receiver<u32>) -> Self {
+        Self {
+            outbox,
+            inbox: Mutex::new(inbox),
+            waiting: Mutex::new(VecDeque::new()),
+            sending: [0; 4],
+            received: AtomicU32::new(0),
+        }
+    }
+
+    /// Two mailboxes connected to each other, for two engines to talk.
+    pub fn pair() -> (Self, Self) {
+        let (left_outbox, right_inbox) = mpsc::channel();
+        let (right_outbox, left_inbox) = mpsc::channel();
+        (
+            Self::new(left_outbox, left_inbox),
+            Self::new(right_outbox, right_inbox),
+        )
+    }
+
+    /// The words waiting to be received, once the channel is drained.
+    fn waiting(&self) -> MutexGuard<'_, VecDeque<u32>> {
+        let mut waiting = self.waiting.lock().unwrap();
+        waiting.extend(self.inbox.lock().unwrap().try_iter());
+        waiting
+    }
+}
+
+impl MmioDevice for Mailbox {
+    /// Reads a register without receiving anything: the DOORBELL shows the
+    /// low byte of the next word, if any.
+    fn read(&self, offset: usize) -> u8 {
+        match offset {
+            DOORBELL => self.waiting().front().map_or(0, |word| *word as u8),
+            _ => self.try_read(offset).unwrap_or(0),
+        }
+    }
+
+    fn try_read(&self, offset: usize) -> Option<u8> {
+        match offset {
+            DATA..DOORBELL => {
+                Some(self.received.load(Ordering::Relaxed).to_le_bytes()[offset - DATA])
+            }
+            DOORBELL => {
+                let word = self.waiting().pop_front()?;
+                self.received.store(word, Ordering::Relaxed);
+                Some(word as u8)
+            }
+            STATUS => Some(self.waiting().len().min(u8::MAX as usize) as u8),
+            _ => Some(0),
+        }
+    }
+
+    fn write(&mut self, offset: usize, value: u8) {
+        match offset {
+            DATA..DOORBELL => self.sending[offset - DATA] = value,
+            DOORBELL => {
+                let _ = self.outbox.send(u32::from_le_bytes(self.sending));
+            }
+            _ => (),
+        }
+    }
+}
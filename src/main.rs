@@ -1,3 +1,7 @@
+use tracing_subscriber::EnvFilter;
+
 fn main() {
-    env_logger::init();
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
 }
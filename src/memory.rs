@@ -1,38 +1,77 @@
+use std::ops::Range;
+
+use crate::{cpu::Cpu, deopt::SideExit, error::VmError, host::HostCalls};
+
+/// Accesses outside of the memory fail with `VmError::MemoryOutOfBounds`,
+/// which the engine reports as a guest trap.
+///
+/// The engine runs on any implementation, so the guest memory can be backed
+/// by something else than the Memory below (sparse, mmap-ed, instrumented...).
 pub trait Addressable<T> {
-    fn read(&self, address: usize) -> T;
-    fn write(&mut self, address: usize, value: T);
-    fn write_chunk(&mut self, chunk: Vec<T>) -> Result<(), String>;
+    fn read(&self, address: usize) -> Result<T, VmError>;
+    fn write(&mut self, address: usize, value: T) -> Result<(), VmError>;
+    fn write_chunk(&mut self, chunk: Vec<T>) -> Result<(), VmError>;
+
+    /// The number of addressable cells.
+    fn size(&self) -> usize;
+
+    /// Appends `additional` zeroed cells, fixed-size memories refuse to.
+    fn grow(&mut self, additional: usize) -> Result<(), VmError> {
+        Err(VmError::MemoryLimitExceeded {
+            size: self.size().saturating_add(additional),
+            limit: self.size(),
+        })
+    }
+
+    /// The cells reserved for the guest stack, none by default.
+    fn stack(&self) -> Range<usize> {
+        0..0
+    }
 }
 
 pub const MEMORY_SIZE: usize = 1024 * 64;
 
 pub struct Memory {
-    data: [u8; MEMORY_SIZE], // Reserve 64KB for programs
+    data: Vec<u8>, // Reserve 64KB for programs by default
 }
 
-impl Default for Memory {
-    fn default() -> Self {
+impl Memory {
+    pub fn new(size: usize) -> Self {
         Self {
-            data: [0; MEMORY_SIZE],
+            data: vec![0; size],
         }
     }
 }
 
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new(MEMORY_SIZE)
+    }
+}
+
 impl Addressable<u8> for Memory {
-    fn read(&self, address: usize) -> u8 {
-        self.data[address]
+    fn read(&self, address: usize) -> Result<u8, VmError> {
+        self.data
+            .get(address)
+            .copied()
+            .ok_or(VmError::MemoryOutOfBounds { address })
     }
 
-    fn write(&mut self, address: usize, value: u8) {
-        self.data[address] = value;
+    fn write(&mut self, address: usize, value: u8) -> Result<(), VmError> {
+        let cell = self
+            .data
+            .get_mut(address)
+            .ok_or(VmError::MemoryOutOfBounds { address })?;
+        *cell = value;
+        Ok(())
     }
 
-    fn write_chunk(&mut self, chunk: Vec<u8>) -> Result<(), String> {
-        if chunk.len() > MEMORY_SIZE {
-            return Err(format!(
-                "Chunk size is larger than maximum memory ({} bytes)",
-                MEMORY_SIZE
-            ));
+    fn write_chunk(&mut self, chunk: Vec<u8>) -> Result<(), VmError> {
+        if chunk.len() > self.size() {
+            return Err(VmError::ProgramTooLarge {
+                size: chunk.len(),
+                capacity: self.size(),
+            });
         }
 
         for (index, data) in chunk.iter().enumerate() {
@@ -41,4 +80,95 @@ impl Addressable<u8> for Memory {
 
         Ok(())
     }
+
+    fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    fn grow(&mut self, additional: usize) -> Result<(), VmError> {
+        self.data.resize(self.data.len() + additional, 0);
+        Ok(())
+    }
+}
+
+/// The guest memory as seen by a running block, together with the host
+/// functions it may call. Compiled code cannot stop the engine, so faults
+/// and written addresses are recorded here and handled once the block
+/// returns.
+pub struct MemoryPort<'a> {
+    memory: &'a mut dyn Addressable<u8>,
+    host_calls: &'a mut HostCalls,
+    written: Vec<usize>,
+    fault: Option<VmError>,
+    side_exit: Option<SideExit>,
+}
+
+impl<'a> MemoryPort<'a> {
+    pub fn new(memory: &'a mut dyn Addressable<u8>, host_calls: &'a mut HostCalls) -> Self {
+        Self {
+            memory,
+            host_calls,
+            written: Vec::new(),
+            fault: None,
+            side_exit: None,
+        }
+    }
+
+    /// Runs the host call `index` on `cpu`, its writes are recorded too.
+    pub fn call_host(&mut self, cpu: &mut Cpu, index: u8) -> Result<(), VmError> {
+        let Some(mut function) = self.host_calls.take(index) else {
+            return Err(VmError::UnknownHostCall { pc: cpu.pc, index });
+        };
+
+        let result = function(cpu, self);
+        self.host_calls.restore(index, function);
+        result
+    }
+
+    /// The time of the host clock, for the guest running on `cpu`.
+    pub fn time(&self, cpu: &Cpu) -> u64 {
+        self.host_calls.clock().time(cpu)
+    }
+
+    pub fn fault(&mut self, error: VmError) {
+        self.fault = Some(error);
+    }
+
+    /// Records the guard compiled code just left through.
+    pub fn side_exit(&mut self, exit: SideExit) {
+        self.side_exit = Some(exit);
+    }
+
+    /// Returns the addresses written through the port, the fault and the
+    /// side exit, if any.
+    pub fn into_parts(self) -> (Vec<usize>, Option<VmError>, Option<SideExit>) {
+        (self.written, self.fault, self.side_exit)
+    }
+}
+
+impl Addressable<u8> for MemoryPort<'_> {
+    fn read(&self, address: usize) -> Result<u8, VmError> {
+        self.memory.read(address)
+    }
+
+    fn write(&mut self, address: usize, value: u8) -> Result<(), VmError> {
+        self.memory.write(address, value)?;
+        self.written.push(address);
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, chunk: Vec<u8>) -> Result<(), VmError> {
+        let length = chunk.len();
+        self.memory.write_chunk(chunk)?;
+        self.written.extend(0..length);
+        Ok(())
+    }
+
+    fn size(&self) -> usize {
+        self.memory.size()
+    }
+
+    fn stack(&self) -> Range<usize> {
+        self.memory.stack()
+    }
 }
@@ -0,0 +1,353 @@
+use crate::{
+    counted::CountedLoop,
+    cpu::{Instruction, OpCode, OverflowMode, FLAG_ZERO},
+    deopt::DeoptReason,
+    peephole::{self, Action},
+    trace::{Trace, TraceStep},
+};
+
+/// Registers, numbered as the operands of register instructions: the
+/// accumulator, the loop counter, then R2 to R7.
+pub type Register = usize;
+
+pub const ACC: Register = 0;
+pub const LC: Register = 1;
+
+// BACK7 jumps back by six bytes, the loop body is made of the instructions
+// encoded in them followed by the BACK7 itself.
+const BACK7_DISTANCE: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Const(i32),
+    Register(Register),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Zero(Register),       // The register is zero
+    NonZero(Register),    // The register is not zero
+    Positive(Register),   // The register is greater than zero
+    Flag(u8, bool),       // The flag is set, or clear
+    Equal(Register, i32), // The register holds the value
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Absolute(u16), // A guest address
+    Back(usize),   // The address of the branch minus these bytes
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+}
+
+/// An operation of the mid-level IR. Every guest instruction lowers to a
+/// few of them, the last one being its `Retire`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Inst {
+    /// Wrapping addition, the flags are left alone.
+    AddConst {
+        register: Register,
+        value: i32,
+    },
+    /// The addition of INC3A, DECA and ADDI to the accumulator: sets the
+    /// flags and follows the overflow mode, faulting on `origin`.
+    AddFlagged {
+        value: i32,
+        origin: Instruction,
+    },
+    /// ADD and SUB, following the overflow mode.
+    Arith {
+        op: ArithOp,
+        rd: Register,
+        rs: Register,
+        origin: Instruction,
+    },
+    SetReg {
+        register: Register,
+        value: Operand,
+    },
+    SetFlags(u8),
+    /// Moves the program counter past an instruction of this length.
+    Next(usize),
+    Jump(u16),
+    /// Jumps when the condition holds, moves past the branch otherwise.
+    CondBranch {
+        condition: Condition,
+        target: Target,
+        length: usize,
+    },
+    /// Writes the state back and leaves at `exit`, telling why, unless the
+    /// condition is `expected`. The code goes on at `next` otherwise.
+    Guard {
+        condition: Condition,
+        expected: bool,
+        exit: usize,
+        next: usize,
+        reason: DeoptReason,
+    },
+    Halt,
+    /// Counts an executed instruction and its cycles.
+    Retire(OpCode),
+}
+
+/// How a lowered block leaves, once its straight-line code ran.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Exit {
+    Return,               // Back to the dispatcher
+    Host(Instruction),    // Through the host, which runs the instruction
+    Loop(Vec<Inst>),      // Into a loop body closed by BACK7, run while LC is positive
+    Counted(CountedLoop), // Into a counted loop, computed in constant time
+}
+
+/// A dynamic basic block lowered to the mid-level IR, which backends
+/// translate instead of the guest instructions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mir {
+    pub code: Vec<Inst>,
+    pub exit: Exit,
+}
+
+/// Lowers a block, after running the peephole optimizer over it. A block
+/// ending with a BACK7 into itself becomes a loop, in closed form when it
+/// is a counted one.
+pub fn lower(block: &[Instruction], overflow_mode: OverflowMode) -> Mir {
+    match loop_head(block) {
+        Some(head) => {
+            let body = &block[head..block.len() - 1];
+            Mir {
+                code: lower_straight(&block[..head], overflow_mode),
+                exit: match CountedLoop::analyze(body, overflow_mode) {
+                    Some(counted) => Exit::Counted(counted),
+                    None => Exit::Loop(lower_straight(body, overflow_mode)),
+                },
+            }
+        }
+        None => match block.split_last() {
+            Some((last, body)) if last.opcode.needs_host() => Mir {
+                code: lower_straight(body, overflow_mode),
+                exit: Exit::Host(*last),
+            },
+            _ => Mir {
+                code: lower_straight(block, overflow_mode),
+                exit: Exit::Return,
+            },
+        },
+    }
+}
+
+/// Lowers a trace, after running the peephole optimizer over it. Its
+/// branches become guards, retired before they may leave the trace. A
+/// trace ending with an instruction run by the host leaves through it.
+pub fn lower_trace(trace: &Trace, overflow_mode: OverflowMode) -> Mir {
+    let (body, exit) = match trace.steps.split_last() {
+        Some((last, body)) if last.instr.opcode.needs_host() => (body, Exit::Host(last.instr)),
+        _ => (&trace.steps[..], Exit::Return),
+    };
+    let block: Vec<Instruction> = body.iter().map(|step| step.instr).collect();
+
+    let mut code = Vec::with_capacity(block.len() * 2);
+    for (step, action) in body.iter().zip(peephole::optimize(&block, overflow_mode)) {
+        match action {
+            Action::Emit if step.is_guard() => {
+                lower_guard(step, &mut code);
+                continue;
+            }
+            Action::Emit => lower_instruction(step.instr, &mut code),
+            Action::Retire => code.push(Inst::Next(step.instr.length())),
+            Action::Fold(value) => {
+                code.push(Inst::AddConst {
+                    register: ACC,
+                    value,
+                });
+                code.push(Inst::Next(step.instr.length()));
+            }
+        }
+        code.push(Inst::Retire(step.instr.opcode));
+    }
+    Mir { code, exit }
+}
+
+/// The number of guest instructions `code` was lowered from.
+pub fn instructions(code: &[Inst]) -> usize {
+    code.iter()
+        .filter(|inst| matches!(inst, Inst::Retire(_)))
+        .count()
+}
+
+/// When the block ends with a BACK7 jumping inside the block itself, on an
+/// instruction boundary, returns the index of the first instruction of the
+/// loop body. The loop body then spans the rest of the block.
+pub(crate) fn loop_head(block: &[Instruction]) -> Option<usize> {
+    let (last, body) = block.split_last()?;
+    if last.opcode != OpCode::BACK7 {
+        return None;
+    }
+
+    let mut distance = 0;
+    for (index, instr) in body.iter().enumerate().rev() {
+        distance += instr.length();
+        if distance >= BACK7_DISTANCE {
+            return (distance == BACK7_DISTANCE).then_some(index);
+        }
+    }
+    None
+}
+
+fn lower_straight(block: &[Instruction], overflow_mode: OverflowMode) -> Vec<Inst> {
+    let mut code = Vec::with_capacity(block.len() * 2);
+    for (instr, action) in block.iter().zip(peephole::optimize(block, overflow_mode)) {
+        match action {
+            Action::Emit => lower_instruction(*instr, &mut code),
+            Action::Retire => code.push(Inst::Next(instr.length())),
+            Action::Fold(value) => {
+                code.push(Inst::AddConst {
+                    register: ACC,
+                    value,
+                });
+                code.push(Inst::Next(instr.length()));
+            }
+        }
+        code.push(Inst::Retire(instr.opcode));
+    }
+    code
+}
+
+fn lower_guard(step: &TraceStep, code: &mut Vec<Inst>) {
+    let instr = step.instr;
+    let condition = match instr.opcode {
+        OpCode::BACK7 => {
+            code.push(Inst::AddConst {
+                register: LC,
+                value: -1,
+            });
+            Condition::Positive(LC)
+        }
+        OpCode::BEQZ => Condition::Zero(ACC),
+        OpCode::BNEZ => Condition::NonZero(ACC),
+        _ => {
+            let (flag, set) = instr.opcode.flag_condition().unwrap();
+            Condition::Flag(flag, set)
+        }
+    };
+    code.push(Inst::Retire(instr.opcode));
+
+    // A branch to the next instruction goes there either way
+    let exit = step.side_exit();
+    if exit == step.next {
+        code.push(Inst::Next(instr.length()));
+        return;
+    }
+    code.push(Inst::Guard {
+        condition,
+        expected: step.next != step.pc + instr.length(),
+        exit,
+        next: step.next,
+        reason: DeoptReason::Branch,
+    });
+}
+
+fn lower_instruction(instr: Instruction, code: &mut Vec<Inst>) {
+    let next = Inst::Next(instr.length());
+    let branch = |condition| Inst::CondBranch {
+        condition,
+        target: Target::Absolute(instr.operand),
+        length: instr.length(),
+    };
+    let (rd, rs) = instr.registers();
+
+    match instr.opcode {
+        OpCode::HALT => code.extend([Inst::Halt, next]),
+        OpCode::CLRA => code.extend([
+            Inst::SetReg {
+                register: ACC,
+                value: Operand::Const(0),
+            },
+            Inst::SetFlags(FLAG_ZERO),
+            next,
+        ]),
+        OpCode::INC3A => code.extend([
+            Inst::AddFlagged {
+                value: 3,
+                origin: instr,
+            },
+            next,
+        ]),
+        OpCode::DECA => code.extend([
+            Inst::AddFlagged {
+                value: -1,
+                origin: instr,
+            },
+            next,
+        ]),
+        OpCode::SETL => code.extend([
+            Inst::SetReg {
+                register: LC,
+                value: Operand::Register(ACC),
+            },
+            next,
+        ]),
+        OpCode::BACK7 => code.extend([
+            Inst::AddConst {
+                register: LC,
+                value: -1,
+            },
+            Inst::CondBranch {
+                condition: Condition::Positive(LC),
+                target: Target::Back(BACK7_DISTANCE),
+                length: instr.length(),
+            },
+        ]),
+        OpCode::ADDI => code.extend([
+            Inst::AddFlagged {
+                value: instr.operand as u8 as i8 as i32,
+                origin: instr,
+            },
+            next,
+        ]),
+        OpCode::LI => code.extend([
+            Inst::SetReg {
+                register: ACC,
+                value: Operand::Const(instr.operand as i16 as i32),
+            },
+            next,
+        ]),
+        OpCode::JMP => code.push(Inst::Jump(instr.operand)),
+        OpCode::BEQZ => code.push(branch(Condition::Zero(ACC))),
+        OpCode::BNEZ => code.push(branch(Condition::NonZero(ACC))),
+        OpCode::BEQ | OpCode::BNE | OpCode::BMI | OpCode::BPL | OpCode::BVS | OpCode::BVC => {
+            let (flag, set) = instr.opcode.flag_condition().unwrap();
+            code.push(branch(Condition::Flag(flag, set)))
+        }
+        OpCode::MOV => code.extend([
+            Inst::SetReg {
+                register: rd,
+                value: Operand::Register(rs),
+            },
+            next,
+        ]),
+        OpCode::ADD | OpCode::SUB => code.extend([
+            Inst::Arith {
+                op: match instr.opcode {
+                    OpCode::ADD => ArithOp::Add,
+                    _ => ArithOp::Sub,
+                },
+                rd,
+                rs,
+                origin: instr,
+            },
+            next,
+        ]),
+        OpCode::LDA
+        | OpCode::STA
+        | OpCode::PUSH
+        | OpCode::POP
+        | OpCode::CALL
+        | OpCode::RET
+        | OpCode::HCALL
+        | OpCode::RDTIME => unreachable!("instructions run by the host end blocks"),
+    }
+}
@@ -0,0 +1,265 @@
+use std::io::{self, BufRead, Write};
+
+use crate::{
+    condition::Condition,
+    error::VmError,
+    program::disasm::{self, Entry},
+    watch::Watchpoint,
+    EmulationEngine,
+};
+
+const HELP: &str = "\
+step [n]            execute n instructions, 1 by default
+continue            run until HALT, a trap, a breakpoint or a watchpoint
+regs                show the registers
+mem <addr> [len]    dump len bytes of memory, 16 by default
+disasm <addr> [n]   disassemble n instructions, 8 by default
+break [pc]          set a breakpoint at pc, or list them
+break <pc> if <condition>
+                    stop at pc only when a condition like `lc < 0 && acc != 3` holds
+delete <pc>         remove the breakpoint at pc
+watch [condition]   stop once a condition like `acc > 1000` or `write 0x2000..0x2100`
+                    is met, or list the watchpoints
+unwatch <id>        remove the watchpoint id
+blocks              list the blocks in the code cache
+cache               show the policy and statistics of the code cache
+quit                leave the monitor";
+
+/// An interactive front-end to an engine, driven by text commands. Numbers
+/// are decimal or `0x` prefixed hexadecimal.
+pub struct Monitor {
+    engine: EmulationEngine,
+}
+
+impl Monitor {
+    pub fn new(engine: EmulationEngine) -> Self {
+        Self { engine }
+    }
+
+    pub fn engine(&self) -> &EmulationEngine {
+        &self.engine
+    }
+
+    pub fn engine_mut(&mut self) -> &mut EmulationEngine {
+        &mut self.engine
+    }
+
+    pub fn into_engine(self) -> EmulationEngine {
+        self.engine
+    }
+
+    /// Executes a single command, returning what it prints. Errors, from
+    /// the command or the guest, are printed too.
+    pub fn execute(&mut self, line: &str) -> String {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return String::new();
+        };
+        let args: Vec<&str> = words.collect();
+
+        let output = match (command, &args[..]) {
+            ("help" | "h", []) => Ok(HELP.to_string()),
+            ("step" | "s", []) => self.step(1),
+            ("step" | "s", [n]) => number(n).and_then(|n| self.step(n)),
+            ("continue" | "c", []) => self
+                .engine
+                .run()
+                .map(|outcome| format!("{:?}", outcome))
+                .map_err(|e| e.to_string()),
+            ("regs" | "r", []) => Ok(self.registers()),
+            ("mem" | "m", [address]) => number(address).and_then(|a| self.memory(a, 16)),
+            ("mem" | "m", [address, length]) => {
+                number(address).and_then(|a| self.memory(a, number(length)?))
+            }
+            ("disasm" | "d", [address]) => number(address).and_then(|a| self.disassemble(a, 8)),
+            ("disasm" | "d", [address, count]) => {
+                number(address).and_then(|a| self.disassemble(a, number(count)?))
+            }
+            ("break" | "b", []) => Ok(self
+                .engine
+                .breakpoints()
+                .iter()
+                .map(|&pc| match self.engine.breakpoint_condition(pc) {
+                    Some(condition) => format!("{:#06x} if {}", pc, condition),
+                    None => format!("{:#06x}", pc),
+                })
+                .collect::<Vec<_>>()
+                .join("\n")),
+            ("break" | "b", [pc]) => number(pc).map(|pc| {
+                self.engine.set_breakpoint(pc);
+                format!("breakpoint at {:#06x}", pc)
+            }),
+            ("break" | "b", [pc, "if", condition @ ..]) => number(pc).and_then(|pc| {
+                let condition: Condition = condition
+                    .join(" ")
+                    .parse()
+                    .map_err(|e: VmError| e.to_string())?;
+                let reply = format!("breakpoint at {:#06x} if {}", pc, condition);
+                self.engine.set_conditional_breakpoint(pc, condition);
+                Ok(reply)
+            }),
+            ("delete", [pc]) => number(pc).map(|pc| {
+                self.engine.clear_breakpoint(pc);
+                format!("breakpoint at {:#06x} removed", pc)
+            }),
+            ("watch" | "w", []) => Ok(self
+                .engine
+                .watchpoints()
+                .iter()
+                .map(|(id, watchpoint)| format!("{}: {}", id, watchpoint))
+                .collect::<Vec<_>>()
+                .join("\n")),
+            ("watch" | "w", condition) => condition
+                .join(" ")
+                .parse::<Watchpoint>()
+                .map(|watchpoint| {
+                    let text = watchpoint.to_string();
+                    let id = self.engine.add_watchpoint(watchpoint);
+                    format!("watchpoint {}: {}", id, text)
+                })
+                .map_err(|e| e.to_string()),
+            ("unwatch", [id]) => {
+                number(id).and_then(|id| match self.engine.remove_watchpoint(id) {
+                    Some(watchpoint) => Ok(format!("watchpoint {}: {} removed", id, watchpoint)),
+                    None => Err(format!("no watchpoint {}", id)),
+                })
+            }
+            ("blocks", []) => Ok(self
+                .engine
+                .cached_blocks()
+                .iter()
+                .map(|block| {
+                    format!(
+                        "{:#06x}: {} instructions, {} executions, {:?}",
+                        block.pc, block.instructions, block.executions, block.tier
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n")),
+            ("cache", []) => {
+                let (policy, capacity) = self.engine.cache_policy();
+                let stats = self.engine.cache_stats();
+                Ok(format!(
+                    "{:?}, {} of {} blocks\n{} hits, {} misses, {} insertions, {} evictions, {} invalidations, {} splits, {} reaped",
+                    policy,
+                    self.engine.cached_blocks().len(),
+                    capacity,
+                    stats.hits,
+                    stats.misses,
+                    stats.insertions,
+                    stats.evictions,
+                    stats.invalidations,
+                    stats.splits,
+                    stats.reaped
+                ))
+            }
+            _ => Err(format!("unknown command `{}`, try `help`", line.trim())),
+        };
+
+        output.unwrap_or_else(|msg| format!("error: {}", msg))
+    }
+
+    /// Reads commands from `input` until `quit` or the end of the input,
+    /// printing a prompt and the output of every command to `output`.
+    pub fn run(&mut self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        write!(output, "(vtvm) ")?;
+        output.flush()?;
+        for line in input.lines() {
+            let line = line?;
+            if matches!(line.trim(), "quit" | "q") {
+                break;
+            }
+            let reply = self.execute(&line);
+            if !reply.is_empty() {
+                writeln!(output, "{}", reply)?;
+            }
+            write!(output, "(vtvm) ")?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, count: usize) -> Result<String, String> {
+        let mut lines = Vec::new();
+        for _ in 0..count {
+            let pc = self.engine.cpu().pc;
+            match self.engine.step() {
+                Ok((instr, _)) => lines.push(format!("{:#06x}: {}", pc, Entry::Instruction(instr))),
+                Err(VmError::MachineHalted) if !lines.is_empty() => break,
+                Err(e) => {
+                    lines.push(format!("error: {}", e));
+                    break;
+                }
+            }
+        }
+        Ok(lines.join("\n"))
+    }
+
+    fn registers(&self) -> String {
+        let cpu = self.engine.cpu();
+        let mut registers = format!(
+            "pc: {:#06x}  acc: {}  lc: {}  sp: {:#06x}  flags: {:#04x}",
+            cpu.pc, cpu.acc, cpu.lc, cpu.sp, cpu.flags
+        );
+        for (index, value) in cpu.gpr.iter().enumerate() {
+            registers += &format!(
+                "{}r{}: {}",
+                if index == 0 { "\n" } else { "  " },
+                index + 2,
+                value
+            );
+        }
+        registers += &format!(
+            "\ninstret: {}  cycles: {}  halted: {}",
+            cpu.instret, cpu.cycles, cpu.halt
+        );
+        if let Some(trap) = cpu.trap {
+            registers += &format!("  trapped: {:?} at {:#06x}", trap.cause, trap.pc);
+        }
+        registers
+    }
+
+    fn memory(&self, address: usize, length: usize) -> Result<String, String> {
+        let end = address
+            .saturating_add(length)
+            .min(self.engine.memory_size());
+        let bytes = self
+            .engine
+            .read_memory_range(address..end.max(address))
+            .map_err(|e| e.to_string())?;
+        Ok(bytes
+            .chunks(16)
+            .enumerate()
+            .map(|(line, chunk)| {
+                let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+                format!("{:#06x}: {}", address + line * 16, hex.join(" "))
+            })
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+
+    fn disassemble(&self, address: usize, count: usize) -> Result<String, String> {
+        // Instructions take at most 3 bytes
+        let end = address
+            .saturating_add(count.saturating_mul(3))
+            .min(self.engine.memory_size());
+        let code = self
+            .engine
+            .read_memory_range(address..end.max(address))
+            .map_err(|e| e.to_string())?;
+        Ok(disasm::entries(&code, address)
+            .into_iter()
+            .take(count)
+            .map(|(address, entry)| format!("{:#06x}: {}", address, entry))
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+}
+
+fn number(text: &str) -> Result<usize, String> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    }
+    .map_err(|_| format!("invalid number `{}`", text))
+}
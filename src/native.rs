@@ -0,0 +1,57 @@
+use capstone::prelude::*;
+
+use crate::error::VmError;
+
+/// Disassembles `code`, host machine code loaded at `address`, one
+/// instruction per line in the format of `disasm::disassemble`. Only x86-64
+/// and AArch64 hosts are supported.
+pub fn disassemble(code: &[u8], address: usize) -> Result<String, VmError> {
+    let capstone = host_capstone()?;
+    let instructions = capstone
+        .disasm_all(code, address as u64)
+        .map_err(|e| VmError::DisassemblyFailed(e.to_string()))?;
+
+    let mut listing = String::new();
+    for instr in instructions.iter() {
+        let bytes = instr
+            .bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mnemonic = instr.mnemonic().unwrap_or("?");
+        let line = match instr.op_str() {
+            Some(operands) if !operands.is_empty() => format!("{} {}", mnemonic, operands),
+            _ => mnemonic.to_string(),
+        };
+        listing += &format!("{:#x}: {:<32}  {}\n", instr.address(), bytes, line);
+    }
+    Ok(listing)
+}
+
+/// A disassembler for the architecture the engine runs on.
+#[cfg(target_arch = "x86_64")]
+fn host_capstone() -> Result<Capstone, VmError> {
+    Capstone::new()
+        .x86()
+        .mode(arch::x86::ArchMode::Mode64)
+        .build()
+        .map_err(|e| VmError::DisassemblyFailed(e.to_string()))
+}
+
+#[cfg(target_arch = "aarch64")]
+fn host_capstone() -> Result<Capstone, VmError> {
+    Capstone::new()
+        .arm64()
+        .mode(arch::arm64::ArchMode::Arm)
+        .build()
+        .map_err(|e| VmError::DisassemblyFailed(e.to_string()))
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn host_capstone() -> Result<Capstone, VmError> {
+    Err(VmError::DisassemblyFailed(format!(
+        "{} hosts are not supported",
+        std::env::consts::ARCH
+    )))
+}
@@ -0,0 +1,350 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    ffi::{c_char, c_int, c_void, CString},
+    fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+};
+
+use inkwell::{
+    module::Linkage,
+    targets::TargetMachine,
+    values::{BasicMetadataValueEnum, CallableValue},
+    AddressSpace, OptimizationLevel,
+};
+
+use crate::{
+    artifact::{self, ArtifactKind},
+    codegen::{deoptimize, execute_on_host, CompiledFunc},
+    cpu::Instruction,
+    error::VmError,
+    translation::{TranslationContext, TranslationOptions},
+};
+
+/// Points to the function of the block in its library.
+const BLOCK_NAME: &str = "vt_block";
+
+/// The bytes of machine code of the block.
+const CODE_SIZE_NAME: &str = "vt_code_size";
+
+/// What the block was compiled from, see `ObjectCache::identity`, and its
+/// length in bytes.
+const IDENTITY_NAME: &str = "vt_identity";
+const IDENTITY_LENGTH_NAME: &str = "vt_identity_length";
+
+const RTLD_NOW: c_int = 2;
+
+#[cfg_attr(target_os = "linux", link(name = "dl"))]
+extern "C" {
+    fn dlopen(filename: *const c_char, flags: c_int) -> *mut c_void;
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+    fn dlclose(handle: *mut c_void) -> c_int;
+}
+
+/// The functions of the host compiled code calls, with the slot of the
+/// library of a cached block they are reached through, and their address.
+/// The addresses change from a run to the next, so the libraries call
+/// through slots the loader fills.
+fn host_functions() -> [(&'static str, &'static str, usize); 2] {
+    [
+        (
+            "execute_on_host",
+            "vt_execute_on_host",
+            execute_on_host as *const () as usize,
+        ),
+        (
+            "deoptimize",
+            "vt_deoptimize",
+            deoptimize as *const () as usize,
+        ),
+    ]
+}
+
+/// Keeps the native code of the blocks compiled by LLVM on disk, for later
+/// runs to load it rather than compiling the blocks again, e.g. the runs of
+/// a benchmark suite. Each block is a shared library in the directory of
+/// the cache, named by the hash of its instructions and of what else the
+/// code depends on: the translation options and the host CPU. Neither the
+/// LLVM C API nor inkwell expose LLVM's `ObjectCache`, nor a way to hand
+/// object files to MCJIT, so the libraries are loaded with `dlopen`.
+pub struct ObjectCache {
+    dir: PathBuf,
+    opt_level: OptimizationLevel,
+    key: String, // The options and the host CPU
+}
+
+impl ObjectCache {
+    /// The cache of the blocks translated with `options` in `dir`, created
+    /// if needed.
+    pub fn new(dir: &Path, options: &TranslationOptions) -> Result<Self, VmError> {
+        fs::create_dir_all(dir).map_err(|e| VmError::Io(format!("{}: {}", dir.display(), e)))?;
+        let key = format!(
+            "{:?} {} {}",
+            options,
+            TargetMachine::get_host_cpu_name(),
+            TargetMachine::get_host_cpu_features()
+        );
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            opt_level: options.opt_level,
+            key,
+        })
+    }
+
+    /// The library holding the code of `block`.
+    pub fn path(&self, block: &[Instruction]) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        self.identity(block).hash(&mut hasher);
+        self.dir.join(format!("{:016x}.so", hasher.finish()))
+    }
+
+    /// The key of the cache and the instructions of `block`, which the
+    /// library of the block embeds and loads compare, as hashes may collide.
+    fn identity(&self, block: &[Instruction]) -> Vec<u8> {
+        let mut identity = self.key.as_bytes().to_vec();
+        for instruction in block {
+            identity.push(instruction.opcode as u8);
+            identity.extend(instruction.operand.to_le_bytes());
+        }
+        identity
+    }
+
+    /// Loads the code of `block` a previous run cached, if any.
+    pub fn load(&self, block: &[Instruction]) -> Option<LoadedBlock> {
+        let path = self.path(block);
+        if !path.exists() {
+            return None;
+        }
+        let library = Library::open(&path)?;
+
+        let identity = self.identity(block);
+        let length = library.symbol(IDENTITY_LENGTH_NAME)?.cast::<u64>();
+        // SAFETY: the library was written by `store`, which defines the
+        // identity, its length and the code size
+        if unsafe { *length } != identity.len() as u64 {
+            return None;
+        }
+        let stored = library.symbol(IDENTITY_NAME)?.cast::<u8>();
+        if unsafe { std::slice::from_raw_parts(stored, identity.len()) } != identity {
+            return None;
+        }
+        let code_size = unsafe { *library.symbol(CODE_SIZE_NAME)?.cast::<u64>() };
+
+        for (_, slot, address) in host_functions() {
+            // Libraries have a slot for every host function the block declares
+            if let Some(slot) = library.symbol(slot) {
+                unsafe { *slot.cast::<usize>() = address };
+            }
+        }
+        let fun = unsafe { *library.symbol(BLOCK_NAME)?.cast::<CompiledFunc>() };
+        Some(LoadedBlock {
+            fun,
+            _library: library,
+            code_size: code_size as usize,
+        })
+    }
+
+    /// Writes the code of the block compiled by `tbb` to the cache, for
+    /// later runs to load it. The library is written next to its final
+    /// path, then moved there, so that runs sharing the cache never load a
+    /// partial one.
+    pub fn store(&self, tbb: &TranslationContext) -> Result<(), VmError> {
+        let module = tbb.module().clone();
+        let context = module.get_context();
+        let builder = context.create_builder();
+
+        for (name, slot, _) in host_functions() {
+            let Some(function) = module.get_function(name) else {
+                continue;
+            };
+            let pointer_type = function.get_type().ptr_type(AddressSpace::default());
+            let slot = module.add_global(pointer_type, None, slot);
+            slot.set_initializer(&pointer_type.const_null());
+
+            // The declaration becomes a function calling through the slot
+            function.set_linkage(Linkage::Internal);
+            builder.position_at_end(context.append_basic_block(function, "entry"));
+            let callee = builder.build_load(slot.as_pointer_value(), "callee");
+            let callee = CallableValue::try_from(callee.into_pointer_value())
+                .expect("slots point to functions");
+            let args: Vec<BasicMetadataValueEnum> =
+                function.get_params().into_iter().map(Into::into).collect();
+            let result = builder.build_call(callee, &args, "");
+            match result.try_as_basic_value().left() {
+                Some(value) => builder.build_return(Some(&value)),
+                None => builder.build_return(None),
+            };
+        }
+
+        let function = module
+            .get_function(tbb.name())
+            .ok_or_else(|| VmError::CompilationFailed(format!("no function {}", tbb.name())))?;
+        let pointer_type = function.get_type().ptr_type(AddressSpace::default());
+        let block = module.add_global(pointer_type, None, BLOCK_NAME);
+        block.set_initializer(&function.as_global_value().as_pointer_value());
+        block.set_constant(true);
+
+        // Loaded blocks report the size of the code the JIT emitted, as the
+        // code budget and the perf map weigh them with it
+        let i64_type = context.i64_type();
+        let code_size = tbb.code_stats().code_size.unwrap_or_default();
+        let size = module.add_global(i64_type, None, CODE_SIZE_NAME);
+        size.set_initializer(&i64_type.const_int(code_size as u64, false));
+        size.set_constant(true);
+
+        let i8_type = context.i8_type();
+        let identity: Vec<_> = self
+            .identity(tbb.bytecode())
+            .into_iter()
+            .map(|byte| i8_type.const_int(byte as u64, false))
+            .collect();
+        let stored = module.add_global(
+            i8_type.array_type(identity.len() as u32),
+            None,
+            IDENTITY_NAME,
+        );
+        stored.set_initializer(&i8_type.const_array(&identity));
+        stored.set_constant(true);
+        let length = module.add_global(i64_type, None, IDENTITY_LENGTH_NAME);
+        length.set_initializer(&i64_type.const_int(identity.len() as u64, false));
+        length.set_constant(true);
+
+        let path = self.path(tbb.bytecode());
+        let partial = path.with_extension(format!("{}.so", std::process::id()));
+        let written = artifact::write_module(
+            &module,
+            self.opt_level,
+            &partial,
+            ArtifactKind::SharedLibrary,
+        )
+        .and_then(|()| {
+            fs::rename(&partial, &path)
+                .map_err(|e| VmError::Io(format!("{}: {}", path.display(), e)))
+        });
+        let _ = fs::remove_file(partial.with_extension("o"));
+        if written.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+        written
+    }
+}
+
+/// A shared library loaded with `dlopen`, unloaded when dropped.
+struct Library {
+    handle: *mut c_void,
+}
+
+impl Library {
+    fn open(path: &Path) -> Option<Self> {
+        let path = CString::new(path.to_str()?).ok()?;
+        let handle = unsafe { dlopen(path.as_ptr(), RTLD_NOW) };
+        (!handle.is_null()).then_some(Self { handle })
+    }
+
+    /// The address of the symbol `name` of the library, if defined.
+    fn symbol(&self, name: &str) -> Option<*mut c_void> {
+        let name = CString::new(name).ok()?;
+        let address = unsafe { dlsym(self.handle, name.as_ptr()) };
+        (!address.is_null()).then_some(address)
+    }
+}
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        unsafe { dlclose(self.handle) };
+    }
+}
+
+/// A block whose code was loaded from an `ObjectCache`. Its library stays
+/// loaded as long as the block, which the thread loading it keeps, see
+/// `LlvmBackend`.
+pub struct LoadedBlock {
+    fun: CompiledFunc, // Native function of the block, in the library
+    _library: Library,
+    code_size: usize, // Bytes of machine code, as measured when it was stored
+}
+
+impl LoadedBlock {
+    pub fn native_function(&self) -> CompiledFunc {
+        self.fun
+    }
+
+    /// The bytes of machine code of the block.
+    pub fn code_size(&self) -> usize {
+        self.code_size
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+
+    use std::{env, fs, process};
+
+    use crate::{program::asm, tests::init, EmulationEngine};
+
+    #[test]
+    pub fn compiled_blocks_are_cached_on_disk() {
+        init();
+        // The memory accesses call back the host through the library
+        let prog = asm::assemble(
+            "
+                LI 100
+                SETL
+            loop: LDA 0x40
+                INC3A
+                STA 0x40
+                MOV A, L
+                DECA
+                SETL
+                BNEZ loop
+                LDA 0x40
+                HALT
+            ",
+        )
+        .unwrap();
+        let dir = env::temp_dir().join(format!("vt-vm-dyn-{}-objects", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let run = || {
+            let mut vm = EmulationEngine::builder()
+                .compile_threshold(1)
+                .object_cache(&dir)
+                .build()
+                .unwrap();
+            vm.load_program(prog.clone()).unwrap();
+            vm.main_loop().unwrap();
+            vm
+        };
+
+        let first = run();
+        let libraries = fs::read_dir(&dir).unwrap().count();
+        assert!(libraries > 0);
+        let built = |vm: &EmulationEngine| {
+            let stats = vm.compile_stats();
+            assert!(!stats.is_empty());
+            stats
+                .iter()
+                .filter(|stats| stats.code.ir_instructions.is_some())
+                .count()
+        };
+        assert_eq!(built(&first), first.compile_stats().len());
+
+        // The next run loads every block rather than building it, and knows
+        // the size of its code
+        let second = run();
+        assert_eq!(built(&second), 0);
+        let sizes = |vm: &EmulationEngine| -> Vec<_> {
+            vm.compile_stats()
+                .iter()
+                .map(|stats| stats.code.code_size)
+                .collect()
+        };
+        assert_eq!(sizes(&second), sizes(&first));
+        assert!(sizes(&second)
+            .iter()
+            .all(|size| size.unwrap_or_default() > 0));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), libraries);
+        assert_eq!(second.cpu, first.cpu);
+        assert_eq!(second.cpu.acc, 300);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
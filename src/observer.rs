@@ -0,0 +1,36 @@
+use crate::{
+    cpu::{Cpu, Instruction},
+    deopt::SideExit,
+};
+
+/// The way a block has been executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tier {
+    Interpreter,
+    Baseline,
+    Native, // Code emitted by the engine's backend
+}
+
+/// Hooks invoked by the EmulationEngine while running a guest program.
+/// Every method has an empty default implementation, so observers only
+/// need to implement the events they are interested in. Observers move
+/// with the engine, which may run on another thread than the one creating
+/// it, or be shared by several threads.
+pub trait ExecutionObserver: Send + Sync {
+    /// A translation block starting at `pc` has been compiled by the backend.
+    fn on_block_compiled(&mut self, _pc: usize, _block: &[Instruction]) {}
+
+    /// A block starting at `pc` has been executed, `cpu` is the state after it.
+    fn on_block_executed(&mut self, _pc: usize, _tier: Tier, _cpu: &Cpu) {}
+
+    /// The instruction at `pc` has been interpreted. Compiled blocks do not
+    /// report their single instructions.
+    fn on_instruction(&mut self, _pc: usize, _instr: Instruction, _cpu: &Cpu) {}
+
+    /// Compiled code left through a guard, `cpu` is the state it wrote back.
+    /// The interpreter runs the next block.
+    fn on_side_exit(&mut self, _exit: SideExit, _cpu: &Cpu) {}
+
+    /// The guest executed HALT.
+    fn on_halt(&mut self, _cpu: &Cpu) {}
+}
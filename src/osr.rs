@@ -0,0 +1,30 @@
+use std::{ops::Range, sync::Arc};
+
+use crate::{backend::CompiledBlock, cpu::Instruction, mir};
+
+/// The loop closing a compiled block, compiled on its own so that the guest
+/// can enter native code at the loop header (on-stack replacement): the
+/// interpreter reaches the header with the registers of the iteration in
+/// progress, which compiled code loads from the Cpu like any block.
+pub(crate) struct OsrEntry<'ctx> {
+    pub header: usize,          // Address of the first instruction of the loop body
+    pub body: Vec<Instruction>, // The loop body followed by its BACK7
+    pub compiled: Arc<dyn CompiledBlock + 'ctx>,
+}
+
+impl OsrEntry<'_> {
+    /// The guest addresses of the loop.
+    pub fn span(&self) -> Range<usize> {
+        let length: usize = self.body.iter().map(Instruction::length).sum();
+        self.header..self.header + length
+    }
+}
+
+/// The header address and the instructions of the loop closing the block
+/// at `pc`, when the loop starts past the entry of the block. A loop
+/// starting at the entry is the block itself.
+pub(crate) fn loop_header(pc: usize, block: &[Instruction]) -> Option<(usize, &[Instruction])> {
+    let head = mir::loop_head(block).filter(|head| *head > 0)?;
+    let offset: usize = block[..head].iter().map(Instruction::length).sum();
+    Some((pc + offset, &block[head..]))
+}
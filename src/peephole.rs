@@ -0,0 +1,96 @@
+use crate::cpu::{Instruction, OpCode, OverflowMode};
+
+/// What code generation emits for an instruction of a block, once the
+/// peephole optimizer went over it. Every instruction is still retired, so
+/// that the instruction and cycle counts are the ones of the interpreter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Emit,      // The instruction as is
+    Retire,    // Nothing but moving past it: its effects are overwritten before being seen
+    Fold(i32), // Adding a constant to the accumulator, flags untouched, then retiring it
+}
+
+/// Rewrites a run of instructions about to be translated:
+///
+/// - runs of INC3A and DECA become a single constant addition followed by
+///   their last instruction, which sets the flags,
+/// - arithmetic on the accumulator clobbered by a CLRA is dropped,
+/// - SETL overwritten by a later SETL before the loop counter is read is
+///   dropped.
+///
+/// Folding only applies to wrapping arithmetic, where additions commute.
+/// Instructions that may trap are never dropped.
+pub fn optimize(block: &[Instruction], overflow_mode: OverflowMode) -> Vec<Action> {
+    let mut actions = vec![Action::Emit; block.len()];
+    let traps = |opcode: OpCode| may_trap(opcode, overflow_mode);
+
+    if overflow_mode == OverflowMode::Wrapping {
+        let mut start = 0;
+        while start < block.len() {
+            let run = block[start..]
+                .iter()
+                .take_while(|instr| matches!(instr.opcode, OpCode::INC3A | OpCode::DECA))
+                .count();
+            if run > 1 {
+                let folded = block[start..start + run - 1]
+                    .iter()
+                    .map(|instr| acc_increment(instr.opcode))
+                    .fold(0i32, i32::wrapping_add);
+                actions[start] = Action::Fold(folded);
+                actions[start + 1..start + run - 1].fill(Action::Retire);
+            }
+            start += run.max(1);
+        }
+    }
+
+    for (index, instr) in block.iter().enumerate() {
+        match instr.opcode {
+            OpCode::CLRA => {
+                // Everything CLRA overwrites: the accumulator and the flags
+                let dead = block[..index]
+                    .iter()
+                    .rev()
+                    .take_while(|instr| writes_acc_only(instr.opcode) && !traps(instr.opcode))
+                    .count();
+                actions[index - dead..index].fill(Action::Retire);
+            }
+            OpCode::SETL => {
+                // The loop counter is not read in between, nor may a trap
+                // reveal it
+                let mut next = block[index + 1..]
+                    .iter()
+                    .skip_while(|instr| writes_acc_only(instr.opcode) && !traps(instr.opcode));
+                if next
+                    .next()
+                    .is_some_and(|instr| instr.opcode == OpCode::SETL)
+                {
+                    actions[index] = Action::Retire;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    actions
+}
+
+/// Whether the instruction only writes the accumulator and the flags,
+/// without reading anything but the accumulator.
+fn writes_acc_only(opcode: OpCode) -> bool {
+    matches!(
+        opcode,
+        OpCode::INC3A | OpCode::DECA | OpCode::ADDI | OpCode::LI | OpCode::CLRA
+    )
+}
+
+fn may_trap(opcode: OpCode, overflow_mode: OverflowMode) -> bool {
+    overflow_mode == OverflowMode::Trapping
+        && matches!(opcode, OpCode::INC3A | OpCode::DECA | OpCode::ADDI)
+}
+
+fn acc_increment(opcode: OpCode) -> i32 {
+    match opcode {
+        OpCode::INC3A => 3,
+        _ => -1,
+    }
+}
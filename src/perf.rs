@@ -0,0 +1,42 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+use crate::error::VmError;
+
+/// The perf map of the process, `/tmp/perf-<pid>.map`. perf reads it to
+/// name the samples taken in code found in no binary, so that time spent
+/// in compiled blocks is attributed to the guest code they came from
+/// instead of `[unknown]`.
+pub struct PerfMap {
+    file: File,
+}
+
+impl PerfMap {
+    /// Opens the map of the process, which may be shared by many engines.
+    pub fn open() -> Result<Self, VmError> {
+        let path = Self::path();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| VmError::Io(format!("{}: {}", path.display(), e)))?;
+        Ok(Self { file })
+    }
+
+    pub fn path() -> PathBuf {
+        PathBuf::from(format!("/tmp/perf-{}.map", std::process::id()))
+    }
+
+    /// Names `size` bytes of native code at `address` after `symbol`, the
+    /// function of a block, see `backend::symbol`.
+    pub fn add(&mut self, address: usize, size: usize, symbol: &str) -> Result<(), VmError> {
+        // Lines are written at once, other engines may be appending too
+        let line = format!("{:x} {:x} {}\n", address, size, symbol);
+        self.file
+            .write_all(line.as_bytes())
+            .map_err(|e| VmError::Io(format!("{}: {}", Self::path().display(), e)))
+    }
+}
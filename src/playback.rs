@@ -0,0 +1,102 @@
+use std::fmt::Display;
+
+use crate::{
+    cpu::{Cpu, REGISTER_COUNT},
+    export::TraceEvent,
+    EmulationEngine, Outcome,
+};
+
+/// The first event of a trace an engine did not reproduce.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceMismatch {
+    pub index: usize, // Of the event in the trace
+    pub event: TraceEvent,
+    pub registers: [i32; REGISTER_COUNT], // Expected once the event completed
+    pub entered: Option<usize>,           // Where the engine started the event, if it ran it
+    pub outcome: Outcome,                 // How the engine stopped
+    pub cpu: Cpu,
+}
+
+impl Display for TraceMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "event {} expected the engine to run from {:#04x} up to {} instructions with {:?}",
+            self.index, self.event.pc, self.event.instret, self.registers
+        )?;
+        let entered = match self.entered {
+            Some(pc) => format!("{:#04x}", pc),
+            None => "nowhere".to_string(),
+        };
+        write!(
+            f,
+            "the engine ran from {} and stopped with {:?} on {:?}",
+            entered, self.outcome, self.cpu
+        )
+    }
+}
+
+/// Drives an engine through the events of an exported trace, see
+/// `export::read_trace`, e.g. to find where another version of the crate
+/// or another tier stops behaving as the one that exported it. Every event
+/// is a run of as much fuel as the instructions it covers: the engine must
+/// start it at the same pc, retire them all, and end up with the same
+/// registers. The trace must have been exported from the start of the
+/// program the engine has loaded.
+pub struct TraceReplayer {
+    events: Vec<TraceEvent>,
+    next: usize,                      // The first event not replayed
+    registers: [i32; REGISTER_COUNT], // As of the last event replayed
+}
+
+impl TraceReplayer {
+    pub fn new(events: Vec<TraceEvent>) -> Self {
+        Self {
+            events,
+            next: 0,
+            registers: [0; REGISTER_COUNT],
+        }
+    }
+
+    /// Replays the events left on `vm`, returning how the engine stopped
+    /// after the last one, or the first one it did not reproduce.
+    pub fn replay(&mut self, vm: &mut EmulationEngine) -> Result<Outcome, Box<TraceMismatch>> {
+        let mut outcome = Outcome::FuelExhausted;
+        while let Some(event) = self.events.get(self.next) {
+            for &(index, value) in &event.deltas {
+                self.registers[index] = value;
+            }
+            let fuel = event.instret.saturating_sub(vm.cpu().instret);
+
+            vm.start_recording();
+            outcome = match fuel {
+                0 => Outcome::FuelExhausted,
+                fuel => vm.run_for(fuel).unwrap_or_else(Outcome::Trapped),
+            };
+            let log = vm.stop_recording().unwrap_or_default();
+
+            let cpu = *vm.cpu();
+            let entered = log.blocks.first().map(|record| record.pc);
+            let reproduced = entered == Some(event.pc)
+                && cpu.instret == event.instret
+                && (0..REGISTER_COUNT).all(|index| cpu.register(index) == self.registers[index]);
+            if !reproduced {
+                return Err(Box::new(TraceMismatch {
+                    index: self.next,
+                    event: event.clone(),
+                    registers: self.registers,
+                    entered,
+                    outcome,
+                    cpu,
+                }));
+            }
+            self.next += 1;
+        }
+        Ok(outcome)
+    }
+
+    /// The events reproduced so far.
+    pub fn replayed(&self) -> usize {
+        self.next
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    cmp::Reverse, collections::HashMap, fmt::Display, fs, ops::Range, path::Path, str::FromStr,
+    time::Duration,
+};
+
+use crate::{error::VmError, observer::Tier};
+
+/// What the engine spent on the block at an address while profiling, over
+/// every tier that ran it. Blocks evicted from the code cache keep their
+/// profile, so that eviction shows up as repeated compilations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockProfile {
+    pub pc: usize,              // Entry point of the block
+    pub span: Range<usize>,     // Guest addresses of the block when it last ran
+    pub executions: u64,        // Runs of the block, in any tier
+    pub instructions: u64,      // Instructions retired by those runs
+    pub interpreted: Duration,  // Time spent running it in the interpreter
+    pub baseline: Duration,     // Time spent running its baseline code
+    pub native: Duration,       // Time spent running the code of the backend
+    pub compilations: u64,      // Times the block was compiled, in background too
+    pub compile_time: Duration, // Time spent compiling it
+}
+
+impl BlockProfile {
+    fn new(pc: usize) -> Self {
+        Self {
+            pc,
+            span: pc..pc,
+            executions: 0,
+            instructions: 0,
+            interpreted: Duration::ZERO,
+            baseline: Duration::ZERO,
+            native: Duration::ZERO,
+            compilations: 0,
+            compile_time: Duration::ZERO,
+        }
+    }
+
+    /// The time spent running the block, compilations excluded.
+    pub fn run_time(&self) -> Duration {
+        self.interpreted + self.baseline + self.native
+    }
+}
+
+impl Display for BlockProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:#06x}: {} executions, {} instructions in {:?} \
+             ({:?} interpreted, {:?} baseline, {:?} native), {} compilations in {:?}",
+            self.pc,
+            self.executions,
+            self.instructions,
+            self.run_time(),
+            self.interpreted,
+            self.baseline,
+            self.native,
+            self.compilations,
+            self.compile_time
+        )
+    }
+}
+
+/// The blocks run while profiling, by entry point.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    blocks: HashMap<usize, BlockProfile>,
+}
+
+impl Profile {
+    pub(crate) fn record_run(
+        &mut self,
+        span: Range<usize>,
+        tier: Tier,
+        executed: u64,
+        time: Duration,
+    ) {
+        let block = self
+            .blocks
+            .entry(span.start)
+            .or_insert_with(|| BlockProfile::new(span.start));
+        block.span = span;
+        block.executions += 1;
+        block.instructions += executed;
+        match tier {
+            Tier::Interpreter => block.interpreted += time,
+            Tier::Baseline => block.baseline += time,
+            Tier::Native => block.native += time,
+        }
+    }
+
+    pub(crate) fn record_compilation(&mut self, pc: usize, time: Duration) {
+        let block = self
+            .blocks
+            .entry(pc)
+            .or_insert_with(|| BlockProfile::new(pc));
+        block.compilations += 1;
+        block.compile_time += time;
+    }
+
+    pub fn get(&self, pc: usize) -> Option<&BlockProfile> {
+        self.blocks.get(&pc)
+    }
+
+    /// The blocks ordered by entry point.
+    pub fn blocks(&self) -> Vec<&BlockProfile> {
+        let mut blocks: Vec<&BlockProfile> = self.blocks.values().collect();
+        blocks.sort_by_key(|block| block.pc);
+        blocks
+    }
+
+    /// The `count` blocks run the most times, the slowest first on ties.
+    pub fn most_executed(&self, count: usize) -> Vec<&BlockProfile> {
+        let mut blocks = self.blocks();
+        blocks.sort_by_key(|block| Reverse((block.executions, block.run_time())));
+        blocks.truncate(count);
+        blocks
+    }
+
+    /// The `count` blocks the engine spent the most time running.
+    pub fn most_time(&self, count: usize) -> Vec<&BlockProfile> {
+        let mut blocks = self.blocks();
+        blocks.sort_by_key(|block| Reverse((block.run_time(), block.executions)));
+        blocks.truncate(count);
+        blocks
+    }
+}
+
+/// How often a conditional branch went each way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BranchCounts {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+impl BranchCounts {
+    /// The share of the runs taking the branch, if it ran at all.
+    pub fn taken_probability(&self) -> Option<f64> {
+        let total = self.taken + self.not_taken;
+        (total > 0).then(|| self.taken as f64 / total as f64)
+    }
+}
+
+/// The outcomes of the BACK7 instructions run by the interpreter, by
+/// address. Compiled loops are laid out after them, the most likely side
+/// of their backward branch being the fall-through path.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BranchProfile {
+    branches: HashMap<usize, BranchCounts>,
+}
+
+impl BranchProfile {
+    pub(crate) fn record(&mut self, pc: usize, taken: bool) {
+        let counts = self.branches.entry(pc).or_default();
+        match taken {
+            true => counts.taken += 1,
+            false => counts.not_taken += 1,
+        }
+    }
+
+    /// Adds `counts` to the outcomes of the branch at `pc`.
+    pub(crate) fn merge(&mut self, pc: usize, counts: BranchCounts) {
+        let merged = self.branches.entry(pc).or_default();
+        merged.taken += counts.taken;
+        merged.not_taken += counts.not_taken;
+    }
+
+    pub fn get(&self, pc: usize) -> Option<BranchCounts> {
+        self.branches.get(&pc).copied()
+    }
+
+    /// The branches ordered by address.
+    pub fn branches(&self) -> Vec<(usize, BranchCounts)> {
+        let mut branches: Vec<_> = self
+            .branches
+            .iter()
+            .map(|(pc, counts)| (*pc, *counts))
+            .collect();
+        branches.sort_by_key(|(pc, _)| *pc);
+        branches
+    }
+}
+
+/// The hotness gathered by a run, saved so that the next runs of the same
+/// program compile its hot blocks at startup instead of warming up again,
+/// see `EmulationEngine::warm_up`.
+///
+/// The profile is stored as text, one block or branch per line.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WarmupProfile {
+    pub blocks: Vec<(usize, u64)>, // Entry point and executions of the blocks, by entry point
+    pub branches: BranchProfile,
+}
+
+impl WarmupProfile {
+    /// Reads a profile written by `save`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, VmError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| VmError::Io(format!("{}: {}", path.display(), e)))?;
+        text.parse()
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), VmError> {
+        let path = path.as_ref();
+        fs::write(path, self.to_string())
+            .map_err(|e| VmError::Io(format!("{}: {}", path.display(), e)))
+    }
+}
+
+impl Display for WarmupProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (pc, executions) in &self.blocks {
+            writeln!(f, "block {:#06x} {}", pc, executions)?;
+        }
+        for (pc, counts) in self.branches.branches() {
+            writeln!(
+                f,
+                "branch {:#06x} {} {}",
+                pc, counts.taken, counts.not_taken
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for WarmupProfile {
+    type Err = VmError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |line: usize| VmError::InvalidProfile(format!("malformed line {}", line + 1));
+        let address = |field: &str| {
+            field
+                .strip_prefix("0x")
+                .and_then(|pc| usize::from_str_radix(pc, 16).ok())
+        };
+
+        let mut profile = Self::default();
+        for (index, line) in s.lines().enumerate() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                ["block", pc, executions] => profile.blocks.push((
+                    address(pc).ok_or_else(|| invalid(index))?,
+                    executions.parse().map_err(|_| invalid(index))?,
+                )),
+                ["branch", pc, taken, not_taken] => profile.branches.merge(
+                    address(pc).ok_or_else(|| invalid(index))?,
+                    BranchCounts {
+                        taken: taken.parse().map_err(|_| invalid(index))?,
+                        not_taken: not_taken.parse().map_err(|_| invalid(index))?,
+                    },
+                ),
+                _ => return Err(invalid(index)),
+            }
+        }
+
+        Ok(profile)
+    }
+}
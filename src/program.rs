@@ -1,7 +1,23 @@
+pub mod analysis;
+pub mod asm;
+pub mod disasm;
+pub mod generator;
+
+use std::{fs, path::Path};
+
+use crate::error::VmError;
+
+// Program files start with the magic and the version of their layout
+const MAGIC: &[u8; 4] = b"VTPG";
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 29; // Magic, version, entry point, ACC, LC and code length
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Program {
     pub data: Vec<u8>,
     pub initial_acc: i32,
     pub initial_lc: i32,
+    pub entry_point: usize, // Where execution starts, the program is loaded at 0
 }
 
 impl Program {
@@ -10,6 +26,92 @@ impl Program {
             data,
             initial_acc,
             initial_lc,
+            entry_point: 0,
         }
     }
+
+    pub fn with_entry_point(self, entry_point: usize) -> Self {
+        Self {
+            entry_point,
+            ..self
+        }
+    }
+
+    /// Encodes the program in the little-endian layout of program files:
+    /// the header, the code, then a FNV-1a checksum of both.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.data.len() + 40);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&(self.entry_point as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.initial_acc.to_le_bytes());
+        bytes.extend_from_slice(&self.initial_lc.to_le_bytes());
+        bytes.extend_from_slice(&(self.data.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes.extend_from_slice(&checksum(&bytes).to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, VmError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(VmError::InvalidProgram("not a program".to_string()));
+        }
+        if bytes.len() < HEADER_SIZE + 8 {
+            return Err(VmError::InvalidProgram("truncated program".to_string()));
+        }
+        let (content, sum) = bytes.split_at(bytes.len() - 8);
+        if checksum(content).to_le_bytes() != sum {
+            return Err(VmError::InvalidProgram("checksum mismatch".to_string()));
+        }
+        if content[4] != VERSION {
+            return Err(VmError::InvalidProgram(format!(
+                "unsupported version {}",
+                content[4]
+            )));
+        }
+
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(content[offset..offset + 8].try_into().unwrap());
+        let i32_at =
+            |offset: usize| i32::from_le_bytes(content[offset..offset + 4].try_into().unwrap());
+        let entry_point = u64_at(5);
+        let length = u64_at(21);
+        let data = &content[HEADER_SIZE..];
+        if data.len() as u64 != length {
+            return Err(VmError::InvalidProgram(format!(
+                "the code takes {} bytes instead of {}",
+                data.len(),
+                length
+            )));
+        }
+
+        Ok(Self {
+            data: data.to_vec(),
+            initial_acc: i32_at(13),
+            initial_lc: i32_at(17),
+            entry_point: usize::try_from(entry_point).map_err(|_| {
+                VmError::InvalidProgram(format!("{} does not fit the host", entry_point))
+            })?,
+        })
+    }
+
+    /// Reads a program file written by `save`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, VmError> {
+        let path = path.as_ref();
+        let bytes =
+            fs::read(path).map_err(|e| VmError::Io(format!("{}: {}", path.display(), e)))?;
+        Self::from_bytes(&bytes)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), VmError> {
+        let path = path.as_ref();
+        fs::write(path, self.to_bytes())
+            .map_err(|e| VmError::Io(format!("{}: {}", path.display(), e)))
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
 }
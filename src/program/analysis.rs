@@ -0,0 +1,218 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Range,
+};
+
+use crate::cpu::{Instruction, OpCode};
+
+use super::{
+    disasm::{self, Entry},
+    Program,
+};
+
+/// How the guest goes from a basic block to the next one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    Fallthrough, // To the next address, past a branch not taken or into a leader
+    Branch,      // To the target of a jump, taken branch or BACK7
+    Call,        // To the function called, the return going to the fallthrough
+}
+
+/// A basic block of the static control-flow graph: straight-line code
+/// starting at a leader, ended by a branch, HALT or the next leader.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub instructions: Vec<Instruction>,
+    pub successors: Vec<(usize, EdgeKind)>,
+}
+
+impl BasicBlock {
+    /// The addresses the block was decoded from.
+    pub fn span(&self) -> Range<usize> {
+        let length: usize = self.instructions.iter().map(Instruction::length).sum();
+        self.start..self.start + length
+    }
+}
+
+/// The control-flow graph of a program, built without running it: the
+/// basic blocks reachable from its entry points, by start address.
+/// Returns are not followed, the block after a call stands for them, and
+/// code only reached through computed returns is missing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cfg {
+    pub entries: Vec<usize>,
+    pub blocks: BTreeMap<usize, BasicBlock>,
+}
+
+impl Cfg {
+    /// The graph of `program`, entered at its entry point.
+    pub fn build(program: &Program) -> Self {
+        Self::from_code(&program.data, [program.entry_point])
+    }
+
+    /// The graph of `code`, loaded at 0, entered at `entries`, e.g. the
+    /// entry point, the trap handler and the timer vector.
+    pub fn from_code(code: &[u8], entries: impl IntoIterator<Item = usize>) -> Self {
+        let entries: Vec<usize> = entries.into_iter().filter(|pc| *pc < code.len()).collect();
+        let leaders = leaders(code, entries.iter().copied());
+
+        let mut blocks = BTreeMap::new();
+        for &start in &leaders {
+            let mut block = BasicBlock {
+                start,
+                instructions: Vec::new(),
+                successors: Vec::new(),
+            };
+            let mut pc = start;
+            while let Entry::Instruction(instr) = disasm::decode(&code[pc..]) {
+                block.instructions.push(instr);
+                let next = pc + instr.length();
+                if instr.opcode.is_branch() || instr.opcode == OpCode::HALT {
+                    block.successors = successors(pc, instr);
+                    break;
+                }
+                if next >= code.len() {
+                    break;
+                }
+                if leaders.contains(&next) {
+                    block.successors = vec![(next, EdgeKind::Fallthrough)];
+                    break;
+                }
+                pc = next;
+            }
+            // Leaders which do not decode trap, they get no block
+            if !block.instructions.is_empty() {
+                blocks.insert(start, block);
+            }
+        }
+        Self { entries, blocks }
+    }
+
+    /// The blocks the engine runs over the graph, by start address: the
+    /// basic blocks cut after every instruction run by the host, which ends
+    /// the code compiled for a block, and before those starting blocks.
+    pub fn engine_blocks(&self) -> Vec<(usize, &[Instruction])> {
+        let mut blocks = Vec::new();
+        for block in self.blocks.values() {
+            let mut pc = block.start;
+            for chunk in block
+                .instructions
+                .split_inclusive(|instr| instr.opcode.needs_host())
+            {
+                let (body, last) = chunk.split_at(chunk.len() - 1);
+                let chunks = match last[0].opcode.starts_block() && !body.is_empty() {
+                    true => vec![body, last],
+                    false => vec![chunk],
+                };
+                for chunk in chunks {
+                    blocks.push((pc, chunk));
+                    pc += chunk.iter().map(Instruction::length).sum::<usize>();
+                }
+            }
+        }
+        blocks
+    }
+
+    /// The block starting at `pc`.
+    pub fn block(&self, pc: usize) -> Option<&BasicBlock> {
+        self.blocks.get(&pc)
+    }
+
+    /// The starts of the blocks going to `pc`, ordered.
+    pub fn predecessors(&self, pc: usize) -> Vec<usize> {
+        self.blocks
+            .values()
+            .filter(|block| block.successors.iter().any(|(next, _)| *next == pc))
+            .map(|block| block.start)
+            .collect()
+    }
+
+    /// The graph in the DOT language of graphviz, e.g. for `dot -Tsvg`:
+    /// blocks list their instructions, entry points have a double border and
+    /// taken branches are labelled.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph cfg {\n");
+        dot += "    node [shape=box, fontname=\"monospace\"];\n";
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            let mut pc = block.start;
+            for instr in &block.instructions {
+                label += &format!("{:#06x}: {}\\l", pc, Entry::Instruction(*instr));
+                pc += instr.length();
+            }
+            let entry = match self.entries.contains(&block.start) {
+                true => ", peripheries=2",
+                false => "",
+            };
+            dot += &format!(
+                "    \"{:#06x}\" [label=\"{}\"{}];\n",
+                block.start, label, entry
+            );
+        }
+        for block in self.blocks.values() {
+            for (next, kind) in &block.successors {
+                let attributes = match kind {
+                    EdgeKind::Fallthrough => "",
+                    EdgeKind::Branch => " [label=\"taken\"]",
+                    EdgeKind::Call => " [label=\"call\", style=dashed]",
+                };
+                dot += &format!(
+                    "    \"{:#06x}\" -> \"{:#06x}\"{};\n",
+                    block.start, next, attributes
+                );
+            }
+        }
+        dot += "}\n";
+        dot
+    }
+}
+
+/// Finds the leaders of the static basic blocks of `code`, loaded at 0:
+/// the addresses starting a block, i.e. the `entries`, the targets of the
+/// branches and the addresses they fall through to. The code is followed
+/// from the entries, so that data mixed with it is never decoded, and
+/// every branch is assumed to go both ways.
+pub fn leaders(code: &[u8], entries: impl IntoIterator<Item = usize>) -> BTreeSet<usize> {
+    let mut leaders = BTreeSet::new();
+    let mut visited = BTreeSet::new();
+    let mut pending: Vec<usize> = entries.into_iter().filter(|pc| *pc < code.len()).collect();
+    leaders.extend(pending.iter().copied());
+
+    while let Some(pc) = pending.pop() {
+        if !visited.insert(pc) {
+            continue;
+        }
+        let Entry::Instruction(instr) = disasm::decode(&code[pc..]) else {
+            continue;
+        };
+
+        let successors = successors(pc, instr);
+        if instr.opcode.is_branch() {
+            leaders.extend(successors.iter().map(|(pc, _)| *pc));
+        }
+        let reachable = successors.into_iter().map(|(pc, _)| pc);
+        pending.extend(reachable.filter(|pc| *pc < code.len()));
+    }
+
+    leaders.retain(|pc| *pc < code.len());
+    leaders
+}
+
+/// The addresses the guest may run after the instruction at `pc`. Returns
+/// are left out, the addresses following calls are their successors.
+pub fn successors(pc: usize, instr: Instruction) -> Vec<(usize, EdgeKind)> {
+    let next = (pc + instr.length(), EdgeKind::Fallthrough);
+    let target = instr.operand as usize;
+    match instr.opcode {
+        OpCode::HALT | OpCode::RET => vec![],
+        OpCode::JMP => vec![(target, EdgeKind::Branch)],
+        OpCode::CALL => vec![(target, EdgeKind::Call), next],
+        OpCode::BACK7 => match pc.checked_sub(6) {
+            Some(head) => vec![(head, EdgeKind::Branch), next],
+            None => vec![next],
+        },
+        _ if instr.opcode.is_branch() => vec![(target, EdgeKind::Branch), next],
+        _ => vec![next],
+    }
+}
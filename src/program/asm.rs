@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+
+use crate::{
+    cpu::{OpCode, REGISTER_COUNT},
+    error::VmError,
+};
+
+use super::Program;
+
+/// Assembles `source` into a program.
+///
+/// Statements are separated by new lines or `;`, and `#` starts a comment
+/// running to the end of the line. A statement is either:
+/// - an instruction, its mnemonic followed by operands separated by commas,
+///   e.g. `LDA 0x80`, `ADDI -1` or `MOV R2, A`. Registers are `A`, `L` or
+///   `R0` to `R7`, addresses are numbers or labels;
+/// - a label, `loop:`, naming the address of what follows it;
+/// - `.acc n` and `.lc n`, setting the initial ACC and LC;
+/// - `.entry address`, where execution starts;
+/// - `.byte n, ...`, emitting raw bytes.
+///
+/// BACK7 may name the label it branches to, which must be 6 bytes before
+/// it: `loop: INC3A; DECA; INC3A; DECA; INC3A; DECA; BACK7 loop`.
+pub fn assemble(source: &str) -> Result<Program, VmError> {
+    let mut statements = Vec::new();
+    let mut labels = HashMap::new();
+    let mut initial_acc = 0;
+    let mut initial_lc = 0;
+    let mut entry_point = None;
+    let mut address = 0;
+
+    // First pass: place the statements, so that labels can be used before
+    // being defined
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let text = text.split('#').next().unwrap_or_default();
+        for statement in text.split(';') {
+            let mut statement = statement.trim();
+            while let Some((label, rest)) = statement.split_once(':') {
+                let label = label.trim();
+                if !is_identifier(label) {
+                    return Err(error(line, format!("invalid label `{}`", label)));
+                }
+                if labels.insert(label, address).is_some() {
+                    return Err(error(line, format!("label `{}` is defined twice", label)));
+                }
+                statement = rest.trim();
+            }
+            if statement.is_empty() {
+                continue;
+            }
+
+            // `stringify!` puts spaces between the tokens given to `vt_asm!`
+            let (directive, statement) = match statement.strip_prefix('.') {
+                Some(rest) => (true, rest.trim_start()),
+                None => (false, statement),
+            };
+            let (mnemonic, operands) = statement
+                .split_once(char::is_whitespace)
+                .map_or((statement, ""), |(mnemonic, operands)| {
+                    (mnemonic, operands.trim())
+                });
+            let operands: Vec<&str> = match operands {
+                "" => Vec::new(),
+                operands => operands.split(',').map(str::trim).collect(),
+            };
+
+            match (directive, mnemonic.to_ascii_lowercase().as_str()) {
+                (true, "acc") => initial_acc = register_value(line, &operands)?,
+                (true, "lc") => initial_lc = register_value(line, &operands)?,
+                (true, "entry") => match operands[..] {
+                    [operand] => entry_point = Some((line, operand)),
+                    _ => return Err(error(line, "expected a single address".to_string())),
+                },
+                (true, "byte") => {
+                    let bytes = operands
+                        .iter()
+                        .map(|operand| immediate(line, operand, -128, 255).map(|byte| byte as u8))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    address += bytes.len();
+                    statements.push((line, address, Statement::Bytes(bytes)));
+                }
+                (true, _) => return Err(error(line, format!("unknown directive `.{}`", mnemonic))),
+                (false, _) => {
+                    let opcode = opcode(mnemonic)
+                        .ok_or_else(|| error(line, format!("unknown mnemonic `{}`", mnemonic)))?;
+                    address += opcode.length();
+                    statements.push((line, address, Statement::Instruction(opcode, operands)));
+                }
+            }
+        }
+    }
+
+    // Second pass: encode the statements, `end` is the address following each
+    let mut data = Vec::with_capacity(address);
+    for (line, end, statement) in statements {
+        match statement {
+            Statement::Bytes(bytes) => data.extend(bytes),
+            Statement::Instruction(opcode, operands) => {
+                let pc = end - opcode.length();
+                let operand = encode(line, pc, opcode, &operands, &labels)?;
+                data.push(opcode as u8);
+                data.extend_from_slice(&operand.to_le_bytes()[..opcode.length() - 1]);
+            }
+        }
+    }
+
+    let entry_point = match entry_point {
+        Some((line, operand)) => self::address(line, operand, &labels)? as usize,
+        None => 0,
+    };
+    Ok(Program::new(data, initial_acc, initial_lc).with_entry_point(entry_point))
+}
+
+enum Statement<'a> {
+    Instruction(OpCode, Vec<&'a str>),
+    Bytes(Vec<u8>),
+}
+
+/// The operand of the instruction at `pc`, before its little-endian encoding.
+fn encode(
+    line: usize,
+    pc: usize,
+    opcode: OpCode,
+    operands: &[&str],
+    labels: &HashMap<&str, usize>,
+) -> Result<u16, VmError> {
+    let expected = match opcode {
+        _ if opcode.has_register_operands() => 2,
+        OpCode::BACK7 if operands.len() == 1 => 1,
+        _ => opcode.length().min(2) - 1,
+    };
+    if operands.len() != expected {
+        return Err(error(
+            line,
+            format!(
+                "{:?} takes {} operands, not {}",
+                opcode,
+                expected,
+                operands.len()
+            ),
+        ));
+    }
+
+    match opcode {
+        OpCode::BACK7 if expected == 1 => {
+            let target = address(line, operands[0], labels)?;
+            if pc.checked_sub(6) != Some(target as usize) {
+                return Err(error(
+                    line,
+                    format!("BACK7 at {:#04x} cannot branch to {:#04x}", pc, target),
+                ));
+            }
+            Ok(0)
+        }
+        OpCode::MOV | OpCode::ADD | OpCode::SUB => {
+            let rd = register(line, operands[0])?;
+            let rs = register(line, operands[1])?;
+            Ok((rd << 4 | rs) as u16)
+        }
+        OpCode::ADDI => Ok(immediate(line, operands[0], -128, 255)? as u8 as u16),
+        OpCode::HCALL => Ok(immediate(line, operands[0], 0, 255)? as u16),
+        OpCode::LI => Ok(immediate(line, operands[0], -32768, 65535)? as u16),
+        _ if expected == 1 => address(line, operands[0], labels),
+        _ => Ok(0),
+    }
+}
+
+fn opcode(mnemonic: &str) -> Option<OpCode> {
+    (0..=u8::MAX)
+        .map_while(|byte| OpCode::try_from(byte).ok())
+        .find(|opcode| format!("{:?}", opcode).eq_ignore_ascii_case(mnemonic))
+}
+
+fn register(line: usize, operand: &str) -> Result<usize, VmError> {
+    let index = match operand.to_ascii_uppercase().as_str() {
+        "A" | "ACC" => Some(0),
+        "L" | "LC" => Some(1),
+        name => name.strip_prefix('R').and_then(|index| index.parse().ok()),
+    };
+    index
+        .filter(|&index| index < REGISTER_COUNT)
+        .ok_or_else(|| error(line, format!("unknown register `{}`", operand)))
+}
+
+fn register_value(line: usize, operands: &[&str]) -> Result<i32, VmError> {
+    match operands {
+        [operand] => Ok(immediate(line, operand, i32::MIN as i64, i32::MAX as i64)? as i32),
+        _ => Err(error(line, "expected a single value".to_string())),
+    }
+}
+
+fn address(line: usize, operand: &str, labels: &HashMap<&str, usize>) -> Result<u16, VmError> {
+    if is_identifier(operand) {
+        let address = *labels
+            .get(operand)
+            .ok_or_else(|| error(line, format!("undefined label `{}`", operand)))?;
+        return u16::try_from(address)
+            .map_err(|_| error(line, format!("label `{}` is out of reach", operand)));
+    }
+    Ok(immediate(line, operand, 0, u16::MAX as i64)? as u16)
+}
+
+/// A decimal or `0x` prefixed hexadecimal number between `min` and `max`.
+fn immediate(line: usize, operand: &str, min: i64, max: i64) -> Result<i64, VmError> {
+    let (negative, digits) = match operand.strip_prefix('-') {
+        Some(digits) => (true, digits.trim_start()),
+        None => (false, operand),
+    };
+    let value = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    }
+    .map_err(|_| error(line, format!("invalid number `{}`", operand)))?;
+    let value = if negative { -value } else { value };
+
+    if value < min || value > max {
+        return Err(error(
+            line,
+            format!("{} does not fit between {} and {}", value, min, max),
+        ));
+    }
+    Ok(value)
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn error(line: usize, message: String) -> VmError {
+    VmError::InvalidAssembly { line, message }
+}
+
+/// Assembles a guest program written inline, e.g.
+/// `vt_asm!{ LI 3; loop: ADDI -1; BNEZ loop; HALT }`, with the syntax of
+/// `asm::assemble`. Statements must be separated by `;` and Rust comments
+/// may be used. The program is assembled when the macro runs, which panics
+/// if the source is invalid.
+#[macro_export]
+macro_rules! vt_asm {
+    ($($source:tt)*) => {
+        // `stringify!` may break long sources in lines anywhere
+        match $crate::program::asm::assemble(&stringify!($($source)*).replace('\n', " ")) {
+            Ok(program) => program,
+            Err(error) => panic!("{}", error),
+        }
+    };
+}
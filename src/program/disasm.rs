@@ -0,0 +1,110 @@
+use std::{collections::HashMap, fmt::Display};
+
+use crate::cpu::{Instruction, OpCode, REGISTER_COUNT};
+
+/// What is found at an address of a listing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entry {
+    Instruction(Instruction),
+    Byte(u8), // A byte which does not start a valid instruction
+}
+
+impl Entry {
+    pub fn length(&self) -> usize {
+        match self {
+            Entry::Instruction(instr) => instr.length(),
+            Entry::Byte(_) => 1,
+        }
+    }
+}
+
+/// Prints the entry in the syntax of `asm::assemble`, so that listings can
+/// be assembled back.
+impl Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let instr = match self {
+            Entry::Instruction(instr) => instr,
+            Entry::Byte(byte) => return write!(f, ".byte {:#04x}", byte),
+        };
+
+        write!(f, "{:?}", instr.opcode)?;
+        match instr.opcode {
+            _ if instr.opcode.has_register_operands() => {
+                let (rd, rs) = instr.registers();
+                write!(f, " {}, {}", register(rd), register(rs))
+            }
+            OpCode::ADDI => write!(f, " {}", instr.operand as u8 as i8),
+            OpCode::LI => write!(f, " {}", instr.operand as i16),
+            OpCode::HCALL => write!(f, " {}", instr.operand),
+            _ if instr.length() == 3 => write!(f, " {:#06x}", instr.operand),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn register(index: usize) -> String {
+    match index {
+        0 => "A".to_string(),
+        1 => "L".to_string(),
+        _ => format!("R{}", index),
+    }
+}
+
+/// Decodes `code`, loaded at address `base`, from its first byte on. Bytes
+/// which do not decode, e.g. data, are listed one by one.
+pub fn entries(code: &[u8], base: usize) -> Vec<(usize, Entry)> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let entry = decode(&code[offset..]);
+        entries.push((base + offset, entry));
+        offset += entry.length();
+    }
+    entries
+}
+
+pub(crate) fn decode(code: &[u8]) -> Entry {
+    let Ok(opcode) = OpCode::try_from(code[0]) else {
+        return Entry::Byte(code[0]);
+    };
+    let Some(operand) = code.get(1..opcode.length()) else {
+        return Entry::Byte(code[0]);
+    };
+
+    let mut bytes = [0; 2];
+    bytes[..operand.len()].copy_from_slice(operand);
+    let instr = Instruction::new(opcode, u16::from_le_bytes(bytes));
+    if opcode.has_register_operands() {
+        let (rd, rs) = instr.registers();
+        if rd >= REGISTER_COUNT || rs >= REGISTER_COUNT {
+            return Entry::Byte(code[0]);
+        }
+    }
+    Entry::Instruction(instr)
+}
+
+/// A listing of `code` loaded at `base`, one entry per line with its
+/// address and bytes, e.g. `0x0003: 08 ff     ADDI -1`.
+pub fn disassemble(code: &[u8], base: usize) -> String {
+    annotate(code, base, &HashMap::new())
+}
+
+/// Like `disassemble`, appending to every address found in `counts` the
+/// number of times it was executed.
+pub fn annotate(code: &[u8], base: usize, counts: &HashMap<usize, u64>) -> String {
+    let mut listing = String::new();
+    for (address, entry) in entries(code, base) {
+        let offset = address - base;
+        let bytes = code[offset..offset + entry.length()]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let line = format!("{:#06x}: {:<8}  {}", address, bytes, entry);
+        match counts.get(&address) {
+            Some(count) => listing += &format!("{:<32}# {}x\n", line, count),
+            None => listing += &format!("{}\n", line),
+        }
+    }
+    listing
+}
@@ -0,0 +1,129 @@
+use crate::cpu::OpCode;
+
+use super::Program;
+
+/// A generated program together with the state it ends in when run with
+/// wrapping arithmetic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scenario {
+    pub program: Program,
+    pub expected_acc: i32,
+    pub expected_lc: i32,
+    pub expected_instret: u64, // Instructions retired, HALT included
+}
+
+/// Generates a program of `size` opcodes between CLRA and BACK7, ending
+/// with HALT. `probabilities` are the relative weights of CLRA, INC3A, DECA,
+/// SETL and BACK7, e.g. `[5, 1, 1, 1, 1]` picks CLRA five times more often
+/// than the others.
+///
+/// The same seed and probabilities always give the same program, the one
+/// generated by the `init` function of the course's C generator. As there,
+/// a seed of zero leaves the generator unseeded: it draws one value from its
+/// initial state instead.
+///
+/// BACK7 never appears among the first 7 opcodes nor with a SETL among the
+/// 6 opcodes before it, so every loop ends.
+pub fn generate(size: usize, seed: u32, probabilities: [u32; 5]) -> Scenario {
+    assert!(size > 0, "a program holds at least HALT");
+
+    // Prefix sums of the probabilities, e.g. [5, 6, 7, 8, 9] for the above
+    let mut sums = [0; 5];
+    let mut sum = 0;
+    for (total, probability) in sums.iter_mut().zip(probabilities) {
+        sum += probability;
+        *total = sum;
+    }
+    assert!(sum > 0, "at least one opcode must be possible");
+
+    let mut rng = Rng::new(seed);
+    let initial_acc = (rng.next() & 7) as i32;
+    let initial_lc = (rng.next() & 7) as i32;
+
+    let random_opcode = |rng: &mut Rng| {
+        let value = rng.next() % sum;
+        sums.iter()
+            .take(4)
+            .take_while(|&&total| value >= total)
+            .count() as u8
+            + 1
+    };
+
+    let mut data = Vec::with_capacity(size);
+    while data.len() < size {
+        let opcode = random_opcode(&mut rng);
+        if opcode == OpCode::BACK7 as u8 {
+            let i = data.len();
+            if i < 7 {
+                continue;
+            }
+            for previous in data[i - 6..].iter_mut() {
+                if *previous == OpCode::SETL as u8 {
+                    while *previous == OpCode::SETL as u8 || *previous == OpCode::BACK7 as u8 {
+                        *previous = random_opcode(&mut rng);
+                    }
+                }
+            }
+        }
+        data.push(opcode);
+    }
+    data[size - 1] = OpCode::HALT as u8;
+
+    let (expected_acc, expected_lc, expected_instret) = expect(&data, initial_acc, initial_lc);
+    Scenario {
+        program: Program::new(data, initial_acc, initial_lc),
+        expected_acc,
+        expected_lc,
+        expected_instret,
+    }
+}
+
+/// Runs the generated opcodes on their own, without the engine: they only
+/// touch ACC and LC, and loops only go backwards.
+fn expect(data: &[u8], mut acc: i32, mut lc: i32) -> (i32, i32, u64) {
+    let mut pc = 0;
+    let mut instret = 1;
+    loop {
+        match OpCode::try_from(data[pc]).unwrap() {
+            OpCode::HALT => return (acc, lc, instret),
+            OpCode::CLRA => acc = 0,
+            OpCode::INC3A => acc = acc.wrapping_add(3),
+            OpCode::DECA => acc = acc.wrapping_sub(1),
+            OpCode::SETL => lc = acc,
+            OpCode::BACK7 => {
+                lc = lc.wrapping_sub(1);
+                if lc > 0 {
+                    pc -= 7;
+                }
+            }
+            opcode => unreachable!("{:?} is never generated", opcode),
+        }
+        pc += 1;
+        instret += 1;
+    }
+}
+
+/// The PRNG of the C generator, giving 31 bits at a time.
+struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    /// The generator after `myrand(seed)`, which only seeds it when `seed`
+    /// is not zero and draws a value otherwise.
+    fn new(seed: u32) -> Self {
+        let mut rng = Self { state: 1 };
+        match seed {
+            0 => {
+                rng.next();
+            }
+            seed => rng.state = seed,
+        }
+        rng
+    }
+
+    fn next(&mut self) -> u32 {
+        self.state = self.state.wrapping_mul(1103515245).wrapping_add(12345) & 0x7fffffff;
+        self.state
+    }
+}
@@ -0,0 +1,294 @@
+use std::{
+    sync::{
+        atomic::AtomicBool,
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread,
+};
+
+use crate::{
+    backend::{Backend, CodeStats, CompiledBlock},
+    codegen::CompiledFunc,
+    cpu::{Cpu, Instruction},
+    error::VmError,
+    memory::MemoryPort,
+    profile::BranchCounts,
+    shared::SharedCode,
+    trace::Trace,
+};
+
+/// The native code of a block compiled by a `Compiler`: its function, the
+/// name of the function and what it takes.
+pub(crate) struct Native {
+    pub fun: CompiledFunc,
+    pub name: String, // See `backend::symbol`
+    pub stats: CodeStats,
+}
+
+/// A code generator whose state cannot leave the thread creating it, like
+/// an LLVM context with its modules, run by a `CompilerThread`. It keeps
+/// what the code of a block depends on until the block is freed, by the
+/// key the block was compiled with.
+pub(crate) trait Compiler {
+    fn compile(
+        &mut self,
+        key: u64,
+        pc: usize,
+        block: &[Instruction],
+        back7: Option<BranchCounts>,
+    ) -> Result<Native, VmError>;
+
+    /// Compiles a trace recorded across blocks, see `Backend::compile_trace`.
+    fn compile_trace(&mut self, _key: u64, _trace: &Trace) -> Result<Native, VmError> {
+        Err(VmError::CompilationFailed(
+            "the backend does not compile traces".to_string(),
+        ))
+    }
+
+    /// The IR built for the block compiled with `key`, if kept.
+    fn ir_text(&self, _key: u64) -> Option<String> {
+        None
+    }
+
+    /// Frees what the code of the block compiled with `key` depends on.
+    fn free(&mut self, key: u64);
+}
+
+/// What the engine asks of a `CompilerThread`.
+pub(crate) enum Request {
+    Compile {
+        pc: usize,
+        block: Vec<Instruction>,
+        back7: Option<BranchCounts>,
+        reply: Sender<Result<(Native, u64), VmError>>,
+    },
+    CompileTrace {
+        trace: Trace,
+        reply: Sender<Result<(Native, u64), VmError>>,
+    },
+    IrText(u64, Sender<Option<String>>), // Of the block compiled with the key
+    Free(u64),                           // A block dropped by the engine, by key
+}
+
+/// A backend whose `Compiler` runs on a thread of its own, so that the
+/// engine moves to other threads while the state of the compiler stays
+/// where it was created. Blocks are handed out as their native functions,
+/// see `NativeBlock`, and the thread frees what the code of a block depends
+/// on once the block is dropped. It stops once the backend and every block
+/// it compiled are dropped, so that no code is freed while a block, e.g.
+/// one another engine got from the `SharedCache`, may still run it.
+pub struct CompilerThread {
+    name: &'static str,
+    share_key: Option<String>, // See `Backend::share_key`
+    requests: Sender<Request>,
+}
+
+/// The requests a `CompilerThread` serves, handed to the function creating
+/// its compiler.
+pub(crate) struct Requests {
+    requests: Receiver<Request>,
+    ready: Sender<Result<(), VmError>>, // Whether the compiler could be created
+}
+
+impl CompilerThread {
+    /// Spawns the thread of the backend named `name`, on which `run`
+    /// creates the compiler and serves the requests with it, see
+    /// `Requests::serve`. Fails if the compiler could not be created.
+    pub(crate) fn spawn(
+        name: &'static str,
+        share_key: Option<String>,
+        run: impl FnOnce(Requests) + Send + 'static,
+    ) -> Result<Self, VmError> {
+        let (requests, receiver) = mpsc::channel();
+        let (ready, created) = mpsc::channel();
+        thread::Builder::new()
+            .name(format!("{}-compiler", name))
+            .spawn(move || {
+                run(Requests {
+                    requests: receiver,
+                    ready,
+                })
+            })
+            .map_err(|e| VmError::JitCreationFailed(e.to_string()))?;
+        created
+            .recv()
+            .unwrap_or_else(|_| Err(VmError::JitCreationFailed(format!("{} exited", name))))?;
+        Ok(Self {
+            name,
+            share_key,
+            requests,
+        })
+    }
+
+    /// Sends `request`, built around the channel of its reply, and waits for
+    /// the reply.
+    fn ask<T>(&self, request: impl FnOnce(Sender<T>) -> Request) -> Option<T> {
+        let (reply, receiver) = mpsc::channel();
+        self.requests.send(request(reply)).ok()?;
+        receiver.recv().ok()
+    }
+
+    /// The block of `compiled`, the reply to a compilation.
+    fn block(
+        &self,
+        compiled: Option<Result<(Native, u64), VmError>>,
+    ) -> Result<NativeBlock, VmError> {
+        let gone = || VmError::CompilationFailed(format!("the {} compiler exited", self.name));
+        let (native, key) = compiled.ok_or_else(gone)??;
+        Ok(NativeBlock {
+            fun: native.fun,
+            name: native.name,
+            stats: native.stats,
+            key,
+            requests: self.requests.clone(),
+        })
+    }
+}
+
+impl Requests {
+    /// Serves the requests with `compiler` until the backend and every block
+    /// it compiled are dropped, or reports why the compiler could not be
+    /// created.
+    pub fn serve(self, compiler: Result<&mut dyn Compiler, VmError>) {
+        let compiler = match compiler {
+            Ok(compiler) => compiler,
+            Err(e) => {
+                let _ = self.ready.send(Err(e));
+                return;
+            }
+        };
+        let _ = self.ready.send(Ok(()));
+
+        let mut next_key = 0;
+        for request in self.requests {
+            match request {
+                Request::Compile {
+                    pc,
+                    block,
+                    back7,
+                    reply,
+                } => {
+                    let compiled = compiler.compile(next_key, pc, &block, back7);
+                    let _ = reply.send(compiled.map(|native| (native, next_key)));
+                    next_key += 1;
+                }
+                Request::CompileTrace { trace, reply } => {
+                    let compiled = compiler.compile_trace(next_key, &trace);
+                    let _ = reply.send(compiled.map(|native| (native, next_key)));
+                    next_key += 1;
+                }
+                Request::IrText(key, reply) => {
+                    let _ = reply.send(compiler.ir_text(key));
+                }
+                Request::Free(key) => compiler.free(key),
+            }
+        }
+    }
+}
+
+impl<'ctx> Backend<'ctx> for CompilerThread {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn compile(
+        &self,
+        pc: usize,
+        block: &[Instruction],
+    ) -> Result<Box<dyn CompiledBlock + 'ctx>, VmError> {
+        self.compile_profiled(pc, block, None)
+    }
+
+    fn compile_profiled(
+        &self,
+        pc: usize,
+        block: &[Instruction],
+        back7: Option<BranchCounts>,
+    ) -> Result<Box<dyn CompiledBlock + 'ctx>, VmError> {
+        let compiled = self.ask(|reply| Request::Compile {
+            pc,
+            block: block.to_vec(),
+            back7,
+            reply,
+        });
+        Ok(Box::new(self.block(compiled)?))
+    }
+
+    fn share_key(&self) -> Option<String> {
+        self.share_key.clone()
+    }
+
+    fn compile_shared(
+        &self,
+        pc: usize,
+        block: &[Instruction],
+        back7: Option<BranchCounts>,
+    ) -> Result<SharedCode, VmError> {
+        // The block keeps the compiler alive, and its code with it, once the
+        // engine compiling it is dropped
+        let compiled = self.ask(|reply| Request::Compile {
+            pc,
+            block: block.to_vec(),
+            back7,
+            reply,
+        });
+        Ok(Arc::new(self.block(compiled)?))
+    }
+
+    fn compile_trace(&self, trace: &Trace) -> Result<Box<dyn CompiledBlock + 'ctx>, VmError> {
+        let compiled = self.ask(|reply| Request::CompileTrace {
+            trace: trace.clone(),
+            reply,
+        });
+        Ok(Box::new(self.block(compiled)?))
+    }
+}
+
+/// A block compiled on a `CompilerThread`, whose native function runs on
+/// the thread running the engine. The compiler keeps the code and what it
+/// depends on until the block is dropped.
+pub struct NativeBlock {
+    fun: CompiledFunc,
+    name: String, // Of the native function, see `backend::symbol`
+    stats: CodeStats,
+    key: u64,                  // Of the block in the compiler
+    requests: Sender<Request>, // Of the compiler, keeping it alive
+}
+
+impl CompiledBlock for NativeBlock {
+    fn execute(
+        &self,
+        cpu: &mut Cpu,
+        memory: &mut MemoryPort,
+        budget: u64,
+        stop: &AtomicBool,
+    ) -> u64 {
+        // SAFETY: the compiler keeps the code until the block is dropped
+        unsafe { (self.fun)(cpu, (memory as *mut MemoryPort).cast(), budget, stop) }
+    }
+
+    fn code_stats(&self) -> CodeStats {
+        self.stats
+    }
+
+    fn code_address(&self) -> Option<usize> {
+        Some(self.fun as usize)
+    }
+
+    fn ir_text(&self) -> Option<String> {
+        let (reply, ir) = mpsc::channel();
+        self.requests.send(Request::IrText(self.key, reply)).ok()?;
+        ir.recv().ok().flatten()
+    }
+
+    fn symbol(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+}
+
+impl Drop for NativeBlock {
+    fn drop(&mut self) {
+        let _ = self.requests.send(Request::Free(self.key));
+    }
+}
@@ -0,0 +1,194 @@
+use std::{fmt::Display, str::FromStr};
+
+use crate::{cpu::Cpu, error::VmError};
+
+/// A block executed while recording, with the state of the Cpu after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRecord {
+    pub pc: usize,    // Entry point of the block
+    pub instret: u64, // Instructions retired once the block completed
+    pub hash: u64,    // Hash of the Cpu state once the block completed
+}
+
+/// The blocks executed by a recorded run, in order. Reads from devices and
+/// host calls are not part of the log: replays expect them to behave the
+/// same way they did while recording.
+///
+/// The log is stored as text, one block per line.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecutionLog {
+    pub blocks: Vec<BlockRecord>,
+}
+
+/// Replays a log, checking that the Cpu reaches the same states.
+pub(crate) struct Replay {
+    log: ExecutionLog,
+    next: usize, // The first record not yet compared
+}
+
+impl Replay {
+    pub fn new(log: ExecutionLog) -> Self {
+        Self { log, next: 0 }
+    }
+
+    /// Compares the state reached by the block at `pc` with the log. Tiers
+    /// split the code in different blocks, e.g. a native loop runs many
+    /// iterations at once, so only the states reached after the same number
+    /// of instructions can be compared.
+    pub fn check(&mut self, pc: usize, cpu: &Cpu) -> Result<(), VmError> {
+        let skipped = self.log.blocks[self.next..]
+            .iter()
+            .take_while(|record| record.instret < cpu.instret)
+            .count();
+        self.next += skipped;
+
+        match self.log.blocks.get(self.next) {
+            Some(record) if record.instret == cpu.instret => {
+                self.next += 1;
+                if record.hash != state_hash(cpu) {
+                    return Err(VmError::ReplayDiverged {
+                        pc,
+                        instret: cpu.instret,
+                    });
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that the replay did not stop before the recording did.
+    pub fn finish(&self, cpu: &Cpu) -> Result<(), VmError> {
+        match self.log.blocks.last() {
+            Some(record) if record.instret > cpu.instret => Err(VmError::ReplayDiverged {
+                pc: cpu.pc,
+                instret: cpu.instret,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A FNV-1a hash of the architectural state of `cpu`, stable across hosts
+/// and compilers so that logs can be replayed anywhere.
+pub fn state_hash(cpu: &Cpu) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut feed = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    };
+
+    feed(&cpu.acc.to_le_bytes());
+    feed(&cpu.lc.to_le_bytes());
+    feed(&(cpu.pc as u64).to_le_bytes());
+    feed(&[cpu.halt as u8]);
+    feed(&(cpu.sp as u64).to_le_bytes());
+    for register in cpu.gpr {
+        feed(&register.to_le_bytes());
+    }
+    feed(&[cpu.flags]);
+    feed(&cpu.instret.to_le_bytes());
+    feed(&cpu.cycles.to_le_bytes());
+    hash
+}
+
+impl Display for ExecutionLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for record in &self.blocks {
+            writeln!(
+                f,
+                "{:#06x} {} {:016x}",
+                record.pc, record.instret, record.hash
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ExecutionLog {
+    type Err = VmError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |line: usize| VmError::InvalidLog(format!("malformed line {}", line + 1));
+
+        let blocks = s
+            .lines()
+            .enumerate()
+            .map(|(index, line)| {
+                let mut fields = line.split_whitespace();
+                let (Some(pc), Some(instret), Some(hash), None) =
+                    (fields.next(), fields.next(), fields.next(), fields.next())
+                else {
+                    return Err(invalid(index));
+                };
+                Ok(BlockRecord {
+                    pc: pc
+                        .strip_prefix("0x")
+                        .and_then(|pc| usize::from_str_radix(pc, 16).ok())
+                        .ok_or_else(|| invalid(index))?,
+                    instret: instret.parse().map_err(|_| invalid(index))?,
+                    hash: u64::from_str_radix(hash, 16).map_err(|_| invalid(index))?,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { blocks })
+    }
+}
+
+/// A rolling hash of the pc, acc and lc of the guest, folded every
+/// `interval` instructions, see `EmulationEngine::start_digest`. Tiers
+/// split the code in different blocks, so blocks are cut short where the
+/// next fold is due, whichever tier runs them: the digest of a run does
+/// not depend on the backend, nor on the host, and can be compared across
+/// them instead of whole logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateDigest {
+    hash: u64,
+    interval: u64,
+    next: u64, // Instructions retired when the next fold is due
+}
+
+impl StateDigest {
+    /// A digest folding the state every `interval` instructions retired
+    /// after `instret`.
+    pub fn new(interval: u64, instret: u64) -> Result<Self, VmError> {
+        if interval == 0 {
+            return Err(VmError::InvalidConfig(
+                "the digest must be folded at least every instruction".to_string(),
+            ));
+        }
+        Ok(Self {
+            hash: 0xcbf29ce484222325,
+            interval,
+            next: instret.saturating_add(interval),
+        })
+    }
+
+    /// The digest of the states folded so far and of `cpu`, the state the
+    /// run ended on.
+    pub fn value(&self, cpu: &Cpu) -> u64 {
+        Self::fold(self.hash, cpu)
+    }
+
+    /// Folds the state of `cpu` if due, returning the instructions the
+    /// guest may run before the next fold.
+    pub(crate) fn update(&mut self, cpu: &Cpu) -> u64 {
+        if cpu.instret >= self.next {
+            self.hash = Self::fold(self.hash, cpu);
+            self.next = cpu.instret.saturating_add(self.interval);
+        }
+        self.next - cpu.instret
+    }
+
+    fn fold(mut hash: u64, cpu: &Cpu) -> u64 {
+        let words = [cpu.pc as u64, cpu.acc as u32 as u64, cpu.lc as u32 as u64];
+        for word in words {
+            hash ^= word;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        hash
+    }
+}
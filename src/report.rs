@@ -0,0 +1,194 @@
+use std::{fmt::Display, time::Duration};
+
+use crate::{backend::CodeStats, cpu::Cpu, observer::Tier};
+
+/// What the engine did during `main_loop`, for embedders that need more
+/// than the final Cpu state.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecutionReport {
+    pub interpreted: u64,        // Instructions run by the interpreter
+    pub baseline: u64,           // Instructions run by baseline blocks
+    pub native: u64,             // Instructions run by code emitted by the backend
+    pub blocks_compiled: u64,    // Blocks compiled by the backend, in background too
+    pub blocks_recompiled: u64,  // Among them, blocks compiled again at a higher level
+    pub blocks_shared: u64,      // Blocks given the code of the same instructions elsewhere
+    pub blocks_imported: u64,    // Blocks given the code another engine compiled, see `SharedCache`
+    pub blocks_precompiled: u64, // Blocks compiled, or queued, before getting hot
+    pub traces_compiled: u64,    // Traces compiled by the backend
+    pub side_exits: u64,         // Runs of compiled code left through a guard
+    pub osr_entries: u64,        // Loops entered at their header in the code of the enclosing block
+    pub compile_time: Duration,  // Time spent compiling them
+    pub cache_hits: u64,         // Blocks found in the code cache
+    pub cache_misses: u64,       // Blocks missing from the code cache, thus interpreted
+    pub wall_time: Duration,     // Time spent in `main_loop`
+    pub paced: Duration,         // Part of it slept to pace the guest, see `EngineConfig::pace`
+    pub cpu: Cpu,                // The Cpu state at the end of the run
+}
+
+impl ExecutionReport {
+    /// The instructions run by every tier.
+    pub fn instructions(&self) -> u64 {
+        self.interpreted + self.baseline + self.native
+    }
+
+    pub(crate) fn record_block(&mut self, tier: Tier, executed: u64) {
+        match tier {
+            Tier::Interpreter => self.interpreted += executed,
+            Tier::Baseline => self.baseline += executed,
+            Tier::Native => self.native += executed,
+        }
+    }
+
+    pub(crate) fn record_compilation(&mut self, time: Duration, compiled: bool) {
+        self.compile_time += time;
+        if compiled {
+            self.blocks_compiled += 1;
+        }
+    }
+}
+
+impl Display for ExecutionReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} instructions ({} interpreted, {} baseline, {} native) in {:?} ({:?} paced), \
+             {} blocks compiled ({} recompiled) in {:?}, {} blocks shared, \
+             {} blocks imported, {} blocks precompiled, \
+             {} traces compiled ({} side exits), {} OSR entries, {} cache hits, \
+             {} cache misses, {}",
+            self.instructions(),
+            self.interpreted,
+            self.baseline,
+            self.native,
+            self.wall_time,
+            self.paced,
+            self.blocks_compiled,
+            self.blocks_recompiled,
+            self.compile_time,
+            self.blocks_shared,
+            self.blocks_imported,
+            self.blocks_precompiled,
+            self.traces_compiled,
+            self.side_exits,
+            self.osr_entries,
+            self.cache_hits,
+            self.cache_misses,
+            self.cpu
+        )
+    }
+}
+
+/// How the compilation of a block went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompileStats {
+    pub pc: usize,                // Entry point of the block
+    pub instructions: usize,      // Guest instructions of the block
+    pub time: Duration,           // Wall-clock time spent by the backend
+    pub code: CodeStats,          // What the backend produced
+    pub queued: Option<Duration>, // Wait in the compile queue, for blocks compiled in background
+}
+
+/// Counts values in buckets of powers of two: the first bucket holds 0, the
+/// bucket `i` the values from `2^(i-1)` to `2^i - 1`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram {
+    buckets: Vec<u64>,
+}
+
+impl Histogram {
+    pub fn record(&mut self, value: u64) {
+        let bucket = (u64::BITS - value.leading_zeros()) as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+    }
+
+    /// The number of values recorded.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// The non-empty buckets, as the range of their values and their count.
+    pub fn buckets(&self) -> impl Iterator<Item = (std::ops::RangeInclusive<u64>, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(bucket, &count)| {
+                let range = match bucket {
+                    0 => 0..=0,
+                    _ => 1 << (bucket - 1)..=u64::MAX >> (u64::BITS as usize - bucket),
+                };
+                (range, count)
+            })
+    }
+}
+
+impl FromIterator<u64> for Histogram {
+    fn from_iter<I: IntoIterator<Item = u64>>(values: I) -> Self {
+        let mut histogram = Histogram::default();
+        values.into_iter().for_each(|value| histogram.record(value));
+        histogram
+    }
+}
+
+impl Display for Histogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (range, count) in self.buckets() {
+            writeln!(f, "{:>10}..={:<10} {}", range.start(), range.end(), count)?;
+        }
+        Ok(())
+    }
+}
+
+/// The compilations of an engine, aggregated. Backends which cannot tell
+/// the size of their code leave the matching histograms empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompileSummary {
+    pub blocks: usize,              // Blocks compiled
+    pub time: Duration,             // Total time spent compiling them
+    pub time_us: Histogram,         // Compile time per block, in microseconds
+    pub queued_us: Histogram,       // Wait in the compile queue per block, in microseconds
+    pub ir_instructions: Histogram, // IR instructions per block
+    pub code_size: Histogram,       // Bytes of machine code per block
+}
+
+impl<'a> FromIterator<&'a CompileStats> for CompileSummary {
+    fn from_iter<I: IntoIterator<Item = &'a CompileStats>>(stats: I) -> Self {
+        let mut summary = CompileSummary::default();
+        for stats in stats {
+            summary.blocks += 1;
+            summary.time += stats.time;
+            summary.time_us.record(stats.time.as_micros() as u64);
+            if let Some(queued) = stats.queued {
+                summary.queued_us.record(queued.as_micros() as u64);
+            }
+            if let Some(instructions) = stats.code.ir_instructions {
+                summary.ir_instructions.record(instructions as u64);
+            }
+            if let Some(size) = stats.code.code_size {
+                summary.code_size.record(size as u64);
+            }
+        }
+        summary
+    }
+}
+
+impl Display for CompileSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} blocks compiled in {:?}", self.blocks, self.time)?;
+        let histograms = [
+            ("compile time (us)", &self.time_us),
+            ("queue wait (us)", &self.queued_us),
+            ("IR instructions", &self.ir_instructions),
+            ("code size (bytes)", &self.code_size),
+        ];
+        for (name, histogram) in histograms {
+            if histogram.count() > 0 {
+                write!(f, "{}:\n{}", name, histogram)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::bus::MmioDevice;
+
+/// Where the random number generator is mapped unless configured otherwise.
+pub const RNG_ADDRESS: usize = 0xff04;
+
+/// Maps a random number generator, see `Rng`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RngConfig {
+    pub address: usize,
+    pub seed: u64,
+}
+
+/// A device answering every read of its only register with the next byte
+/// of a splitmix64 sequence. The sequence only depends on the seed, so an
+/// engine configured with the same one sees the same bytes on every run,
+/// replaying a recording or checked against the reference interpreter.
+/// Reads of the host peek at the next byte, writes are ignored.
+pub struct Rng {
+    state: AtomicU64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: AtomicU64::new(seed),
+        }
+    }
+
+    /// The next byte of the sequence after `state`, and the state following.
+    fn next(mut state: u64) -> (u8, u64) {
+        let byte = (splitmix64(&mut state) >> 56) as u8;
+        (byte, state)
+    }
+}
+
+impl MmioDevice for Rng {
+    fn read(&self, _offset: usize) -> u8 {
+        Self::next(self.state.load(Ordering::Relaxed)).0
+    }
+
+    fn try_read(&self, _offset: usize) -> Option<u8> {
+        let (byte, state) = Self::next(self.state.load(Ordering::Relaxed));
+        self.state.store(state, Ordering::Relaxed);
+        Some(byte)
+    }
+
+    fn write(&mut self, _offset: usize, _value: u8) {}
+}
+
+/// Advances the splitmix64 generator at `state`, returning its next value.
+pub fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,182 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::{
+    config::EngineConfig, cpu::Cpu, error::VmError, memory::Memory, program::Program,
+    EmulationEngine, Outcome,
+};
+
+/// Identifies a task of a Scheduler, numbered in the order of their spawn.
+pub type TaskId = usize;
+
+/// Where a task of a Scheduler stands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TaskState {
+    Ready,              // In the run queue, waiting for its next slice
+    Waiting,            // In the run queue too, blocked on a device until it answers
+    Suspended(Outcome), // Out of it, at a breakpoint or stopped by the host, until resumed
+    Finished(Outcome),  // Out of it for good: halted, trapped, or failed as Trapped
+}
+
+/// A snapshot of a task, as reported by `Scheduler::tasks`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub state: TaskState,
+    pub slices: u64, // Quanta the task ran so far
+    pub cpu: Cpu,
+}
+
+struct Task {
+    engine: EmulationEngine,
+    state: TaskState,
+    slices: u64,
+}
+
+/// Time-slices guest programs on the calling thread, each on an engine of
+/// its own: the tasks of the run queue take turns running `quantum`
+/// instructions, the fuel of a run, and go to the back of the queue once
+/// it is exhausted. The end of a quantum is the only preemption point, so
+/// the guest is preempted between instructions, halfway through a block
+/// if need be, and the next slice resumes it there. Tasks blocked on a
+/// device, e.g. waiting for the message of another task through a
+/// `Mailbox`, stay in the queue and try again on their next turn.
+pub struct Scheduler {
+    config: EngineConfig, // Of the engines running the programs spawned
+    quantum: u64,
+    tasks: BTreeMap<TaskId, Task>,
+    run_queue: VecDeque<TaskId>,
+    next_id: TaskId,
+}
+
+impl Scheduler {
+    pub fn new(config: EngineConfig, quantum: u64) -> Result<Self, VmError> {
+        config.validate()?;
+        if quantum == 0 {
+            return Err(VmError::InvalidConfig(
+                "tasks must run at least one instruction per slice".to_string(),
+            ));
+        }
+        Ok(Self {
+            config,
+            quantum,
+            tasks: BTreeMap::new(),
+            run_queue: VecDeque::new(),
+            next_id: 0,
+        })
+    }
+
+    /// Loads `program` on an engine configured like the scheduler, and queues
+    /// it as a new task.
+    pub fn spawn(&mut self, program: Program) -> Result<TaskId, VmError> {
+        let memory = Box::new(Memory::new(self.config.memory_size));
+        let mut engine = EmulationEngine::from_config(self.config.clone(), memory)?;
+        engine.load_program(program)?;
+        Ok(self.spawn_engine(engine))
+    }
+
+    /// Queues `engine`, with its program loaded, as a new task, e.g. to give
+    /// it host calls or devices first.
+    pub fn spawn_engine(&mut self, engine: EmulationEngine) -> TaskId {
+        let id = self.next_id;
+        self.next_id += 1;
+        let task = Task {
+            engine,
+            state: TaskState::Ready,
+            slices: 0,
+        };
+        self.tasks.insert(id, task);
+        self.run_queue.push_back(id);
+        id
+    }
+
+    /// Runs the task at the front of the run queue for a quantum, returning
+    /// its id and the state it is left in, or None if no task is ready.
+    pub fn step(&mut self) -> Option<(TaskId, TaskState)> {
+        let id = self.run_queue.pop_front()?;
+        let task = self.tasks.get_mut(&id).expect("queued tasks exist");
+        task.slices += 1;
+        task.state = match task.engine.run_for(self.quantum) {
+            Ok(Outcome::FuelExhausted) => TaskState::Ready,
+            Ok(Outcome::WaitingForMessage(_)) => TaskState::Waiting,
+            Ok(outcome @ (Outcome::Halted | Outcome::Trapped(_))) => TaskState::Finished(outcome),
+            Ok(outcome) => TaskState::Suspended(outcome),
+            Err(e) => TaskState::Finished(Outcome::Trapped(e)),
+        };
+        if matches!(task.state, TaskState::Ready | TaskState::Waiting) {
+            self.run_queue.push_back(id);
+        }
+        Some((id, task.state.clone()))
+    }
+
+    /// Runs the tasks until none is ready, or every task of the queue is
+    /// blocked and did not run an instruction since its last turn, returning
+    /// the slices run.
+    pub fn run(&mut self) -> u64 {
+        let mut slices = 0;
+        let mut idle = 0; // Slices in a row which left a task blocked where it was
+        while idle < self.run_queue.len() {
+            let Some(&id) = self.run_queue.front() else {
+                break;
+            };
+            let before = self.tasks[&id].engine.cpu().instret;
+            let Some((_, state)) = self.step() else {
+                break;
+            };
+            slices += 1;
+            let after = self.tasks[&id].engine.cpu().instret;
+            match state == TaskState::Waiting && after == before {
+                true => idle += 1,
+                false => idle = 0,
+            }
+        }
+        slices
+    }
+
+    /// Queues the suspended task `id` again. Returns whether it was
+    /// suspended.
+    pub fn resume(&mut self, id: TaskId) -> bool {
+        let Some(task) = self.tasks.get_mut(&id) else {
+            return false;
+        };
+        if !matches!(task.state, TaskState::Suspended(_)) {
+            return false;
+        }
+        task.state = TaskState::Ready;
+        self.run_queue.push_back(id);
+        true
+    }
+
+    /// Takes the task `id` out of the scheduler, returning its engine.
+    pub fn remove(&mut self, id: TaskId) -> Option<EmulationEngine> {
+        self.run_queue.retain(|queued| *queued != id);
+        self.tasks.remove(&id).map(|task| task.engine)
+    }
+
+    /// The tasks ready to run, in the order they will.
+    pub fn run_queue(&self) -> Vec<TaskId> {
+        self.run_queue.iter().copied().collect()
+    }
+
+    /// Every task of the scheduler, by id.
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        self.tasks.keys().filter_map(|id| self.task(*id)).collect()
+    }
+
+    pub fn task(&self, id: TaskId) -> Option<TaskInfo> {
+        self.tasks.get(&id).map(|task| TaskInfo {
+            id,
+            state: task.state.clone(),
+            slices: task.slices,
+            cpu: *task.engine.cpu(),
+        })
+    }
+
+    /// The engine of the task `id`, e.g. to inspect its memory.
+    pub fn engine(&self, id: TaskId) -> Option<&EmulationEngine> {
+        self.tasks.get(&id).map(|task| &task.engine)
+    }
+
+    pub fn engine_mut(&mut self, id: TaskId) -> Option<&mut EmulationEngine> {
+        self.tasks.get_mut(&id).map(|task| &mut task.engine)
+    }
+}
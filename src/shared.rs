@@ -0,0 +1,78 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use crate::{backend::CompiledBlock, cpu::Instruction};
+
+/// Code compiled for a block that any engine may run, on any thread.
+pub type SharedCode = Arc<dyn CompiledBlock>;
+
+/// The code compiled by the engines of the process which share it, see
+/// `EngineConfig::share_code`: when many engines run the same guest, e.g.
+/// to sweep its parameters, the first one compiling a block publishes its
+/// code and the others run it rather than compiling the block again.
+/// Code is found by a hash of the instructions of the block and of what
+/// else the code depends on, the backend and its options. It is kept until
+/// the cache is cleared, and freed once no engine runs it anymore.
+#[derive(Default)]
+pub struct SharedCache {
+    code: Mutex<HashMap<u64, Entry>>,
+}
+
+struct Entry {
+    key: String,                // The backend and its options, see `Backend::share_key`
+    bytecode: Vec<Instruction>, // Compared on lookups, as hashes may collide
+    code: SharedCode,
+}
+
+impl SharedCache {
+    /// The cache of the process.
+    pub fn global() -> &'static SharedCache {
+        static CACHE: OnceLock<SharedCache> = OnceLock::new();
+        CACHE.get_or_init(SharedCache::default)
+    }
+
+    /// The code compiled from `bytecode` by the backend with `key`, if any.
+    pub fn get(&self, key: &str, bytecode: &[Instruction]) -> Option<SharedCode> {
+        let code = self.code.lock().unwrap();
+        code.get(&content_hash(key, bytecode))
+            .filter(|entry| entry.key == key && entry.bytecode == bytecode)
+            .map(|entry| entry.code.clone())
+    }
+
+    /// Publishes `code`, compiled from `bytecode` by the backend with `key`.
+    pub fn insert(&self, key: &str, bytecode: &[Instruction], code: SharedCode) {
+        let entry = Entry {
+            key: key.to_string(),
+            bytecode: bytecode.to_vec(),
+            code,
+        };
+        let mut code = self.code.lock().unwrap();
+        code.insert(content_hash(key, bytecode), entry);
+    }
+
+    /// The blocks whose code is published.
+    pub fn len(&self) -> usize {
+        self.code.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets the code published so far. Engines keep running the code
+    /// they got from the cache.
+    pub fn clear(&self) {
+        self.code.lock().unwrap().clear();
+    }
+}
+
+/// The hash of the code compiled from `bytecode` by the backend with `key`.
+pub fn content_hash(key: &str, bytecode: &[Instruction]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    bytecode.hash(&mut hasher);
+    hasher.finish()
+}
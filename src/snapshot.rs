@@ -0,0 +1,138 @@
+use crate::{
+    cpu::{Cpu, Trap},
+    error::VmError,
+};
+
+// Snapshots start with the magic and the version of their layout
+const MAGIC: &[u8; 4] = b"VTVM";
+const VERSION: u8 = 1;
+
+/// The state of a guest, enough to resume it in another engine built with
+/// the same configuration. The code cache is not part of it: blocks are
+/// translated again as the restored guest runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub cpu: Cpu,
+    pub memory: Vec<u8>,
+}
+
+impl Snapshot {
+    /// Encodes the snapshot in a little-endian binary layout, the overflow
+    /// mode is left out as it belongs to the engine configuration.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let cpu = &self.cpu;
+        let mut bytes = Vec::with_capacity(self.memory.len() + 128);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+
+        bytes.extend_from_slice(&cpu.acc.to_le_bytes());
+        bytes.extend_from_slice(&cpu.lc.to_le_bytes());
+        bytes.extend_from_slice(&(cpu.pc as u64).to_le_bytes());
+        bytes.push(cpu.halt as u8);
+        bytes.extend_from_slice(&(cpu.sp as u64).to_le_bytes());
+        for register in cpu.gpr {
+            bytes.extend_from_slice(&register.to_le_bytes());
+        }
+        bytes.push(cpu.flags);
+        bytes.extend_from_slice(&cpu.instret.to_le_bytes());
+        bytes.extend_from_slice(&cpu.cycles.to_le_bytes());
+        bytes.extend_from_slice(&cpu.timecmp.to_le_bytes());
+        match cpu.trap {
+            Some(trap) => {
+                bytes.push(trap.cause as u8);
+                bytes.extend_from_slice(&(trap.pc as u64).to_le_bytes());
+            }
+            None => bytes.push(0),
+        }
+
+        bytes.extend_from_slice(&(self.memory.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.memory);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, VmError> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(VmError::InvalidSnapshot("not a snapshot".to_string()));
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(VmError::InvalidSnapshot(format!(
+                "unsupported version {}",
+                version
+            )));
+        }
+
+        let mut cpu = Cpu {
+            acc: reader.i32()?,
+            lc: reader.i32()?,
+            pc: reader.usize()?,
+            halt: reader.u8()? != 0,
+            sp: reader.usize()?,
+            ..Cpu::default()
+        };
+        for register in cpu.gpr.iter_mut() {
+            *register = reader.i32()?;
+        }
+        cpu.flags = reader.u8()?;
+        cpu.instret = reader.u64()?;
+        cpu.cycles = reader.u64()?;
+        cpu.timecmp = reader.u64()?;
+        cpu.trap = match reader.u8()? {
+            0 => None,
+            cause => Some(Trap {
+                cause: cause.try_into().map_err(|_| {
+                    VmError::InvalidSnapshot(format!("unknown trap cause {}", cause))
+                })?,
+                pc: reader.usize()?,
+            }),
+        };
+
+        let length = reader.usize()?;
+        let memory = reader.take(length)?.to_vec();
+        if !reader.bytes.is_empty() {
+            return Err(VmError::InvalidSnapshot(
+                "trailing bytes after the memory".to_string(),
+            ));
+        }
+
+        Ok(Self { cpu, memory })
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], VmError> {
+        if length > self.bytes.len() {
+            return Err(VmError::InvalidSnapshot("truncated snapshot".to_string()));
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], VmError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, VmError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn i32(&mut self) -> Result<i32, VmError> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, VmError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn usize(&mut self) -> Result<usize, VmError> {
+        let value = self.u64()?;
+        usize::try_from(value)
+            .map_err(|_| VmError::InvalidSnapshot(format!("{} does not fit the host", value)))
+    }
+}
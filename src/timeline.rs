@@ -0,0 +1,118 @@
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::{error::VmError, observer::Tier};
+
+/// What the engine was busy with during an event of the timeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activity {
+    Run(Tier),  // Running a block
+    Compile,    // Compiling a block on the engine's thread
+    Background, // Compiling a block on the compilation thread
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimelineEvent {
+    pub activity: Activity,
+    pub pc: usize,           // Entry point of the block
+    pub instructions: usize, // Instructions run or compiled
+    pub start: Duration,     // Since the timeline started
+    pub duration: Duration,
+}
+
+impl TimelineEvent {
+    pub fn name(&self) -> String {
+        let action = match self.activity {
+            Activity::Run(Tier::Interpreter) => "interpret block",
+            Activity::Run(Tier::Baseline) => "baseline exec",
+            Activity::Run(Tier::Native) => "native exec",
+            Activity::Compile | Activity::Background => "compile block",
+        };
+        format!("{} @{:#06x}", action, self.pc)
+    }
+}
+
+/// The blocks run and compiled by the engine while recording a timeline,
+/// with the time they took. Events may nest: blocks compiled before being
+/// run are part of the run.
+#[derive(Debug, Clone)]
+pub struct Timeline {
+    origin: Instant,
+    pub events: Vec<TimelineEvent>,
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Self {
+            origin: Instant::now(),
+            events: Vec::new(),
+        }
+    }
+}
+
+impl Timeline {
+    pub(crate) fn record(
+        &mut self,
+        activity: Activity,
+        pc: usize,
+        instructions: usize,
+        start: Instant,
+        duration: Duration,
+    ) {
+        self.events.push(TimelineEvent {
+            activity,
+            pc,
+            instructions,
+            start: start.saturating_duration_since(self.origin),
+            duration,
+        });
+    }
+
+    /// The timeline in the Trace Event Format read by chrome://tracing and
+    /// Perfetto. Compilations in background are shown on their own thread.
+    pub fn to_chrome_trace(&self) -> String {
+        let threads = [(1, "engine"), (2, "compiler")].map(|(tid, name)| {
+            format!(
+                "  {{\"name\": \"thread_name\", \"ph\": \"M\", \"pid\": 1, \"tid\": {}, \
+                 \"args\": {{\"name\": \"{}\"}}}}",
+                tid, name
+            )
+        });
+        let events = self.events.iter().map(|event| {
+            let (category, tid) = match event.activity {
+                Activity::Run(Tier::Interpreter) => ("interpreter", 1),
+                Activity::Run(Tier::Baseline) => ("baseline", 1),
+                Activity::Run(Tier::Native) => ("native", 1),
+                Activity::Compile => ("compile", 1),
+                Activity::Background => ("compile", 2),
+            };
+            format!(
+                "  {{\"name\": \"{}\", \"cat\": \"{}\", \"ph\": \"X\", \"ts\": {}, \"dur\": {}, \
+                 \"pid\": 1, \"tid\": {}, \"args\": {{\"pc\": {}, \"instructions\": {}}}}}",
+                event.name(),
+                category,
+                micros(event.start),
+                micros(event.duration),
+                tid,
+                event.pc,
+                event.instructions
+            )
+        });
+        let entries: Vec<String> = threads.into_iter().chain(events).collect();
+        format!("{{\"traceEvents\": [\n{}\n]}}\n", entries.join(",\n"))
+    }
+
+    /// Writes the timeline as a Chrome trace file.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), VmError> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_chrome_trace())
+            .map_err(|e| VmError::Io(format!("{}: {}", path.display(), e)))
+    }
+}
+
+/// Timestamps are in microseconds, fractions are allowed.
+fn micros(duration: Duration) -> f64 {
+    duration.as_nanos() as f64 / 1000.0
+}
@@ -0,0 +1,157 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::cpu::{Cpu, Instruction, OpCode};
+
+/// Cycles taken by the instructions missing from a CostTable.
+pub const DEFAULT_CYCLES: u64 = 1;
+
+/// A timer raising an interrupt every `period` virtual cycles, which sends
+/// the guest to the handler at `vector`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerConfig {
+    pub period: u64,
+    pub vector: u16,
+}
+
+/// The virtual cycles taken by every OpCode, which drive the cycle counter
+/// of the Cpu. Time is deterministic: it only depends on the executed
+/// instructions, never on the tier running them.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct CostTable {
+    costs: HashMap<OpCode, u64>,
+}
+
+// Lists the costs by opcode rather than in the order of the map, which
+// changes from a run to the next: caches of compiled code are keyed by it.
+impl fmt::Debug for CostTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut costs: Vec<_> = self.costs.iter().collect();
+        costs.sort_by_key(|(opcode, _)| **opcode as u8);
+        f.debug_struct("CostTable").field("costs", &costs).finish()
+    }
+}
+
+impl CostTable {
+    /// Creates a table where every instruction takes DEFAULT_CYCLES.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_cost(mut self, opcode: OpCode, cycles: u64) -> Self {
+        self.set_cost(opcode, cycles);
+        self
+    }
+
+    pub fn set_cost(&mut self, opcode: OpCode, cycles: u64) {
+        self.costs.insert(opcode, cycles);
+    }
+
+    pub fn cost(&self, opcode: OpCode) -> u64 {
+        self.costs.get(&opcode).copied().unwrap_or(DEFAULT_CYCLES)
+    }
+
+    /// The cycles taken by running `instructions` one after the other.
+    pub fn cost_of(&self, instructions: &[Instruction]) -> u64 {
+        instructions
+            .iter()
+            .map(|instr| self.cost(instr.opcode))
+            .sum()
+    }
+}
+
+/// The clock the guest reads with RDTIME, counting cycles. By default it
+/// counts the virtual ones of the Cpu, so that the time read only depends
+/// on the instructions executed before. A wall clock rather counts the
+/// cycles a processor at `frequency` hertz would have run since it was
+/// started, e.g. to pace the guest on real time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Clock {
+    wall: Option<(Instant, u64)>, // When the wall clock started, and its frequency
+}
+
+impl Clock {
+    /// A clock counting the cycles of a processor at `frequency` hertz from
+    /// now on.
+    pub fn wall(frequency: u64) -> Self {
+        Self {
+            wall: Some((Instant::now(), frequency)),
+        }
+    }
+
+    /// The time seen by the guest running on `cpu`.
+    pub fn time(&self, cpu: &Cpu) -> u64 {
+        match self.wall {
+            Some((start, frequency)) => {
+                (start.elapsed().as_nanos() * frequency as u128 / 1_000_000_000) as u64
+            }
+            None => cpu.cycles,
+        }
+    }
+}
+
+/// How far ahead of real time the guest may run before the Pacer sleeps,
+/// as sleeping for less is not precise.
+pub const PACING_SLACK: Duration = Duration::from_millis(1);
+
+/// Paces a run on real time, see `EngineConfig::pace`: the guest runs its
+/// virtual cycles at `frequency` hertz, as the processor it emulates would,
+/// the host sleeping whenever the guest gets ahead of the wall clock. A
+/// guest running slower than that is not made to catch up.
+pub struct Pacer {
+    frequency: u64,
+    start: Instant,
+    cycles: u64, // Of the Cpu when the run started
+}
+
+impl Pacer {
+    pub fn new(frequency: u64, cycles: u64) -> Self {
+        Self {
+            frequency,
+            start: Instant::now(),
+            cycles,
+        }
+    }
+
+    /// How long to sleep for the wall clock to catch up with the guest,
+    /// which ran up to `cycles`. Zero while it is less than PACING_SLACK.
+    pub fn delay(&self, cycles: u64) -> Duration {
+        let due = (cycles - self.cycles) as u128 * 1_000_000_000 / self.frequency as u128;
+        let due = Duration::from_nanos(due.min(u64::MAX as u128) as u64);
+        match due.saturating_sub(self.start.elapsed()) {
+            delay if delay < PACING_SLACK => Duration::ZERO,
+            delay => delay,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::CostTable;
+    use crate::cpu::OpCode;
+
+    #[test]
+    pub fn cost_tables_print_the_same_whatever_their_order() {
+        let opcodes = [
+            OpCode::LDA,
+            OpCode::STA,
+            OpCode::INC3A,
+            OpCode::DECA,
+            OpCode::HALT,
+        ];
+        let table = |opcodes: &mut dyn Iterator<Item = &OpCode>| {
+            opcodes.fold(CostTable::new(), |costs, &opcode| {
+                costs.with_cost(opcode, opcode as u64 + 2)
+            })
+        };
+
+        // Equal hash maps iterate in an order of their own
+        let first = table(&mut opcodes.iter());
+        let second = table(&mut opcodes.iter().rev());
+        assert_eq!(format!("{:?}", first), format!("{:?}", second));
+    }
+}
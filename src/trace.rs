@@ -0,0 +1,243 @@
+use std::{collections::HashMap, ops::Range};
+
+use crate::{
+    backend::CompiledBlock,
+    cpu::{Cpu, Handler, Instruction, OpCode, HANDLERS},
+    deopt::{DeoptReason, SideExit},
+    memory::MemoryPort,
+    timing::CostTable,
+};
+
+/// Blocks a trace spans at most, longer paths are cut there.
+pub const MAX_TRACE_BLOCKS: usize = 16;
+
+/// An instruction run while recording a trace, with where it went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceStep {
+    pub pc: usize,          // Address of the instruction
+    pub instr: Instruction, // The instruction
+    pub next: usize,        // Address run after it while recording
+}
+
+impl TraceStep {
+    /// Whether the instruction is a branch, checked by a guard when the
+    /// trace runs. Jumps always go the recorded way.
+    pub fn is_guard(&self) -> bool {
+        self.instr.opcode.is_branch() && self.instr.opcode != OpCode::JMP
+    }
+
+    /// Where the branch goes when it does not go the recorded way.
+    pub fn side_exit(&self) -> usize {
+        let fallthrough = self.pc + self.instr.length();
+        let target = match self.instr.opcode {
+            OpCode::BACK7 => self.pc.wrapping_sub(6),
+            _ => self.instr.operand as usize,
+        };
+        match self.next == fallthrough {
+            true => target,
+            false => fallthrough,
+        }
+    }
+}
+
+/// The path the guest ran from the target of a backward branch, the head
+/// of the trace, across blocks: it ends with the first backward branch,
+/// usually back to the head, or an instruction run by the host. Traces are
+/// compiled as a whole, every branch being a guard leaving the trace when
+/// it does not go the way it was recorded going.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trace {
+    pub head: usize,
+    pub steps: Vec<TraceStep>,
+}
+
+impl Trace {
+    pub fn instructions(&self) -> Vec<Instruction> {
+        self.steps.iter().map(|step| step.instr).collect()
+    }
+
+    /// Whether one of the instructions of the trace lies in `range`.
+    pub fn overlaps(&self, range: &Range<usize>) -> bool {
+        self.steps
+            .iter()
+            .any(|step| step.pc < range.end && range.start < step.pc + step.instr.length())
+    }
+
+    /// The guest addresses from the lowest instruction of the trace to the
+    /// end of the highest one.
+    pub fn span(&self) -> Range<usize> {
+        let start = self.steps.iter().map(|step| step.pc).min();
+        let end = self
+            .steps
+            .iter()
+            .map(|step| step.pc + step.instr.length())
+            .max();
+        start.unwrap_or(self.head)..end.unwrap_or(self.head)
+    }
+}
+
+/// Finds the hot loops of the guest the NET way (next executing tail):
+/// targets of backward branches are counted, and once one reached the
+/// threshold the blocks run from there are recorded into a trace.
+#[derive(Debug)]
+pub(crate) struct TraceRecorder {
+    threshold: u64,
+    heads: HashMap<usize, u64>, // Times every backward branch target was reached
+    recording: Option<(Trace, usize)>, // The trace being recorded and its blocks
+}
+
+impl TraceRecorder {
+    pub fn new(threshold: u64) -> Self {
+        Self {
+            threshold,
+            heads: HashMap::new(),
+            recording: None,
+        }
+    }
+
+    /// Follows the block at `pc` the engine just ran, `block` being its
+    /// instructions when it ran to its end, after which the guest went to
+    /// `next`. Returns the trace recorded, once complete.
+    pub fn on_block(
+        &mut self,
+        pc: usize,
+        block: Option<&[Instruction]>,
+        next: usize,
+    ) -> Option<Trace> {
+        let Some(block) = block.filter(|block| !block.is_empty()) else {
+            self.recording = None;
+            return None;
+        };
+        let expected = self
+            .recording
+            .as_ref()
+            .map(|(trace, _)| trace.steps.last().map_or(trace.head, |step| step.next));
+        if expected.is_some_and(|expected| expected != pc) {
+            self.recording = None;
+        }
+
+        // Reads of the clock start blocks, see `OpCode::starts_block`
+        if block[0].opcode.starts_block() {
+            return self
+                .recording
+                .take()
+                .filter(|(_, blocks)| *blocks > 1)
+                .map(|(trace, _)| trace);
+        }
+
+        let mut steps = Vec::with_capacity(block.len());
+        let mut address = pc;
+        for instr in block {
+            steps.push(TraceStep {
+                pc: address,
+                instr: *instr,
+                next: address + instr.length(),
+            });
+            address += instr.length();
+        }
+        let last = steps.last_mut().unwrap();
+        last.next = next;
+        let opcode = last.instr.opcode;
+        let backward = opcode.is_branch() && next <= last.pc;
+
+        if let Some((trace, blocks)) = &mut self.recording {
+            trace.steps.extend(steps);
+            *blocks += 1;
+            let ended = backward || opcode.needs_host() || opcode == OpCode::HALT;
+            if ended || *blocks == MAX_TRACE_BLOCKS {
+                // A single block gains nothing from being a trace
+                return self
+                    .recording
+                    .take()
+                    .filter(|(_, blocks)| *blocks > 1)
+                    .map(|(trace, _)| trace);
+            }
+            return None;
+        }
+
+        if backward {
+            let visits = self.heads.entry(next).or_default();
+            *visits += 1;
+            if *visits == self.threshold {
+                let trace = Trace {
+                    head: next,
+                    steps: Vec::new(),
+                };
+                self.recording = Some((trace, 0));
+            }
+        }
+        None
+    }
+
+    /// Forgets the heads in `range`, and drops the trace being recorded
+    /// when it ran code there.
+    pub fn invalidate(&mut self, range: &Range<usize>) {
+        self.heads.retain(|head, _| !range.contains(head));
+        if let Some((trace, _)) = &self.recording {
+            if trace.overlaps(range) {
+                self.recording = None;
+            }
+        }
+    }
+}
+
+/// A trace installed by the engine at its head, together with its code.
+pub(crate) struct CompiledTrace<'ctx> {
+    pub trace: Trace,
+    pub compiled: Box<dyn CompiledBlock + 'ctx>,
+}
+
+/// A trace turned into threaded code, the way the interpreter backend
+/// compiles them: guards compare the program counter with the recorded
+/// one after every branch.
+pub struct ThreadedTrace {
+    steps: Vec<(Handler, TraceStep)>,
+    cycles: Vec<u64>, // Cycles taken by the first n instructions of the trace, at index n
+}
+
+impl ThreadedTrace {
+    pub fn compile(trace: &Trace, costs: &CostTable) -> Self {
+        Self {
+            steps: trace
+                .steps
+                .iter()
+                .map(|step| (HANDLERS[step.instr.opcode as usize], *step))
+                .collect(),
+            cycles: std::iter::once(0)
+                .chain(trace.steps.iter().scan(0, |cycles, step| {
+                    *cycles += costs.cost(step.instr.opcode);
+                    Some(*cycles)
+                }))
+                .collect(),
+        }
+    }
+
+    /// Runs the trace up to its end or a side exit, returning the number of
+    /// executed instructions. A faulting instruction stops the trace and
+    /// leaves its error in `memory`.
+    pub fn execute(&self, cpu: &mut Cpu, memory: &mut MemoryPort) -> u64 {
+        let executed = self.run(cpu, memory);
+        cpu.instret += executed;
+        cpu.cycles += self.cycles[executed as usize];
+        executed
+    }
+
+    fn run(&self, cpu: &mut Cpu, memory: &mut MemoryPort) -> u64 {
+        let mut executed = 0;
+        for (handler, step) in &self.steps {
+            if let Err(e) = handler(cpu, memory, step.instr) {
+                memory.fault(e);
+                return executed;
+            }
+            executed += 1;
+            if cpu.pc != step.next {
+                memory.side_exit(SideExit {
+                    pc: cpu.pc,
+                    reason: DeoptReason::Branch,
+                });
+                break;
+            }
+        }
+        executed
+    }
+}
@@ -1,374 +1,1621 @@
-use std::cell::RefCell;
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    sync::atomic::AtomicBool,
+};
 
 use inkwell::{
     builder::Builder,
     context::Context,
     execution_engine::{ExecutionEngine, FunctionLookupError, JitFunction},
     module::Module,
-    values::{FunctionValue, PointerValue},
-    AddressSpace, OptimizationLevel,
+    passes::PassManager,
+    targets::{CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine},
+    types::IntType,
+    values::{BasicValue, FunctionValue, InstructionValue, IntValue, PointerValue},
+    AddressSpace, AtomicOrdering, OptimizationLevel,
 };
 
-use crate::cpu::{self, Cpu, OpCode};
-
-const FUNC_NAME: &str = "dbb";
+#[cfg(unix)]
+use crate::objcache::{LoadedBlock, ObjectCache};
+use crate::{
+    backend::{CodeStats, CompiledBlock},
+    codegen::{deoptimize, execute_on_host, CompiledFunc, Symbols},
+    config::{EngineConfig, Pass},
+    counted::CountedLoop,
+    cpu::{Cpu, Instruction, OpCode, OverflowMode, FLAG_NEGATIVE, FLAG_OVERFLOW, FLAG_ZERO},
+    deopt::DeoptReason,
+    error::VmError,
+    memory::MemoryPort,
+    mir::{self, ArithOp, Condition, Exit, Inst, Operand, Register},
+    profile::BranchCounts,
+    remote::{Compiler, CompilerThread, Native},
+    timing::CostTable,
+    trace::Trace,
+};
 
-type CompiledFunc = unsafe extern "C" fn(*mut cpu::Cpu) -> ();
+/// Creates the execution engine shared by the blocks translated in
+/// `context`. Each block lives in its own module, added to the engine once
+/// built and removed from it when the block is dropped.
+pub fn create_execution_engine(
+    context: &Context,
+    opt_level: OptimizationLevel,
+) -> Result<ExecutionEngine<'_>, VmError> {
+    context
+        .create_module("engine")
+        .create_jit_execution_engine(opt_level)
+        .map_err(|msg| VmError::JitCreationFailed(msg.to_string()))
+}
 
-pub struct TranslationBlock<'ctx> {
-    fun: JitFunction<'ctx, CompiledFunc>,
+// The native code is owned by the execution engine that emitted it, which
+// must outlive the block.
+pub struct TranslationBlock {
+    fun: CompiledFunc,
 }
 
-impl<'ctx> TranslationBlock<'ctx> {
-    fn new(fun: JitFunction<'ctx, CompiledFunc>) -> Self {
+impl TranslationBlock {
+    /// # Safety
+    ///
+    /// The execution engine owning `fun` must outlive the block.
+    pub(crate) unsafe fn new(fun: CompiledFunc) -> Self {
         Self { fun }
     }
 
-    pub fn execute(&self, cpu: &mut Cpu) {
-        unsafe {
-            self.fun.call(cpu);
+    pub fn execute(
+        &self,
+        cpu: &mut Cpu,
+        memory: &mut MemoryPort,
+        budget: u64,
+        stop: &AtomicBool,
+    ) -> u64 {
+        unsafe { (self.fun)(cpu, (memory as *mut MemoryPort).cast(), budget, stop) }
+    }
+}
+
+impl CompiledBlock for TranslationBlock {
+    fn execute(
+        &self,
+        cpu: &mut Cpu,
+        memory: &mut MemoryPort,
+        budget: u64,
+        stop: &AtomicBool,
+    ) -> u64 {
+        TranslationBlock::execute(self, cpu, memory, budget, stop)
+    }
+
+    fn code_address(&self) -> Option<usize> {
+        Some(self.fun as usize)
+    }
+}
+
+/// How the blocks of a backend are translated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranslationOptions {
+    pub opt_level: OptimizationLevel,
+    pub passes: Vec<Pass>, // Run over every function before handing it to the JIT
+    pub overflow_mode: OverflowMode,
+    pub costs: CostTable,
+    pub safepoint_interval: u64, // Iterations of native loops between polls of the stop flag
+}
+
+impl TranslationOptions {
+    /// The options `config` asks for.
+    pub fn from_config(config: &EngineConfig) -> Self {
+        Self {
+            opt_level: config.opt_level,
+            passes: config.llvm_passes(),
+            overflow_mode: config.overflow_mode,
+            costs: config.cost_table.clone(),
+            safepoint_interval: config.safepoint_interval,
         }
     }
 }
 
-extern "C" fn debug_cpu_state(cpu: &Cpu) {
-    log::warn!(
-        "[LLVM] :: PC: {:#04x}, ACC: {:#4}, LC: {:#4}",
-        cpu.pc,
-        cpu.acc,
-        cpu.lc
-    );
+/// Compiles blocks with LLVM, on the `CompilerThread` owning its context,
+/// see `LlvmBackend::spawn`. The translation of every block is kept until
+/// the engine drops the block.
+pub struct LlvmBackend<'ctx> {
+    context: &'ctx Context,
+    execution_engine: ExecutionEngine<'ctx>,
+    options: TranslationOptions,
+    symbols: Symbols, // Names of the functions, shared with the compilation workers
+    translations: HashMap<u64, Translation<'ctx>>, // By key of their block
+    #[cfg(unix)]
+    object_cache: Option<ObjectCache>, // Native code of the blocks compiled by previous runs
+}
+
+/// What the code of a block compiled by the `LlvmBackend` depends on.
+enum Translation<'ctx> {
+    Jit(Box<TranslationContext<'ctx>>), // Its module, in the execution engine
+    // Its library, loaded from the object cache
+    #[cfg(unix)]
+    Loaded {
+        _block: LoadedBlock,
+    },
+}
+
+impl<'ctx> LlvmBackend<'ctx> {
+    pub fn new(
+        context: &'ctx Context,
+        options: TranslationOptions,
+        symbols: Symbols,
+    ) -> Result<Self, VmError> {
+        Ok(Self {
+            context,
+            execution_engine: create_execution_engine(context, options.opt_level)?,
+            options,
+            symbols,
+            translations: HashMap::new(),
+            #[cfg(unix)]
+            object_cache: None,
+        })
+    }
+
+    /// Keeps the native code of the blocks compiled from now on in `dir`,
+    /// and loads the blocks found there rather than compiling them, see
+    /// `ObjectCache`.
+    #[cfg(unix)]
+    pub fn set_object_cache(&mut self, dir: &std::path::Path) -> Result<(), VmError> {
+        let cache = ObjectCache::new(dir, &self.options)?;
+        self.object_cache = Some(cache);
+        Ok(())
+    }
+
+    /// Keeps `tbb`, the translation of the block compiled with `key`,
+    /// returning its native code.
+    fn keep(&mut self, key: u64, tbb: TranslationContext<'ctx>) -> Native {
+        let native = Native {
+            fun: tbb.native_function().expect("compiled by the caller"),
+            name: tbb.name().to_string(),
+            stats: tbb.code_stats(),
+        };
+        self.translations
+            .insert(key, Translation::Jit(Box::new(tbb)));
+        native
+    }
+}
+
+impl LlvmBackend<'_> {
+    /// Runs the backend `config` asks for on a `CompilerThread`, which
+    /// creates its context, the functions being named in `symbols`.
+    pub(crate) fn spawn(
+        config: &EngineConfig,
+        symbols: Symbols,
+    ) -> Result<CompilerThread, VmError> {
+        let options = TranslationOptions::from_config(config);
+        #[cfg(unix)]
+        let object_cache = config.object_cache.clone();
+        let share_key = format!("{:?}", options);
+        CompilerThread::spawn("llvm", Some(share_key), move |requests| {
+            let context = Context::create();
+            let backend = LlvmBackend::new(&context, options, symbols);
+            #[cfg(unix)]
+            let backend = backend.and_then(|mut backend| {
+                if let Some(dir) = &object_cache {
+                    backend.set_object_cache(dir)?;
+                }
+                Ok(backend)
+            });
+            match backend {
+                Ok(mut backend) => requests.serve(Ok(&mut backend)),
+                Err(e) => requests.serve(Err(e)),
+            }
+        })
+    }
+}
+
+impl Compiler for LlvmBackend<'_> {
+    fn compile(
+        &mut self,
+        key: u64,
+        pc: usize,
+        block: &[Instruction],
+        back7: Option<BranchCounts>,
+    ) -> Result<Native, VmError> {
+        let name = self.symbols.next(pc);
+        // The profile only weighs the layout of the code, cached blocks are
+        // loaded whatever it is
+        #[cfg(unix)]
+        if let Some(loaded) = self
+            .object_cache
+            .as_ref()
+            .and_then(|cache| cache.load(block))
+        {
+            let native = Native {
+                fun: loaded.native_function(),
+                name,
+                stats: CodeStats {
+                    ir_instructions: None,
+                    code_size: Some(loaded.code_size()),
+                },
+            };
+            self.translations
+                .insert(key, Translation::Loaded { _block: loaded });
+            return Ok(native);
+        }
+
+        let mut tbb = TranslationContext::new(
+            self.context,
+            &self.execution_engine,
+            name,
+            block.to_vec(),
+            self.options.clone(),
+        )?;
+        tbb.set_branch_profile(back7);
+        tbb.compile_dynamic_basic_block()?;
+        #[cfg(unix)]
+        if let Some(cache) = &self.object_cache {
+            if let Err(e) = cache.store(&tbb) {
+                tracing::warn!("wasn't capable to cache the block {:#04x}: {}", pc, e);
+            }
+        }
+        Ok(self.keep(key, tbb))
+    }
+
+    fn compile_trace(&mut self, key: u64, trace: &Trace) -> Result<Native, VmError> {
+        let tbb = TranslationContext::new(
+            self.context,
+            &self.execution_engine,
+            self.symbols.next(trace.head),
+            trace.instructions(),
+            self.options.clone(),
+        )?;
+        tbb.compile_trace(trace)?;
+        Ok(self.keep(key, tbb))
+    }
+
+    fn ir_text(&self, key: u64) -> Option<String> {
+        match self.translations.get(&key)? {
+            Translation::Jit(tbb) => Some(tbb.ir_text()),
+            #[cfg(unix)]
+            Translation::Loaded { .. } => None,
+        }
+    }
+
+    fn free(&mut self, key: u64) {
+        // Removes the module of the block from the engine
+        self.translations.remove(&key);
+    }
+}
+
+/// The target machine emitting code for the host, as the JIT does.
+pub fn host_target_machine(
+    opt_level: OptimizationLevel,
+    reloc_mode: RelocMode,
+    code_model: CodeModel,
+) -> Result<TargetMachine, VmError> {
+    let failed = |msg: String| VmError::JitCreationFailed(msg);
+    Target::initialize_native(&InitializationConfig::default()).map_err(failed)?;
+    let triple = TargetMachine::get_default_triple();
+    let cpu = TargetMachine::get_host_cpu_name();
+    let features = TargetMachine::get_host_cpu_features();
+    Target::from_triple(&triple)
+        .map_err(|msg| failed(msg.to_string()))?
+        .create_target_machine(
+            &triple,
+            cpu.to_str().unwrap_or_default(),
+            features.to_str().unwrap_or_default(),
+            opt_level,
+            reloc_mode,
+            code_model,
+        )
+        .ok_or_else(|| failed(format!("no target machine for {}", triple)))
 }
 
 struct FunctionContext<'ctx> {
     function: FunctionValue<'ctx>,
-    _debug_function: FunctionValue<'ctx>,
-    _cpu_ptr: PointerValue<'ctx>,
+    host_function: FunctionValue<'ctx>,
+    deopt_function: FunctionValue<'ctx>,
+    cpu_ptr: PointerValue<'ctx>,
+    memory_ptr: PointerValue<'ctx>,
     acc_ptr: PointerValue<'ctx>,
     lc_ptr: PointerValue<'ctx>,
     pc_ptr: PointerValue<'ctx>,
     halt_ptr: PointerValue<'ctx>,
+    gpr_ptrs: Vec<PointerValue<'ctx>>, // R2 to R7, accessed in memory unlike ACC and LC
+    flags_ptr: PointerValue<'ctx>,
+    instret_ptr: PointerValue<'ctx>,
+    cycles_ptr: PointerValue<'ctx>,
+    budget: IntValue<'ctx>,
+    stop_ptr: PointerValue<'ctx>,
+    // Registers are loaded once in the prologue and carried through the
+    // block as SSA values, they are written back only in the epilogue.
+    acc: IntValue<'ctx>,
+    lc: IntValue<'ctx>,
+    pc: IntValue<'ctx>,
+    flags: IntValue<'ctx>,
+    executed: IntValue<'ctx>, // Instructions executed before the one being built
+    cycles: IntValue<'ctx>,   // Cycles taken by the instructions executed before it
+    halted: bool,
 }
 
+/// The LLVM structures of a single block, whose function is compiled by an
+/// execution engine shared with the other blocks. Dropping it removes its
+/// module from the engine and frees it, so evicting the block from the code
+/// cache frees its IR. MCJIT keeps the native code until the engine is
+/// dropped.
 pub struct TranslationContext<'ctx> {
-    pub executions: u64,
-    bytecode: Vec<OpCode>,
+    name: String, // Of the function, unique in the execution engine, see `backend::symbol`
+    bytecode: Vec<Instruction>,
+    options: TranslationOptions,
+    back7: Option<BranchCounts>, // Outcomes of the BACK7 ending the block, seen by the interpreter
     module: Module<'ctx>,
     builder: Builder<'ctx>,
     execution_engine: ExecutionEngine<'ctx>,
     fun_context: RefCell<Option<FunctionContext<'ctx>>>,
-    translation_block: RefCell<Option<TranslationBlock<'ctx>>>,
+    translation_block: RefCell<Option<TranslationBlock>>,
+    ir_instructions: Cell<usize>, // Instructions of the function handed to the JIT
 }
 
 impl<'ctx> TranslationContext<'ctx> {
-    pub fn new(context: &'ctx Context, bytecode: Vec<OpCode>) -> Self {
-        let module = context.create_module("mod");
-        let execution_engine = module
-            .create_jit_execution_engine(OptimizationLevel::Default)
-            .unwrap();
+    pub fn new(
+        context: &'ctx Context,
+        execution_engine: &ExecutionEngine<'ctx>,
+        name: String,
+        bytecode: Vec<Instruction>,
+        options: TranslationOptions,
+    ) -> Result<Self, VmError> {
+        let module = context.create_module(&name);
+        let execution_engine = execution_engine.clone();
         let builder = context.create_builder();
-        Self {
-            executions: 0,
+        Ok(Self {
+            name,
             bytecode,
+            options,
+            back7: None,
             module,
             execution_engine,
             builder,
             fun_context: RefCell::new(None),
             translation_block: RefCell::new(None),
-        }
+            ir_instructions: Cell::new(0),
+        })
+    }
+
+    pub fn bytecode(&self) -> &[Instruction] {
+        &self.bytecode
+    }
+
+    /// The name of the function of the block.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn module(&self) -> &Module<'ctx> {
+        &self.module
+    }
+
+    /// Weighs the backward branch of the loop closing the block with the
+    /// outcomes of its BACK7, when the block is compiled.
+    pub fn set_branch_profile(&mut self, back7: Option<BranchCounts>) {
+        self.back7 = back7;
+    }
+
+    pub fn instruction_count(&self) -> usize {
+        self.bytecode.len()
     }
 
     pub fn has_compiled(&self) -> bool {
         self.translation_block.borrow().is_some()
     }
 
-    pub fn execute(&self, cpu: &mut Cpu) {
+    pub(crate) fn native_function(&self) -> Option<CompiledFunc> {
+        self.translation_block.borrow().as_ref().map(|tb| tb.fun)
+    }
+
+    /// Runs the compiled block, which must not be handed a budget smaller
+    /// than its instruction count. Returns the executed instructions.
+    pub fn execute(
+        &self,
+        cpu: &mut Cpu,
+        memory: &mut MemoryPort,
+        budget: u64,
+        stop: &AtomicBool,
+    ) -> u64 {
         let tb = self.translation_block.borrow();
-        tb.as_ref().unwrap().execute(cpu);
+        tb.as_ref().unwrap().execute(cpu, memory, budget, stop)
     }
 
-    pub fn compile_dynamic_basic_block(&self) -> Result<(), String> {
-        self.setup_prologue();
+    pub fn compile_dynamic_basic_block(&self) -> Result<(), VmError> {
+        self.compile_mir(&mir::lower(&self.bytecode, self.options.overflow_mode))
+    }
 
-        self.bytecode.iter().for_each(|instr| match instr {
-            OpCode::HALT => self.halt(),
-            OpCode::CLRA => self.clra(),
-            OpCode::INC3A => self.inc3a(),
-            OpCode::DECA => self.deca(),
-            OpCode::SETL => self.setl(),
-            OpCode::BACK7 => self.back7(),
-        });
+    /// Builds the function of the block without handing it to the JIT, for
+    /// its module to be linked in another one, see `artifact`.
+    pub fn build_dynamic_basic_block(&self) -> Result<(), VmError> {
+        self.build_mir(&mir::lower(&self.bytecode, self.options.overflow_mode))
+    }
+
+    /// The LLVM IR of the module of the block, once the passes ran over it.
+    pub fn ir_text(&self) -> String {
+        self.module.print_to_string().to_string()
+    }
+
+    /// Compiles `trace`, whose instructions are the bytecode of the context.
+    pub fn compile_trace(&self, trace: &Trace) -> Result<(), VmError> {
+        self.compile_mir(&mir::lower_trace(trace, self.options.overflow_mode))
+    }
 
-        self.setup_epilogue();
+    fn compile_mir(&self, mir: &mir::Mir) -> Result<(), VmError> {
+        self.build_mir(mir)?;
+        self.execution_engine
+            .add_module(&self.module)
+            .map_err(|()| VmError::CompilationFailed("module added twice".to_string()))?;
 
-        // Print LLVM module to the stderr
-        // self.module.print_to_stderr();
+        self.jit_compile()
+            .map(|compiled_fun| {
+                // The execution engine lives as long as this context, and
+                // keeps the code of removed modules
+                let tb = unsafe { TranslationBlock::new(compiled_fun.into_raw()) };
+                self.translation_block.replace(Some(tb));
+            })
+            .map_err(|err| VmError::CompilationFailed(err.to_string()))
+    }
+
+    /// Builds the function of `mir`, verifies it and runs the passes.
+    fn build_mir(&self, mir: &mir::Mir) -> Result<(), VmError> {
+        self.setup_prologue();
+        self.build_code(&mir.code);
+
+        match &mir.exit {
+            Exit::Return => {
+                let executed = self
+                    .module
+                    .get_context()
+                    .i64_type()
+                    .const_int(self.bytecode.len() as u64, false);
+                self.setup_epilogue(executed);
+            }
+            Exit::Host(instr) => self.build_host_instruction(*instr),
+            Exit::Loop(body) => self.build_loop(body),
+            Exit::Counted(counted) => self.build_counted_loop(counted),
+        }
 
         // Verify the module's correctness before executing it.
         self.module
             .verify()
-            .map_err(|msg| format!("Function's verification failed: {}", msg.to_string()))?;
+            .map_err(|msg| VmError::VerificationFailed(msg.to_string()))?;
+        self.run_passes();
+        self.ir_instructions.set(self.count_ir_instructions());
+        Ok(())
+    }
 
-        self.jit_compile()
-            .map(|compiled_fun| {
-                self.translation_block
-                    .replace(Some(TranslationBlock::new(compiled_fun)));
+    /// The IR instructions of the block and the size of the machine code
+    /// emitted for them. MCJIT does not report what it emitted, so the size
+    /// is the one of the code LLVM generates for a copy of the module.
+    pub fn code_stats(&self) -> CodeStats {
+        CodeStats {
+            ir_instructions: Some(self.ir_instructions.get()),
+            code_size: self.emitted_code_size(),
+        }
+    }
+
+    /// Runs the configured function passes over the block, in order.
+    fn run_passes(&self) {
+        if self.options.passes.is_empty() {
+            return;
+        }
+        let Some(function) = self.module.get_function(&self.name) else {
+            return;
+        };
+
+        let pass_manager = PassManager::create(&self.module);
+        for pass in &self.options.passes {
+            match pass {
+                Pass::PromoteMemoryToRegister => pass_manager.add_promote_memory_to_register_pass(),
+                Pass::InstructionCombining => pass_manager.add_instruction_combining_pass(),
+                Pass::Reassociate => pass_manager.add_reassociate_pass(),
+                Pass::Gvn => pass_manager.add_gvn_pass(),
+                Pass::CfgSimplification => pass_manager.add_cfg_simplification_pass(),
+                Pass::LoopRotate => pass_manager.add_loop_rotate_pass(),
+                Pass::Licm => pass_manager.add_licm_pass(),
+                Pass::IndVarSimplify => pass_manager.add_ind_var_simplify_pass(),
+                Pass::LoopUnroll => pass_manager.add_loop_unroll_pass(),
+                Pass::DeadStoreElimination => pass_manager.add_dead_store_elimination_pass(),
+            }
+        }
+        pass_manager.initialize();
+        pass_manager.run_on(&function);
+        pass_manager.finalize();
+    }
+
+    fn count_ir_instructions(&self) -> usize {
+        let Some(function) = self.module.get_function(&self.name) else {
+            return 0;
+        };
+        function
+            .get_basic_blocks()
+            .into_iter()
+            .map(|bb| {
+                std::iter::successors(bb.get_first_instruction(), |instr| {
+                    instr.get_next_instruction()
+                })
+                .count()
             })
-            .map_err(|err| {
-                format!(
-                    "Something went wrong when compiling the dynamic basic block: {}",
-                    err
-                )
+            .sum()
+    }
+
+    fn emitted_code_size(&self) -> Option<usize> {
+        let opt_level = self.options.opt_level;
+        let machine =
+            host_target_machine(opt_level, RelocMode::Default, CodeModel::JITDefault).ok()?;
+        let object = machine
+            .write_to_memory_buffer(&self.module.clone(), FileType::Object)
+            .ok()?
+            .create_object_file()
+            .ok()?;
+
+        // ELF and COFF name the code section .text, Mach-O __text
+        let size = object
+            .get_sections()
+            .filter(|section| {
+                section
+                    .get_name()
+                    .is_some_and(|name| matches!(name.to_bytes(), b".text" | b"__text"))
             })
+            .map(|section| section.size() as usize)
+            .sum();
+        Some(size)
+    }
+
+    fn build_code(&self, code: &[Inst]) {
+        for inst in code {
+            self.build_inst(*inst);
+        }
+    }
+
+    fn build_inst(&self, inst: Inst) {
+        let mut fun_context = self.fun_context.borrow_mut();
+        let fun_context = fun_context.as_mut().unwrap();
+        let context = self.module.get_context();
+        let i32_type = context.i32_type();
+
+        match inst {
+            Inst::AddConst { register, value } => {
+                let value = i32_type.const_int(value as i64 as u64, true);
+                let lhs = self.read_register(fun_context, register);
+                let sum = self.builder.build_int_add(lhs, value, "");
+                self.write_register(fun_context, register, sum);
+            }
+            Inst::AddFlagged { value, origin } => self.build_add_to_acc(fun_context, origin, value),
+            Inst::Arith { op, rd, rs, origin } => {
+                let source = self.read_register(fun_context, rs);
+                let destination = self.read_register(fun_context, rd);
+                let (wrapped, overflow) = self.build_overflowing(op, destination, source);
+                let value =
+                    self.build_overflow(fun_context, origin, destination, wrapped, overflow);
+                self.write_register(fun_context, rd, value);
+            }
+            Inst::SetReg { register, value } => {
+                let value = match value {
+                    Operand::Const(value) => i32_type.const_int(value as i64 as u64, true),
+                    Operand::Register(source) => self.read_register(fun_context, source),
+                };
+                self.write_register(fun_context, register, value);
+            }
+            Inst::SetFlags(flags) => {
+                fun_context.flags = context.i8_type().const_int(flags as u64, false);
+            }
+            Inst::Next(length) => self.build_advance_program_counter(fun_context, length as u64),
+            Inst::Jump(target) => {
+                fun_context.pc = self.pc_type().const_int(target as u64, false);
+            }
+            Inst::CondBranch {
+                condition,
+                target,
+                length,
+            } => self.build_conditional_jump(fun_context, condition, target, length),
+            Inst::Guard {
+                condition,
+                expected,
+                exit,
+                next,
+                reason,
+            } => self.build_guard(fun_context, condition, expected, exit, next, reason),
+            Inst::Halt => fun_context.halted = true,
+            Inst::Retire(opcode) => self.build_count_instruction(fun_context, opcode),
+        }
+    }
+
+    /// Retires an instruction: instructions and cycles are only stored in
+    /// the epilogue.
+    fn build_count_instruction(&self, fun_context: &mut FunctionContext<'ctx>, opcode: OpCode) {
+        let i64_type = self.module.get_context().i64_type();
+        let one = i64_type.const_int(1, false);
+        let cost = i64_type.const_int(self.options.costs.cost(opcode), false);
+        fun_context.executed = self
+            .builder
+            .build_int_nuw_add(fun_context.executed, one, "");
+        fun_context.cycles = self.builder.build_int_nuw_add(fun_context.cycles, cost, "");
     }
 
     fn jit_compile(&self) -> Result<JitFunction<'ctx, CompiledFunc>, FunctionLookupError> {
-        unsafe { self.execution_engine.get_function(FUNC_NAME) }
+        unsafe { self.execution_engine.get_function(&self.name) }
     }
 
-    fn _call_debug(&self) {
+    /// Ends the block with an instruction run by the host, called after the
+    /// registers are flushed.
+    fn build_host_instruction(&self, instr: Instruction) {
         let fun_context = self.fun_context.borrow();
-        let fun_context = fun_context.as_ref().unwrap();
-        let cpu = self.builder.build_load(fun_context._cpu_ptr, "");
-        self.builder
-            .build_call(fun_context._debug_function, &[cpu.into()], "");
+        self.build_exit_to_host(fun_context.as_ref().unwrap(), instr);
     }
 
-    fn setup_prologue(&self) {
-        let i32_type = self.module.get_context().i32_type();
-        let i32_ptr_type = i32_type.ptr_type(AddressSpace::default());
-        let bool_ptr_type = self
-            .module
+    /// Leaves the block by running `instr` on the host, which either
+    /// completes it or records its fault.
+    fn build_exit_to_host(&self, fun_context: &FunctionContext<'ctx>, instr: Instruction) {
+        self.build_store_registers(fun_context);
+
+        let context = self.module.get_context();
+        let opcode = context.i8_type().const_int(instr.opcode as u64, false);
+        let operand = context.i16_type().const_int(instr.operand as u64, false);
+        let done = self
+            .builder
+            .build_call(
+                fun_context.host_function,
+                &[
+                    fun_context.cpu_ptr.into(),
+                    fun_context.memory_ptr.into(),
+                    opcode.into(),
+                    operand.into(),
+                ],
+                "done",
+            )
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_int_value();
+
+        let executed = self
+            .builder
+            .build_int_nuw_add(fun_context.executed, done, "executed");
+        let cost = context
+            .i64_type()
+            .const_int(self.options.costs.cost(instr.opcode), false);
+        let cost = self.builder.build_int_mul(done, cost, "");
+        let cycles = self
+            .builder
+            .build_int_nuw_add(fun_context.cycles, cost, "cycles");
+        self.build_retire(fun_context, executed, cycles);
+        self.builder.build_return(Some(&executed));
+    }
+
+    fn pc_type(&self) -> IntType<'ctx> {
+        // The program counter is an usize on the Rust side
+        self.module
             .get_context()
-            .bool_type()
-            .ptr_type(AddressSpace::default());
+            .ptr_sized_int_type(self.execution_engine.get_target_data(), None)
+    }
 
+    fn setup_prologue(&self) {
+        let i32_type = self.module.get_context().i32_type();
+        let pc_type = self.pc_type();
+        let i64_type = self.module.get_context().i64_type();
         let unit_type = self.module.get_context().void_type();
         let bool_type = self.module.get_context().bool_type();
 
+        // The trailing trap state and overflow mode are left out, compiled code
+        // never touches them
         let cpu_type = self.module.get_context().opaque_struct_type("struct.cpu");
         cpu_type.set_body(
             &[
                 i32_type.into(),
                 i32_type.into(),
-                i32_type.into(),
+                pc_type.into(),
                 bool_type.into(),
+                pc_type.into(),
+                i32_type.array_type(6).into(),
+                self.module.get_context().i8_type().into(),
+                i64_type.into(),
+                i64_type.into(),
+                i64_type.into(),
             ],
             false,
         );
 
         let cpu_struct_ptr_type = cpu_type.ptr_type(AddressSpace::default());
 
-        let print_fun_type = unit_type.fn_type(&[cpu_struct_ptr_type.into()], false);
-        let print_fun = self.module.add_function(
-            "debug_cpu_state",
-            print_fun_type,
+        // The MemoryPort is opaque to the generated code
+        let memory_ptr_type = self
+            .module
+            .get_context()
+            .i8_type()
+            .ptr_type(AddressSpace::default());
+        let host_fun_type = i64_type.fn_type(
+            &[
+                cpu_struct_ptr_type.into(),
+                memory_ptr_type.into(),
+                self.module.get_context().i8_type().into(),
+                self.module.get_context().i16_type().into(),
+            ],
+            false,
+        );
+        let host_fun = self.module.add_function(
+            "execute_on_host",
+            host_fun_type,
             Some(inkwell::module::Linkage::External),
         );
+        self.execution_engine
+            .add_global_mapping(&host_fun, execute_on_host as *const () as usize);
 
-        // Magic trick with execution engine
+        let deopt_fun_type = unit_type.fn_type(
+            &[
+                cpu_struct_ptr_type.into(),
+                memory_ptr_type.into(),
+                self.module.get_context().i8_type().into(),
+            ],
+            false,
+        );
+        let deopt_fun = self.module.add_function(
+            "deoptimize",
+            deopt_fun_type,
+            Some(inkwell::module::Linkage::External),
+        );
         self.execution_engine
-            .add_global_mapping(&print_fun, debug_cpu_state as usize);
+            .add_global_mapping(&deopt_fun, deoptimize as *const () as usize);
 
-        let fn_type = unit_type.fn_type(&[cpu_struct_ptr_type.into()], false);
-        let fun_val = self.module.add_function(FUNC_NAME, fn_type, None);
+        let fn_type = i64_type.fn_type(
+            &[
+                cpu_struct_ptr_type.into(),
+                memory_ptr_type.into(),
+                i64_type.into(),
+                memory_ptr_type.into(),
+            ],
+            false,
+        );
+        let fun_val = self.module.add_function(&self.name, fn_type, None);
 
         let entry_bb = self
             .module
             .get_context()
             .append_basic_block(fun_val, "entry");
-        let code_bb = self
-            .module
-            .get_context()
-            .append_basic_block(fun_val, "start");
 
         self.builder.position_at_end(entry_bb);
 
-        // Alloca struct point
-        let cpu_param = fun_val.get_first_param().unwrap().into_pointer_value();
-        let cpu_ptr = self.builder.build_alloca(cpu_struct_ptr_type, "cpu");
-
-        let acc_ptr = self.builder.build_alloca(i32_ptr_type, "acc_ptr");
-        let lc_ptr = self.builder.build_alloca(i32_ptr_type, "lc_ptr");
-        let pc_ptr = self.builder.build_alloca(i32_ptr_type, "pc_ptr");
-        let halt_ptr = self.builder.build_alloca(bool_ptr_type, "halt_ptr");
-
-        self.builder.build_store(cpu_ptr, cpu_param);
-
-        let struct_ptr = self.builder.build_load(cpu_ptr, "").into_pointer_value();
+        let cpu_ptr = fun_val.get_first_param().unwrap().into_pointer_value();
+        let memory_ptr = fun_val.get_nth_param(1).unwrap().into_pointer_value();
+        let budget = fun_val.get_nth_param(2).unwrap().into_int_value();
+        let stop_ptr = fun_val.get_nth_param(3).unwrap().into_pointer_value();
 
-        let acc_ptr_val = self.builder.build_struct_gep(struct_ptr, 0, "").unwrap();
-        self.builder.build_store(acc_ptr, acc_ptr_val);
-
-        let lc_ptr_val = self.builder.build_struct_gep(struct_ptr, 1, "").unwrap();
-        self.builder.build_store(lc_ptr, lc_ptr_val);
-
-        let pc_ptr_val = self.builder.build_struct_gep(struct_ptr, 2, "").unwrap();
-        self.builder.build_store(pc_ptr, pc_ptr_val);
-
-        let halt_ptr_val = self.builder.build_struct_gep(struct_ptr, 3, "").unwrap();
-        self.builder.build_store(halt_ptr, halt_ptr_val);
+        let acc_ptr = self
+            .builder
+            .build_struct_gep(cpu_ptr, 0, "acc_ptr")
+            .unwrap();
+        let lc_ptr = self.builder.build_struct_gep(cpu_ptr, 1, "lc_ptr").unwrap();
+        let pc_ptr = self.builder.build_struct_gep(cpu_ptr, 2, "pc_ptr").unwrap();
+        let halt_ptr = self
+            .builder
+            .build_struct_gep(cpu_ptr, 3, "halt_ptr")
+            .unwrap();
+        let gpr_ptr = self
+            .builder
+            .build_struct_gep(cpu_ptr, 5, "gpr_ptr")
+            .unwrap();
+        let gpr_ptrs = (0..6)
+            .map(|index| unsafe {
+                self.builder.build_in_bounds_gep(
+                    gpr_ptr,
+                    &[i32_type.const_zero(), i32_type.const_int(index, false)],
+                    "",
+                )
+            })
+            .collect();
+        let flags_ptr = self
+            .builder
+            .build_struct_gep(cpu_ptr, 6, "flags_ptr")
+            .unwrap();
+        let instret_ptr = self
+            .builder
+            .build_struct_gep(cpu_ptr, 7, "instret_ptr")
+            .unwrap();
+        let cycles_ptr = self
+            .builder
+            .build_struct_gep(cpu_ptr, 8, "cycles_ptr")
+            .unwrap();
 
-        self.builder.build_unconditional_branch(code_bb);
-        self.builder.position_at_end(code_bb);
+        let acc = self.builder.build_load(acc_ptr, "acc").into_int_value();
+        let lc = self.builder.build_load(lc_ptr, "lc").into_int_value();
+        let pc = self.builder.build_load(pc_ptr, "pc").into_int_value();
+        let flags = self.builder.build_load(flags_ptr, "flags").into_int_value();
 
         self.fun_context.replace(Some(FunctionContext {
             function: fun_val,
-            _cpu_ptr: cpu_ptr,
+            host_function: host_fun,
+            deopt_function: deopt_fun,
+            cpu_ptr,
+            memory_ptr,
             acc_ptr,
             lc_ptr,
             pc_ptr,
             halt_ptr,
-            _debug_function: print_fun,
+            gpr_ptrs,
+            flags_ptr,
+            instret_ptr,
+            cycles_ptr,
+            budget,
+            stop_ptr,
+            acc,
+            lc,
+            pc,
+            flags,
+            executed: i64_type.const_zero(),
+            cycles: i64_type.const_zero(),
+            halted: false,
         }));
     }
 
-    fn setup_epilogue(&self) {
-        self.builder.build_return(None);
+    fn store_registers(&self) {
+        let fun_context = self.fun_context.borrow();
+        self.build_store_registers(fun_context.as_ref().unwrap());
     }
 
-    fn build_increase_program_counter(&self) {
-        let fun_context = self.fun_context.borrow();
-        let fun_context = fun_context.as_ref().unwrap();
-        let one = self.module.get_context().i32_type().const_int(1, false);
-        let pc_ptr = self
-            .builder
-            .build_load(fun_context.pc_ptr, "")
-            .into_pointer_value();
-        let old_pc = self.builder.build_load(pc_ptr, "").into_int_value();
-        let inc_pc = self.builder.build_int_nuw_add(old_pc, one, "");
-        self.builder.build_store(pc_ptr, inc_pc);
+    fn build_store_registers(&self, fun_context: &FunctionContext<'ctx>) {
+        self.builder
+            .build_store(fun_context.acc_ptr, fun_context.acc);
+        self.builder.build_store(fun_context.lc_ptr, fun_context.lc);
+        self.builder.build_store(fun_context.pc_ptr, fun_context.pc);
+        self.builder
+            .build_store(fun_context.flags_ptr, fun_context.flags);
+        if fun_context.halted {
+            let true_val = self.module.get_context().bool_type().const_int(1, false);
+            self.builder.build_store(fun_context.halt_ptr, true_val);
+        }
     }
 
-    fn halt(&self) {
+    fn setup_epilogue(&self, executed: IntValue<'ctx>) {
+        self.store_registers();
         let fun_context = self.fun_context.borrow();
         let fun_context = fun_context.as_ref().unwrap();
-        let true_val = self.module.get_context().bool_type().const_int(1, false);
-        let halt_ptr = self
-            .builder
-            .build_load(fun_context.halt_ptr, "")
-            .into_pointer_value();
-        self.builder.build_store(halt_ptr, true_val);
-        self.build_increase_program_counter();
+        self.build_retire(fun_context, executed, fun_context.cycles);
+        self.builder.build_return(Some(&executed));
     }
 
-    fn clra(&self) {
-        let fun_context = self.fun_context.borrow();
-        let fun_context = fun_context.as_ref().unwrap();
-        let zero = self.module.get_context().i32_type().const_zero();
-        let acc_ptr = self
-            .builder
-            .build_load(fun_context.acc_ptr, "")
-            .into_pointer_value();
-        self.builder.build_store(acc_ptr, zero);
-        self.build_increase_program_counter();
+    /// Adds the instructions executed by the block to the retired ones, and
+    /// the cycles they took to the elapsed ones.
+    fn build_retire(
+        &self,
+        fun_context: &FunctionContext<'ctx>,
+        executed: IntValue<'ctx>,
+        cycles: IntValue<'ctx>,
+    ) {
+        for (ptr, value) in [
+            (fun_context.instret_ptr, executed),
+            (fun_context.cycles_ptr, cycles),
+        ] {
+            let counter = self.builder.build_load(ptr, "").into_int_value();
+            let counter = self.builder.build_int_nuw_add(counter, value, "");
+            self.builder.build_store(ptr, counter);
+        }
     }
 
-    fn inc3a(&self) {
-        let fun_context = self.fun_context.borrow();
-        let fun_context = fun_context.as_ref().unwrap();
-        let three = self.module.get_context().i32_type().const_int(3, false);
-        let acc_ptr = self
+    /// Emits the lowered loop `body` as a native loop. Before taking
+    /// the backward branch the loop checks that another iteration still fits
+    /// in the budget, that the timer interrupt is not due and, at its
+    /// safepoint, every `safepoint_interval` iterations, that the host did
+    /// not ask to stop, otherwise it leaves the Cpu at the loop head.
+    fn build_loop(&self, body: &[Inst]) {
+        let context = self.module.get_context();
+        let i32_type = context.i32_type();
+        let i64_type = context.i64_type();
+        let pc_type = self.pc_type();
+
+        // Instructions executed by an iteration, BACK7 included
+        let body_length = i64_type.const_int(mir::instructions(body) as u64 + 1, false);
+
+        let (function, cpu_ptr, acc, lc, flags, head_pc, budget, prologue_cycles) = {
+            let fun_context = self.fun_context.borrow();
+            let fun_context = fun_context.as_ref().unwrap();
+            (
+                fun_context.function,
+                fun_context.cpu_ptr,
+                fun_context.acc,
+                fun_context.lc,
+                fun_context.flags,
+                fun_context.pc,
+                fun_context.budget,
+                fun_context.cycles,
+            )
+        };
+        let prologue_executed = self.fun_context.borrow().as_ref().unwrap().executed;
+
+        // The dispatcher guarantees that the first iteration fits the budget
+        let limit = self.builder.build_int_nuw_sub(budget, body_length, "limit");
+
+        // The cycle counter in memory is only updated in the epilogue
+        let cycles_ptr = self.builder.build_struct_gep(cpu_ptr, 8, "").unwrap();
+        let timecmp_ptr = self.builder.build_struct_gep(cpu_ptr, 9, "").unwrap();
+        let entry_cycles = self.builder.build_load(cycles_ptr, "").into_int_value();
+        let timecmp = self
             .builder
-            .build_load(fun_context.acc_ptr, "")
-            .into_pointer_value();
-        let old_acc = self.builder.build_load(acc_ptr, "").into_int_value();
-        let new_acc = self.builder.build_int_nsw_add(old_acc, three, "");
-        self.builder.build_store(acc_ptr, new_acc);
-        self.build_increase_program_counter();
-    }
+            .build_load(timecmp_ptr, "timecmp")
+            .into_int_value();
+        let preheader_bb = self.builder.get_insert_block().unwrap();
+        let loop_bb = context.append_basic_block(function, "loop");
+        let exit_bb = context.append_basic_block(function, "loop.exit");
 
-    fn deca(&self) {
-        let fun_context = self.fun_context.borrow();
-        let fun_context = fun_context.as_ref().unwrap();
-        let one = self.module.get_context().i32_type().const_int(1, false);
-        let acc_ptr = self
+        self.builder.build_unconditional_branch(loop_bb);
+        self.builder.position_at_end(loop_bb);
+
+        let acc_phi = self.builder.build_phi(i32_type, "acc");
+        let lc_phi = self.builder.build_phi(i32_type, "lc");
+        let flags_phi = self.builder.build_phi(context.i8_type(), "flags");
+        let executed_phi = self.builder.build_phi(i64_type, "executed");
+        let cycles_phi = self.builder.build_phi(i64_type, "cycles");
+        // Iterations left before the safepoint, when it is not every one
+        let interval = self.options.safepoint_interval;
+        let interval_value = i64_type.const_int(interval, false);
+        let countdown_phi = (interval > 1).then(|| {
+            let countdown_phi = self.builder.build_phi(i64_type, "countdown");
+            countdown_phi.add_incoming(&[(&interval_value, preheader_bb)]);
+            countdown_phi
+        });
+        acc_phi.add_incoming(&[(&acc, preheader_bb)]);
+        lc_phi.add_incoming(&[(&lc, preheader_bb)]);
+        flags_phi.add_incoming(&[(&flags, preheader_bb)]);
+        executed_phi.add_incoming(&[(&prologue_executed, preheader_bb)]);
+        cycles_phi.add_incoming(&[(&prologue_cycles, preheader_bb)]);
+
+        {
+            let mut fun_context = self.fun_context.borrow_mut();
+            let fun_context = fun_context.as_mut().unwrap();
+            fun_context.acc = acc_phi.as_basic_value().into_int_value();
+            fun_context.lc = lc_phi.as_basic_value().into_int_value();
+            fun_context.flags = flags_phi.as_basic_value().into_int_value();
+            fun_context.executed = executed_phi.as_basic_value().into_int_value();
+            fun_context.cycles = cycles_phi.as_basic_value().into_int_value();
+            fun_context.pc = head_pc;
+        }
+
+        self.build_code(body);
+
+        let mut guard = self.fun_context.borrow_mut();
+        let fun_context = guard.as_mut().unwrap();
+
+        // BACK7, with the backward branch turned into the loop latch
+        let one = i32_type.const_int(1, false);
+        fun_context.lc = self.builder.build_int_sub(fun_context.lc, one, "");
+        let executed = self.builder.build_int_nuw_add(
+            executed_phi.as_basic_value().into_int_value(),
+            body_length,
+            "",
+        );
+        let back7_cost = i64_type.const_int(self.options.costs.cost(OpCode::BACK7), false);
+        fun_context.cycles = self
             .builder
-            .build_load(fun_context.acc_ptr, "")
-            .into_pointer_value();
-        let old_acc = self.builder.build_load(acc_ptr, "").into_int_value();
-        let new_acc = self.builder.build_int_nsw_sub(old_acc, one, "");
-        self.builder.build_store(acc_ptr, new_acc);
-        self.build_increase_program_counter();
-    }
+            .build_int_nuw_add(fun_context.cycles, back7_cost, "");
 
-    fn setl(&self) {
-        let fun_context = self.fun_context.borrow();
-        let fun_context = fun_context.as_ref().unwrap();
-        let acc_ptr = self
+        let taken = self.builder.build_int_compare(
+            inkwell::IntPredicate::SGT,
+            fun_context.lc,
+            i32_type.const_zero(),
+            "",
+        );
+        let fits = self
+            .builder
+            .build_int_compare(inkwell::IntPredicate::ULE, executed, limit, "");
+        let now = self
             .builder
-            .build_load(fun_context.acc_ptr, "")
-            .into_pointer_value();
-        let acc_val = self.builder.build_load(acc_ptr, "").into_int_value();
-        let lc_ptr = self
+            .build_int_nuw_add(entry_cycles, fun_context.cycles, "");
+        let on_time = self
             .builder
-            .build_load(fun_context.lc_ptr, "")
-            .into_pointer_value();
-        self.builder.build_store(lc_ptr, acc_val);
-        self.build_increase_program_counter();
+            .build_int_compare(inkwell::IntPredicate::ULT, now, timecmp, "");
+        let again = self.builder.build_and(taken, fits, "");
+        let again = self.builder.build_and(again, on_time, "");
+
+        let back_edges = match countdown_phi {
+            None => {
+                let running = self.build_stop_poll(fun_context.stop_ptr);
+                let again = self.builder.build_and(again, running, "");
+                let latch_bb = self.builder.get_insert_block().unwrap();
+                let latch = self
+                    .builder
+                    .build_conditional_branch(again, loop_bb, exit_bb);
+                if let Some(back7) = self.back7 {
+                    self.set_branch_weights(latch, back7);
+                }
+                vec![latch_bb]
+            }
+            Some(countdown_phi) => {
+                let countdown = self.builder.build_int_sub(
+                    countdown_phi.as_basic_value().into_int_value(),
+                    i64_type.const_int(1, false),
+                    "countdown",
+                );
+                let check_bb = context.append_basic_block(function, "loop.check");
+                let safepoint_bb = context.append_basic_block(function, "loop.safepoint");
+                let latch = self
+                    .builder
+                    .build_conditional_branch(again, check_bb, exit_bb);
+                if let Some(back7) = self.back7 {
+                    self.set_branch_weights(latch, back7);
+                }
+
+                self.builder.position_at_end(check_bb);
+                let due = self.builder.build_int_compare(
+                    inkwell::IntPredicate::EQ,
+                    countdown,
+                    i64_type.const_zero(),
+                    "",
+                );
+                self.builder
+                    .build_conditional_branch(due, safepoint_bb, loop_bb);
+
+                self.builder.position_at_end(safepoint_bb);
+                let running = self.build_stop_poll(fun_context.stop_ptr);
+                self.builder
+                    .build_conditional_branch(running, loop_bb, exit_bb);
+                countdown_phi
+                    .add_incoming(&[(&countdown, check_bb), (&interval_value, safepoint_bb)]);
+                vec![check_bb, safepoint_bb]
+            }
+        };
+        for back_edge in back_edges {
+            acc_phi.add_incoming(&[(&fun_context.acc, back_edge)]);
+            lc_phi.add_incoming(&[(&fun_context.lc, back_edge)]);
+            flags_phi.add_incoming(&[(&fun_context.flags, back_edge)]);
+            executed_phi.add_incoming(&[(&executed, back_edge)]);
+            cycles_phi.add_incoming(&[(&fun_context.cycles, back_edge)]);
+        }
+
+        // Leaving the loop: either the loop is over, the budget is, an
+        // interrupt is due or the host asked to stop
+        self.builder.position_at_end(exit_bb);
+        let next_pc =
+            self.builder
+                .build_int_nuw_add(fun_context.pc, pc_type.const_int(1, false), "");
+        fun_context.pc = self
+            .builder
+            .build_select(taken, head_pc, next_pc, "")
+            .into_int_value();
+        drop(guard);
+
+        self.setup_epilogue(executed);
     }
 
-    fn back7(&self) {
-        let fun_context = self.fun_context.borrow();
-        let fun_context = fun_context.as_ref().unwrap();
-        let i32_type = self.module.get_context().i32_type();
+    /// Loads the stop flag of the host, returning whether the guest may go
+    /// on running.
+    fn build_stop_poll(&self, stop_ptr: PointerValue<'ctx>) -> IntValue<'ctx> {
+        // The flag is shared with other threads: the load must not be hoisted
+        let stop = self.builder.build_load(stop_ptr, "stop");
+        let load = stop.as_instruction_value().unwrap();
+        load.set_atomic_ordering(AtomicOrdering::Monotonic).unwrap();
+        load.set_alignment(1).unwrap();
+        self.builder.build_int_compare(
+            inkwell::IntPredicate::EQ,
+            stop.into_int_value(),
+            self.module.get_context().i8_type().const_zero(),
+            "",
+        )
+    }
 
-        let zero = i32_type.const_zero();
-        let one = i32_type.const_int(1, false);
-        let six = i32_type.const_int(6, false);
+    /// Emits the counted loop `counted` in closed form: the registers are
+    /// computed from the number of iterations, which is the trip count
+    /// unless the budget, the timer interrupt or the host stops the loop
+    /// earlier, as `CountedLoop::execute` does.
+    fn build_counted_loop(&self, counted: &CountedLoop) {
+        let context = self.module.get_context();
+        let i32_type = context.i32_type();
+        let i64_type = context.i64_type();
+        let one = i64_type.const_int(1, false);
+        let unlimited = i64_type.const_all_ones();
+        let unsigned_min = |lhs: IntValue<'ctx>, rhs: IntValue<'ctx>| {
+            let less = self
+                .builder
+                .build_int_compare(inkwell::IntPredicate::ULT, lhs, rhs, "");
+            self.builder
+                .build_select(less, lhs, rhs, "")
+                .into_int_value()
+        };
 
-        let lc_ptr = self
+        let mut guard = self.fun_context.borrow_mut();
+        let fun_context = guard.as_mut().unwrap();
+        let instructions = i64_type.const_int(counted.instructions(), false);
+        let cycles = counted.cycles(&self.options.costs);
+
+        // The body runs once whatever LC holds, then again as long as BACK7
+        // leaves it positive
+        let remaining_lc =
+            self.builder
+                .build_int_sub(fun_context.lc, i32_type.const_int(1, false), "");
+        let positive = self.builder.build_int_compare(
+            inkwell::IntPredicate::SGT,
+            remaining_lc,
+            i32_type.const_zero(),
+            "",
+        );
+        let remaining_lc = self
             .builder
-            .build_load(fun_context.lc_ptr, "")
-            .into_pointer_value();
-        let lc_val = self.builder.build_load(lc_ptr, "").into_int_value();
-        let dec_lc_val = self.builder.build_int_nsw_sub(lc_val, one, "");
-        self.builder.build_store(lc_ptr, dec_lc_val);
+            .build_select(positive, remaining_lc, i32_type.const_zero(), "")
+            .into_int_value();
+        let remaining_lc = self.builder.build_int_z_extend(remaining_lc, i64_type, "");
+        let trips = self.builder.build_int_nuw_add(remaining_lc, one, "trips");
 
-        let pc_ptr = self
+        // The first iteration fits the budget, the next ones as long as they do
+        let budget = self
+            .builder
+            .build_int_nuw_sub(fun_context.budget, fun_context.executed, "");
+        let fitting = self
+            .builder
+            .build_int_unsigned_div(budget, instructions, "");
+        let empty = self.builder.build_int_compare(
+            inkwell::IntPredicate::EQ,
+            fitting,
+            i64_type.const_zero(),
+            "",
+        );
+        let fitting = self
             .builder
-            .build_load(fun_context.pc_ptr, "")
-            .into_pointer_value();
-        let pc_val = self.builder.build_load(pc_ptr, "").into_int_value();
+            .build_select(empty, one, fitting, "")
+            .into_int_value();
 
-        // if.then
-        let then_bb = self
-            .module
-            .get_context()
-            .append_basic_block(fun_context.function, "if.then");
-        let else_bb = self
-            .module
-            .get_context()
-            .append_basic_block(fun_context.function, "if.else");
-        let cont_bb = self
-            .module
-            .get_context()
-            .append_basic_block(fun_context.function, "if.cont");
+        // Iterations until the cycles reach the timer compare value
+        let timecmp_ptr = self
+            .builder
+            .build_struct_gep(fun_context.cpu_ptr, 9, "")
+            .unwrap();
+        let entry_cycles = self
+            .builder
+            .build_load(fun_context.cycles_ptr, "")
+            .into_int_value();
+        let timecmp = self
+            .builder
+            .build_load(timecmp_ptr, "timecmp")
+            .into_int_value();
+        let now = self
+            .builder
+            .build_int_nuw_add(entry_cycles, fun_context.cycles, "");
+        let on_time = self
+            .builder
+            .build_int_compare(inkwell::IntPredicate::ULT, now, timecmp, "");
+        let due = match cycles {
+            0 => unlimited,
+            _ => {
+                let cycles = i64_type.const_int(cycles, false);
+                let gap = self.builder.build_int_sub(timecmp, now, "");
+                let whole = self.builder.build_int_unsigned_div(gap, cycles, "");
+                let rest = self.builder.build_int_unsigned_rem(gap, cycles, "");
+                let partial = self.builder.build_int_compare(
+                    inkwell::IntPredicate::NE,
+                    rest,
+                    i64_type.const_zero(),
+                    "",
+                );
+                let partial = self.builder.build_int_z_extend(partial, i64_type, "");
+                self.builder.build_int_nuw_add(whole, partial, "")
+            }
+        };
+        let due = self
+            .builder
+            .build_select(on_time, due, one, "")
+            .into_int_value();
 
-        let icmp = self
+        let running = self.build_stop_poll(fun_context.stop_ptr);
+        let stopped = self
             .builder
-            .build_int_compare(inkwell::IntPredicate::SGT, dec_lc_val, zero, "");
+            .build_select(running, unlimited, one, "")
+            .into_int_value();
+
+        let iterations = unsigned_min(unsigned_min(trips, fitting), unsigned_min(due, stopped));
+
+        // Both registers wrap, only the iterations modulo 2^32 matter
+        let count = self.builder.build_int_truncate(iterations, i32_type, "");
+        let step = i32_type.const_int(counted.step() as i64 as u64, true);
+        let added = self.builder.build_int_mul(count, step, "");
+        let acc = self.builder.build_int_add(fun_context.acc, added, "");
+        // The flags are the ones of the last addition of the body
+        let last = counted.last();
+        let before =
+            self.builder
+                .build_int_sub(acc, i32_type.const_int(last as i64 as u64, true), "");
+        let predicate = if last < 0 {
+            inkwell::IntPredicate::SGT
+        } else {
+            inkwell::IntPredicate::SLT
+        };
+        let overflow = self.builder.build_int_compare(predicate, acc, before, "");
+        let zero = self.builder.build_int_compare(
+            inkwell::IntPredicate::EQ,
+            acc,
+            i32_type.const_zero(),
+            "",
+        );
+        let negative = self.builder.build_int_compare(
+            inkwell::IntPredicate::SLT,
+            acc,
+            i32_type.const_zero(),
+            "",
+        );
+        let flags = [
+            (zero, FLAG_ZERO),
+            (negative, FLAG_NEGATIVE),
+            (overflow, FLAG_OVERFLOW),
+        ]
+        .into_iter()
+        .map(|(condition, flag)| self.build_flag(condition, flag))
+        .reduce(|flags, flag| self.builder.build_or(flags, flag, ""))
+        .unwrap();
+
+        fun_context.acc = acc;
+        fun_context.flags = flags;
+        fun_context.lc = self.builder.build_int_sub(fun_context.lc, count, "");
+
+        // The loop is over once every trip ran, the Cpu stays at the header otherwise
+        let over = self
+            .builder
+            .build_int_compare(inkwell::IntPredicate::EQ, iterations, trips, "");
+        let next_pc = self.builder.build_int_nuw_add(
+            fun_context.pc,
+            self.pc_type().const_int(counted.length() as u64, false),
+            "",
+        );
+        fun_context.pc = self
+            .builder
+            .build_select(over, next_pc, fun_context.pc, "")
+            .into_int_value();
+
+        let looped = self.builder.build_int_mul(iterations, instructions, "");
+        let executed = self
+            .builder
+            .build_int_nuw_add(fun_context.executed, looped, "");
+        let looped_cycles =
+            self.builder
+                .build_int_mul(iterations, i64_type.const_int(cycles, false), "");
+        fun_context.cycles = self
+            .builder
+            .build_int_nuw_add(fun_context.cycles, looped_cycles, "");
+        drop(guard);
+
+        self.setup_epilogue(executed);
+    }
+
+    /// Attaches the `prof` metadata telling LLVM how often the conditional
+    /// `branch` goes to its first successor rather than to its second.
+    fn set_branch_weights(&self, branch: InstructionValue<'ctx>, counts: BranchCounts) {
+        if counts.taken == 0 && counts.not_taken == 0 {
+            return;
+        }
+
+        // Weights are 32 bits wide, only their ratio matters
+        let scale = counts.taken.max(counts.not_taken) / u32::MAX as u64 + 1;
+        let context = self.module.get_context();
+        let weight = |count: u64| context.i32_type().const_int(count / scale, false).into();
+        let weights = context.metadata_node(&[
+            context.metadata_string("branch_weights").into(),
+            weight(counts.taken),
+            weight(counts.not_taken),
+        ]);
+        if let Err(e) = branch.set_metadata(weights, context.get_kind_id("prof")) {
+            tracing::warn!("wasn't capable to weigh the loop branch: {}", e);
+        }
+    }
+
+    fn build_advance_program_counter(&self, fun_context: &mut FunctionContext<'ctx>, length: u64) {
+        let length = self.pc_type().const_int(length, false);
+        fun_context.pc = self.builder.build_int_nuw_add(fun_context.pc, length, "");
+    }
+
+    /// Adds `value` to the accumulator and sets the flags from the result
+    /// as the interpreter does.
+    fn build_add_to_acc(
+        &self,
+        fun_context: &mut FunctionContext<'ctx>,
+        instr: Instruction,
+        value: i32,
+    ) {
+        let i32_type = self.module.get_context().i32_type();
+        let value_int = i32_type.const_int(value as i64 as u64, true);
+        let wrapped = self.builder.build_int_add(fun_context.acc, value_int, "");
+
+        // Adding a constant overflows when the result moves the wrong way
+        let predicate = if value < 0 {
+            inkwell::IntPredicate::SGT
+        } else {
+            inkwell::IntPredicate::SLT
+        };
+        let overflow = self
+            .builder
+            .build_int_compare(predicate, wrapped, fun_context.acc, "");
+        let result = self.build_overflow(fun_context, instr, fun_context.acc, wrapped, overflow);
+        let zero = self.builder.build_int_compare(
+            inkwell::IntPredicate::EQ,
+            result,
+            i32_type.const_zero(),
+            "",
+        );
+        let negative = self.builder.build_int_compare(
+            inkwell::IntPredicate::SLT,
+            result,
+            i32_type.const_zero(),
+            "",
+        );
+
+        let flags = [
+            (zero, FLAG_ZERO),
+            (negative, FLAG_NEGATIVE),
+            (overflow, FLAG_OVERFLOW),
+        ]
+        .into_iter()
+        .map(|(condition, flag)| self.build_flag(condition, flag))
+        .reduce(|flags, flag| self.builder.build_or(flags, flag, ""))
+        .unwrap();
+
+        fun_context.acc = result;
+        fun_context.flags = flags;
+    }
+
+    /// Applies the overflow mode to the `wrapped` result of an operation
+    /// whose left operand is `lhs`, returning the value to keep. Trapping
+    /// overflows leave the block through the host, which faults on `instr`.
+    fn build_overflow(
+        &self,
+        fun_context: &FunctionContext<'ctx>,
+        instr: Instruction,
+        lhs: IntValue<'ctx>,
+        wrapped: IntValue<'ctx>,
+        overflow: IntValue<'ctx>,
+    ) -> IntValue<'ctx> {
+        let context = self.module.get_context();
+        let i32_type = context.i32_type();
+
+        match self.options.overflow_mode {
+            OverflowMode::Wrapping => wrapped,
+            OverflowMode::Saturating => {
+                // Only results of the sign of `lhs` can overflow
+                let negative = self.builder.build_int_compare(
+                    inkwell::IntPredicate::SLT,
+                    lhs,
+                    i32_type.const_zero(),
+                    "",
+                );
+                let min = i32_type.const_int(i32::MIN as i64 as u64, true);
+                let max = i32_type.const_int(i32::MAX as u64, false);
+                let saturated = self.builder.build_select(negative, min, max, "");
+                self.builder
+                    .build_select(overflow, saturated.into_int_value(), wrapped, "")
+                    .into_int_value()
+            }
+            OverflowMode::Trapping => {
+                let trap_bb = context.append_basic_block(fun_context.function, "overflow");
+                let cont_bb = context.append_basic_block(fun_context.function, "no_overflow");
+                self.builder
+                    .build_conditional_branch(overflow, trap_bb, cont_bb);
+
+                self.builder.position_at_end(trap_bb);
+                self.build_exit_to_host(fun_context, instr);
+
+                self.builder.position_at_end(cont_bb);
+                wrapped
+            }
+        }
+    }
+
+    /// The flags byte with `flag` set when `condition` holds.
+    fn build_flag(&self, condition: IntValue<'ctx>, flag: u8) -> IntValue<'ctx> {
+        let i8_type = self.module.get_context().i8_type();
         self.builder
-            .build_conditional_branch(icmp, then_bb, else_bb);
+            .build_select(
+                condition,
+                i8_type.const_int(flag as u64, false),
+                i8_type.const_zero(),
+                "",
+            )
+            .into_int_value()
+    }
 
-        // then block
-        self.builder.position_at_end(then_bb);
+    fn read_register(&self, fun_context: &FunctionContext<'ctx>, index: usize) -> IntValue<'ctx> {
+        match index {
+            0 => fun_context.acc,
+            1 => fun_context.lc,
+            _ => self
+                .builder
+                .build_load(fun_context.gpr_ptrs[index - 2], "")
+                .into_int_value(),
+        }
+    }
 
-        let dec_pc_six = self.builder.build_int_nuw_sub(pc_val, six, "");
+    fn write_register(
+        &self,
+        fun_context: &mut FunctionContext<'ctx>,
+        index: usize,
+        value: IntValue<'ctx>,
+    ) {
+        match index {
+            0 => fun_context.acc = value,
+            1 => fun_context.lc = value,
+            _ => {
+                self.builder
+                    .build_store(fun_context.gpr_ptrs[index - 2], value);
+            }
+        }
+    }
 
-        self.builder.build_unconditional_branch(cont_bb);
+    /// The wrapped result of ADD or SUB and whether it overflowed, i.e. it
+    /// has not the sign of `lhs` when `rhs` pushed it past the bounds.
+    fn build_overflowing(
+        &self,
+        op: ArithOp,
+        lhs: IntValue<'ctx>,
+        rhs: IntValue<'ctx>,
+    ) -> (IntValue<'ctx>, IntValue<'ctx>) {
+        let (result, sign_bits) = match op {
+            ArithOp::Add => {
+                let result = self.builder.build_int_add(lhs, rhs, "");
+                let lhs_flipped = self.builder.build_xor(lhs, result, "");
+                let rhs_flipped = self.builder.build_xor(rhs, result, "");
+                (result, self.builder.build_and(lhs_flipped, rhs_flipped, ""))
+            }
+            ArithOp::Sub => {
+                let result = self.builder.build_int_sub(lhs, rhs, "");
+                let signs_differ = self.builder.build_xor(lhs, rhs, "");
+                let lhs_flipped = self.builder.build_xor(lhs, result, "");
+                (
+                    result,
+                    self.builder.build_and(signs_differ, lhs_flipped, ""),
+                )
+            }
+        };
+        let overflow = self.builder.build_int_compare(
+            inkwell::IntPredicate::SLT,
+            sign_bits,
+            self.module.get_context().i32_type().const_zero(),
+            "",
+        );
+        (result, overflow)
+    }
 
-        // else block
-        self.builder.position_at_end(else_bb);
-        let inc_pc_one = self.builder.build_int_nuw_add(pc_val, one, "");
-        self.builder.build_unconditional_branch(cont_bb);
+    /// Jumps to `target` when `condition` holds, moves past the branch of
+    /// `length` bytes otherwise.
+    fn build_conditional_jump(
+        &self,
+        fun_context: &mut FunctionContext<'ctx>,
+        condition: Condition,
+        target: mir::Target,
+        length: usize,
+    ) {
+        let pc_type = self.pc_type();
+        let taken = self.build_condition(fun_context, condition);
+        let target = match target {
+            mir::Target::Absolute(address) => pc_type.const_int(address as u64, false),
+            mir::Target::Back(distance) => {
+                let distance = pc_type.const_int(distance as u64, false);
+                self.builder.build_int_sub(fun_context.pc, distance, "")
+            }
+        };
+        let next = self.builder.build_int_nuw_add(
+            fun_context.pc,
+            pc_type.const_int(length as u64, false),
+            "",
+        );
+        fun_context.pc = self
+            .builder
+            .build_select(taken, target, next, "")
+            .into_int_value();
+    }
+
+    /// Leaves the code through a side exit at `exit` unless `condition` is
+    /// `expected`, the code then goes on at `next`. The exit tells the engine
+    /// why, once the registers are written back.
+    fn build_guard(
+        &self,
+        fun_context: &mut FunctionContext<'ctx>,
+        condition: Condition,
+        expected: bool,
+        exit: usize,
+        next: usize,
+        reason: DeoptReason,
+    ) {
+        let context = self.module.get_context();
+        let pc_type = self.pc_type();
+        let holds = self.build_condition(fun_context, condition);
+        let on_trace = match expected {
+            true => holds,
+            false => self.builder.build_not(holds, ""),
+        };
 
-        // cont block
-        self.builder.position_at_end(cont_bb);
-        let phi = self.builder.build_phi(i32_type, "");
-        phi.add_incoming(&[(&dec_pc_six, then_bb), (&inc_pc_one, else_bb)]);
-        // Store the new program counter
+        let exit_bb = context.append_basic_block(fun_context.function, "side_exit");
+        let trace_bb = context.append_basic_block(fun_context.function, "trace");
         self.builder
-            .build_store(pc_ptr, phi.as_basic_value().into_int_value());
+            .build_conditional_branch(on_trace, trace_bb, exit_bb);
+
+        self.builder.position_at_end(exit_bb);
+        fun_context.pc = pc_type.const_int(exit as u64, false);
+        self.build_store_registers(fun_context);
+        self.builder.build_call(
+            fun_context.deopt_function,
+            &[
+                fun_context.cpu_ptr.into(),
+                fun_context.memory_ptr.into(),
+                context.i8_type().const_int(reason as u64, false).into(),
+            ],
+            "",
+        );
+        self.build_retire(fun_context, fun_context.executed, fun_context.cycles);
+        self.builder.build_return(Some(&fun_context.executed));
+
+        self.builder.position_at_end(trace_bb);
+        fun_context.pc = pc_type.const_int(next as u64, false);
+    }
+
+    fn build_condition(
+        &self,
+        fun_context: &FunctionContext<'ctx>,
+        condition: Condition,
+    ) -> IntValue<'ctx> {
+        let context = self.module.get_context();
+        let compare = |predicate, register: Register| {
+            let value = self.read_register(fun_context, register);
+            self.builder
+                .build_int_compare(predicate, value, context.i32_type().const_zero(), "")
+        };
+
+        match condition {
+            Condition::Zero(register) => compare(inkwell::IntPredicate::EQ, register),
+            Condition::NonZero(register) => compare(inkwell::IntPredicate::NE, register),
+            Condition::Positive(register) => compare(inkwell::IntPredicate::SGT, register),
+            Condition::Equal(register, value) => {
+                let value = context.i32_type().const_int(value as i64 as u64, true);
+                let register = self.read_register(fun_context, register);
+                self.builder
+                    .build_int_compare(inkwell::IntPredicate::EQ, register, value, "")
+            }
+            Condition::Flag(flag, set) => {
+                let i8_type = context.i8_type();
+                let masked = self.builder.build_and(
+                    fun_context.flags,
+                    i8_type.const_int(flag as u64, false),
+                    "",
+                );
+                let predicate = if set {
+                    inkwell::IntPredicate::NE
+                } else {
+                    inkwell::IntPredicate::EQ
+                };
+                self.builder
+                    .build_int_compare(predicate, masked, i8_type.const_zero(), "")
+            }
+        }
+    }
+}
+
+impl Drop for TranslationContext<'_> {
+    fn drop(&mut self) {
+        // Modules that failed to build were never added
+        let _ = self.execution_engine.remove_module(&self.module);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{program::generator, tests::init, EmulationEngine};
+
+    #[test]
+    pub fn blocks_share_an_execution_engine() {
+        init();
+        let prog = generator::generate(2_000, 3, [1, 9, 1, 5, 5]);
+        let build = || {
+            let mut vm = EmulationEngine::builder()
+                .compile_threshold(1)
+                .cache_size(4)
+                .build()
+                .unwrap();
+            vm.load_program(prog.program.clone()).unwrap();
+            vm
+        };
+
+        // Evicted blocks remove their module from the execution engine of
+        // their backend, which keeps running the others, and the blocks
+        // compiled again get a module and a function of their own
+        let mut engines = [build(), build()];
+        while engines.iter().any(|vm| !vm.cpu.halt) {
+            for vm in engines.iter_mut().filter(|vm| !vm.cpu.halt) {
+                vm.run_for(1_000).unwrap();
+            }
+        }
+        for vm in engines {
+            assert_eq!(vm.cpu.acc, prog.expected_acc);
+            assert_eq!(vm.cpu.instret, prog.expected_instret);
+            assert!(vm.cache_stats().evictions > 0);
+            assert!(vm.compile_stats().len() > 4);
+        }
     }
 }
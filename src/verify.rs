@@ -0,0 +1,173 @@
+use std::{collections::BTreeSet, fmt::Display, ops::Range};
+
+use crate::{
+    bus::Bus,
+    cpu::{Cpu, Instruction, OpCode},
+    decoder::decode,
+    error::VmError,
+    host::HostCalls,
+    memory::{Addressable, MemoryPort},
+    timing::CostTable,
+};
+
+/// A native block which did not do what the interpreter did from the same
+/// state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub pc: usize,
+    pub opcodes: Vec<OpCode>,
+    pub registers: Vec<(&'static str, i64, i64)>, // Name, value from the interpreter, from the block
+    pub memory: Vec<usize>,                       // Addresses holding different bytes
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "block at {:#04x} {:?}", self.pc, self.opcodes)?;
+        for (name, expected, actual) in &self.registers {
+            write!(f, ", {} is {} instead of {}", name, actual, expected)?;
+        }
+        if !self.memory.is_empty() {
+            write!(f, ", memory differs at {:#04x?}", self.memory)?;
+        }
+        Ok(())
+    }
+}
+
+/// A copy of the guest taken before running a native block, on which the
+/// interpreter replays the block to check its outcome.
+pub(crate) struct Shadow {
+    cpu: Cpu,
+    memory: ShadowMemory,
+}
+
+impl Shadow {
+    pub fn new(cpu: &Cpu, bus: &Bus) -> Result<Self, VmError> {
+        Ok(Self {
+            cpu: *cpu,
+            memory: ShadowMemory {
+                data: (0..bus.size())
+                    .map(|address| bus.read(address))
+                    .collect::<Result<_, _>>()?,
+                stack: bus.stack(),
+            },
+        })
+    }
+
+    /// Interprets the `executed` instructions run by `block`, then compares
+    /// the Cpu and the bytes written by either side with the real ones.
+    /// Devices are seen as plain memory by the shadow, so they are left out.
+    pub fn verify(
+        mut self,
+        block: &[Instruction],
+        executed: u64,
+        cpu: &Cpu,
+        bus: &Bus,
+        written: &[usize],
+        costs: &CostTable,
+    ) -> Result<(), VmError> {
+        let pc = self.cpu.pc;
+        let mut host_calls = HostCalls::new();
+        let mut port = MemoryPort::new(&mut self.memory, &mut host_calls);
+        for _ in 0..executed {
+            // A fault shows up as diverging registers
+            let Ok(instr) = decode(&port, self.cpu.pc) else {
+                break;
+            };
+            if self.cpu.execute(instr, &mut port).is_err() {
+                break;
+            }
+            self.cpu.instret += 1;
+            self.cpu.cycles += costs.cost(instr.opcode);
+        }
+        let (shadow_written, ..) = port.into_parts();
+
+        let registers = diff(&self.cpu, cpu);
+        let memory: Vec<usize> = written
+            .iter()
+            .chain(&shadow_written)
+            .copied()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .filter(|&address| !bus.is_mapped(address))
+            .filter(|&address| self.memory.read(address).ok() != bus.read(address).ok())
+            .collect();
+
+        if registers.is_empty() && memory.is_empty() {
+            return Ok(());
+        }
+        Err(VmError::VerificationMismatch(Box::new(Mismatch {
+            pc,
+            opcodes: block.iter().map(|instr| instr.opcode).collect(),
+            registers,
+            memory,
+        })))
+    }
+}
+
+/// The registers of `actual` differing from `expected`, the trap state and
+/// the configuration of the Cpu are never touched by native code.
+fn diff(expected: &Cpu, actual: &Cpu) -> Vec<(&'static str, i64, i64)> {
+    let registers = |cpu: &Cpu| {
+        [
+            ("acc", cpu.acc as i64),
+            ("lc", cpu.lc as i64),
+            ("pc", cpu.pc as i64),
+            ("halt", cpu.halt as i64),
+            ("sp", cpu.sp as i64),
+            ("r2", cpu.gpr[0] as i64),
+            ("r3", cpu.gpr[1] as i64),
+            ("r4", cpu.gpr[2] as i64),
+            ("r5", cpu.gpr[3] as i64),
+            ("r6", cpu.gpr[4] as i64),
+            ("r7", cpu.gpr[5] as i64),
+            ("flags", cpu.flags as i64),
+            ("instret", cpu.instret as i64),
+            ("cycles", cpu.cycles as i64),
+        ]
+    };
+
+    registers(expected)
+        .into_iter()
+        .zip(registers(actual))
+        .filter(|((_, expected), (_, actual))| expected != actual)
+        .map(|((name, expected), (_, actual))| (name, expected, actual))
+        .collect()
+}
+
+struct ShadowMemory {
+    data: Vec<u8>,
+    stack: Range<usize>,
+}
+
+impl Addressable<u8> for ShadowMemory {
+    fn read(&self, address: usize) -> Result<u8, VmError> {
+        self.data
+            .get(address)
+            .copied()
+            .ok_or(VmError::MemoryOutOfBounds { address })
+    }
+
+    fn write(&mut self, address: usize, value: u8) -> Result<(), VmError> {
+        let cell = self
+            .data
+            .get_mut(address)
+            .ok_or(VmError::MemoryOutOfBounds { address })?;
+        *cell = value;
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, chunk: Vec<u8>) -> Result<(), VmError> {
+        for (address, value) in chunk.into_iter().enumerate() {
+            self.write(address, value)?;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    fn stack(&self) -> Range<usize> {
+        self.stack.clone()
+    }
+}
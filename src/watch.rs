@@ -0,0 +1,268 @@
+use std::{collections::BTreeMap, fmt::Display, ops::Range, str::FromStr};
+
+use crate::{
+    cpu::{Cpu, Instruction, OpCode, REGISTER_COUNT},
+    error::VmError,
+};
+
+/// How a register watchpoint compares the register with its value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+const COMPARISONS: [(&str, Comparison); 6] = [
+    ("==", Comparison::Equal),
+    ("!=", Comparison::NotEqual),
+    ("<", Comparison::Less),
+    ("<=", Comparison::LessOrEqual),
+    (">", Comparison::Greater),
+    (">=", Comparison::GreaterOrEqual),
+];
+
+impl Comparison {
+    pub fn holds<T: Ord>(self, left: T, right: T) -> bool {
+        match self {
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+            Comparison::Less => left < right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterOrEqual => left >= right,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        COMPARISONS.iter().find(|(_, c)| *c == self).unwrap().0
+    }
+
+    /// The comparison written `symbol`, as in `<=`.
+    pub fn from_symbol(symbol: &str) -> Option<Self> {
+        COMPARISONS
+            .iter()
+            .find(|(s, _)| *s == symbol)
+            .map(|(_, comparison)| *comparison)
+    }
+}
+
+/// What stops the guest after the instruction triggering it, see
+/// `EmulationEngine::add_watchpoint`.
+///
+/// Watchpoints parse from text: `acc > 1000` compares a register, named
+/// `acc`, `lc` or `r2` to `r7`, with a value, and `write 0x2000..0x2100`
+/// watches the stores to a range of addresses, `write 0x2000` to a single
+/// one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Watchpoint {
+    // Fires once the comparison starts holding, by register index
+    Register {
+        index: usize,
+        comparison: Comparison,
+        value: i32,
+    },
+    Write(Range<usize>), // Fires on every store to the addresses, the stack's included
+}
+
+impl Watchpoint {
+    /// Whether running `instr` may fire the watchpoint. Compiled code does
+    /// not check watchpoints, so blocks holding such instructions are
+    /// interpreted instead.
+    pub fn may_fire(&self, instr: Instruction) -> bool {
+        let writes_register = |index: usize| match instr.opcode {
+            _ if instr.opcode.has_register_operands() => instr.registers().0 == index,
+            OpCode::HCALL => true,
+            OpCode::SETL | OpCode::BACK7 => index == 1,
+            OpCode::CLRA
+            | OpCode::INC3A
+            | OpCode::DECA
+            | OpCode::ADDI
+            | OpCode::LI
+            | OpCode::LDA
+            | OpCode::POP
+            | OpCode::RDTIME => index == 0,
+            _ => false,
+        };
+        match self {
+            Watchpoint::Register { index, .. } => writes_register(*index),
+            Watchpoint::Write(range) => match instr.opcode {
+                OpCode::STA => range.contains(&(instr.operand as usize)),
+                OpCode::PUSH | OpCode::CALL | OpCode::HCALL => true,
+                _ => false,
+            },
+        }
+    }
+
+    /// Whether the register comparison holds on `cpu`, false for writes.
+    fn holds(&self, cpu: &Cpu) -> bool {
+        match self {
+            Watchpoint::Register {
+                index,
+                comparison,
+                value,
+            } => comparison.holds(cpu.register(*index), *value),
+            Watchpoint::Write(_) => false,
+        }
+    }
+}
+
+impl Display for Watchpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Watchpoint::Register {
+                index,
+                comparison,
+                value,
+            } => write!(
+                f,
+                "{} {} {}",
+                register_name(*index),
+                comparison.symbol(),
+                value
+            ),
+            Watchpoint::Write(range) => {
+                write!(f, "write {:#06x}..{:#06x}", range.start, range.end)
+            }
+        }
+    }
+}
+
+impl FromStr for Watchpoint {
+    type Err = VmError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || VmError::InvalidWatchpoint(s.trim().to_string());
+        let words: Vec<&str> = s.split_whitespace().collect();
+        match words[..] {
+            ["write", range] => {
+                let address = |text| {
+                    number(text)
+                        .and_then(|address| usize::try_from(address).ok())
+                        .ok_or_else(invalid)
+                };
+                let range = match range.split_once("..") {
+                    Some((start, end)) => address(start)?..address(end)?,
+                    None => address(range)?..address(range)? + 1,
+                };
+                match range.is_empty() {
+                    true => Err(invalid()),
+                    false => Ok(Watchpoint::Write(range)),
+                }
+            }
+            [register, symbol, value] => {
+                let comparison = Comparison::from_symbol(symbol).ok_or_else(invalid)?;
+                let value = number(value)
+                    .and_then(|value| i32::try_from(value).ok())
+                    .ok_or_else(invalid)?;
+                Ok(Watchpoint::Register {
+                    index: register_index(register).ok_or_else(invalid)?,
+                    comparison,
+                    value,
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// The index of the register named `name`, as in `acc`, `lc` or `r2`.
+pub(crate) fn register_index(name: &str) -> Option<usize> {
+    let index = match name.to_ascii_lowercase().as_str() {
+        "a" | "acc" => Some(0),
+        "l" | "lc" => Some(1),
+        name => name.strip_prefix('r').and_then(|index| index.parse().ok()),
+    };
+    index.filter(|&index| index < REGISTER_COUNT)
+}
+
+pub(crate) fn register_name(index: usize) -> String {
+    match index {
+        0 => "acc".to_string(),
+        1 => "lc".to_string(),
+        _ => format!("r{}", index),
+    }
+}
+
+/// A decimal, or `0x` prefixed hexadecimal, number, possibly negative.
+pub(crate) fn number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    }
+    .ok()?;
+    Some(if negative { -value } else { value })
+}
+
+/// A watchpoint that fired, after the instruction at `pc` ran.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchpointHit {
+    pub id: usize,
+    pub pc: usize,
+}
+
+/// The watchpoints of an engine, by id, with whether their comparison held
+/// when last checked.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Watchpoints {
+    watchpoints: BTreeMap<usize, (Watchpoint, bool)>,
+    next_id: usize,
+}
+
+impl Watchpoints {
+    /// Adds `watchpoint`, returning its id. A comparison holding on `cpu`
+    /// already only fires once it stopped holding.
+    pub fn add(&mut self, watchpoint: Watchpoint, cpu: &Cpu) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        let held = watchpoint.holds(cpu);
+        self.watchpoints.insert(id, (watchpoint, held));
+        id
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Watchpoint> {
+        self.watchpoints
+            .remove(&id)
+            .map(|(watchpoint, _)| watchpoint)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Watchpoint)> {
+        self.watchpoints
+            .iter()
+            .map(|(id, (watchpoint, _))| (*id, watchpoint))
+    }
+
+    /// Whether running `block` may fire a watchpoint.
+    pub fn watch(&self, block: &[Instruction]) -> bool {
+        self.iter()
+            .any(|(_, watchpoint)| block.iter().any(|instr| watchpoint.may_fire(*instr)))
+    }
+
+    /// Checks the watchpoints after the instruction at `pc` ran, storing to
+    /// `written`, returning the first that fired.
+    pub fn check(&mut self, pc: usize, cpu: &Cpu, written: &[usize]) -> Option<WatchpointHit> {
+        let mut hit = None;
+        for (id, (watchpoint, held)) in self.watchpoints.iter_mut() {
+            let fired = match watchpoint {
+                Watchpoint::Register { .. } => {
+                    let holds = watchpoint.holds(cpu);
+                    let fired = holds && !*held;
+                    *held = holds;
+                    fired
+                }
+                Watchpoint::Write(range) => written.iter().any(|address| range.contains(address)),
+            };
+            if fired && hit.is_none() {
+                hit = Some(WatchpointHit { id: *id, pc });
+            }
+        }
+        hit
+    }
+}
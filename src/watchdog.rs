@@ -0,0 +1,39 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// A thread setting the stop flag of the engine once a run goes past its
+/// deadline, so that the guest stops like the host asked it to: between
+/// blocks, or at the next safepoint of a native loop.
+pub struct Watchdog {
+    cancel: Sender<()>,
+    handle: JoinHandle<bool>, // Whether the deadline passed
+}
+
+impl Watchdog {
+    /// Starts counting `deadline` down, to set `stop` once it is over.
+    pub fn arm(deadline: Duration, stop: Arc<AtomicBool>) -> Self {
+        let (cancel, cancelled) = mpsc::channel();
+        let handle = thread::spawn(move || match cancelled.recv_timeout(deadline) {
+            Err(RecvTimeoutError::Timeout) => {
+                stop.store(true, Ordering::Relaxed);
+                true
+            }
+            _ => false,
+        });
+        Self { cancel, handle }
+    }
+
+    /// Stops counting down, returning whether the deadline passed, in which
+    /// case the stop flag was set.
+    pub fn disarm(self) -> bool {
+        let _ = self.cancel.send(());
+        self.handle.join().unwrap_or(false)
+    }
+}